@@ -0,0 +1,9 @@
+-- needed for querying stored messages
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS received_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS s3_prefix text;
+
+CREATE INDEX IF NOT EXISTS smtp_gateway_to_received_at_idx
+    ON data_gateways.smtp_gateway ("to", received_at);
+CREATE INDEX IF NOT EXISTS smtp_gateway_message_id_idx
+    ON data_gateways.smtp_gateway (message_id);
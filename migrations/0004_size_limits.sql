@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_size_limits (
+    -- recipient address or domain, lower case
+    address text PRIMARY KEY,
+    max_size bigint NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS size bigint,
+    ADD COLUMN IF NOT EXISTS declared_size bigint;
@@ -0,0 +1,3 @@
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS bucket text,
+    ADD COLUMN IF NOT EXISTS tags text[];
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_tenants (
+    -- recipient domain, lower case
+    domain text PRIMARY KEY,
+    bucket text,
+    prefix text,
+    webhook_url text,
+    max_size bigint,
+    retention_days integer
+);
+
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS tenant text,
+    ADD COLUMN IF NOT EXISTS expires_at timestamptz;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_quotas (
+    -- recipient address or tenant domain, lower case
+    key text PRIMARY KEY,
+    max_messages bigint,
+    max_bytes bigint
+);
+
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_usage (
+    key text NOT NULL,
+    day date NOT NULL,
+    messages bigint NOT NULL DEFAULT 0,
+    bytes bigint NOT NULL DEFAULT 0,
+    PRIMARY KEY (key, day)
+);
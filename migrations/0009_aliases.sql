@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_aliases (
+    -- recipient address or local part followed by `@`, lower case
+    alias text PRIMARY KEY,
+    canonical text NOT NULL
+);
+
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS canonical_rcpt text;
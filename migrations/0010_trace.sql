@@ -0,0 +1,2 @@
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS trace jsonb;
@@ -0,0 +1,3 @@
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS origin_ip text,
+    ADD COLUMN IF NOT EXISTS origin_host text;
@@ -0,0 +1,3 @@
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS kind text NOT NULL DEFAULT 'message',
+    ADD COLUMN IF NOT EXISTS report jsonb;
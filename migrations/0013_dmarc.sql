@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_dmarc_records (
+    id bigserial PRIMARY KEY,
+    message_id text NOT NULL,
+    org_name text NOT NULL,
+    email text,
+    report_id text NOT NULL,
+    date_begin timestamptz,
+    date_end timestamptz,
+    domain text NOT NULL,
+    policy text,
+    source_ip text NOT NULL,
+    count bigint NOT NULL,
+    disposition text NOT NULL,
+    dkim text,
+    spf text,
+    header_from text NOT NULL,
+    envelope_from text,
+    auth_results jsonb NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS smtp_gateway_dmarc_records_domain_idx
+    ON data_gateways.smtp_gateway_dmarc_records (domain, date_begin);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_tls_reports (
+    id bigserial PRIMARY KEY,
+    message_id text NOT NULL,
+    organization_name text NOT NULL,
+    contact_info text,
+    report_id text NOT NULL,
+    date_begin timestamptz NOT NULL,
+    date_end timestamptz NOT NULL,
+    policy_type text,
+    policy_domain text,
+    policy jsonb NOT NULL,
+    successful_count bigint NOT NULL,
+    failure_count bigint NOT NULL,
+    failure_details jsonb NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS smtp_gateway_tls_reports_domain_idx
+    ON data_gateways.smtp_gateway_tls_reports (policy_domain, date_begin);
@@ -0,0 +1,11 @@
+-- audit log of GDPR deletion requests
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_deletions (
+    id bigserial PRIMARY KEY,
+    address text NOT NULL,
+    anonymized boolean NOT NULL,
+    -- `api` or `cli`
+    requested_via text NOT NULL,
+    messages bigint NOT NULL,
+    objects bigint NOT NULL,
+    deleted_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+-- text extracted from PDF and DOCX attachments, for searching alongside the bodies
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS attachment_text text;
@@ -0,0 +1,6 @@
+-- for grouping replies
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS thread_id text;
+
+CREATE INDEX IF NOT EXISTS smtp_gateway_thread_id_idx
+    ON data_gateways.smtp_gateway (thread_id);
@@ -0,0 +1,7 @@
+-- to tell mailing list and automated mail from mail written by humans
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS list_id text,
+    ADD COLUMN IF NOT EXISTS list_unsubscribe text[],
+    ADD COLUMN IF NOT EXISTS auto_submitted text,
+    ADD COLUMN IF NOT EXISTS precedence text,
+    ADD COLUMN IF NOT EXISTS automated boolean;
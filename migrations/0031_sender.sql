@@ -0,0 +1,5 @@
+-- for phishing triage, see sender::Sender
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS header_from text,
+    ADD COLUMN IF NOT EXISTS reply_to text,
+    ADD COLUMN IF NOT EXISTS from_mismatch text[];
@@ -0,0 +1,17 @@
+-- messages accepted over SMTP and waiting to be stored, see queue::Queue
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_queue (
+    id bigserial PRIMARY KEY,
+    -- see queue::QueuedEnvelope
+    envelope jsonb NOT NULL,
+    data bytea NOT NULL,
+    queued_at timestamptz NOT NULL DEFAULT now(),
+    attempts integer NOT NULL DEFAULT 0,
+    -- pushed back while a worker stores the message, to retry it if the worker dies
+    next_attempt timestamptz NOT NULL DEFAULT now(),
+    last_error text,
+    -- retries are exhausted or the message was refused
+    dead boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX IF NOT EXISTS smtp_gateway_queue_next_attempt_idx
+    ON data_gateways.smtp_gateway_queue (next_attempt) WHERE NOT dead;
@@ -0,0 +1,20 @@
+-- status of every message and recipient as it is processed, see status::Status
+CREATE TABLE IF NOT EXISTS data_gateways.smtp_gateway_deliveries (
+    id bigserial PRIMARY KEY,
+    message_id text,
+    "from" text NOT NULL,
+    rcpt text NOT NULL,
+    -- received and stored, or the final notified, failed and quarantined
+    status text NOT NULL,
+    received_at timestamptz NOT NULL DEFAULT now(),
+    stored_at timestamptz,
+    notified_at timestamptz,
+    failed_at timestamptz,
+    quarantined_at timestamptz,
+    s3_prefix text,
+    error text
+);
+
+CREATE INDEX IF NOT EXISTS smtp_gateway_deliveries_pending_idx
+    ON data_gateways.smtp_gateway_deliveries (received_at)
+    WHERE status IN ('received', 'stored');
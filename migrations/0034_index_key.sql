@@ -0,0 +1,3 @@
+-- key of the index/ entry of the message, to delete it with the message
+ALTER TABLE data_gateways.smtp_gateway
+    ADD COLUMN IF NOT EXISTS index_key text;
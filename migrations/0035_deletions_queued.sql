@@ -0,0 +1,3 @@
+-- queued messages removed by a deletion request, including dead ones
+ALTER TABLE data_gateways.smtp_gateway_deletions
+    ADD COLUMN IF NOT EXISTS queued bigint NOT NULL DEFAULT 0;
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+use tracing::instrument;
+
+use crate::rules::glob_match;
+
+/// How the null reverse-path `MAIL FROM:<>` of bounces and other notifications is treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NullSender {
+    /// accept it regardless of the sender allowlists
+    #[default]
+    Allow,
+    /// match it as `<>` against `ALLOWED_FROMS` and `ALLOWED_PAIRS`
+    Check,
+    /// refuse it with a 550
+    Reject,
+}
+
+impl NullSender {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "allow" => Ok(NullSender::Allow),
+            "check" => Ok(NullSender::Check),
+            "reject" => Ok(NullSender::Reject),
+            _ => bail!("unknown null sender handling {}", s),
+        }
+    }
+}
+
+/// Senders allowed per recipient, evaluated without the DB. Read from a YAML file like
+///
+/// ```yaml
+/// inbox@example.org:
+///   - service@example.com
+///   - "*@trusted.example.com"
+/// ```
+///
+/// or given as `rcpt=from` pairs. Both sides are case-insensitive globs, recipients not
+/// matching any entry are not restricted.
+#[derive(Debug, Default)]
+pub struct PairAllowlist {
+    senders: Vec<(String, Vec<String>)>,
+}
+
+impl PairAllowlist {
+    #[instrument]
+    pub fn new(path: Option<&str>, pairs: Vec<(String, String)>) -> Result<Self> {
+        let mut allowlist = Self::default();
+        if let Some(path) = path {
+            let file = fs::read_to_string(path).context("could not read allowed pairs")?;
+            let file: HashMap<String, Vec<String>> =
+                serde_yaml::from_str(&file).context("could not parse allowed pairs")?;
+            allowlist.senders.extend(file);
+        }
+        for (rcpt, from) in pairs {
+            match allowlist.senders.iter_mut().find(|(r, _)| *r == rcpt) {
+                Some((_, froms)) => froms.push(from),
+                None => allowlist.senders.push((rcpt, vec![from])),
+            }
+        }
+        Ok(allowlist)
+    }
+
+    pub fn allows(&self, from: &str, rcpt: &str) -> bool {
+        let mut restricted = self
+            .senders
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, rcpt))
+            .peekable();
+        restricted.peek().is_none()
+            || restricted.any(|(_, froms)| froms.iter().any(|f| glob_match(f, from)))
+    }
+}
@@ -0,0 +1,311 @@
+/// Detection of password-protected archives, a common wrapper of malware to get it past
+/// scanners. Only the archive structure is read, nothing is decompressed.
+pub fn is_encrypted(content: &[u8]) -> bool {
+    if content.starts_with(b"PK\x03\x04") {
+        zip_encrypted(content)
+    } else if content.starts_with(b"7z\xbc\xaf\x27\x1c") {
+        seven_zip_encrypted(content)
+    } else if content.starts_with(b"Rar!\x1a\x07\x00") {
+        rar4_encrypted(&content[7..])
+    } else if content.starts_with(b"Rar!\x1a\x07\x01\x00") {
+        rar5_encrypted(&content[8..])
+    } else {
+        false
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    let bytes = data.get(pos..pos + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    let bytes = data.get(pos..pos + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Whether an entry of the central directory has the encryption flag, which is set for
+/// both ZipCrypto and AES.
+fn zip_encrypted(content: &[u8]) -> bool {
+    // the end of central directory record is 22 bytes and an up to 64 KiB comment
+    let search_start = content.len().saturating_sub(22 + 0xffff);
+    let Some(eocd) = (search_start..content.len().saturating_sub(21))
+        .rev()
+        .find(|&pos| content[pos..].starts_with(b"PK\x05\x06"))
+    else {
+        return false;
+    };
+    let (Some(entries), Some(offset)) = (u16_at(content, eocd + 10), u32_at(content, eocd + 16))
+    else {
+        return false;
+    };
+
+    let mut pos = offset as usize;
+    for _ in 0..entries {
+        if !content
+            .get(pos..)
+            .is_some_and(|entry| entry.starts_with(b"PK\x01\x02"))
+        {
+            return false;
+        }
+        let (Some(flags), Some(name_len), Some(extra_len), Some(comment_len)) = (
+            u16_at(content, pos + 8),
+            u16_at(content, pos + 28),
+            u16_at(content, pos + 30),
+            u16_at(content, pos + 32),
+        ) else {
+            return false;
+        };
+        if flags & 1 != 0 {
+            return true;
+        }
+        pos += 46 + name_len as usize + extra_len as usize + comment_len as usize;
+    }
+    false
+}
+
+/// Whether the header mentions the AES coder. Only found in unencrypted headers if they
+/// are not compressed, which 7-Zip does by default, but always in encrypted ones.
+fn seven_zip_encrypted(content: &[u8]) -> bool {
+    const AES_CODER: &[u8] = b"\x06\xf1\x07\x01";
+    let (Some(offset), Some(size)) = (u64_at(content, 12), u64_at(content, 20)) else {
+        return false;
+    };
+    let start = usize::try_from(offset).map_or(usize::MAX, |offset| offset.saturating_add(32));
+    let end = usize::try_from(size).map_or(usize::MAX, |size| start.saturating_add(size));
+    content
+        .get(start..end.min(content.len()))
+        .is_some_and(|header| header.windows(AES_CODER.len()).any(|w| w == AES_CODER))
+}
+
+/// Whether the archive header has encrypted headers or a file header the encryption flag.
+fn rar4_encrypted(blocks: &[u8]) -> bool {
+    const MAIN_HEADER: u8 = 0x73;
+    const FILE_HEADER: u8 = 0x74;
+    const MAIN_PASSWORD: u16 = 0x0080;
+    const FILE_PASSWORD: u16 = 0x0004;
+    const LONG_BLOCK: u16 = 0x8000;
+
+    let mut pos = 0;
+    while let (Some(&kind), Some(flags), Some(size)) = (
+        blocks.get(pos + 2),
+        u16_at(blocks, pos + 3),
+        u16_at(blocks, pos + 5),
+    ) {
+        match kind {
+            MAIN_HEADER if flags & MAIN_PASSWORD != 0 => return true,
+            FILE_HEADER if flags & FILE_PASSWORD != 0 => return true,
+            _ => {}
+        }
+        let data_size = if flags & LONG_BLOCK != 0 || kind == FILE_HEADER {
+            u32_at(blocks, pos + 7).unwrap_or(0) as usize
+        } else {
+            0
+        };
+        if size < 7 {
+            return false;
+        }
+        pos = pos.saturating_add(size as usize).saturating_add(data_size);
+    }
+    false
+}
+
+/// A RAR5 variable length integer and the number of its bytes.
+fn vint(data: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (ix, byte) in data.get(pos..)?.iter().take(10).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * ix);
+        if byte & 0x80 == 0 {
+            return Some((value, ix + 1));
+        }
+    }
+    None
+}
+
+/// Whether there is an archive encryption header, i.e. encrypted headers, or a file
+/// header with a file encryption record.
+fn rar5_encrypted(headers: &[u8]) -> bool {
+    const FILE_HEADER: u64 = 2;
+    const ENCRYPTION_HEADER: u64 = 4;
+    const EXTRA_AREA: u64 = 0x0001;
+    const DATA_AREA: u64 = 0x0002;
+    const FILE_ENCRYPTION: u64 = 1;
+
+    let mut pos = 0;
+    loop {
+        // after the CRC32
+        let Some((size, size_len)) = vint(headers, pos + 4) else {
+            return false;
+        };
+        let start = pos + 4 + size_len;
+        let Some(end) = usize::try_from(size)
+            .ok()
+            .and_then(|size| start.checked_add(size))
+        else {
+            return false;
+        };
+        let Some(header) = headers.get(start..end) else {
+            return false;
+        };
+        let Some((kind, kind_len)) = vint(header, 0) else {
+            return false;
+        };
+        if kind == ENCRYPTION_HEADER {
+            return true;
+        }
+        let Some((flags, flags_len)) = vint(header, kind_len) else {
+            return false;
+        };
+        let mut field = kind_len + flags_len;
+        let mut extra_size = 0;
+        if flags & EXTRA_AREA != 0 {
+            let Some((size, len)) = vint(header, field) else {
+                return false;
+            };
+            extra_size = size;
+            field += len;
+        }
+        let mut data_size = 0;
+        if flags & DATA_AREA != 0 {
+            let Some((size, _)) = vint(header, field) else {
+                return false;
+            };
+            data_size = size;
+        }
+
+        // the extra area ends the header, a record is its size, type and data
+        if kind == FILE_HEADER && extra_size > 0 {
+            let Some(mut record) = usize::try_from(extra_size)
+                .ok()
+                .and_then(|size| header.len().checked_sub(size))
+            else {
+                return false;
+            };
+            while let Some((record_size, size_len)) = vint(header, record) {
+                if vint(header, record + size_len).is_some_and(|(t, _)| t == FILE_ENCRYPTION) {
+                    return true;
+                }
+                let Some(next) = usize::try_from(record_size)
+                    .ok()
+                    .and_then(|size| (record + size_len).checked_add(size))
+                else {
+                    return false;
+                };
+                record = next;
+            }
+        }
+
+        let Some(next) = usize::try_from(data_size)
+            .ok()
+            .and_then(|size| end.checked_add(size))
+        else {
+            return false;
+        };
+        pos = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ZIP archive with one empty file, `flags` as in its central directory entry.
+    fn zip(flags: u16) -> Vec<u8> {
+        let mut zip = b"PK\x03\x04".to_vec();
+        zip.extend_from_slice(&[0; 22]);
+        zip.extend_from_slice(&1u16.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        zip.push(b'a');
+
+        let central = zip.len() as u32;
+        zip.extend_from_slice(b"PK\x01\x02");
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&flags.to_le_bytes());
+        zip.extend_from_slice(&[0; 18]);
+        zip.extend_from_slice(&1u16.to_le_bytes());
+        zip.extend_from_slice(&[0; 16]);
+        zip.push(b'a');
+
+        zip.extend_from_slice(b"PK\x05\x06");
+        zip.extend_from_slice(&[0; 6]);
+        zip.extend_from_slice(&1u16.to_le_bytes());
+        zip.extend_from_slice(&47u32.to_le_bytes());
+        zip.extend_from_slice(&central.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        zip
+    }
+
+    #[test]
+    fn zip_archives() {
+        assert!(!is_encrypted(&zip(0)));
+        assert!(is_encrypted(&zip(1)));
+        // e.g. a data descriptor
+        assert!(!is_encrypted(&zip(8)));
+    }
+
+    #[test]
+    fn truncated_zip() {
+        let encrypted = zip(1);
+        // without the end of central directory record
+        assert!(!is_encrypted(&encrypted[..encrypted.len() - 1]));
+        assert!(!is_encrypted(b"PK\x03\x04"));
+        // pointing past the end
+        let mut dangling = encrypted.clone();
+        let len = dangling.len();
+        dangling[len - 6..len - 2].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(!is_encrypted(&dangling));
+    }
+
+    #[test]
+    fn seven_zip() {
+        let mut archive = b"7z\xbc\xaf\x27\x1c\x00\x04".to_vec();
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&0u64.to_le_bytes());
+        archive.extend_from_slice(&8u64.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        let mut encrypted = archive.clone();
+        encrypted.extend_from_slice(b"\x01\x04\x06\xf1\x07\x01\x00\x00");
+        archive.extend_from_slice(b"\x01\x04\x06\x00\x00\x00\x00\x00");
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(&archive));
+        assert!(!is_encrypted(&encrypted[..20]));
+    }
+
+    #[test]
+    fn rar4() {
+        const SIGNATURE: &[u8] = b"Rar!\x1a\x07\x00";
+        let main = |flags: u8| [0, 0, 0x73, flags, 0, 13, 0, 0, 0, 0, 0, 0, 0];
+        let file = [0, 0, 0x74, 0x04, 0, 32, 0, 0, 0, 0, 0];
+
+        assert!(!is_encrypted(&[SIGNATURE, &main(0)].concat()));
+        // encrypted headers
+        assert!(is_encrypted(&[SIGNATURE, &main(0x80)].concat()));
+        assert!(is_encrypted(&[SIGNATURE, &main(0), &file].concat()));
+        // the file header is cut before its flags
+        assert!(!is_encrypted(&[SIGNATURE, &main(0), &file[..4]].concat()));
+    }
+
+    #[test]
+    fn rar5() {
+        const SIGNATURE: &[u8] = b"Rar!\x1a\x07\x01\x00";
+        // CRC32, size, type and flags
+        let main = [0, 0, 0, 0, 2, 1, 0];
+        let encryption = [0, 0, 0, 0, 2, 4, 0];
+        // with an extra area of a file encryption record
+        let file = [0, 0, 0, 0, 5, 2, 1, 2, 1, 1];
+
+        assert!(!is_encrypted(&[SIGNATURE, &main].concat()));
+        assert!(is_encrypted(&[SIGNATURE, &encryption].concat()));
+        assert!(is_encrypted(&[SIGNATURE, &main, &file].concat()));
+        assert!(!is_encrypted(&[SIGNATURE, &main, &file[..8]].concat()));
+        // a size running past the end
+        assert!(!is_encrypted(
+            &[SIGNATURE, &[0, 0, 0, 0, 0xff, 0xff]].concat()
+        ));
+    }
+}
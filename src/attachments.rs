@@ -0,0 +1,123 @@
+use anyhow::{bail, Result};
+
+use crate::rules::glob_match;
+
+/// What happens to messages with blocked attachments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// refuse the message with a 550
+    Reject,
+    /// store a placeholder instead of the attachment
+    Drop,
+    /// store the message under `quarantine/`, with the attachment
+    Quarantine,
+}
+
+impl PolicyAction {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(PolicyAction::Reject),
+            "drop" => Ok(PolicyAction::Drop),
+            "quarantine" => Ok(PolicyAction::Quarantine),
+            _ => bail!("unknown attachment policy action {}", s),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyAction::Reject => "reject",
+            PolicyAction::Drop => "drop",
+            PolicyAction::Quarantine => "quarantine",
+        }
+    }
+}
+
+/// Attachment types not to accept, e.g. executables or macro-enabled Office documents.
+///
+/// Patterns containing a `/` match the declared content type and the one guessed from the
+/// filename, others the filename; `.exe` is short for `*.exe`.
+#[derive(Debug)]
+pub struct AttachmentPolicy {
+    block: Vec<String>,
+    /// if not empty, attachments matching none of these are blocked as well
+    allow: Vec<String>,
+    pub action: PolicyAction,
+}
+
+impl AttachmentPolicy {
+    pub fn new(block: &str, allow: &str, action: PolicyAction) -> Self {
+        let patterns = |list: &str| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| {
+                    if p.starts_with('.') {
+                        format!("*{}", p)
+                    } else {
+                        p.to_string()
+                    }
+                })
+                .collect()
+        };
+        AttachmentPolicy {
+            block: patterns(block),
+            allow: patterns(allow),
+            action,
+        }
+    }
+
+    /// Why the attachment is blocked, i.e. the matching pattern or `not allowed`.
+    pub fn blocked(&self, filename: Option<&str>, content_type: Option<&str>) -> Option<&str> {
+        let guessed = filename.and_then(|name| mime_guess::from_path(name).first_raw());
+        let matches = |pattern: &str| {
+            if pattern.contains('/') {
+                [content_type, guessed]
+                    .into_iter()
+                    .flatten()
+                    .any(|ct| glob_match(pattern, ct))
+            } else {
+                filename.is_some_and(|name| glob_match(pattern, name))
+            }
+        };
+
+        if let Some(pattern) = self.block.iter().find(|p| matches(p.as_str())) {
+            return Some(pattern);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| matches(p.as_str())) {
+            return Some("not allowed");
+        }
+        None
+    }
+}
+
+/// Limits on the attachments of a message, below the maximum message size.
+#[derive(Debug)]
+pub struct AttachmentLimits {
+    /// attachments beyond this many are over the limit
+    pub max_count: Option<usize>,
+    /// in bytes, decoded
+    pub max_size: Option<usize>,
+    /// refuse messages over the limits with a 552, instead of skipping the attachments
+    pub reject: bool,
+}
+
+impl AttachmentLimits {
+    /// Why the attachment at `index` is over the limits, i.e. `count` or `size`.
+    pub fn exceeded(&self, index: usize, size: usize) -> Option<&'static str> {
+        if self.max_count.is_some_and(|max| index >= max) {
+            Some("count")
+        } else if self.max_size.is_some_and(|max| size > max) {
+            Some("size")
+        } else {
+            None
+        }
+    }
+}
+
+/// Stored instead of a dropped attachment.
+pub fn placeholder(filename: &str, size: usize, reason: &str) -> String {
+    format!(
+        "The attachment {:?} ({} bytes) was removed, its type is blocked ({}).\n",
+        filename, size, reason
+    )
+}
@@ -0,0 +1,252 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use sqlx::PgPool;
+use tracing::{error, info, warn};
+
+use crate::db;
+use crate::metrics;
+
+/// The account a submission client authenticated as.
+pub type SharedLogin = Arc<Mutex<Option<String>>>;
+
+#[cfg(feature = "oidc")]
+pub mod oidc;
+
+/// What a client authenticates with.
+pub enum Credentials {
+    Password {
+        username: String,
+        password: String,
+    },
+    /// OAuth 2.0 bearer token, with the user the client claims to be
+    Bearer {
+        user: Option<String>,
+        #[cfg_attr(not(feature = "oidc"), allow(dead_code))]
+        token: String,
+    },
+}
+
+/// Checks the credentials of `AUTH`.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// SASL mechanisms to offer, `PLAIN` and `LOGIN` take passwords, `XOAUTH2` and
+    /// `OAUTHBEARER` bearer tokens.
+    fn mechanisms(&self) -> &'static [&'static str];
+
+    /// The account for valid credentials.
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>>;
+}
+
+/// Checks passwords with the `is_valid_login(username, password)` DB function.
+pub struct DbAuthenticator {
+    pool: PgPool,
+}
+
+impl DbAuthenticator {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl Authenticator for DbAuthenticator {
+    fn mechanisms(&self) -> &'static [&'static str] {
+        &["PLAIN", "LOGIN"]
+    }
+
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>> {
+        let Credentials::Password { username, password } = credentials else {
+            return Ok(None);
+        };
+        let valid = db::check_login(&self.pool, username, password).await?;
+        Ok(valid.then(|| username.to_string()))
+    }
+}
+
+/// Where in a SASL exchange the client is.
+#[derive(Debug)]
+pub enum Step {
+    Plain,
+    LoginUsername,
+    LoginPassword(String),
+    XOAuth2,
+    OAuthBearer,
+}
+
+/// What to do after an `AUTH` command or a SASL response.
+pub enum Outcome {
+    /// reply and end the exchange
+    Reply(&'static str),
+    /// send a challenge and wait for the response
+    Challenge(Step, &'static str),
+    /// check the credentials, resolves to the reply
+    Verify(BoxFuture<'static, &'static str>),
+}
+
+const CHALLENGE_EMPTY: &str = "334 \r\n";
+/// `Username:`
+const CHALLENGE_USERNAME: &str = "334 VXNlcm5hbWU6\r\n";
+/// `Password:`
+const CHALLENGE_PASSWORD: &str = "334 UGFzc3dvcmQ6\r\n";
+
+/// SASL `PLAIN` and `LOGIN` authentication of submission clients (RFC 4954).
+pub struct Sasl {
+    authenticator: Arc<dyn Authenticator>,
+    login: SharedLogin,
+}
+
+impl Sasl {
+    pub fn new(authenticator: Arc<dyn Authenticator>, login: SharedLogin) -> Self {
+        Self {
+            authenticator,
+            login,
+        }
+    }
+
+    /// Handle the arguments of an `AUTH` command.
+    pub fn start(&self, args: &str, tls: bool) -> Outcome {
+        if self.login.lock().unwrap().is_some() {
+            return Outcome::Reply("503 5.5.1 already authenticated\r\n");
+        }
+        // passwords are not sent in plain text
+        if !tls {
+            return Outcome::Reply("538 5.7.11 encryption required for authentication\r\n");
+        }
+        let mut args = args.split_ascii_whitespace();
+        let mechanism = args.next().unwrap_or_default().to_ascii_uppercase();
+        let initial = args.next();
+        if !self
+            .authenticator
+            .mechanisms()
+            .contains(&mechanism.as_str())
+        {
+            return Outcome::Reply("504 5.5.4 unrecognized authentication type\r\n");
+        }
+        let (step, challenge) = match mechanism.as_str() {
+            "PLAIN" => (Step::Plain, CHALLENGE_EMPTY),
+            "LOGIN" => (Step::LoginUsername, CHALLENGE_USERNAME),
+            "XOAUTH2" => (Step::XOAuth2, CHALLENGE_EMPTY),
+            "OAUTHBEARER" => (Step::OAuthBearer, CHALLENGE_EMPTY),
+            _ => return Outcome::Reply("504 5.5.4 unrecognized authentication type\r\n"),
+        };
+        match initial {
+            Some(initial) => self.respond(step, initial),
+            None => Outcome::Challenge(step, challenge),
+        }
+    }
+
+    /// Handle the client's response to a challenge.
+    pub fn respond(&self, step: Step, response: &str) -> Outcome {
+        let response = response.trim();
+        if response == "*" {
+            return Outcome::Reply("501 5.0.0 authentication cancelled\r\n");
+        }
+        let Some(decoded) = decode(response) else {
+            return Outcome::Reply("501 5.5.2 cannot decode response\r\n");
+        };
+        match step {
+            Step::Plain => {
+                let mut parts = decoded.split('\0');
+                let (Some(authzid), Some(username), Some(password), None) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                else {
+                    return Outcome::Reply("501 5.5.2 malformed PLAIN response\r\n");
+                };
+                // acting as another user is not supported
+                if !authzid.is_empty() && authzid != username {
+                    return Outcome::Reply("535 5.7.8 authentication credentials invalid\r\n");
+                }
+                self.verify(Credentials::Password {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            Step::LoginUsername => {
+                Outcome::Challenge(Step::LoginPassword(decoded), CHALLENGE_PASSWORD)
+            }
+            Step::LoginPassword(username) => self.verify(Credentials::Password {
+                username,
+                password: decoded,
+            }),
+            Step::XOAuth2 | Step::OAuthBearer => match bearer(&decoded) {
+                Some(credentials) => self.verify(credentials),
+                None => Outcome::Reply("501 5.5.2 malformed bearer token response\r\n"),
+            },
+        }
+    }
+
+    fn verify(&self, credentials: Credentials) -> Outcome {
+        let authenticator = self.authenticator.clone();
+        let login = self.login.clone();
+        let username = match &credentials {
+            Credentials::Password { username, .. } => username.clone(),
+            Credentials::Bearer { user, .. } => user.clone().unwrap_or_default(),
+        };
+        Outcome::Verify(
+            async move {
+                match authenticator.authenticate(&credentials).await {
+                    Ok(Some(account)) => {
+                        info!("{:?} authenticated as {}", username, account);
+                        metrics::AUTH_ATTEMPTS.with_label_values(&["success"]).inc();
+                        *login.lock().unwrap() = Some(account);
+                        "235 2.7.0 authentication successful\r\n"
+                    }
+                    Ok(None) => {
+                        warn!("authentication of {:?} failed", username);
+                        metrics::AUTH_ATTEMPTS.with_label_values(&["failure"]).inc();
+                        "535 5.7.8 authentication credentials invalid\r\n"
+                    }
+                    Err(e) => {
+                        error!("could not check credentials: {:?}", e);
+                        metrics::AUTH_ATTEMPTS.with_label_values(&["error"]).inc();
+                        "454 4.7.0 temporary authentication failure\r\n"
+                    }
+                }
+            }
+            .boxed(),
+        )
+    }
+}
+
+/// Parse the user and token of `XOAUTH2` (`user=...^Aauth=Bearer ...^A^A`) and
+/// `OAUTHBEARER` (RFC 7628, `n,a=user,^Aauth=Bearer ...^A^A`) responses.
+fn bearer(response: &str) -> Option<Credentials> {
+    let mut user = None;
+    let mut token = None;
+    for (ix, part) in response.split('\x01').enumerate() {
+        if let Some(value) = part.strip_prefix("user=") {
+            user = Some(value.to_string());
+        } else if let Some(value) = part.strip_prefix("auth=") {
+            let (scheme, value) = value.split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("Bearer") {
+                return None;
+            }
+            token = Some(value.trim().to_string());
+        } else if ix == 0 {
+            // GS2 header of OAUTHBEARER
+            user = part
+                .split(',')
+                .find_map(|field| field.strip_prefix("a="))
+                .filter(|a| !a.is_empty())
+                .map(str::to_string);
+        }
+    }
+    Some(Credentials::Bearer {
+        user,
+        token: token?,
+    })
+}
+
+/// Decode a base64 SASL response, `=` being the empty one.
+fn decode(response: &str) -> Option<String> {
+    if response == "=" {
+        return Some(String::new());
+    }
+    String::from_utf8(STANDARD.decode(response).ok()?).ok()
+}
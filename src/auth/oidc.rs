@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::RwLock;
+use tracing::{info, instrument, trace, warn};
+
+use super::{Authenticator, Credentials};
+
+/// refetch the keys for unknown key ids at most this often
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+/// Validates bearer tokens of an OpenID Connect issuer, e.g. Kubernetes service account
+/// tokens, with the keys it publishes.
+pub struct OidcAuthenticator {
+    client: reqwest::Client,
+    issuer: String,
+    audience: String,
+    /// claim naming the account, e.g. `sub` or `email`
+    claim: String,
+    jwks_uri: String,
+    keys: RwLock<(JwkSet, Instant)>,
+}
+
+impl OidcAuthenticator {
+    #[instrument]
+    pub async fn new(issuer: &str, audience: &str, claim: &str) -> Result<Self> {
+        let client = reqwest::Client::new();
+        let discovery: Discovery = client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("could not parse OpenID configuration")?;
+        let keys = fetch_keys(&client, &discovery.jwks_uri).await?;
+        Ok(Self {
+            client,
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            claim: claim.to_string(),
+            jwks_uri: discovery.jwks_uri,
+            keys: RwLock::new((keys, Instant::now())),
+        })
+    }
+
+    /// The key with `kid`, refetching the keys if it is unknown, e.g. after a rotation.
+    async fn key(&self, kid: Option<&str>) -> Result<Option<DecodingKey>> {
+        {
+            let keys = self.keys.read().await;
+            if let Some(key) = find(&keys.0, kid) {
+                return key.map(Some);
+            }
+            if keys.1.elapsed() < MIN_REFRESH_INTERVAL {
+                return Ok(None);
+            }
+        }
+        let mut keys = self.keys.write().await;
+        // another login might have refreshed them meanwhile
+        if keys.1.elapsed() >= MIN_REFRESH_INTERVAL {
+            info!("refreshing OIDC keys");
+            *keys = (
+                fetch_keys(&self.client, &self.jwks_uri).await?,
+                Instant::now(),
+            );
+        }
+        find(&keys.0, kid).transpose()
+    }
+}
+
+async fn fetch_keys(client: &reqwest::Client, jwks_uri: &str) -> Result<JwkSet> {
+    trace!("fetching {}", jwks_uri);
+    let keys = client
+        .get(jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("could not parse JWKS")?;
+    Ok(keys)
+}
+
+/// The key with `kid`, or the only key for tokens without key id.
+fn find(keys: &JwkSet, kid: Option<&str>) -> Option<Result<DecodingKey>> {
+    let jwk = match kid {
+        Some(kid) => keys.find(kid)?,
+        None if keys.keys.len() == 1 => &keys.keys[0],
+        None => return None,
+    };
+    Some(DecodingKey::from_jwk(jwk).context("unsupported JWK"))
+}
+
+#[async_trait]
+impl Authenticator for OidcAuthenticator {
+    fn mechanisms(&self) -> &'static [&'static str] {
+        &["XOAUTH2", "OAUTHBEARER"]
+    }
+
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>> {
+        let Credentials::Bearer { user, token } = credentials else {
+            return Ok(None);
+        };
+        let header = match decode_header(token) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("malformed token: {}", e);
+                return Ok(None);
+            }
+        };
+        let Some(key) = self.key(header.kid.as_deref()).await? else {
+            warn!("token signed with unknown key {:?}", header.kid);
+            return Ok(None);
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.audience]);
+        validation.set_issuer(&[&self.issuer]);
+        let claims = match decode::<Value>(token, &key, &validation) {
+            Ok(data) => data.claims,
+            Err(e) => {
+                warn!("invalid token: {}", e);
+                return Ok(None);
+            }
+        };
+        let account = claims
+            .get(&self.claim)
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        match (&account, user) {
+            (None, _) => warn!("token has no {} claim", self.claim),
+            // the token decides, clients often send their address instead
+            (Some(account), Some(user)) if account != user => {
+                trace!("{} claims to be {}", account, user)
+            }
+            _ => {}
+        }
+        Ok(account)
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::{Args, Subcommand};
+use sqlx::PgPool;
+use tracing::{info, instrument};
+
+use crate::db;
+
+/// Marks encrypted bodies, followed by the key id and the base64 encoded nonce and
+/// ciphertext, e.g. `enc:v1:2023:...`.
+const PREFIX: &str = "enc:v1:";
+const NONCE_SIZE: usize = 12;
+/// rows re-encrypted per transaction
+const BATCH_SIZE: i64 = 100;
+
+/// AES-256-GCM keys for `body_text` and `body_html` in the DB. New bodies are encrypted with
+/// the current key, the others are kept to decrypt older rows.
+pub struct BodyKeys {
+    current: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl BodyKeys {
+    /// `keys` are pairs of key id and base64 encoded 32 byte key.
+    pub fn new(keys: &[(String, String)], current: Option<&str>) -> Result<Self> {
+        let current = current
+            .map(str::to_string)
+            .or_else(|| keys.first().map(|(id, _)| id.clone()))
+            .context("no body encryption keys given")?;
+        let keys = keys
+            .iter()
+            .map(|(id, key)| {
+                if id.contains(':') {
+                    bail!("key id {} must not contain ':'", id);
+                }
+                let key = STANDARD.decode(key).context("could not decode key")?;
+                let cipher = Aes256Gcm::new_from_slice(&key)
+                    .map_err(|_| anyhow!("key {} is not 32 bytes long", id))?;
+                Ok((id.clone(), cipher))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        if !keys.contains_key(&current) {
+            bail!("unknown current key id {}", current);
+        }
+        Ok(Self { current, keys })
+    }
+
+    /// Encrypt with the current key, empty bodies stay empty.
+    pub fn encrypt(&self, body: &str) -> Result<String> {
+        if body.is_empty() {
+            return Ok(String::new());
+        }
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[&self.current]
+            .encrypt(&nonce, body.as_bytes())
+            .map_err(|_| anyhow!("could not encrypt body"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            self.current,
+            STANDARD.encode(sealed)
+        ))
+    }
+
+    /// Decrypt an encrypted body, others are returned as they are.
+    pub fn decrypt(&self, stored: &str) -> Result<String> {
+        let Some(encrypted) = stored.strip_prefix(PREFIX) else {
+            return Ok(stored.to_string());
+        };
+        let (id, sealed) = encrypted
+            .split_once(':')
+            .context("malformed encrypted body")?;
+        let cipher = self
+            .keys
+            .get(id)
+            .with_context(|| format!("unknown key id {}", id))?;
+        let sealed = STANDARD
+            .decode(sealed)
+            .context("malformed encrypted body")?;
+        if sealed.len() < NONCE_SIZE {
+            bail!("malformed encrypted body");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let body = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("could not decrypt body with key {}", id))?;
+        Ok(String::from_utf8(body)?)
+    }
+
+    /// Prefix of bodies encrypted with the current key.
+    fn current_prefix(&self) -> String {
+        format!("{}{}:", PREFIX, self.current)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BodiesArgs {
+    #[command(subcommand)]
+    command: BodiesCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum BodiesCommand {
+    /// Encrypt all bodies with the current key, including unencrypted ones
+    Rotate,
+    /// Decrypt all bodies, e.g. before disabling encryption
+    Decrypt,
+}
+
+/// Re-encrypt or decrypt the bodies of all stored messages.
+#[instrument(skip(pool, keys))]
+pub async fn run(args: BodiesArgs, pool: &PgPool, keys: &BodyKeys) -> Result<()> {
+    let mut total = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let rows = match args.command {
+            BodiesCommand::Rotate => {
+                db::bodies_to_rotate(&mut tx, &keys.current_prefix(), BATCH_SIZE).await?
+            }
+            BodiesCommand::Decrypt => db::encrypted_bodies(&mut tx, BATCH_SIZE).await?,
+        };
+        if rows.is_empty() {
+            break;
+        }
+        for row in &rows {
+            let body_text = keys.decrypt(&row.body_text)?;
+            let body_html = keys.decrypt(&row.body_html)?;
+            let (body_text, body_html) = match args.command {
+                BodiesCommand::Rotate => (keys.encrypt(&body_text)?, keys.encrypt(&body_html)?),
+                BodiesCommand::Decrypt => (body_text, body_html),
+            };
+            db::update_bodies(&mut tx, &row.ctid, &body_text, &body_html).await?;
+        }
+        tx.commit().await?;
+        total += rows.len();
+        info!("updated {} messages", total);
+    }
+    Ok(())
+}
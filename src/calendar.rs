@@ -0,0 +1,114 @@
+use mail_parser::{Message, MessagePart, MimeHeaders};
+use serde::Serialize;
+
+/// A `VEVENT` of an iCalendar (RFC 5545) part.
+#[derive(Debug, Default, Serialize)]
+pub struct CalendarEvent {
+    /// `REQUEST`, `CANCEL` or `REPLY` for invitations (RFC 5546)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    pub uid: Option<String>,
+    pub summary: Option<String>,
+    /// address of the organizer, without `mailto:`
+    pub organizer: Option<String>,
+    /// `DTSTART` and `DTEND` as given, e.g. `20231024T090000Z`
+    pub start: Option<String>,
+    pub end: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+fn is_calendar(part: &MessagePart) -> bool {
+    let content_type = part.content_type().is_some_and(|ct| {
+        let subtype = ct.subtype().unwrap_or("");
+        (ct.ctype().eq_ignore_ascii_case("text") && subtype.eq_ignore_ascii_case("calendar"))
+            || (ct.ctype().eq_ignore_ascii_case("application")
+                && subtype.eq_ignore_ascii_case("ics"))
+    });
+    content_type
+        || part
+            .attachment_name()
+            .is_some_and(|name| name.to_lowercase().ends_with(".ics"))
+}
+
+/// The events of all calendar parts of the message.
+pub fn events(message: &Message) -> Vec<CalendarEvent> {
+    message
+        .parts
+        .iter()
+        .filter(|part| is_calendar(part))
+        .filter_map(|part| std::str::from_utf8(part.contents()).ok())
+        .flat_map(parse_events)
+        .collect()
+}
+
+/// Undo the escaping of TEXT values.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Parse the `VEVENT`s of an iCalendar object leniently, unknown properties are ignored.
+pub fn parse_events(calendar: &str) -> Vec<CalendarEvent> {
+    // unfold continuation lines
+    let unfolded = calendar
+        .replace("\r\n ", "")
+        .replace("\r\n\t", "")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut method = None;
+    let mut events = vec![];
+    let mut event: Option<CalendarEvent> = None;
+    // depth of components within the event, e.g. `VALARM`
+    let mut nested = 0usize;
+    for line in unfolded.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        // strip parameters like `DTSTART;TZID=Europe/Berlin`
+        let name = name.split(';').next().unwrap_or("").to_uppercase();
+        let value = value.trim();
+        match (name.as_str(), event.as_mut()) {
+            ("METHOD", _) => method = Some(value.to_uppercase()),
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VEVENT") => {
+                event = Some(CalendarEvent {
+                    method: method.clone(),
+                    ..Default::default()
+                });
+            }
+            ("BEGIN", Some(_)) => nested += 1,
+            ("END", Some(_)) if nested > 0 => nested -= 1,
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VEVENT") => {
+                events.extend(event.take());
+            }
+            (_, Some(_)) if nested > 0 => {}
+            ("UID", Some(event)) => event.uid = Some(value.to_string()),
+            ("SUMMARY", Some(event)) => event.summary = Some(unescape(value)),
+            ("LOCATION", Some(event)) => event.location = Some(unescape(value)),
+            ("DTSTART", Some(event)) => event.start = Some(value.to_string()),
+            ("DTEND", Some(event)) => event.end = Some(value.to_string()),
+            ("ORGANIZER", Some(event)) => {
+                let organizer = value
+                    .get(..7)
+                    .filter(|scheme| scheme.eq_ignore_ascii_case("mailto:"))
+                    .map_or(value, |_| &value[7..]);
+                event.organizer = Some(organizer.to_string());
+            }
+            _ => {}
+        }
+    }
+    events
+}
@@ -0,0 +1,134 @@
+use mail_parser::{Message, MimeHeaders};
+use serde_json::{json, Map, Value};
+
+/// What kind of message was received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Message,
+    /// delivery status notification (RFC 3464)
+    Dsn,
+    /// out-of-office and other automatic replies (RFC 3834)
+    AutoReply,
+    /// abuse feedback report (RFC 5965)
+    AbuseReport,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Message => "message",
+            Kind::Dsn => "dsn",
+            Kind::AutoReply => "auto-reply",
+            Kind::AbuseReport => "abuse-report",
+        }
+    }
+}
+
+/// The kind of the message and its parsed machine-readable part, if any.
+pub fn classify(message: &Message) -> (Kind, Option<Value>) {
+    let report_type = message
+        .content_type()
+        .filter(|ct| {
+            ct.ctype().eq_ignore_ascii_case("multipart")
+                && ct
+                    .subtype()
+                    .is_some_and(|s| s.eq_ignore_ascii_case("report"))
+        })
+        .and_then(|ct| ct.attribute("report-type"))
+        .map(str::to_lowercase);
+
+    match report_type.as_deref() {
+        Some("delivery-status") => {
+            let report = report_part(message, "delivery-status").map(|fields| {
+                let mut groups = parse_field_groups(fields).into_iter();
+                json!({
+                    "message": groups.next().unwrap_or_default(),
+                    "recipients": groups.collect::<Vec<_>>(),
+                })
+            });
+            (Kind::Dsn, report)
+        }
+        Some("feedback-report") => {
+            let report = report_part(message, "feedback-report").map(|fields| {
+                let fields = parse_field_groups(fields).into_iter().next();
+                Value::Object(fields.unwrap_or_default())
+            });
+            (Kind::AbuseReport, report)
+        }
+        _ if is_auto_reply(message) => (Kind::AutoReply, None),
+        _ => (Kind::Message, None),
+    }
+}
+
+/// Auto-replies as recognized by RFC 3834 and common vendor headers.
+fn is_auto_reply(message: &Message) -> bool {
+    message.headers_raw().any(|(name, value)| {
+        let value = value.trim().to_lowercase();
+        match name.to_lowercase().as_str() {
+            "auto-submitted" => value.starts_with("auto-replied"),
+            "x-autoreply" | "x-autorespond" => true,
+            "precedence" => value == "auto_reply",
+            _ => false,
+        }
+    })
+}
+
+/// The content of the `message/<subtype>` part of a report.
+fn report_part<'a>(message: &'a Message, subtype: &str) -> Option<&'a str> {
+    message
+        .parts
+        .iter()
+        .find(|part| {
+            part.content_type().is_some_and(|ct| {
+                ct.ctype().eq_ignore_ascii_case("message")
+                    && ct
+                        .subtype()
+                        .is_some_and(|s| s.eq_ignore_ascii_case(subtype))
+            })
+        })
+        .and_then(|part| std::str::from_utf8(part.contents()).ok())
+}
+
+/// Parse header-like `Name: value` fields in groups separated by empty lines. Names are
+/// lower case, repeated fields are joined by a new line.
+fn parse_field_groups(fields: &str) -> Vec<Map<String, Value>> {
+    let mut groups = vec![];
+    let mut group = Map::new();
+    let mut last: Option<String> = None;
+    for line in fields.lines() {
+        if line.trim().is_empty() {
+            if !group.is_empty() {
+                groups.push(std::mem::take(&mut group));
+            }
+            last = None;
+            continue;
+        }
+        // folded continuation of the last field
+        if line.starts_with([' ', '\t']) {
+            if let Some(Value::String(value)) = last.as_ref().and_then(|n| group.get_mut(n)) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.trim().to_lowercase();
+        let value = value.trim();
+        match group.get_mut(&name) {
+            Some(Value::String(existing)) => {
+                existing.push('\n');
+                existing.push_str(value);
+            }
+            _ => {
+                group.insert(name.clone(), value.into());
+            }
+        }
+        last = Some(name);
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
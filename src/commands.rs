@@ -0,0 +1,346 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::auth::{Outcome, Sasl, Step};
+use crate::probe::Probes;
+use crate::sessions::Activity;
+use crate::transcript::{SharedTranscript, Transcript};
+use crate::xclient::Proxy;
+
+/// Lines longer than this are passed on without being looked at.
+const MAX_LINE: usize = 1000;
+const READ_SIZE: usize = 8192;
+
+/// Commands handled by the SMTP server, everything else counts as unknown.
+const KNOWN_COMMANDS: &[&str] = &[
+    "HELO", "EHLO", "MAIL", "RCPT", "DATA", "BDAT", "RSET", "NOOP", "QUIT", "STARTTLS", "HELP",
+];
+/// Commands handled here if enabled.
+const INTERCEPTED_COMMANDS: &[&str] = &["AUTH", "XCLIENT", "XFORWARD"];
+
+/// Where in the client's input we are.
+#[derive(Debug)]
+enum State {
+    Command,
+    /// the first bytes of the server's reply to `DATA`, nothing is read until it is sent
+    DataReply(Vec<u8>),
+    /// message content after `354`, until the line with the single dot
+    Data,
+    /// remaining bytes of a `BDAT` chunk
+    Chunk(u64),
+    /// SASL response after `AUTH`
+    Auth(Step),
+}
+
+/// Follows the client's commands for what smtpbis does not handle: counting unknown
+/// commands, answering `XCLIENT` and `XFORWARD` of trusted proxies and `AUTH` of submission
+/// clients. Kept across STARTTLS.
+pub struct Commands {
+    probes: Probes,
+    proxy: Option<Proxy>,
+    sasl: Option<Sasl>,
+    tls: bool,
+    state: State,
+    input: Vec<u8>,
+    /// bytes at the start of `input` to pass on
+    pass: usize,
+    /// the rest of a line too long to look at is passed on
+    skip_line: bool,
+    eof: bool,
+    replies: Vec<u8>,
+    /// replies were written, but not flushed yet
+    unflushed: bool,
+    /// the reply of an intercepted command, once it is handled
+    pending: Option<BoxFuture<'static, &'static str>>,
+    transcript: Option<SharedTranscript>,
+    /// bytes of message content after `DATA` so far
+    content: usize,
+    activity: Arc<Activity>,
+}
+
+impl Commands {
+    pub fn new(
+        probes: Probes,
+        proxy: Option<Proxy>,
+        sasl: Option<Sasl>,
+        transcript: Option<SharedTranscript>,
+        activity: Arc<Activity>,
+    ) -> Self {
+        Self {
+            probes,
+            proxy,
+            sasl,
+            tls: false,
+            state: State::Command,
+            input: vec![],
+            pass: 0,
+            skip_line: false,
+            eof: false,
+            replies: vec![],
+            unflushed: false,
+            pending: None,
+            transcript,
+            content: 0,
+            activity,
+        }
+    }
+
+    fn record(&self, f: impl FnOnce(&mut Transcript)) {
+        if let Some(transcript) = self.transcript.as_ref() {
+            f(&mut transcript.lock().unwrap());
+        }
+    }
+
+    /// Allow `AUTH` from now on.
+    pub fn tls_started(&mut self) {
+        self.tls = true;
+    }
+
+    /// Handle the buffered input up to the next bytes to pass on, returns whether there
+    /// are any.
+    fn advance(&mut self) -> bool {
+        while self.pass == 0 && !self.input.is_empty() {
+            if let State::DataReply(_) = self.state {
+                break;
+            }
+            if let State::Chunk(remaining) = self.state {
+                self.pass = self.input.len().min(remaining as usize);
+                self.state = match remaining - self.pass as u64 {
+                    0 => State::Command,
+                    remaining => State::Chunk(remaining),
+                };
+                break;
+            }
+            let Some(end) = self.input.iter().position(|b| *b == b'\n').map(|ix| ix + 1) else {
+                // wait for the rest of the line
+                if self.eof || self.skip_line || self.input.len() > MAX_LINE {
+                    self.skip_line = true;
+                    self.pass = self.input.len();
+                }
+                break;
+            };
+            if std::mem::take(&mut self.skip_line) || self.line(end) {
+                self.pass = end;
+            } else {
+                // the reply goes out before anything else is read
+                self.input.drain(..end);
+                break;
+            }
+        }
+        self.pass > 0
+    }
+
+    /// Follow the state for the line ending at `end`, returns whether to pass it on.
+    fn line(&mut self, end: usize) -> bool {
+        let line = &self.input[..end];
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match &self.state {
+            State::Data => {
+                if line == b"." {
+                    self.state = State::Command;
+                    let content = std::mem::take(&mut self.content);
+                    self.record(|t| t.client(&format!("[message content, {} bytes]", content)));
+                    self.record(|t| t.client("."));
+                } else {
+                    self.content += end;
+                }
+                return true;
+            }
+            State::Auth(_) => {
+                let State::Auth(step) = std::mem::replace(&mut self.state, State::Command) else {
+                    unreachable!()
+                };
+                let response = String::from_utf8_lossy(line).into_owned();
+                self.record(|t| t.client("[SASL response]"));
+                let outcome = self.sasl.as_ref().unwrap().respond(step, &response);
+                self.sasl_outcome(outcome);
+                return false;
+            }
+            _ => {}
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let (verb, args) = line.split_once(' ').unwrap_or((line.as_ref(), ""));
+        let verb = verb.to_ascii_uppercase();
+        match verb.as_str() {
+            // only the mechanism, the rest may be credentials
+            "AUTH" => {
+                let mechanism = args.split_ascii_whitespace().next().unwrap_or_default();
+                self.record(|t| t.client(&format!("AUTH {}", mechanism)));
+            }
+            _ => self.record(|t| t.client(&line)),
+        }
+        let command = KNOWN_COMMANDS
+            .iter()
+            .chain(INTERCEPTED_COMMANDS)
+            .find(|c| **c == verb)
+            .copied()
+            .unwrap_or("unknown");
+        self.activity.command(command);
+        match verb.as_str() {
+            "DATA" => self.state = State::DataReply(vec![]),
+            "BDAT" => {
+                if let Some(size) = args.split_ascii_whitespace().next() {
+                    match size.parse() {
+                        Ok(0) | Err(_) => {}
+                        Ok(size) => self.state = State::Chunk(size),
+                    }
+                }
+            }
+            "XCLIENT" | "XFORWARD" if self.proxy.is_some() => {
+                let reply = self.proxy.as_ref().unwrap().handle(&verb, args);
+                self.replies.extend(reply.as_bytes());
+                return false;
+            }
+            "AUTH" if self.sasl.is_some() => {
+                let outcome = self.sasl.as_ref().unwrap().start(args, self.tls);
+                self.sasl_outcome(outcome);
+                return false;
+            }
+            verb if !KNOWN_COMMANDS.contains(&verb) => self.probes.probe(verb),
+            _ => {}
+        }
+        true
+    }
+
+    /// Follow the state for the server's `replies`, the content only follows `354` to `DATA`.
+    fn replied(&mut self, replies: &[u8]) {
+        let State::DataReply(code) = &mut self.state else {
+            return;
+        };
+        code.extend(replies.iter().take(3 - code.len()));
+        if code.len() == 3 {
+            self.state = match code.as_slice() {
+                b"354" => State::Data,
+                _ => State::Command,
+            };
+        }
+    }
+
+    fn sasl_outcome(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Reply(reply) => self.replies.extend(reply.as_bytes()),
+            Outcome::Challenge(step, challenge) => {
+                self.state = State::Auth(step);
+                self.replies.extend(challenge.as_bytes());
+            }
+            Outcome::Verify(reply) => self.pending = Some(reply),
+        }
+    }
+}
+
+/// Wraps a connection to watch the commands sent over it, failing reads once more unknown
+/// commands than allowed were sent.
+pub struct CommandWatch<S> {
+    inner: S,
+    commands: Commands,
+}
+
+impl<S> CommandWatch<S> {
+    pub fn new(inner: S, commands: Commands) -> Self {
+        Self { inner, commands }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_parts(self) -> (S, Commands) {
+        (self.inner, self.commands)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.commands.probes.exceeded()
+    }
+
+    pub fn tls_started(&mut self) {
+        self.commands.tls_started();
+    }
+
+    /// Record why the session failed in the transcript.
+    pub fn record_error(&self, error: impl std::fmt::Display) {
+        self.commands.record(|t| t.error(error));
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for CommandWatch<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let commands = &mut this.commands;
+        loop {
+            if let Some(pending) = commands.pending.as_mut() {
+                let reply = ready!(pending.poll_unpin(cx));
+                commands.pending = None;
+                commands.replies.extend(reply.as_bytes());
+            }
+            // answer intercepted commands before reading the next one
+            while !commands.replies.is_empty() {
+                let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &commands.replies))?;
+                let replies: Vec<u8> = commands.replies.drain(..written).collect();
+                commands.record(|t| t.server(&replies));
+                commands.unflushed = true;
+            }
+            if commands.unflushed {
+                ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+                commands.unflushed = false;
+            }
+            let passing = commands.advance();
+            if commands.probes.exceeded() {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+            }
+            if passing {
+                let n = commands.pass.min(buf.remaining());
+                buf.put_slice(&commands.input[..n]);
+                commands.input.drain(..n);
+                commands.pass -= n;
+                return Poll::Ready(Ok(()));
+            }
+            if !commands.replies.is_empty() || commands.pending.is_some() {
+                continue;
+            }
+            if commands.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0; READ_SIZE];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            commands.eof = read.filled().is_empty();
+            commands.activity.received(read.filled().len());
+            commands.input.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CommandWatch<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.commands.record(|t| t.server(&buf[..written]));
+        this.commands.replied(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,35 +1,260 @@
 use anyhow::Result;
-use serde_json::Value;
-use sqlx::postgres::PgPool;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::postgres::{PgConnection, PgPool};
 use tracing::{instrument, trace};
 
-#[allow(clippy::too_many_arguments)]
-#[instrument(skip_all, fields(from, rcpt))]
+use crate::forget;
+use crate::reports;
+
+#[derive(Debug, Serialize)]
+pub struct MailSummary {
+    pub message_id: String,
+    pub rcpt: String,
+    pub from: String,
+    pub received_at: DateTime<Utc>,
+    pub s3_prefix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoredMail {
+    pub message_id: String,
+    pub rcpt: String,
+    pub from: String,
+    pub received_at: DateTime<Utc>,
+    pub s3_prefix: Option<String>,
+    pub body_text: String,
+    pub body_html: String,
+    pub headers: Value,
+    pub attachments: Value,
+    /// `None` for messages stored by versions without the column, i.e. the default bucket
+    pub bucket: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// A message whose raw form can be fetched from S3.
+#[derive(Debug)]
+pub struct StoredObject {
+    pub message_id: String,
+    pub from: String,
+    pub received_at: DateTime<Utc>,
+    pub s3_prefix: Option<String>,
+    /// `None` for the default bucket
+    pub bucket: Option<String>,
+    pub size: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct NewMail<'a> {
+    pub message_id: &'a str,
+    pub rcpt: &'a str,
+    /// storage identity `rcpt` is an alias of
+    pub canonical_rcpt: Option<&'a str>,
+    pub from: &'a str,
+    pub body_text: &'a str,
+    pub body_html: &'a str,
+    /// text extracted from PDF and DOCX attachments, see `extract::Extraction`
+    pub attachment_text: Option<&'a str>,
+    pub headers: Value,
+    /// parsed `Received` headers, the most recent first
+    pub trace: Value,
+    /// address and HELO name of the originating client
+    pub origin_ip: Option<String>,
+    pub origin_host: Option<&'a str>,
+    /// `message`, `dsn`, `auto-reply` or `abuse-report`
+    pub kind: &'a str,
+    /// parsed machine-readable part of DSNs and abuse reports
+    pub report: Option<Value>,
+    /// events of calendar parts, see `calendar::CalendarEvent`
+    pub calendar: Option<Value>,
+    /// distinct URLs of the bodies
+    pub links: &'a [String],
+    /// declared charsets of the bodies, which are stored as UTF-8
+    pub charsets: Value,
+    /// replacements by redaction name, if redactions are configured
+    pub redactions: Option<Value>,
+    /// decryption and signature verification, see `smime::SmimeInfo`
+    pub smime: Option<Value>,
+    /// TLS version, cipher and SNI, see `tls::TlsInfo`
+    pub tls: Option<Value>,
+    /// reverse DNS name of the client, see `rdns::Rdns`
+    pub rdns: Option<Value>,
+    /// HELO name of the client
+    pub helo: Option<&'a str>,
+    /// account the message was submitted with
+    pub submitter: Option<&'a str>,
+    pub attachments: Value,
+    pub s3_prefix: &'a str,
+    /// key of the `index/` entry, if `DAY_INDEX` is set
+    pub index_key: Option<&'a str>,
+    pub urls: Option<Value>,
+    pub dsn: Option<Value>,
+    pub size: i64,
+    pub declared_size: Option<i64>,
+    pub bucket: &'a str,
+    pub tags: &'a [String],
+    /// domain of the recipient's tenant
+    pub tenant: Option<&'a str>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// whether the objects were written to the mirror bucket, if one is configured
+    pub mirrored: Option<bool>,
+    /// whether an attachment is a password-protected archive
+    pub encrypted_archive: bool,
+    /// ISO 639-3 code of the language of `body_text`, see `language::detect`
+    pub language: Option<&'a str>,
+    /// normalized message ID of the thread's root, see `thread::thread_id`
+    pub thread_id: Option<&'a str>,
+    /// mailing list and automation headers, see `list::ListInfo`
+    pub list_id: Option<&'a str>,
+    pub list_unsubscribe: &'a [String],
+    pub auto_submitted: Option<&'a str>,
+    pub precedence: Option<&'a str>,
+    /// from a mailing list or sent automatically
+    pub automated: bool,
+    /// header senders and their mismatches, see `sender::Sender`
+    pub header_from: Option<&'a str>,
+    pub reply_to: Option<&'a str>,
+    pub from_mismatch: &'a [String],
+}
+
+/// The columns `insert_mail` writes, checked by `doctor`.
+pub const MAIL_COLUMNS: &[&str] = &[
+    "message_id",
+    "to",
+    "from",
+    "body_text",
+    "body_html",
+    "headers",
+    "attachments",
+    "s3_prefix",
+    "index_key",
+    "urls",
+    "dsn",
+    "size",
+    "declared_size",
+    "bucket",
+    "tags",
+    "tenant",
+    "expires_at",
+    "canonical_rcpt",
+    "trace",
+    "origin_ip",
+    "origin_host",
+    "kind",
+    "report",
+    "calendar",
+    "links",
+    "charsets",
+    "smime",
+    "redactions",
+    "tls",
+    "submitter",
+    "rdns",
+    "helo",
+    "mirrored",
+    "attachment_text",
+    "encrypted_archive",
+    "language",
+    "thread_id",
+    "list_id",
+    "list_unsubscribe",
+    "auto_submitted",
+    "precedence",
+    "automated",
+    "header_from",
+    "reply_to",
+    "from_mismatch",
+];
+
+/// Insert the mail and, if `notify_channel` is set, notify listeners in the same
+/// transaction.
+#[instrument(skip_all, fields(message_id = mail.message_id, from = mail.from, rcpt = mail.rcpt))]
 pub async fn insert_mail(
     pool: &PgPool,
-    message_id: &str,
-    rcpt: &str,
-    from: &str,
-    body_text: &str,
-    body_html: &str,
-    headers: Value,
-    attachments: Value,
+    mail: &NewMail<'_>,
+    notify_channel: Option<&str>,
 ) -> Result<()> {
     trace!("inserting into DB");
+    let mut tx = pool.begin().await?;
     let query = sqlx::query!(
         r#"INSERT INTO data_gateways.smtp_gateway
-            (message_id, "to", "from", body_text, body_html, headers, attachments)
-            VALUES ($1, $2, $3, $4, $5, $6, $7);"#,
-        message_id,
-        rcpt,
-        from,
-        body_text,
-        body_html,
-        headers,
-        attachments
+            (message_id, "to", "from", body_text, body_html, headers, attachments, s3_prefix,
+                index_key, urls, dsn, size, declared_size, bucket, tags, tenant, expires_at,
+                canonical_rcpt, trace, origin_ip, origin_host, kind, report, calendar, links,
+                charsets, smime, redactions, tls, submitter, rdns, helo, mirrored,
+                attachment_text, encrypted_archive, language, thread_id, list_id,
+                list_unsubscribe, auto_submitted, precedence, automated, header_from, reply_to,
+                from_mismatch)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15,
+                $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31,
+                $32, $33, $34, $35, $36, $37, $38, $39, $40, $41, $42, $43, $44, $45);"#,
+        mail.message_id,
+        mail.rcpt,
+        mail.from,
+        mail.body_text,
+        mail.body_html,
+        mail.headers,
+        mail.attachments,
+        mail.s3_prefix,
+        mail.index_key,
+        mail.urls,
+        mail.dsn,
+        mail.size,
+        mail.declared_size,
+        mail.bucket,
+        mail.tags,
+        mail.tenant,
+        mail.expires_at,
+        mail.canonical_rcpt,
+        mail.trace,
+        mail.origin_ip,
+        mail.origin_host,
+        mail.kind,
+        mail.report,
+        mail.calendar,
+        mail.links,
+        mail.charsets,
+        mail.smime,
+        mail.redactions,
+        mail.tls,
+        mail.submitter,
+        mail.rdns,
+        mail.helo,
+        mail.mirrored,
+        mail.attachment_text,
+        mail.encrypted_archive,
+        mail.language,
+        mail.thread_id,
+        mail.list_id,
+        mail.list_unsubscribe,
+        mail.auto_submitted,
+        mail.precedence,
+        mail.automated,
+        mail.header_from,
+        mail.reply_to,
+        mail.from_mismatch
     );
+    let _ = query.execute(&mut *tx).await?;
 
-    let _ = query.execute(pool).await?;
+    if let Some(channel) = notify_channel {
+        // keep the payload well below the 8000 bytes limit
+        let payload = json!({
+            "message_id": mail.message_id,
+            "from": mail.from,
+            "rcpt": mail.rcpt,
+            "s3_prefix": mail.s3_prefix,
+        })
+        .to_string();
+        let query = sqlx::query!(
+            r#"SELECT 1 AS "notified!" FROM pg_notify($1, $2);"#,
+            channel,
+            payload
+        );
+        let _ = query.fetch_one(&mut *tx).await?;
+    }
+
+    tx.commit().await?;
     Ok(())
 }
 
@@ -41,3 +266,830 @@ pub async fn check_address(pool: &PgPool, from: &str, rcpt: &str) -> Result<bool
     trace!("checked DB, got {}", res.b);
     Ok(res.b)
 }
+
+/// Columns of the message table, to check that all migrations were applied.
+#[instrument(skip(pool))]
+pub async fn mail_columns(pool: &PgPool) -> Result<Vec<String>> {
+    let query = sqlx::query_scalar!(
+        r#"SELECT column_name::text AS "column_name!"
+            FROM information_schema.columns
+            WHERE table_schema = 'data_gateways' AND table_name = 'smtp_gateway';"#
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// The result type of a DB function like `is_valid_rcpt(text,text)`, `None` if it does not
+/// exist.
+#[instrument(skip(pool))]
+pub async fn function_result(pool: &PgPool, signature: &str) -> Result<Option<String>> {
+    let query = sqlx::query_scalar!(
+        r#"SELECT pg_get_function_result(to_regprocedure($1)) AS result;"#,
+        signature
+    );
+    Ok(query.fetch_one(pool).await?)
+}
+
+#[instrument(skip(pool, password))]
+pub async fn check_login(pool: &PgPool, username: &str, password: &str) -> Result<bool> {
+    trace!("checking login in DB");
+    let query = sqlx::query!(
+        r#"SELECT is_valid_login($1, $2) AS "b!";"#,
+        username,
+        password
+    );
+    let res = query.fetch_one(pool).await?;
+    Ok(res.b)
+}
+
+#[derive(Debug)]
+pub struct TenantRow {
+    pub bucket: Option<String>,
+    pub prefix: Option<String>,
+    pub webhook_url: Option<String>,
+    pub max_size: Option<i64>,
+    pub retention_days: Option<i32>,
+}
+
+#[instrument(skip(pool))]
+pub async fn get_tenant(pool: &PgPool, domain: &str) -> Result<Option<TenantRow>> {
+    trace!("looking up tenant in DB");
+    let query = sqlx::query_as!(
+        TenantRow,
+        r#"SELECT bucket, prefix, webhook_url, max_size, retention_days
+            FROM data_gateways.smtp_gateway_tenants
+            WHERE domain = $1;"#,
+        domain.to_lowercase()
+    );
+    Ok(query.fetch_optional(pool).await?)
+}
+
+#[derive(Debug)]
+pub struct QuotaUsage {
+    pub key: String,
+    pub max_messages: Option<i64>,
+    pub max_bytes: Option<i64>,
+    pub messages: i64,
+    pub bytes: i64,
+}
+
+/// Today's usage of the quotas configured for any of `keys`.
+#[instrument(skip(pool))]
+pub async fn quota_usage(pool: &PgPool, keys: &[String]) -> Result<Vec<QuotaUsage>> {
+    trace!("checking quotas");
+    let query = sqlx::query_as!(
+        QuotaUsage,
+        r#"SELECT q.key AS "key!", q.max_messages, q.max_bytes,
+                COALESCE(u.messages, 0) AS "messages!", COALESCE(u.bytes, 0) AS "bytes!"
+            FROM data_gateways.smtp_gateway_quotas q
+            LEFT JOIN data_gateways.smtp_gateway_usage u
+                ON u.key = q.key AND u.day = current_date
+            WHERE q.key = ANY($1);"#,
+        keys
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// Count a stored message against the quotas configured for any of `keys`, returns the
+/// keys that have a quota.
+#[instrument(skip(pool))]
+pub async fn record_usage(pool: &PgPool, keys: &[String], size: i64) -> Result<Vec<String>> {
+    trace!("recording quota usage");
+    let query = sqlx::query_scalar!(
+        r#"INSERT INTO data_gateways.smtp_gateway_usage AS u (key, day, messages, bytes)
+            SELECT key, current_date, 1, $2
+                FROM data_gateways.smtp_gateway_quotas
+                WHERE key = ANY($1)
+            ON CONFLICT (key, day) DO UPDATE
+                SET messages = u.messages + 1, bytes = u.bytes + EXCLUDED.bytes
+            RETURNING key;"#,
+        keys,
+        size
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// The configured maximum message size for the address, falling back to its domain.
+#[instrument(skip(pool))]
+pub async fn max_message_size(pool: &PgPool, rcpt: &str, domain: &str) -> Result<Option<i64>> {
+    trace!("checking size limit in DB");
+    let query = sqlx::query_scalar!(
+        r#"SELECT max_size FROM data_gateways.smtp_gateway_size_limits
+            WHERE address = $1 OR address = $2
+            ORDER BY length(address) DESC
+            LIMIT 1;"#,
+        rcpt.to_lowercase(),
+        domain.to_lowercase()
+    );
+    Ok(query.fetch_optional(pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn list_mails(
+    pool: &PgPool,
+    rcpt: Option<&str>,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+) -> Result<Vec<MailSummary>> {
+    trace!("listing mails");
+    let query = sqlx::query_as!(
+        MailSummary,
+        r#"SELECT message_id, "to" AS rcpt, "from", received_at, s3_prefix
+            FROM data_gateways.smtp_gateway
+            WHERE ($1::text IS NULL OR "to" = $1)
+                AND ($2::timestamptz IS NULL OR received_at >= $2)
+            ORDER BY received_at DESC
+            LIMIT $3;"#,
+        rcpt,
+        since,
+        limit
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// The newest `limit` messages stored for `rcpt`, oldest first.
+#[instrument(skip(pool))]
+pub async fn list_objects(
+    pool: &PgPool,
+    rcpt: &str,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+) -> Result<Vec<StoredObject>> {
+    trace!("listing stored objects");
+    let query = sqlx::query_as!(
+        StoredObject,
+        r#"SELECT * FROM (
+                SELECT message_id, "from", received_at, s3_prefix, bucket, size
+                FROM data_gateways.smtp_gateway
+                WHERE "to" = $1 AND s3_prefix IS NOT NULL
+                    AND ($2::timestamptz IS NULL OR received_at >= $2)
+                ORDER BY received_at DESC
+                LIMIT $3
+            ) AS newest
+            ORDER BY received_at;"#,
+        rcpt,
+        since,
+        limit
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn get_mail(
+    pool: &PgPool,
+    message_id: &str,
+    rcpt: Option<&str>,
+) -> Result<Option<StoredMail>> {
+    trace!("fetching mail");
+    let query = sqlx::query_as!(
+        StoredMail,
+        r#"SELECT message_id, "to" AS rcpt, "from", received_at, s3_prefix,
+                body_text, body_html, headers, attachments, bucket, tags
+            FROM data_gateways.smtp_gateway
+            WHERE message_id = $1 AND ($2::text IS NULL OR "to" = $2)
+            ORDER BY received_at DESC
+            LIMIT 1;"#,
+        message_id,
+        rcpt
+    );
+    Ok(query.fetch_optional(pool).await?)
+}
+
+/// The canonical storage identity of the address, falling back to its local part.
+#[instrument(skip(pool))]
+pub async fn get_alias(pool: &PgPool, rcpt: &str, local_part: &str) -> Result<Option<String>> {
+    trace!("looking up alias in DB");
+    let query = sqlx::query_scalar!(
+        r#"SELECT canonical FROM data_gateways.smtp_gateway_aliases
+            WHERE alias = $1 OR alias = $2
+            ORDER BY length(alias) DESC
+            LIMIT 1;"#,
+        rcpt,
+        local_part
+    );
+    Ok(query.fetch_optional(pool).await?)
+}
+
+/// Insert a row per record of the DMARC aggregate report.
+#[instrument(skip(pool, report), fields(report_id = report.report_metadata.report_id))]
+pub async fn insert_dmarc_report(
+    pool: &PgPool,
+    message_id: &str,
+    report: &reports::Feedback,
+) -> Result<()> {
+    trace!("inserting DMARC report");
+    let metadata = &report.report_metadata;
+    let begin = Utc.timestamp_opt(metadata.date_range.begin, 0).single();
+    let end = Utc.timestamp_opt(metadata.date_range.end, 0).single();
+
+    let mut tx = pool.begin().await?;
+    for record in &report.records {
+        let query = sqlx::query!(
+            r#"INSERT INTO data_gateways.smtp_gateway_dmarc_records
+                (message_id, org_name, email, report_id, date_begin, date_end, domain, policy,
+                    source_ip, count, disposition, dkim, spf, header_from, envelope_from,
+                    auth_results)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15,
+                    $16);"#,
+            message_id,
+            metadata.org_name,
+            metadata.email,
+            metadata.report_id,
+            begin,
+            end,
+            report.policy_published.domain,
+            report.policy_published.p,
+            record.row.source_ip,
+            record.row.count,
+            record.row.policy_evaluated.disposition,
+            record.row.policy_evaluated.dkim,
+            record.row.policy_evaluated.spf,
+            record.identifiers.header_from,
+            record.identifiers.envelope_from,
+            serde_json::to_value(&record.auth_results)?
+        );
+        let _ = query.execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Insert a row per policy of the SMTP TLS report.
+#[instrument(skip(pool, report), fields(report_id = report.report_id))]
+pub async fn insert_tls_report(
+    pool: &PgPool,
+    message_id: &str,
+    report: &reports::TlsReport,
+) -> Result<()> {
+    trace!("inserting TLS report");
+    let mut tx = pool.begin().await?;
+    for result in &report.policies {
+        let query = sqlx::query!(
+            r#"INSERT INTO data_gateways.smtp_gateway_tls_reports
+                (message_id, organization_name, contact_info, report_id, date_begin, date_end,
+                    policy_type, policy_domain, policy, successful_count, failure_count,
+                    failure_details)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12);"#,
+            message_id,
+            report.organization_name,
+            report.contact_info,
+            report.report_id,
+            report.date_range.start_datetime,
+            report.date_range.end_datetime,
+            result.policy["policy-type"].as_str(),
+            result.policy["policy-domain"].as_str(),
+            result.policy,
+            result.summary.total_successful_session_count,
+            result.summary.total_failure_session_count,
+            serde_json::to_value(&result.failure_details)?
+        );
+        let _ = query.execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Bodies of a row, identified by its `ctid` within the transaction.
+#[derive(Debug)]
+pub struct Bodies {
+    pub ctid: String,
+    pub body_text: String,
+    pub body_html: String,
+}
+
+/// Rows with a body that is neither empty nor encrypted with the key of `prefix`, locked
+/// for the transaction.
+#[instrument(skip(conn))]
+pub async fn bodies_to_rotate(
+    conn: &mut PgConnection,
+    prefix: &str,
+    limit: i64,
+) -> Result<Vec<Bodies>> {
+    trace!("fetching bodies to rotate");
+    let query = sqlx::query_as!(
+        Bodies,
+        r#"SELECT ctid::text AS "ctid!", body_text, body_html
+            FROM data_gateways.smtp_gateway
+            WHERE (body_text <> '' AND NOT starts_with(body_text, $1))
+                OR (body_html <> '' AND NOT starts_with(body_html, $1))
+            LIMIT $2
+            FOR UPDATE;"#,
+        prefix,
+        limit
+    );
+    Ok(query.fetch_all(conn).await?)
+}
+
+/// Rows with an encrypted body, locked for the transaction.
+#[instrument(skip(conn))]
+pub async fn encrypted_bodies(conn: &mut PgConnection, limit: i64) -> Result<Vec<Bodies>> {
+    trace!("fetching encrypted bodies");
+    let query = sqlx::query_as!(
+        Bodies,
+        r#"SELECT ctid::text AS "ctid!", body_text, body_html
+            FROM data_gateways.smtp_gateway
+            WHERE starts_with(body_text, 'enc:') OR starts_with(body_html, 'enc:')
+            LIMIT $1
+            FOR UPDATE;"#,
+        limit
+    );
+    Ok(query.fetch_all(conn).await?)
+}
+
+#[instrument(skip(conn, body_text, body_html))]
+pub async fn update_bodies(
+    conn: &mut PgConnection,
+    ctid: &str,
+    body_text: &str,
+    body_html: &str,
+) -> Result<()> {
+    let query = sqlx::query!(
+        r#"UPDATE data_gateways.smtp_gateway
+            SET body_text = $2, body_html = $3
+            WHERE ctid = $1::tid;"#,
+        ctid,
+        body_text,
+        body_html
+    );
+    let _ = query.execute(conn).await?;
+    Ok(())
+}
+
+/// Where the objects of a message are stored.
+#[derive(Debug)]
+pub struct StoredPrefix {
+    /// `None` for the default bucket
+    pub bucket: Option<String>,
+    pub s3_prefix: String,
+    pub index_key: Option<String>,
+}
+
+/// Bucket, S3 prefix and index entry of the messages from or to `address`.
+#[instrument(skip(pool))]
+pub async fn stored_prefixes(pool: &PgPool, address: &str) -> Result<Vec<StoredPrefix>> {
+    trace!("fetching prefixes of address");
+    let query = sqlx::query_as!(
+        StoredPrefix,
+        r#"SELECT bucket, s3_prefix AS "s3_prefix!", index_key
+            FROM data_gateways.smtp_gateway
+            WHERE (lower("from") = $1 OR lower("to") = $1 OR lower(canonical_rcpt) = $1)
+                AND s3_prefix IS NOT NULL;"#,
+        address
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// Delete the messages from or to `address`, returns their number.
+#[instrument(skip(pool))]
+pub async fn delete_mails(pool: &PgPool, address: &str) -> Result<u64> {
+    trace!("deleting messages of address");
+    let query = sqlx::query!(
+        r#"DELETE FROM data_gateways.smtp_gateway
+            WHERE lower("from") = $1 OR lower("to") = $1 OR lower(canonical_rcpt) = $1;"#,
+        address
+    );
+    Ok(query.execute(pool).await?.rows_affected())
+}
+
+/// Replace `address` with `replacement` in the messages from or to it and remove their
+/// content, returns their number.
+#[instrument(skip(pool))]
+pub async fn anonymize_mails(pool: &PgPool, address: &str, replacement: &str) -> Result<u64> {
+    trace!("anonymizing messages of address");
+    let query = sqlx::query!(
+        r#"UPDATE data_gateways.smtp_gateway
+            SET "from" = CASE WHEN lower("from") = $1 THEN $2 ELSE "from" END,
+                "to" = CASE WHEN lower("to") = $1 THEN $2 ELSE "to" END,
+                canonical_rcpt = CASE WHEN lower(canonical_rcpt) = $1 THEN $2
+                    ELSE canonical_rcpt END,
+                body_text = '', body_html = '', attachment_text = NULL, headers = '{}',
+                attachments = '[]', s3_prefix = NULL, index_key = NULL, urls = NULL, dsn = NULL,
+                trace = NULL, origin_ip = NULL, origin_host = NULL, report = NULL,
+                calendar = NULL, links = NULL, smime = NULL, submitter = NULL, rdns = NULL,
+                helo = NULL, thread_id = NULL, list_id = NULL, list_unsubscribe = NULL,
+                header_from = NULL, reply_to = NULL
+            WHERE lower("from") = $1 OR lower("to") = $1 OR lower(canonical_rcpt) = $1;"#,
+        address,
+        replacement
+    );
+    Ok(query.execute(pool).await?.rows_affected())
+}
+
+/// Delete the queued messages from or to `address`, including dead ones, returns their
+/// number. They are deleted even when anonymizing, as they hold the whole message.
+#[instrument(skip(pool))]
+pub async fn delete_queued(pool: &PgPool, address: &str) -> Result<u64> {
+    trace!("deleting queued messages of address");
+    let query = sqlx::query!(
+        r#"DELETE FROM data_gateways.smtp_gateway_queue
+            WHERE lower(envelope->>'from') = $1
+                OR EXISTS (
+                    SELECT 1 FROM jsonb_array_elements_text(envelope->'rcpts') AS rcpt
+                    WHERE lower(rcpt) = $1
+                )
+                OR EXISTS (
+                    SELECT 1 FROM jsonb_each_text(envelope->'aliases') AS alias
+                    WHERE lower(alias.value) = $1
+                );"#,
+        address
+    );
+    Ok(query.execute(pool).await?.rows_affected())
+}
+
+/// Delete the status rows of messages from or to `address`.
+#[instrument(skip(pool))]
+pub async fn delete_deliveries(pool: &PgPool, address: &str) -> Result<u64> {
+    trace!("deleting status of address");
+    let query = sqlx::query!(
+        r#"DELETE FROM data_gateways.smtp_gateway_deliveries
+            WHERE lower("from") = $1 OR lower(rcpt) = $1;"#,
+        address
+    );
+    Ok(query.execute(pool).await?.rows_affected())
+}
+
+/// Replace `address` with `replacement` in the status rows of its messages, dropping the
+/// prefixes and errors, which may name it.
+#[instrument(skip(pool))]
+pub async fn anonymize_deliveries(pool: &PgPool, address: &str, replacement: &str) -> Result<u64> {
+    trace!("anonymizing status of address");
+    let query = sqlx::query!(
+        r#"UPDATE data_gateways.smtp_gateway_deliveries
+            SET "from" = CASE WHEN lower("from") = $1 THEN $2 ELSE "from" END,
+                rcpt = CASE WHEN lower(rcpt) = $1 THEN $2 ELSE rcpt END,
+                s3_prefix = NULL, error = NULL
+            WHERE lower("from") = $1 OR lower(rcpt) = $1;"#,
+        address,
+        replacement
+    );
+    Ok(query.execute(pool).await?.rows_affected())
+}
+
+/// Record a deletion request for auditing.
+#[instrument(skip(pool, forgotten))]
+pub async fn insert_deletion(
+    pool: &PgPool,
+    address: &str,
+    anonymized: bool,
+    requested_via: &str,
+    forgotten: &forget::Forgotten,
+) -> Result<()> {
+    trace!("recording deletion");
+    let query = sqlx::query!(
+        r#"INSERT INTO data_gateways.smtp_gateway_deletions
+            (address, anonymized, requested_via, messages, objects, queued)
+            VALUES ($1, $2, $3, $4, $5, $6);"#,
+        address,
+        anonymized,
+        requested_via,
+        forgotten.messages as i64,
+        forgotten.objects as i64,
+        forgotten.queued as i64
+    );
+    let _ = query.execute(pool).await?;
+    Ok(())
+}
+
+/// A message in the queue, see `queue::Queue`.
+#[derive(Debug)]
+pub struct QueuedRow {
+    pub id: i64,
+    pub envelope: Value,
+    pub data: Vec<u8>,
+    /// including the current one
+    pub attempts: i32,
+}
+
+#[instrument(skip(pool, envelope, data))]
+pub async fn enqueue(pool: &PgPool, envelope: &Value, data: &[u8]) -> Result<i64> {
+    trace!("queueing message");
+    let query = sqlx::query_scalar!(
+        r#"INSERT INTO data_gateways.smtp_gateway_queue (envelope, data)
+            VALUES ($1, $2)
+            RETURNING id;"#,
+        envelope,
+        data
+    );
+    Ok(query.fetch_one(pool).await?)
+}
+
+/// Whether the message was inserted for `rcpt` already, e.g. by an earlier attempt to store
+/// a queued message.
+#[instrument(skip(pool))]
+pub async fn mail_stored(pool: &PgPool, message_id: &str, rcpt: &str) -> Result<bool> {
+    let query = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+                SELECT 1 FROM data_gateways.smtp_gateway WHERE message_id = $1 AND "to" = $2
+            ) AS "stored!";"#,
+        message_id,
+        rcpt
+    );
+    Ok(query.fetch_one(pool).await?)
+}
+
+/// The next queued message that is due, its next attempt pushed back by `lease_secs` so no
+/// other worker takes it meanwhile.
+#[instrument(skip(pool))]
+pub async fn claim_queued(pool: &PgPool, lease_secs: f64) -> Result<Option<QueuedRow>> {
+    let query = sqlx::query_as!(
+        QueuedRow,
+        r#"UPDATE data_gateways.smtp_gateway_queue
+            SET attempts = attempts + 1, next_attempt = now() + make_interval(secs => $1)
+            WHERE id = (
+                SELECT id FROM data_gateways.smtp_gateway_queue
+                WHERE NOT dead AND next_attempt <= now()
+                ORDER BY next_attempt
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, envelope, data, attempts;"#,
+        lease_secs
+    );
+    Ok(query.fetch_optional(pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn dequeue(pool: &PgPool, id: i64) -> Result<()> {
+    trace!("removing stored message from queue");
+    let query = sqlx::query!(
+        r#"DELETE FROM data_gateways.smtp_gateway_queue WHERE id = $1;"#,
+        id
+    );
+    let _ = query.execute(pool).await?;
+    Ok(())
+}
+
+/// Record a failed attempt, the message is tried again after `delay_secs` unless `dead`.
+#[instrument(skip(pool, envelope))]
+pub async fn requeue(
+    pool: &PgPool,
+    id: i64,
+    envelope: &Value,
+    error: &str,
+    delay_secs: f64,
+    dead: bool,
+) -> Result<()> {
+    trace!("recording failed attempt");
+    let query = sqlx::query!(
+        r#"UPDATE data_gateways.smtp_gateway_queue
+            SET envelope = $2, last_error = $3, next_attempt = now() + make_interval(secs => $4),
+                dead = $5
+            WHERE id = $1;"#,
+        id,
+        envelope,
+        error,
+        delay_secs,
+        dead
+    );
+    let _ = query.execute(pool).await?;
+    Ok(())
+}
+
+/// Start tracking the status of a message for every recipient, returns the row ids by
+/// recipient.
+#[instrument(skip(pool))]
+pub async fn insert_deliveries(
+    pool: &PgPool,
+    message_id: Option<&str>,
+    from: &str,
+    rcpts: &[String],
+    status: &str,
+) -> Result<Vec<(String, i64)>> {
+    trace!("tracking message status");
+    let query = sqlx::query!(
+        r#"INSERT INTO data_gateways.smtp_gateway_deliveries (message_id, "from", rcpt, status)
+            SELECT $1, $2, unnest($3::text[]), $4
+            RETURNING id, rcpt;"#,
+        message_id,
+        from,
+        rcpts,
+        status
+    );
+    let rows = query.fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|r| (r.rcpt, r.id)).collect())
+}
+
+/// Set the status and its timestamp, an `s3_prefix` is kept once set.
+#[instrument(skip(pool))]
+pub async fn update_delivery(
+    pool: &PgPool,
+    id: i64,
+    status: &str,
+    s3_prefix: Option<&str>,
+    error: Option<&str>,
+) -> Result<()> {
+    let query = sqlx::query!(
+        r#"UPDATE data_gateways.smtp_gateway_deliveries
+            SET status = $2, s3_prefix = COALESCE($3, s3_prefix), error = $4,
+                stored_at = CASE WHEN $2 = 'stored' THEN now() ELSE stored_at END,
+                notified_at = CASE WHEN $2 = 'notified' THEN now() ELSE notified_at END,
+                failed_at = CASE WHEN $2 = 'failed' THEN now() ELSE failed_at END,
+                quarantined_at = CASE WHEN $2 = 'quarantined' THEN now()
+                    ELSE quarantined_at END
+            WHERE id = $1;"#,
+        id,
+        status,
+        s3_prefix,
+        error
+    );
+    let _ = query.execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeliveryStatus {
+    pub id: i64,
+    pub message_id: Option<String>,
+    pub from: String,
+    pub rcpt: String,
+    pub status: String,
+    pub received_at: DateTime<Utc>,
+    pub stored_at: Option<DateTime<Utc>>,
+    pub s3_prefix: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Messages received more than `older_than_secs` ago that are still received or stored,
+/// oldest first.
+#[instrument(skip(pool))]
+pub async fn stuck_deliveries(
+    pool: &PgPool,
+    older_than_secs: f64,
+    limit: i64,
+) -> Result<Vec<DeliveryStatus>> {
+    trace!("listing stuck messages");
+    let query = sqlx::query_as!(
+        DeliveryStatus,
+        r#"SELECT id, message_id, "from", rcpt, status, received_at, stored_at, s3_prefix, error
+            FROM data_gateways.smtp_gateway_deliveries
+            WHERE status IN ('received', 'stored')
+                AND received_at < now() - make_interval(secs => $1)
+            ORDER BY received_at
+            LIMIT $2;"#,
+        older_than_secs,
+        limit
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// A queued message whose retries are exhausted or that was refused.
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub envelope: Value,
+    pub queued_at: DateTime<Utc>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub size: i64,
+}
+
+#[instrument(skip(pool))]
+pub async fn dead_letters(pool: &PgPool, limit: i64) -> Result<Vec<DeadLetter>> {
+    trace!("listing dead letters");
+    let query = sqlx::query_as!(
+        DeadLetter,
+        r#"SELECT id, envelope, queued_at, attempts, last_error,
+                octet_length(data)::bigint AS "size!"
+            FROM data_gateways.smtp_gateway_queue
+            WHERE dead
+            ORDER BY id
+            LIMIT $1;"#,
+        limit
+    );
+    Ok(query.fetch_all(pool).await?)
+}
+
+/// Queue a dead letter again with fresh attempts, returns whether it exists.
+#[instrument(skip(pool))]
+pub async fn retry_dead_letter(pool: &PgPool, id: i64) -> Result<bool> {
+    trace!("retrying dead letter");
+    let query = sqlx::query!(
+        r#"UPDATE data_gateways.smtp_gateway_queue
+            SET dead = false, attempts = 0, next_attempt = now()
+            WHERE id = $1 AND dead;"#,
+        id
+    );
+    Ok(query.execute(pool).await?.rows_affected() > 0)
+}
+
+/// Delete a dead letter, returns whether it exists.
+#[instrument(skip(pool))]
+pub async fn drop_dead_letter(pool: &PgPool, id: i64) -> Result<bool> {
+    trace!("dropping dead letter");
+    let query = sqlx::query!(
+        r#"DELETE FROM data_gateways.smtp_gateway_queue WHERE id = $1 AND dead;"#,
+        id
+    );
+    Ok(query.execute(pool).await?.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// The columns `anonymize_mails` clears, besides replacing the address in `from`, `to` and
+    /// `canonical_rcpt`: anything that can hold an address, the client or the content.
+    const ANONYMIZED_COLUMNS: &[&str] = &[
+        "from",
+        "to",
+        "canonical_rcpt",
+        "body_text",
+        "body_html",
+        "headers",
+        "attachments",
+        "attachment_text",
+        "s3_prefix",
+        "index_key",
+        "urls",
+        "dsn",
+        "trace",
+        "origin_ip",
+        "origin_host",
+        "report",
+        "calendar",
+        "links",
+        "smime",
+        "submitter",
+        "rdns",
+        "helo",
+        "thread_id",
+        "list_id",
+        "list_unsubscribe",
+        "header_from",
+        "reply_to",
+    ];
+
+    /// The columns `anonymize_mails` keeps, they identify nobody and remain for statistics.
+    const KEPT_COLUMNS: &[&str] = &[
+        "message_id",
+        "received_at",
+        "size",
+        "declared_size",
+        "bucket",
+        "tags",
+        "tenant",
+        "expires_at",
+        "kind",
+        "charsets",
+        "redactions",
+        "tls",
+        "mirrored",
+        "encrypted_archive",
+        "language",
+        "auto_submitted",
+        "precedence",
+        "automated",
+        "from_mismatch",
+    ];
+
+    /// The columns of the message table the migrations add.
+    fn migrated_columns() -> Vec<String> {
+        let mut columns = vec![];
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+        for entry in fs::read_dir(dir).unwrap() {
+            let sql = fs::read_to_string(entry.unwrap().path()).unwrap();
+            for statement in sql.split(';') {
+                let mut lines = statement.lines().filter(|l| !l.starts_with("--"));
+                if lines.next() != Some("ALTER TABLE data_gateways.smtp_gateway") {
+                    continue;
+                }
+                columns.extend(lines.filter_map(|line| {
+                    let column = line.trim().strip_prefix("ADD COLUMN IF NOT EXISTS ")?;
+                    Some(column.split(' ').next()?.to_string())
+                }));
+            }
+        }
+        columns
+    }
+
+    #[test]
+    fn anonymize_handles_every_column() {
+        let columns = MAIL_COLUMNS
+            .iter()
+            .map(|c| c.to_string())
+            .chain(migrated_columns());
+        for column in columns {
+            let anonymized = ANONYMIZED_COLUMNS.contains(&column.as_str());
+            let kept = KEPT_COLUMNS.contains(&column.as_str());
+            assert!(
+                anonymized != kept,
+                "add column {} to either ANONYMIZED_COLUMNS or KEPT_COLUMNS",
+                column
+            );
+        }
+
+        // and the query clears them
+        let source = include_str!("db.rs");
+        let start = source.find("SET \"from\" = CASE").unwrap();
+        let query = &source[start..];
+        let query = &query[..query.find("WHERE").unwrap()];
+        for column in ANONYMIZED_COLUMNS {
+            let assignment = match *column {
+                "from" | "to" => format!("\"{}\" = ", column),
+                column => format!("{} = ", column),
+            };
+            assert!(query.contains(&assignment), "{} is not anonymized", column);
+        }
+    }
+}
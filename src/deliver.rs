@@ -0,0 +1,245 @@
+use std::env;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use mail_parser::MessageParser;
+use rustyknife::behaviour::Intl;
+use rustyknife::rfc5321::{mail_command, rcpt_command};
+use smtpbis::{Handler, Reply};
+use tokio::io::AsyncReadExt;
+use tracing::{error, info, instrument};
+
+use crate::smtp::{Delivery, SmtpBackend, SmtpSession, MAX_MESSAGE_SIZE};
+
+// sysexits.h, as expected from sendmail
+const EX_OK: i32 = 0;
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOPERM: i32 = 77;
+const EX_TEMPFAIL: i32 = 75;
+
+#[derive(Args, Debug)]
+pub struct DeliverArgs {
+    /// Envelope sender address
+    #[arg(short = 'f')]
+    from: Option<String>,
+    /// Read recipients from the To, Cc and Bcc headers
+    #[arg(short = 't')]
+    extract_recipients: bool,
+    /// Do not treat a line with a single dot as end of input
+    #[arg(short = 'i')]
+    ignore_dots: bool,
+    /// Sendmail options, only `-oi` is understood
+    #[arg(short = 'o', hide = true)]
+    options: Vec<String>,
+    /// Full name of the sender, ignored
+    #[arg(short = 'F', hide = true)]
+    #[allow(dead_code)]
+    full_name: Option<String>,
+    /// Recipient addresses
+    recipients: Vec<String>,
+}
+
+/// Deliver one message from stdin, returning a sendmail compatible exit code.
+#[instrument(skip(backend))]
+pub async fn deliver(args: DeliverArgs, backend: SmtpBackend) -> i32 {
+    let ignore_dots = args.ignore_dots || args.options.iter().any(|o| o == "i");
+    let mut raw = match read_message(ignore_dots).await {
+        Ok(raw) => raw,
+        Err(e) => {
+            error!("could not read message: {:?}", e);
+            return EX_DATAERR;
+        }
+    };
+
+    let mut rcpts = args.recipients;
+    if args.extract_recipients {
+        match header_recipients(&raw) {
+            Ok(mut header_rcpts) => rcpts.append(&mut header_rcpts),
+            Err(e) => {
+                error!("could not extract recipients: {:?}", e);
+                return EX_DATAERR;
+            }
+        }
+        // like sendmail, do not tell the other recipients
+        raw = strip_bcc(&raw);
+    }
+    if rcpts.is_empty() {
+        error!("no recipients given");
+        return EX_USAGE;
+    }
+
+    let mut session = match backend.new_session(None) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("could not create session: {:?}", e);
+            return EX_TEMPFAIL;
+        }
+    };
+    session.protocol = "local";
+    let from = args.from.unwrap_or_else(|| {
+        let user = env::var("USER").unwrap_or_else(|_| "root".to_string());
+        format!("{}@{}", user, session.config.domain)
+    });
+
+    match deliver_message(&mut session, &from, &rcpts, raw).await {
+        Ok(()) => {
+            info!("delivered message");
+            EX_OK
+        }
+        Err(e) => {
+            error!("could not deliver message: {:?}", e);
+            e.downcast_ref::<Rejected>()
+                .map_or(EX_TEMPFAIL, |r| r.exit_code())
+        }
+    }
+}
+
+/// A reply refusing the message.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct Rejected(String);
+
+impl Rejected {
+    fn from_reply(reply: Reply) -> Self {
+        Self(reply.to_string().trim().to_string())
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.0.starts_with('5')
+    }
+
+    fn exit_code(&self) -> i32 {
+        if self.is_permanent() {
+            EX_NOPERM
+        } else {
+            EX_TEMPFAIL
+        }
+    }
+}
+
+/// Run a message through the same checks and pipeline as mail received via SMTP.
+pub async fn deliver_message(
+    session: &mut SmtpSession,
+    from: &str,
+    rcpts: &[String],
+    raw: Vec<u8>,
+) -> Result<()> {
+    // run the same checks as for SMTP
+    let mail = format!("MAIL FROM:<{}>\r\n", from);
+    let (_, (path, params)) = mail_command::<Intl>(mail.as_bytes())
+        .map_err(|_| anyhow!("invalid sender address {}", from))?;
+    if let Some(reply) = session.mail(path, params).await {
+        bail!(Rejected::from_reply(reply));
+    }
+
+    for rcpt in rcpts {
+        let line = format!("RCPT TO:<{}>\r\n", rcpt);
+        let (_, (path, params)) = rcpt_command::<Intl>(line.as_bytes())
+            .map_err(|_| anyhow!("invalid recipient address {}", rcpt))?;
+        if let Some(reply) = session.rcpt(path, params).await {
+            bail!(Rejected::from_reply(reply));
+        }
+    }
+
+    session.data = Vec::new();
+    if !session.receive_content(&raw) {
+        bail!(Rejected::from_reply(session.too_big()));
+    }
+    match session.process_message().await? {
+        Delivery::Refused(reply) => bail!(Rejected::from_reply(reply)),
+        Delivery::Delivered(results) => {
+            for (rcpt, result) in results {
+                result.with_context(|| format!("could not deliver to {}", rcpt))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the message from stdin.
+async fn read_message(ignore_dots: bool) -> Result<Vec<u8>> {
+    let mut input = Vec::new();
+    tokio::io::stdin()
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_to_end(&mut input)
+        .await?;
+    if input.len() > MAX_MESSAGE_SIZE {
+        bail!("message exceeds fixed maximum message size");
+    }
+    Ok(normalize_line_endings(&input, !ignore_dots))
+}
+
+/// Convert line endings to CRLF, optionally stopping at a line with a single dot.
+pub fn normalize_line_endings(input: &[u8], stop_at_dot: bool) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(input.len());
+    for line in input.split_inclusive(|b| *b == b'\n') {
+        let content = line
+            .strip_suffix(b"\n")
+            .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+            .unwrap_or(line);
+        if stop_at_dot && content == b"." {
+            break;
+        }
+        raw.extend_from_slice(content);
+        raw.extend_from_slice(b"\r\n");
+    }
+    raw
+}
+
+/// Remove the Bcc header, including its continuation lines, from a message with CRLF line
+/// endings.
+fn strip_bcc(raw: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(raw.len());
+    let mut in_bcc = false;
+    let mut lines = raw.split_inclusive(|b| *b == b'\n');
+    for line in lines.by_ref() {
+        if line == b"\r\n" {
+            stripped.extend_from_slice(line);
+            break;
+        }
+        let continued = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continued {
+            in_bcc = line
+                .get(..4)
+                .is_some_and(|name| name.eq_ignore_ascii_case(b"bcc:"));
+        }
+        if !in_bcc {
+            stripped.extend_from_slice(line);
+        }
+    }
+    for line in lines {
+        stripped.extend_from_slice(line);
+    }
+    stripped
+}
+
+fn header_recipients(raw: &[u8]) -> Result<Vec<String>> {
+    let message = MessageParser::default()
+        .parse(raw)
+        .context("Cannot parse message")?;
+
+    let mut rcpts = vec![];
+    for addrs in [message.to(), message.cc(), message.bcc()]
+        .into_iter()
+        .flatten()
+    {
+        rcpts.extend(addrs.iter().filter_map(|a| a.address().map(str::to_string)));
+    }
+    Ok(rcpts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_bcc_header() {
+        let raw = b"To: a@example.com\r\nBCC: b@example.com,\r\n c@example.com\r\n\
+                    Subject: test\r\n\r\nBcc: body\r\n";
+        assert_eq!(
+            strip_bcc(raw),
+            b"To: a@example.com\r\nSubject: test\r\n\r\nBcc: body\r\n"
+        );
+    }
+}
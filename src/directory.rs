@@ -0,0 +1,12 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+pub mod callout;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+
+/// Looks up whether recipients exist, as an alternative to the `is_valid_rcpt` DB function.
+#[async_trait]
+pub trait Directory: Send + Sync {
+    async fn is_valid_rcpt(&self, rcpt: &str) -> Result<bool>;
+}
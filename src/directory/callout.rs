@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{instrument, trace};
+
+use super::Directory;
+
+/// expired entries are removed once the cache has grown this large
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// Verifies recipients by asking an internal mail server whether it would accept them,
+/// without sending a message (sender callout with `MAIL FROM:<>` and `RCPT TO`).
+pub struct Callout {
+    /// `host:port` of the mail server
+    server: String,
+    /// name sent with `EHLO`
+    helo: String,
+    timeout: Duration,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    /// whether a recipient exists and until when that is assumed
+    cache: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl Callout {
+    pub fn new(
+        server: &str,
+        helo: &str,
+        timeout: Duration,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+    ) -> Self {
+        Self {
+            server: server.to_string(),
+            helo: helo.to_string(),
+            timeout,
+            positive_ttl,
+            negative_ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached(&self, rcpt: &str) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+        let (valid, expires) = cache.get(rcpt)?;
+        (*expires > Instant::now()).then_some(*valid)
+    }
+
+    fn remember(&self, rcpt: &str, valid: bool) {
+        let ttl = match valid {
+            true => self.positive_ttl,
+            false => self.negative_ttl,
+        };
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        cache.insert(rcpt.to_string(), (valid, now + ttl));
+    }
+
+    /// Ask the server about `rcpt`, temporary failures are errors.
+    async fn probe(&self, rcpt: &str) -> Result<bool> {
+        let stream = TcpStream::connect(&self.server)
+            .await
+            .with_context(|| format!("could not connect to {}", self.server))?;
+        let mut stream = BufReader::new(stream);
+
+        expect(&mut stream, 220).await?;
+        command(&mut stream, &format!("EHLO {}", self.helo)).await?;
+        expect(&mut stream, 250).await?;
+        command(&mut stream, "MAIL FROM:<>").await?;
+        expect(&mut stream, 250).await?;
+        command(&mut stream, &format!("RCPT TO:<{}>", rcpt)).await?;
+        let code = reply(&mut stream).await?;
+        // the server's reply does not matter anymore
+        let _ = command(&mut stream, "QUIT").await;
+
+        match code {
+            250 | 251 => Ok(true),
+            500..=599 => Ok(false),
+            code => bail!("callout for {} failed with {}", rcpt, code),
+        }
+    }
+}
+
+async fn command(stream: &mut BufReader<TcpStream>, command: &str) -> Result<()> {
+    trace!("callout: {}", command);
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// The code of a possibly multi-line reply.
+async fn reply(stream: &mut BufReader<TcpStream>) -> Result<u16> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            bail!("callout server closed the connection");
+        }
+        trace!("callout: {}", line.trim_end());
+        let code = line
+            .get(..3)
+            .and_then(|c| c.parse().ok())
+            .with_context(|| format!("malformed reply {:?}", line))?;
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(code);
+        }
+    }
+}
+
+async fn expect(stream: &mut BufReader<TcpStream>, expected: u16) -> Result<()> {
+    let code = reply(stream).await?;
+    if code != expected {
+        bail!("callout server replied {}, expected {}", code, expected);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Directory for Callout {
+    #[instrument(skip(self))]
+    async fn is_valid_rcpt(&self, rcpt: &str) -> Result<bool> {
+        let rcpt = rcpt.to_lowercase();
+        if let Some(valid) = self.cached(&rcpt) {
+            trace!("cached callout result {}", valid);
+            return Ok(valid);
+        }
+        let valid = tokio::time::timeout(self.timeout, self.probe(&rcpt))
+            .await
+            .context("callout timed out")??;
+        self.remember(&rcpt, valid);
+        Ok(valid)
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, Scope, SearchEntry};
+use tracing::{instrument, trace};
+
+use super::Directory;
+use crate::auth::{Authenticator, Credentials};
+
+/// LDAP result code of a failed bind
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// LDAP or Active Directory server checking passwords and recipients.
+///
+/// Users and recipients are searched below `base_dn` with filters like `(uid={username})` and
+/// `(mail={rcpt})`, binding as `bind_dn` first. Passwords are checked by binding as the user.
+pub struct LdapDirectory {
+    url: String,
+    bind_dn: String,
+    bind_password: String,
+    base_dn: String,
+    user_filter: String,
+    rcpt_filter: String,
+}
+
+impl LdapDirectory {
+    pub fn new(
+        url: &str,
+        bind_dn: &str,
+        bind_password: &str,
+        base_dn: &str,
+        user_filter: &str,
+        rcpt_filter: &str,
+    ) -> Self {
+        Self {
+            url: url.to_string(),
+            bind_dn: bind_dn.to_string(),
+            bind_password: bind_password.to_string(),
+            base_dn: base_dn.to_string(),
+            user_filter: user_filter.to_string(),
+            rcpt_filter: rcpt_filter.to_string(),
+        }
+    }
+
+    /// A connection bound as the service account.
+    async fn connect(&self) -> Result<Ldap> {
+        let (conn, mut ldap) = LdapConnAsync::new(&self.url).await?;
+        ldap3::drive!(conn);
+        ldap.simple_bind(&self.bind_dn, &self.bind_password)
+            .await?
+            .success()?;
+        Ok(ldap)
+    }
+
+    /// DNs of the entries matching `filter`.
+    async fn search(&self, ldap: &mut Ldap, filter: &str) -> Result<Vec<String>> {
+        trace!("searching {}", filter);
+        let (entries, _) = ldap
+            .search(&self.base_dn, Scope::Subtree, filter, vec!["1.1"])
+            .await?
+            .success()?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| SearchEntry::construct(entry).dn)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Directory for LdapDirectory {
+    #[instrument(skip(self))]
+    async fn is_valid_rcpt(&self, rcpt: &str) -> Result<bool> {
+        let filter = self.rcpt_filter.replace("{rcpt}", &ldap_escape(rcpt));
+        let mut ldap = self.connect().await?;
+        let found = self.search(&mut ldap, &filter).await?;
+        ldap.unbind().await?;
+        Ok(!found.is_empty())
+    }
+}
+
+#[async_trait]
+impl Authenticator for LdapDirectory {
+    fn mechanisms(&self) -> &'static [&'static str] {
+        &["PLAIN", "LOGIN"]
+    }
+
+    #[instrument(skip_all)]
+    async fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>> {
+        let Credentials::Password { username, password } = credentials else {
+            return Ok(None);
+        };
+        // binding without password succeeds anonymously
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let filter = self
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let mut ldap = self.connect().await?;
+        let dns = self.search(&mut ldap, &filter).await?;
+        let dn = match dns.as_slice() {
+            [] => {
+                ldap.unbind().await?;
+                return Ok(None);
+            }
+            [dn] => dn,
+            _ => bail!("{} matches several users", username),
+        };
+        let result = ldap.simple_bind(dn, password).await?;
+        ldap.unbind().await?;
+        match result.rc {
+            0 => Ok(Some(username.clone())),
+            INVALID_CREDENTIALS => Ok(None),
+            _ => {
+                result.success()?;
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use serde_json::json;
+use tracing::{info, instrument, warn};
+
+use crate::db;
+use crate::smtp::Config;
+
+#[derive(Args, Debug)]
+pub struct DlqArgs {
+    #[command(subcommand)]
+    command: DlqCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum DlqCommand {
+    /// List the dead messages with their envelope and last error
+    List {
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Queue dead messages again, e.g. after fixing the cause of their failure
+    Retry {
+        /// Ids as listed
+        #[arg(required = true)]
+        ids: Vec<i64>,
+    },
+    /// Delete dead messages for good
+    Drop {
+        /// Ids as listed
+        #[arg(required = true)]
+        ids: Vec<i64>,
+    },
+}
+
+/// Handle the `dlq` command, printing the dead messages or what was done as JSON.
+#[instrument(skip(config))]
+pub async fn dlq_command(args: DlqArgs, config: &Config) -> Result<()> {
+    let pool = &config.pg_pool;
+    match args.command {
+        DlqCommand::List { limit } => {
+            for dead in db::dead_letters(pool, limit).await? {
+                println!("{}", serde_json::to_string(&dead)?);
+            }
+        }
+        DlqCommand::Retry { ids } => {
+            let mut retried = 0;
+            for id in ids {
+                if db::retry_dead_letter(pool, id).await? {
+                    info!(id, "queued dead message again");
+                    retried += 1;
+                } else {
+                    warn!(id, "no such dead message");
+                }
+            }
+            println!("{}", json!({ "retried": retried }));
+        }
+        DlqCommand::Drop { ids } => {
+            let mut dropped = 0;
+            for id in ids {
+                if db::drop_dead_letter(pool, id).await? {
+                    info!(id, "dropped dead message");
+                    dropped += 1;
+                } else {
+                    warn!(id, "no such dead message");
+                }
+            }
+            println!("{}", json!({ "dropped": dropped }));
+        }
+    }
+    Ok(())
+}
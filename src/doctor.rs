@@ -0,0 +1,258 @@
+use std::env;
+use std::fmt::Display;
+use std::net::IpAddr;
+
+use anyhow::{bail, Context, Result};
+use chrono::{TimeZone, Utc};
+use clap::Args;
+use hickory_resolver::TokioAsyncResolver;
+use tokio_rustls::rustls::{Error as TlsError, InconsistentKeys};
+use tracing::instrument;
+
+use crate::db;
+use crate::rdns::{Policy, ReverseDns};
+use crate::s3;
+use crate::tls;
+
+/// Certificates expiring sooner are warned about.
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+#[derive(Args, Debug)]
+pub struct DoctorArgs {
+    /// Skip the DNS checks, e.g. for gateways only reachable internally
+    #[arg(long)]
+    skip_dns: bool,
+}
+
+/// Outcome of the checks, printed as they run.
+#[derive(Debug, Default)]
+struct Report {
+    warnings: usize,
+    errors: usize,
+}
+
+impl Report {
+    fn ok(&mut self, check: &str, message: impl Display) {
+        println!("ok       {}: {}", check, message);
+    }
+
+    fn warning(&mut self, check: &str, message: impl Display) {
+        self.warnings += 1;
+        println!("WARNING  {}: {}", check, message);
+    }
+
+    fn error(&mut self, check: &str, message: impl Display) {
+        self.errors += 1;
+        println!("ERROR    {}: {}", check, message);
+    }
+}
+
+/// Validate the configuration end to end: the TLS certificate, DNS, S3 and the DB schema.
+/// Fails if any check does.
+#[instrument]
+pub async fn doctor_command(args: DoctorArgs) -> Result<()> {
+    let mut report = Report::default();
+    check_tls(&mut report).await;
+    if !args.skip_dns {
+        check_dns(&mut report).await;
+    }
+    check_s3(&mut report).await;
+    check_db(&mut report).await;
+
+    println!("{} errors, {} warnings", report.errors, report.warnings);
+    if report.errors > 0 {
+        bail!("{} checks failed", report.errors);
+    }
+    Ok(())
+}
+
+async fn check_tls(report: &mut Report) {
+    let resolver = async {
+        let cert_path =
+            env::var("SMTP_CERT_FILE").context("env variable SMTP_CERT_FILE not provided")?;
+        let key = crate::tls_key_from_env().await?;
+        let provider = tls::crypto_provider(&env::var("TLS_CRYPTO_PROVIDER").unwrap_or_default())?;
+        tls::CertificateResolver::new(&cert_path, key, provider)
+    };
+    let resolver = match resolver.await {
+        Ok(resolver) => resolver,
+        Err(e) => return report.error("tls", format!("could not load certificate: {:#}", e)),
+    };
+
+    let certified_key = resolver.certified_key.load();
+    match certified_key.keys_match() {
+        Ok(()) => report.ok("tls", "certificate matches the private key"),
+        Err(TlsError::InconsistentKeys(InconsistentKeys::Unknown)) => {
+            report.warning("tls", "could not compare the certificate with a remote key")
+        }
+        Err(e) => report.error("tls", format!("certificate does not match the key: {}", e)),
+    }
+
+    let not_after = certified_key
+        .cert
+        .first()
+        .context("no certificate found")
+        .and_then(tls::not_after);
+    match not_after.map(|t| Utc.timestamp_opt(t, 0).single()) {
+        Ok(Some(not_after)) => {
+            let days = (not_after - Utc::now()).num_days();
+            if not_after < Utc::now() {
+                report.error("tls", format!("certificate expired at {}", not_after));
+            } else if days < EXPIRY_WARNING_DAYS {
+                report.warning("tls", format!("certificate expires in {} days", days));
+            } else {
+                report.ok("tls", format!("certificate valid until {}", not_after));
+            }
+        }
+        Ok(None) => report.error("tls", "certificate has an invalid expiry"),
+        Err(e) => report.error("tls", format!("could not read certificate expiry: {:#}", e)),
+    }
+}
+
+/// MX records for `SMTP_DOMAIN` and PTR records for the addresses of the exchanges.
+async fn check_dns(report: &mut Report) {
+    let Ok(domain) = env::var("SMTP_DOMAIN") else {
+        return report.error("dns", "env variable SMTP_DOMAIN not provided");
+    };
+    let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+        Ok(resolver) => resolver,
+        Err(e) => return report.error("dns", format!("could not configure resolver: {}", e)),
+    };
+    let rdns = match ReverseDns::from_system_conf(Policy::Any) {
+        Ok(rdns) => rdns,
+        Err(e) => return report.error("dns", format!("could not configure resolver: {}", e)),
+    };
+
+    let exchanges: Vec<String> = match resolver.mx_lookup(format!("{}.", domain)).await {
+        Ok(mx) => mx.iter().map(|mx| mx.exchange().to_utf8()).collect(),
+        Err(e) => {
+            return report.error(
+                "dns",
+                format!(
+                    "no MX records for {}, senders will not find the gateway: {}",
+                    domain, e
+                ),
+            )
+        }
+    };
+    report.ok(
+        "dns",
+        format!("MX records for {}: {}", domain, exchanges.join(", ")),
+    );
+
+    for exchange in exchanges {
+        let addrs: Vec<IpAddr> = match resolver.lookup_ip(exchange.as_str()).await {
+            Ok(addrs) => addrs.iter().collect(),
+            Err(e) => {
+                report.error(
+                    "dns",
+                    format!("MX host {} does not resolve: {}", exchange, e),
+                );
+                continue;
+            }
+        };
+        for addr in addrs {
+            match rdns.lookup(addr).await {
+                Ok(rdns) if rdns.confirmed => report.ok(
+                    "dns",
+                    format!("{} has PTR {}", addr, rdns.name.unwrap_or_default()),
+                ),
+                Ok(rdns) => match rdns.name {
+                    Some(name) => report.warning(
+                        "dns",
+                        format!("PTR {} of {} does not resolve back to it", name, addr),
+                    ),
+                    None => report.warning(
+                        "dns",
+                        format!("{} has no PTR record, receivers may distrust it", addr),
+                    ),
+                },
+                Err(e) => report.error("dns", format!("could not look up PTR of {}: {}", addr, e)),
+            }
+        }
+    }
+}
+
+/// Write and delete a probe object.
+async fn check_s3(report: &mut Report) {
+    let Ok(bucket) = env::var("BUCKET_NAME") else {
+        return report.error("s3", "env variable BUCKET_NAME not provided");
+    };
+    let s3_config = match crate::s3_config_from_env().await {
+        Ok(s3_config) => s3_config,
+        Err(e) => return report.error("s3", format!("{:#}", e)),
+    };
+    match s3::probe_bucket(&s3_config, &bucket).await {
+        Ok(()) => report.ok("s3", format!("can put and delete objects in {}", bucket)),
+        Err(e) => report.error(
+            "s3",
+            format!(
+                "{:#}, check that the bucket exists and the credentials allow s3:PutObject \
+                and s3:DeleteObject",
+                e
+            ),
+        ),
+    }
+}
+
+/// The message table with all migrations applied and the DB functions.
+async fn check_db(report: &mut Report) {
+    let Ok(database_url) = env::var("DATABASE_URL") else {
+        return report.error("db", "env variable DATABASE_URL not provided");
+    };
+    let pool = match crate::pg_pool_from_env(&database_url, 1).await {
+        Ok(pool) => pool,
+        Err(e) => return report.error("db", format!("could not connect: {:#}", e)),
+    };
+
+    match db::mail_columns(&pool).await {
+        Ok(columns) if columns.is_empty() => report.error(
+            "db",
+            "table data_gateways.smtp_gateway is missing, apply the migrations with \
+            `sqlx migrate run`",
+        ),
+        Ok(columns) => {
+            let missing: Vec<&str> = db::MAIL_COLUMNS
+                .iter()
+                .copied()
+                .filter(|c| !columns.iter().any(|column| column == c))
+                .collect();
+            if missing.is_empty() {
+                report.ok("db", "table data_gateways.smtp_gateway is up to date");
+            } else {
+                report.error(
+                    "db",
+                    format!(
+                        "columns {} are missing, apply the migrations with `sqlx migrate run`",
+                        missing.join(", ")
+                    ),
+                );
+            }
+        }
+        Err(e) => report.error("db", format!("could not read the schema: {:#}", e)),
+    }
+
+    let mut functions = vec!["is_valid_rcpt(text,text)"];
+    if env::var("SUBMISSION_BIND_ADDR").is_ok() || env::var("POP3_BIND_ADDR").is_ok() {
+        functions.push("is_valid_login(text,text)");
+    }
+    for function in functions {
+        match db::function_result(&pool, function).await {
+            Ok(Some(result)) if result == "boolean" => {
+                report.ok("db", format!("function {} exists", function))
+            }
+            Ok(Some(result)) => report.error(
+                "db",
+                format!(
+                    "function {} returns {} instead of boolean",
+                    function, result
+                ),
+            ),
+            Ok(None) => report.error(
+                "db",
+                format!("function {} is missing, see the README", function),
+            ),
+            Err(e) => report.error("db", format!("could not check {}: {:#}", function, e)),
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rustyknife::rfc5321::Param;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use smtpbis::{EnhancedCode, Reply};
+use tracing::{info, instrument};
+
+use crate::smtp::Config;
+
+/// What to return in a bounce, from the `RET` parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+/// DSN parameters of the MAIL command (RFC 3461).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EnvelopeDsn {
+    pub ret: Option<Ret>,
+    pub envid: Option<String>,
+}
+
+/// DSN parameters of a RCPT command (RFC 3461).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RcptDsn {
+    /// `NEVER` or a list of `SUCCESS`, `FAILURE` and `DELAY`
+    pub notify: Option<Vec<String>>,
+    /// address type and decoded original recipient, e.g. `rfc822;user@example.com`
+    pub orcpt: Option<String>,
+}
+
+/// A recipient the message could not be stored for.
+#[derive(Debug)]
+pub struct Failure {
+    pub rcpt: String,
+    pub orcpt: Option<String>,
+    pub diagnostic: String,
+}
+
+impl Failure {
+    /// The failures to report for the recipients and their errors, honoring `NOTIFY=NEVER`.
+    pub fn notified(
+        rcpt_dsn: &HashMap<String, RcptDsn>,
+        failed: impl IntoIterator<Item = (String, anyhow::Error)>,
+    ) -> Vec<Failure> {
+        failed
+            .into_iter()
+            .filter_map(|(rcpt, error)| {
+                let dsn = rcpt_dsn.get(&rcpt).cloned().unwrap_or_default();
+                dsn.notify_failure().then(|| Failure {
+                    diagnostic: format!("{:#}", error),
+                    orcpt: dsn.orcpt,
+                    rcpt,
+                })
+            })
+            .collect()
+    }
+}
+
+fn param_parts(params: &[Param]) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    params.iter().map(|Param(keyword, value)| {
+        (
+            keyword.to_string().to_uppercase(),
+            value.as_ref().map(|v| v.to_string()),
+        )
+    })
+}
+
+fn syntax_error(message: &'static str) -> Reply {
+    Reply::new(501, Some(EnhancedCode(5, 5, 4)), message)
+}
+
+/// Decode xtext (RFC 3461 section 4), where `+XX` encodes a byte in hex.
+pub fn decode_xtext(xtext: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(xtext.len());
+    let mut bytes = xtext.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'+' {
+            let hex = [bytes.next()?, bytes.next()?];
+            // from_str_radix would accept a sign, e.g. `++1`
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+impl EnvelopeDsn {
+    pub fn from_params(params: &[Param]) -> Result<Self, Reply> {
+        let mut dsn = Self::default();
+        for (keyword, value) in param_parts(params) {
+            match (keyword.as_str(), value) {
+                ("RET", Some(ret)) if dsn.ret.is_none() => {
+                    dsn.ret = Some(match ret.to_uppercase().as_str() {
+                        "FULL" => Ret::Full,
+                        "HDRS" => Ret::Hdrs,
+                        _ => return Err(syntax_error("invalid RET parameter")),
+                    });
+                }
+                ("ENVID", Some(envid)) if dsn.envid.is_none() => {
+                    let envid = decode_xtext(&envid)
+                        .ok_or_else(|| syntax_error("invalid ENVID parameter"))?;
+                    dsn.envid = Some(envid);
+                }
+                ("RET" | "ENVID", _) => return Err(syntax_error("invalid DSN parameter")),
+                _ => {}
+            }
+        }
+        Ok(dsn)
+    }
+}
+
+impl RcptDsn {
+    pub fn from_params(params: &[Param]) -> Result<Self, Reply> {
+        let mut dsn = Self::default();
+        for (keyword, value) in param_parts(params) {
+            match (keyword.as_str(), value) {
+                ("NOTIFY", Some(notify)) if dsn.notify.is_none() => {
+                    let notify: Vec<String> = notify.split(',').map(str::to_uppercase).collect();
+                    let valid = notify == ["NEVER"]
+                        || notify
+                            .iter()
+                            .all(|n| matches!(n.as_str(), "SUCCESS" | "FAILURE" | "DELAY"));
+                    if !valid {
+                        return Err(syntax_error("invalid NOTIFY parameter"));
+                    }
+                    dsn.notify = Some(notify);
+                }
+                ("ORCPT", Some(orcpt)) if dsn.orcpt.is_none() => {
+                    let orcpt = decode_xtext(&orcpt)
+                        .filter(|o| o.contains(';'))
+                        .ok_or_else(|| syntax_error("invalid ORCPT parameter"))?;
+                    dsn.orcpt = Some(orcpt);
+                }
+                ("NOTIFY" | "ORCPT", _) => return Err(syntax_error("invalid DSN parameter")),
+                _ => {}
+            }
+        }
+        Ok(dsn)
+    }
+
+    /// Whether the sender asked for (or did not opt out of) failure notifications.
+    pub fn notify_failure(&self) -> bool {
+        self.notify
+            .as_ref()
+            .is_none_or(|n| n.iter().any(|n| n == "FAILURE"))
+    }
+}
+
+/// The DSN parameters to store with the message, if any were given.
+pub fn to_json(envelope: &EnvelopeDsn, rcpt: Option<&RcptDsn>) -> Option<Value> {
+    let rcpt = rcpt.cloned().unwrap_or_default();
+    if envelope.ret.is_none()
+        && envelope.envid.is_none()
+        && rcpt.notify.is_none()
+        && rcpt.orcpt.is_none()
+    {
+        return None;
+    }
+    Some(json!({
+        "ret": envelope.ret,
+        "envid": envelope.envid,
+        "notify": rcpt.notify,
+        "orcpt": rcpt.orcpt,
+    }))
+}
+
+/// Build an RFC 3464 delivery status notification for the failed recipients.
+///
+/// Only the original message's headers are returned, unless `RET=FULL` was requested.
+pub fn failure_report(
+    domain: &str,
+    from: &str,
+    envelope: &EnvelopeDsn,
+    failed: &[Failure],
+    raw: &[u8],
+) -> Vec<u8> {
+    let now = Utc::now();
+    let boundary = format!("{}.{}/{}", now.timestamp(), std::process::id(), domain);
+    let full = envelope.ret == Some(Ret::Full);
+    let returned = if full {
+        raw
+    } else {
+        raw.windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map_or(raw, |pos| &raw[..pos + 2])
+    };
+
+    let mut report = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{domain}>\r\n\
+         To: <{from}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}.{pid}@{domain}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         Your message could not be delivered to the following recipients:\r\n\
+         \r\n",
+        date = now.to_rfc2822(),
+        id = now.timestamp_nanos_opt().unwrap_or_default(),
+        pid = std::process::id(),
+    );
+    for failure in failed {
+        report.push_str(&format!(
+            "<{}>: {}\r\n",
+            failure.rcpt,
+            failure.diagnostic.replace(['\r', '\n'], " "),
+        ));
+    }
+
+    report.push_str(&format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n"
+    ));
+    if let Some(envid) = envelope.envid.as_ref() {
+        report.push_str(&format!("Original-Envelope-Id: {}\r\n", envid));
+    }
+    report.push_str(&format!(
+        "Reporting-MTA: dns; {domain}\r\n\
+         Arrival-Date: {date}\r\n",
+        date = now.to_rfc2822(),
+    ));
+    for failure in failed {
+        report.push_str("\r\n");
+        if let Some(orcpt) = failure.orcpt.as_ref() {
+            report.push_str(&format!("Original-Recipient: {}\r\n", orcpt));
+        }
+        report.push_str(&format!(
+            "Final-Recipient: rfc822; {}\r\n\
+             Action: failed\r\n\
+             Status: 5.3.0\r\n\
+             Diagnostic-Code: smtp; {}\r\n",
+            failure.rcpt,
+            failure.diagnostic.replace(['\r', '\n'], " "),
+        ));
+    }
+
+    let content_type = if full {
+        "message/rfc822"
+    } else {
+        "text/rfc822-headers"
+    };
+    report.push_str(&format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: {content_type}\r\n\
+         \r\n"
+    ));
+    let mut report = report.into_bytes();
+    report.extend_from_slice(returned);
+    report.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    report
+}
+
+/// Send a bounce for the failed recipients to the envelope sender via the relay.
+#[instrument(skip(config, envelope, raw))]
+pub async fn send_bounce(
+    config: &Config,
+    from: &str,
+    envelope: &EnvelopeDsn,
+    failed: &[Failure],
+    raw: &[u8],
+) -> Result<()> {
+    // never bounce bounces
+    if from.is_empty() || failed.is_empty() {
+        return Ok(());
+    }
+    let relay = config
+        .relay
+        .as_ref()
+        .context("cannot send bounce without relay")?;
+
+    let domain = config.domain.to_string();
+    let report = failure_report(&domain, from, envelope, failed, raw);
+    relay.send("", &[from.to_string()], &report).await?;
+    info!("sent bounce to {}", from);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rustyknife::behaviour::Intl;
+    use rustyknife::rfc5321::{mail_command, rcpt_command};
+
+    use super::*;
+
+    fn mail(params: &str) -> Result<EnvelopeDsn, String> {
+        let line = format!("MAIL FROM:<a@example.com> {}\r\n", params);
+        let (_, (_, params)) = mail_command::<Intl>(line.as_bytes()).unwrap();
+        EnvelopeDsn::from_params(&params).map_err(|reply| reply.to_string())
+    }
+
+    fn rcpt(params: &str) -> Result<RcptDsn, String> {
+        let line = format!("RCPT TO:<b@example.com> {}\r\n", params);
+        let (_, (_, params)) = rcpt_command::<Intl>(line.as_bytes()).unwrap();
+        RcptDsn::from_params(&params).map_err(|reply| reply.to_string())
+    }
+
+    #[test]
+    fn xtext() {
+        assert_eq!(decode_xtext("plain").as_deref(), Some("plain"));
+        assert_eq!(decode_xtext("a+2Bb+3D").as_deref(), Some("a+b="));
+        assert_eq!(decode_xtext("a+2bb+3d").as_deref(), Some("a+b="));
+        assert_eq!(decode_xtext("+C3+A4").as_deref(), Some("ä"));
+        // passed through as in utf-8-xtext (RFC 6533)
+        assert_eq!(decode_xtext("ä").as_deref(), Some("ä"));
+    }
+
+    #[test]
+    fn invalid_xtext() {
+        assert_eq!(decode_xtext("a+"), None);
+        assert_eq!(decode_xtext("a+2"), None);
+        assert_eq!(decode_xtext("+2G"), None);
+        assert_eq!(decode_xtext("++1"), None);
+        assert_eq!(decode_xtext("+ä"), None);
+        // not UTF-8
+        assert_eq!(decode_xtext("+FF"), None);
+    }
+
+    #[test]
+    fn envelope_params() {
+        let dsn = mail("RET=hdrs ENVID=id+2B1").unwrap();
+        assert_eq!(dsn.ret, Some(Ret::Hdrs));
+        assert_eq!(dsn.envid.as_deref(), Some("id+1"));
+        assert_eq!(mail("RET=FULL").unwrap().ret, Some(Ret::Full));
+        assert_eq!(mail("SIZE=10").unwrap().ret, None);
+
+        let invalid = "501 5.5.4 invalid RET parameter\r\n";
+        assert_eq!(mail("RET=BODY").unwrap_err(), invalid);
+        assert_eq!(
+            mail("ENVID=a+2").unwrap_err(),
+            "501 5.5.4 invalid ENVID parameter\r\n"
+        );
+        assert_eq!(
+            mail("RET=FULL RET=HDRS").unwrap_err(),
+            "501 5.5.4 invalid DSN parameter\r\n"
+        );
+        assert_eq!(
+            mail("RET").unwrap_err(),
+            "501 5.5.4 invalid DSN parameter\r\n"
+        );
+    }
+
+    #[test]
+    fn rcpt_params() {
+        let dsn = rcpt("NOTIFY=success,Delay ORCPT=rfc822;user+2Bx@example.com").unwrap();
+        assert_eq!(
+            dsn.notify.as_deref(),
+            Some(&["SUCCESS", "DELAY"].map(String::from)[..])
+        );
+        assert_eq!(dsn.orcpt.as_deref(), Some("rfc822;user+x@example.com"));
+        assert!(!dsn.notify_failure());
+        assert!(rcpt("NOTIFY=FAILURE").unwrap().notify_failure());
+        assert!(!rcpt("NOTIFY=NEVER").unwrap().notify_failure());
+        assert!(RcptDsn::default().notify_failure());
+
+        let invalid = "501 5.5.4 invalid NOTIFY parameter\r\n";
+        assert_eq!(rcpt("NOTIFY=NEVER,FAILURE").unwrap_err(), invalid);
+        assert_eq!(rcpt("NOTIFY=ALWAYS").unwrap_err(), invalid);
+        assert_eq!(
+            rcpt("ORCPT=user@example.com").unwrap_err(),
+            "501 5.5.4 invalid ORCPT parameter\r\n"
+        );
+        assert_eq!(
+            rcpt("NOTIFY=FAILURE NOTIFY=DELAY").unwrap_err(),
+            "501 5.5.4 invalid DSN parameter\r\n"
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+#[cfg(feature = "age")]
+pub mod age;
+#[cfg(feature = "pgp")]
+pub mod pgp;
+
+/// Encrypts stored objects to public keys, so that the S3 provider cannot read them.
+pub trait Encryption: Send + Sync {
+    /// `age` or `pgp`
+    fn method(&self) -> &'static str;
+    /// identifies the keys objects are encrypted to, age recipients or PGP key fingerprints
+    fn fingerprints(&self) -> Vec<String>;
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Decrypts stored objects with the private keys, e.g. to export messages.
+pub trait Decryption: Send + Sync {
+    /// `age` or `pgp`
+    fn method(&self) -> &'static str;
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Decrypt an object encrypted with `method` as recorded in the manifest, objects of
+/// messages stored without encryption are returned as they are.
+pub fn decrypt(
+    decryption: Option<&dyn Decryption>,
+    method: Option<&str>,
+    data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let Some(method) = method else {
+        return Ok(data);
+    };
+    match decryption {
+        Some(decryption) if decryption.method() == method => decryption.decrypt(&data),
+        Some(decryption) => bail!(
+            "object is {} encrypted, but a {} key is configured",
+            method,
+            decryption.method()
+        ),
+        None => bail!(
+            "object is {} encrypted, set DECRYPT_AGE_IDENTITY or DECRYPT_PGP_KEY to decrypt it",
+            method
+        ),
+    }
+}
+
+/// Description of the encryption for the manifest.
+pub fn to_json(encryption: &dyn Encryption) -> Value {
+    json!({
+        "method": encryption.method(),
+        "fingerprints": encryption.fingerprints(),
+    })
+}
@@ -0,0 +1,99 @@
+use std::io::{Read, Write};
+
+use age::x25519::{Identity, Recipient};
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::instrument;
+
+use super::{Decryption, Encryption};
+
+/// Encrypts to X25519 age recipients (`age1...`).
+pub struct AgeEncryption {
+    recipients: Vec<Recipient>,
+}
+
+impl AgeEncryption {
+    #[instrument]
+    pub fn new(recipients: &[String]) -> Result<Self> {
+        let recipients = recipients
+            .iter()
+            .map(|r| {
+                r.parse()
+                    .map_err(|e| anyhow!("could not parse age recipient {}: {}", r, e))
+            })
+            .collect::<Result<Vec<Recipient>>>()?;
+        if recipients.is_empty() {
+            bail!("no age recipients given");
+        }
+        Ok(Self { recipients })
+    }
+}
+
+impl Encryption for AgeEncryption {
+    fn method(&self) -> &'static str {
+        "age"
+    }
+
+    /// age has no fingerprints, the recipients are public anyway
+    fn fingerprints(&self) -> Vec<String> {
+        self.recipients.iter().map(|r| r.to_string()).collect()
+    }
+
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|r| Box::new(r.clone()) as Box<dyn age::Recipient + Send>)
+            .collect();
+        let encryptor = age::Encryptor::with_recipients(recipients).context("no age recipients")?;
+
+        let mut encrypted = Vec::with_capacity(data.len() + 256);
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+}
+
+/// Decrypts with the X25519 identities (`AGE-SECRET-KEY-1...`) of an age identity file.
+pub struct AgeDecryption {
+    identities: Vec<Identity>,
+}
+
+impl AgeDecryption {
+    #[instrument]
+    pub fn new(path: &str) -> Result<Self> {
+        let file =
+            std::fs::read_to_string(path).with_context(|| format!("could not read {}", path))?;
+        let identities = file
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                l.parse()
+                    .map_err(|e| anyhow!("could not parse age identity in {}: {}", path, e))
+            })
+            .collect::<Result<Vec<Identity>>>()?;
+        if identities.is_empty() {
+            bail!("no age identities in {}", path);
+        }
+        Ok(Self { identities })
+    }
+}
+
+impl Decryption for AgeDecryption {
+    fn method(&self) -> &'static str {
+        "age"
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let age::Decryptor::Recipients(decryptor) = age::Decryptor::new(data)? else {
+            bail!("object is encrypted with a passphrase");
+        };
+        let identities = self.identities.iter().map(|i| i as &dyn age::Identity);
+        let mut reader = decryptor.decrypt(identities)?;
+
+        let mut decrypted = Vec::with_capacity(data.len());
+        reader.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use pgp::composed::{
+    Deserializable, Message, SignedPublicKey, SignedPublicSubKey, SignedSecretKey,
+};
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::ser::Serialize;
+use pgp::types::KeyTrait;
+use tracing::instrument;
+
+use super::{Decryption, Encryption};
+
+/// Encrypts to the encryption subkeys of an OpenPGP public key, or the primary key if it has
+/// none. Objects are binary OpenPGP messages.
+pub struct PgpEncryption {
+    key: SignedPublicKey,
+}
+
+impl PgpEncryption {
+    /// `path` is an ASCII armored public key.
+    #[instrument]
+    pub fn new(path: &str) -> Result<Self> {
+        let armored =
+            std::fs::read_to_string(path).with_context(|| format!("could not read {}", path))?;
+        let (key, _) = SignedPublicKey::from_string(&armored).context("could not parse PGP key")?;
+        key.verify().context("invalid PGP key")?;
+        Ok(Self { key })
+    }
+
+    fn subkeys(&self) -> Vec<&SignedPublicSubKey> {
+        self.key
+            .public_subkeys
+            .iter()
+            .filter(|k| k.is_encryption_key())
+            .collect()
+    }
+}
+
+fn fingerprint(key: &impl KeyTrait) -> String {
+    key.fingerprint()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+impl Encryption for PgpEncryption {
+    fn method(&self) -> &'static str {
+        "pgp"
+    }
+
+    fn fingerprints(&self) -> Vec<String> {
+        let subkeys = self.subkeys();
+        if subkeys.is_empty() {
+            return vec![fingerprint(&self.key)];
+        }
+        subkeys.into_iter().map(fingerprint).collect()
+    }
+
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let message = Message::new_literal_bytes("", data);
+        let mut rng = rand::thread_rng();
+        let subkeys = self.subkeys();
+        let encrypted = if subkeys.is_empty() {
+            message.encrypt_to_keys(&mut rng, SymmetricKeyAlgorithm::AES256, &[&self.key])?
+        } else {
+            message.encrypt_to_keys(&mut rng, SymmetricKeyAlgorithm::AES256, &subkeys)?
+        };
+        Ok(encrypted.to_bytes()?)
+    }
+}
+
+/// Decrypts with an OpenPGP secret key.
+pub struct PgpDecryption {
+    key: SignedSecretKey,
+    passphrase: String,
+}
+
+impl PgpDecryption {
+    /// `path` is an ASCII armored secret key, protected with `passphrase` if any.
+    #[instrument(skip(passphrase))]
+    pub fn new(path: &str, passphrase: Option<String>) -> Result<Self> {
+        let armored =
+            std::fs::read_to_string(path).with_context(|| format!("could not read {}", path))?;
+        let (key, _) =
+            SignedSecretKey::from_string(&armored).context("could not parse PGP secret key")?;
+        key.verify().context("invalid PGP secret key")?;
+        Ok(Self {
+            key,
+            passphrase: passphrase.unwrap_or_default(),
+        })
+    }
+}
+
+impl Decryption for PgpDecryption {
+    fn method(&self) -> &'static str {
+        "pgp"
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let message = Message::from_bytes(data).context("could not parse PGP message")?;
+        let passphrase = self.passphrase.clone();
+        let (mut decrypter, _) = message.decrypt(|| passphrase, &[&self.key])?;
+        let decrypted = decrypter
+            .next()
+            .context("empty PGP message")??
+            .get_content()?
+            .context("PGP message without content")?;
+        Ok(decrypted)
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::try_join_all;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{error, instrument, trace};
+
+use crate::trace::Origin;
+
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod feed;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// Published after a message was stored for a recipient.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageStored {
+    pub message_id: String,
+    pub from: String,
+    pub rcpt: String,
+    /// storage identity the recipient is an alias of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_rcpt: Option<String>,
+    pub received_at: DateTime<Utc>,
+    pub bucket: String,
+    pub s3_prefix: String,
+    pub attachments: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub urls: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// originating client, from the `Received` headers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+    /// normalized message ID of the thread's root, see `thread::thread_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+}
+
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the SMTP reply has to wait until the event is acknowledged.
+    fn wait_for_ack(&self) -> bool;
+
+    async fn publish(&self, event: &MessageStored) -> Result<()>;
+}
+
+#[derive(Default)]
+pub struct Events {
+    sinks: Vec<Arc<dyn EventSink>>,
+}
+
+impl Events {
+    #[cfg_attr(
+        not(any(feature = "kafka", feature = "amqp", feature = "redis")),
+        allow(dead_code)
+    )]
+    pub fn add(&mut self, sink: impl EventSink + 'static) {
+        self.sinks.push(Arc::new(sink));
+    }
+
+    /// Publish to all sinks. Failures of sinks that are waited for are returned, the
+    /// others are only logged.
+    #[instrument(skip_all, fields(message_id = event.message_id, rcpt = event.rcpt))]
+    pub async fn publish(&self, event: &MessageStored) -> Result<()> {
+        feed::send(event);
+        let mut acked = vec![];
+        for sink in &self.sinks {
+            if sink.wait_for_ack() {
+                acked.push(sink.publish(event));
+            } else {
+                let sink = sink.clone();
+                let event = event.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = sink.publish(&event).await {
+                        error!("could not publish event to {}: {:?}", sink.name(), e);
+                    }
+                });
+            }
+        }
+        try_join_all(acked).await?;
+        trace!("published events");
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
+use tokio::sync::Mutex;
+use tracing::{instrument, trace};
+
+use super::{EventSink, MessageStored};
+
+pub struct AmqpSink {
+    url: String,
+    exchange: String,
+    wait_for_confirm: bool,
+    channel: Mutex<Option<Channel>>,
+}
+
+impl AmqpSink {
+    #[instrument(skip(url))]
+    pub fn new(url: &str, exchange: &str, wait_for_confirm: bool) -> Self {
+        Self {
+            url: url.to_string(),
+            exchange: exchange.to_string(),
+            wait_for_confirm,
+            channel: Mutex::new(None),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn connect(&self) -> Result<Channel> {
+        trace!("connecting to AMQP broker");
+        let connection = Connection::connect(&self.url, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        Ok(channel)
+    }
+}
+
+#[async_trait]
+impl EventSink for AmqpSink {
+    fn name(&self) -> &'static str {
+        "amqp"
+    }
+
+    fn wait_for_ack(&self) -> bool {
+        self.wait_for_confirm
+    }
+
+    /// Publish with the recipient's domain as routing key.
+    #[instrument(skip_all)]
+    async fn publish(&self, event: &MessageStored) -> Result<()> {
+        let payload = serde_json::to_vec(event)?;
+        let routing_key = event
+            .rcpt
+            .rsplit_once('@')
+            .map_or(event.rcpt.as_str(), |(_, domain)| domain)
+            .to_lowercase();
+
+        let mut channel = self.channel.lock().await;
+        let current = match channel.take() {
+            Some(c) if c.status().connected() => c,
+            _ => self.connect().await?,
+        };
+
+        let confirmation = current
+            .basic_publish(
+                &self.exchange,
+                &routing_key,
+                BasicPublishOptions::default(),
+                &payload,
+                BasicProperties::default()
+                    .with_content_type("application/json".into())
+                    .with_message_id(event.message_id.as_str().into())
+                    .with_delivery_mode(2),
+            )
+            .await?
+            .await?;
+        *channel = Some(current);
+
+        if confirmation.is_nack() {
+            bail!("AMQP broker did not confirm message");
+        }
+        trace!(
+            "published to exchange {} with {}",
+            self.exchange,
+            routing_key
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use super::MessageStored;
+
+/// Events buffered per subscriber before it misses some.
+const CAPACITY: usize = 1024;
+
+/// Process wide, so subscribers keep receiving events across configuration reloads.
+static FEED: Lazy<Sender<MessageStored>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+/// Receive every stored message from now on, e.g. for the live feed of the HTTP API.
+pub fn subscribe() -> Receiver<MessageStored> {
+    FEED.subscribe()
+}
+
+/// Recipients a subscriber is interested in, all if `None`.
+#[derive(Debug)]
+pub struct Filter(pub Option<Vec<String>>);
+
+impl Filter {
+    pub fn matches(&self, event: &MessageStored) -> bool {
+        let Some(rcpts) = self.0.as_ref() else {
+            return true;
+        };
+        rcpts.iter().any(|rcpt| {
+            rcpt.eq_ignore_ascii_case(&event.rcpt)
+                || event
+                    .canonical_rcpt
+                    .as_ref()
+                    .is_some_and(|c| rcpt.eq_ignore_ascii_case(c))
+        })
+    }
+}
+
+/// Pass the event to the current subscribers, if any.
+pub(super) fn send(event: &MessageStored) {
+    if FEED.receiver_count() > 0 {
+        let _ = FEED.send(event.clone());
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use tracing::{instrument, trace};
+
+use super::{EventSink, MessageStored};
+
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+    timeout: Duration,
+    wait_for_ack: bool,
+}
+
+impl KafkaSink {
+    /// `properties` are additional librdkafka settings, e.g. `security.protocol` or
+    /// `sasl.mechanisms`.
+    #[instrument(skip(properties))]
+    pub fn new(
+        brokers: &str,
+        topic: &str,
+        properties: &[(String, String)],
+        timeout: Duration,
+        wait_for_ack: bool,
+    ) -> Result<Self> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", timeout.as_millis().to_string());
+        if wait_for_ack {
+            config.set("acks", "all");
+        }
+        for (key, value) in properties {
+            config.set(key, value);
+        }
+
+        Ok(Self {
+            producer: config.create()?,
+            topic: topic.to_string(),
+            timeout,
+            wait_for_ack,
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn wait_for_ack(&self) -> bool {
+        self.wait_for_ack
+    }
+
+    #[instrument(skip_all)]
+    async fn publish(&self, event: &MessageStored) -> Result<()> {
+        let payload = serde_json::to_vec(event)?;
+        let record = FutureRecord::to(&self.topic)
+            .key(&event.message_id)
+            .payload(&payload);
+
+        let (partition, offset) = self
+            .producer
+            .send(record, self.timeout)
+            .await
+            .map_err(|(e, _)| anyhow!("could not publish to kafka: {}", e))?;
+        trace!("published to partition {} at offset {}", partition, offset);
+        Ok(())
+    }
+}
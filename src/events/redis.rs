@@ -0,0 +1,85 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use tracing::{instrument, trace};
+
+use super::{EventSink, MessageStored};
+
+pub struct RedisSink {
+    connection: ConnectionManager,
+    channel: Option<String>,
+    stream: Option<String>,
+    stream_maxlen: Option<usize>,
+    wait_for_ack: bool,
+}
+
+impl RedisSink {
+    #[instrument(skip(url))]
+    pub async fn new(
+        url: &str,
+        channel: Option<String>,
+        stream: Option<String>,
+        stream_maxlen: Option<usize>,
+        wait_for_ack: bool,
+    ) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+        Ok(Self {
+            connection,
+            channel,
+            stream,
+            stream_maxlen,
+            wait_for_ack,
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for RedisSink {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn wait_for_ack(&self) -> bool {
+        self.wait_for_ack
+    }
+
+    /// `PUBLISH` the whole event as JSON and/or `XADD` its main fields to a stream.
+    #[instrument(skip_all)]
+    async fn publish(&self, event: &MessageStored) -> Result<()> {
+        let mut connection = self.connection.clone();
+
+        if let Some(channel) = self.channel.as_ref() {
+            let payload = serde_json::to_string(event)?;
+            let receivers: usize = connection.publish(channel, payload).await?;
+            trace!("published to {} receivers", receivers);
+        }
+
+        if let Some(stream) = self.stream.as_ref() {
+            let mut cmd = redis::cmd("XADD");
+            cmd.arg(stream);
+            if let Some(maxlen) = self.stream_maxlen {
+                cmd.arg("MAXLEN").arg("~").arg(maxlen);
+            }
+            let id: String = cmd
+                .arg("*")
+                .arg("message_id")
+                .arg(&event.message_id)
+                .arg("from")
+                .arg(&event.from)
+                .arg("rcpt")
+                .arg(&event.rcpt)
+                .arg("bucket")
+                .arg(&event.bucket)
+                .arg("s3_prefix")
+                .arg(&event.s3_prefix)
+                .arg("received_at")
+                .arg(event.received_at.to_rfc3339())
+                .query_async(&mut connection)
+                .await?;
+            trace!("added stream entry {}", id);
+        }
+        Ok(())
+    }
+}
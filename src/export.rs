@@ -0,0 +1,296 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{NaiveDate, TimeZone, Utc};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use tracing::{info, instrument, warn};
+
+use crate::db::{self, StoredObject};
+use crate::encryption;
+use crate::s3;
+use crate::smtp::Config;
+
+/// Headers of the original message describing its structure, replaced when reassembling.
+const STRUCTURE_HEADERS: &[&str] = &["content-type", "content-transfer-encoding", "mime-version"];
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Recipient whose messages to export
+    #[arg(long)]
+    rcpt: String,
+    /// Only messages received on or after this day, e.g. `2024-01-01`
+    #[arg(long)]
+    since: Option<NaiveDate>,
+    #[arg(long, value_enum, default_value_t = Format::Mbox)]
+    format: Format,
+    /// mbox file or Maildir directory to write, created if missing
+    #[arg(long, short)]
+    output: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// mboxrd, i.e. `From ` lines are quoted with `>`
+    Mbox,
+    Maildir,
+}
+
+/// What was written by an export.
+#[derive(Debug, Default, Serialize)]
+pub struct Exported {
+    pub messages: u64,
+    /// messages without `raw.eml`, rebuilt from the stored bodies and attachments
+    pub reassembled: u64,
+}
+
+/// Write all messages stored for a recipient to an mbox or Maildir, e.g. for a migration or
+/// an e-discovery request.
+#[instrument(skip(config))]
+pub async fn export_command(args: ExportArgs, config: &Config) -> Result<()> {
+    let since = args
+        .since
+        .map(|day| Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap()));
+    let messages = db::list_objects(&config.pg_pool, &args.rcpt, since, i64::MAX).await?;
+
+    let mut writer = match args.format {
+        Format::Mbox => Writer::Mbox(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&args.output)
+                .with_context(|| format!("could not open {}", args.output.display()))?,
+        ),
+        Format::Maildir => {
+            for dir in ["cur", "new", "tmp"] {
+                fs::create_dir_all(args.output.join(dir))?;
+            }
+            Writer::Maildir(args.output.clone())
+        }
+    };
+
+    let mut exported = Exported::default();
+    for message in &messages {
+        let method = object_encryption(config, message).await?;
+        let method = method.as_deref();
+        let (raw, reassembled) = match fetch_raw(config, message, method).await? {
+            Some(raw) => (raw, false),
+            None => (reassemble(config, &args.rcpt, message, method).await?, true),
+        };
+        writer.write(message, &raw, exported.messages)?;
+        exported.messages += 1;
+        if reassembled {
+            exported.reassembled += 1;
+        }
+    }
+    info!(
+        "exported {} messages for {} to {}",
+        exported.messages,
+        args.rcpt,
+        args.output.display()
+    );
+    println!("{}", serde_json::to_string(&exported)?);
+    Ok(())
+}
+
+/// How the objects of a message were encrypted before uploading them, as recorded in its
+/// manifest, e.g. `age`.
+pub async fn object_encryption(config: &Config, message: &StoredObject) -> Result<Option<String>> {
+    let Some(prefix) = message.s3_prefix.as_deref() else {
+        return Ok(None);
+    };
+    let bucket = message.bucket.as_deref().unwrap_or(&config.bucket);
+    let key = format!("{}manifest.json", prefix);
+    let Some(manifest) = s3::get_object(&config.s3_config, bucket, &key).await? else {
+        return Ok(None);
+    };
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest)
+        .with_context(|| format!("could not parse manifest of {}", message.message_id))?;
+    Ok(manifest["encryption"]["method"]
+        .as_str()
+        .map(str::to_string))
+}
+
+async fn fetch_raw(
+    config: &Config,
+    message: &StoredObject,
+    method: Option<&str>,
+) -> Result<Option<Vec<u8>>> {
+    let Some(prefix) = message.s3_prefix.as_deref() else {
+        return Ok(None);
+    };
+    let bucket = message.bucket.as_deref().unwrap_or(&config.bucket);
+    let raw = s3::get_raw_message(&config.s3_config, bucket, prefix)
+        .await
+        .with_context(|| format!("could not fetch message {}", message.message_id))?;
+    raw.map(|raw| encryption::decrypt(config.decryption.as_deref(), method, raw))
+        .transpose()
+        .with_context(|| format!("could not decrypt message {}", message.message_id))
+}
+
+/// Rebuild a message not stored as received, e.g. when redacting, from the headers and
+/// bodies in the DB and the attachments in S3.
+#[instrument(skip(config, message), fields(message_id = message.message_id))]
+async fn reassemble(
+    config: &Config,
+    rcpt: &str,
+    message: &StoredObject,
+    method: Option<&str>,
+) -> Result<Vec<u8>> {
+    warn!("reassembling message without raw.eml");
+    let mut mail = db::get_mail(&config.pg_pool, &message.message_id, Some(rcpt))
+        .await?
+        .with_context(|| format!("message {} vanished", message.message_id))?;
+    if let Some(keys) = config.body_keys.as_ref() {
+        mail.body_text = keys.decrypt(&mail.body_text)?;
+        mail.body_html = keys.decrypt(&mail.body_html)?;
+    }
+
+    let boundary = format!("smtp-s3-dump-{}", mail.received_at.timestamp_micros());
+    let mut out = String::new();
+    for (name, value) in mail.headers.as_object().into_iter().flatten() {
+        if STRUCTURE_HEADERS.contains(&name.to_lowercase().as_str()) {
+            continue;
+        }
+        out.push_str(&format!(
+            "{}: {}\r\n",
+            name,
+            value.as_str().unwrap_or_default()
+        ));
+    }
+    out.push_str("MIME-Version: 1.0\r\n");
+    out.push_str(&format!(
+        "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n",
+        boundary
+    ));
+
+    out.push_str(&format!("--{}\r\n", boundary));
+    if mail.body_html.is_empty() {
+        out.push_str(&part(
+            "text/plain; charset=utf-8",
+            None,
+            mail.body_text.as_bytes(),
+        ));
+    } else {
+        let alternative = format!("{}-alt", boundary);
+        out.push_str(&format!(
+            "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n",
+            alternative
+        ));
+        out.push_str(&format!("--{}\r\n", alternative));
+        out.push_str(&part(
+            "text/plain; charset=utf-8",
+            None,
+            mail.body_text.as_bytes(),
+        ));
+        out.push_str(&format!("--{}\r\n", alternative));
+        out.push_str(&part(
+            "text/html; charset=utf-8",
+            None,
+            mail.body_html.as_bytes(),
+        ));
+        out.push_str(&format!("--{}--\r\n", alternative));
+    }
+
+    let bucket = mail.bucket.as_deref().unwrap_or(&config.bucket);
+    for attachment in mail.attachments.as_array().into_iter().flatten() {
+        let (Some(key), Some(filename)) = (
+            attachment["rel_path"].as_str(),
+            attachment["filename"].as_str(),
+        ) else {
+            continue;
+        };
+        let Some(content) = s3::get_object(&config.s3_config, bucket, key).await? else {
+            warn!("attachment {} is missing", key);
+            continue;
+        };
+        let content = encryption::decrypt(config.decryption.as_deref(), method, content)
+            .with_context(|| format!("could not decrypt attachment {}", key))?;
+        let content_type = attachment["content_type"]
+            .as_str()
+            .unwrap_or("application/octet-stream");
+        out.push_str(&format!("--{}\r\n", boundary));
+        out.push_str(&part(content_type, Some(filename), &content));
+    }
+    out.push_str(&format!("--{}--\r\n", boundary));
+    Ok(out.into_bytes())
+}
+
+/// A base64 encoded MIME part, an attachment if `filename` is given.
+pub fn part(content_type: &str, filename: Option<&str>, content: &[u8]) -> String {
+    let filename = filename.map(|f| f.replace(['"', '\\', '\r', '\n'], "_"));
+    let mut out = match filename.as_deref() {
+        Some(filename) => format!(
+            "Content-Type: {}; name=\"{}\"\r\nContent-Disposition: attachment; filename=\"{}\"\r\n",
+            content_type, filename, filename
+        ),
+        None => format!("Content-Type: {}\r\n", content_type),
+    };
+    out.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+    let encoded = STANDARD.encode(content);
+    for line in encoded.as_bytes().chunks(76) {
+        // base64 is ASCII
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push_str("\r\n");
+    }
+    out
+}
+
+enum Writer {
+    Mbox(fs::File),
+    Maildir(PathBuf),
+}
+
+impl Writer {
+    fn write(&mut self, message: &StoredObject, raw: &[u8], n: u64) -> Result<()> {
+        match self {
+            Writer::Mbox(file) => {
+                let mut out = format!(
+                    "From {} {}\n",
+                    envelope_sender(&message.from),
+                    message.received_at.format("%a %b %e %H:%M:%S %Y")
+                )
+                .into_bytes();
+                for line in raw.split_inclusive(|b| *b == b'\n') {
+                    let line = line.strip_suffix(b"\n").unwrap_or(line);
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    let unquoted = line.iter().position(|b| *b != b'>').unwrap_or(line.len());
+                    if line[unquoted..].starts_with(b"From ") {
+                        out.push(b'>');
+                    }
+                    out.extend_from_slice(line);
+                    out.push(b'\n');
+                }
+                out.push(b'\n');
+                file.write_all(&out)?;
+            }
+            Writer::Maildir(dir) => {
+                let name = format!(
+                    "{}.M{}P{}Q{}.smtp-s3-dump:2,S",
+                    message.received_at.timestamp(),
+                    message.received_at.timestamp_subsec_micros(),
+                    std::process::id(),
+                    n
+                );
+                // written to tmp first, so readers never see partial messages
+                let tmp = dir.join("tmp").join(&name);
+                fs::write(&tmp, raw)?;
+                fs::rename(&tmp, Path::new(dir).join("cur").join(&name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The sender for the `From ` line, which must not contain spaces.
+fn envelope_sender(from: &str) -> String {
+    if from.is_empty() {
+        "MAILER-DAEMON".to_string()
+    } else {
+        from.replace(char::is_whitespace, "_")
+    }
+}
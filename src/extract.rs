@@ -0,0 +1,99 @@
+use std::io::{Cursor, Read};
+
+use anyhow::Result;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use tracing::warn;
+
+const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+pub const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+/// Decompressing stops beyond this, documents are untrusted input.
+const MAX_DOCUMENT_XML: u64 = 64 * 1024 * 1024;
+
+/// Plain text of PDF and DOCX attachments, stored next to them and in the DB so their
+/// content is searchable like the bodies.
+#[derive(Debug)]
+pub struct Extraction {
+    /// longer texts are truncated
+    pub max_bytes: usize,
+}
+
+impl Extraction {
+    /// The text of a document with the given content type, `None` for other content types
+    /// and documents without text.
+    pub fn extract(&self, content_type: Option<&str>, content: &[u8]) -> Option<String> {
+        let content_type = content_type?;
+        let text = match content_type {
+            "application/pdf" => pdf(content),
+            DOCX => docx(content),
+            _ => return None,
+        };
+        let mut text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                warn!(
+                    "could not extract text of {} attachment: {:?}",
+                    content_type, e
+                );
+                return None;
+            }
+        };
+        if text.len() > self.max_bytes {
+            let mut end = self.max_bytes;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+        }
+        let text = text.trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+}
+
+#[cfg(feature = "pdf")]
+fn pdf(content: &[u8]) -> Result<String> {
+    // the parser panics on some malformed documents
+    match std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(content)) {
+        Ok(text) => Ok(text?),
+        Err(_) => anyhow::bail!("PDF parser panicked"),
+    }
+}
+
+/// PDFs are skipped without the `pdf` feature.
+#[cfg(not(feature = "pdf"))]
+fn pdf(_content: &[u8]) -> Result<String> {
+    Ok(String::new())
+}
+
+/// The paragraphs of `word/document.xml`, a line each.
+fn docx(content: &[u8]) -> Result<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(content))?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")?
+        .take(MAX_DOCUMENT_XML)
+        .read_to_string(&mut xml)?;
+
+    let mut reader = Reader::from_str(&xml);
+    let mut text = String::new();
+    let mut in_text = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.name().as_ref() == b"w:t" => in_text = true,
+            Event::End(e) => match e.name().as_ref() {
+                b"w:t" => in_text = false,
+                b"w:p" => text.push('\n'),
+                _ => {}
+            },
+            Event::Empty(e) => match e.name().as_ref() {
+                b"w:tab" => text.push('\t'),
+                b"w:br" | b"w:cr" => text.push('\n'),
+                _ => {}
+            },
+            Event::Text(e) if in_text => text.push_str(&e.unescape()?),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(text)
+}
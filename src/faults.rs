@@ -0,0 +1,69 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use crate::metrics;
+
+/// Failures injected at `rate` (0 to 1), after waiting for `latency`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fault {
+    pub rate: f64,
+    pub latency: Duration,
+}
+
+/// Artificial failures and latency, to validate retries and spooling in staging.
+#[derive(Debug, Default)]
+pub struct Faults {
+    /// before uploading a message
+    pub s3: Fault,
+    /// before DB queries of received mail
+    pub db: Fault,
+    /// the session stalls for `latency` at `rate`, as if the client were slow
+    pub client: Fault,
+}
+
+impl Faults {
+    pub async fn s3(&self) -> Result<()> {
+        inject("s3", &self.s3).await
+    }
+
+    pub async fn db(&self) -> Result<()> {
+        inject("db", &self.db).await
+    }
+
+    pub async fn client(&self) {
+        if hit(self.client.rate) {
+            metrics::FAULTS_INJECTED
+                .with_label_values(&["client"])
+                .inc();
+            warn!("injecting client stall of {:?}", self.client.latency);
+            tokio::time::sleep(self.client.latency).await;
+        }
+    }
+}
+
+async fn inject(kind: &str, fault: &Fault) -> Result<()> {
+    if !fault.latency.is_zero() {
+        tokio::time::sleep(fault.latency).await;
+    }
+    if hit(fault.rate) {
+        metrics::FAULTS_INJECTED.with_label_values(&[kind]).inc();
+        warn!("injecting {} failure", kind);
+        bail!("injected {} failure", kind);
+    }
+    Ok(())
+}
+
+fn hit(rate: f64) -> bool {
+    rate > 0.0 && random() < rate
+}
+
+/// Uniform in [0, 1). Every `RandomState` is keyed differently, which is random enough for
+/// injecting faults without a random number generator dependency.
+fn random() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
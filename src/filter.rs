@@ -0,0 +1,183 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{instrument, trace, warn};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Reject(String),
+    Quarantine,
+}
+
+#[derive(Debug)]
+pub enum ContentFilter {
+    Command(String),
+    Http(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HttpAction {
+    Accept,
+    Reject,
+    Quarantine,
+}
+
+#[derive(Deserialize)]
+struct HttpResponse {
+    action: HttpAction,
+    message: Option<String>,
+}
+
+const DEFAULT_REJECT_MESSAGE: &str = "message rejected by content filter";
+/// Longer reject texts are cut, replies should fit in a line.
+const MAX_REJECT_MESSAGE: usize = 200;
+
+/// The reply text for the filter's reject message: control characters like CR, which
+/// smtpbis panics on, are dropped and long messages are cut.
+fn reject_message(message: Option<&str>) -> String {
+    let message: String = message
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_REJECT_MESSAGE)
+        .collect();
+    match message.trim() {
+        "" => DEFAULT_REJECT_MESSAGE.to_string(),
+        message => message.to_string(),
+    }
+}
+
+pub struct ContentFilterHook {
+    pub filter: ContentFilter,
+    pub timeout: Duration,
+    http_client: reqwest::Client,
+}
+
+impl ContentFilterHook {
+    /// URLs starting with `http://` or `https://` are POSTed to, everything else is run
+    /// with `/bin/sh -c`.
+    #[instrument]
+    pub fn new(filter: &str, timeout: Duration) -> Result<Self> {
+        let filter = if filter.starts_with("http://") || filter.starts_with("https://") {
+            ContentFilter::Http(filter.to_string())
+        } else {
+            ContentFilter::Command(filter.to_string())
+        };
+        let http_client = reqwest::Client::builder().timeout(timeout).build()?;
+
+        Ok(Self {
+            filter,
+            timeout,
+            http_client,
+        })
+    }
+
+    #[instrument(skip(self, raw))]
+    pub async fn check(&self, from: &str, rcpts: &[String], raw: &[u8]) -> Result<Verdict> {
+        trace!("running content filter");
+        let envelope = json!({ "from": from, "rcpts": rcpts });
+
+        let verdict = match &self.filter {
+            ContentFilter::Command(cmd) => {
+                let check = self.check_command(cmd, from, rcpts, &envelope, raw);
+                tokio::time::timeout(self.timeout, check)
+                    .await
+                    .context("content filter timed out")??
+            }
+            ContentFilter::Http(url) => self.check_http(url, &envelope, raw).await?,
+        };
+        trace!("content filter verdict {:?}", verdict);
+        Ok(verdict)
+    }
+
+    /// Exit status 0 accepts, 1 rejects (with the first line of stdout as reply text), 2
+    /// quarantines. Anything else is treated as a temporary failure.
+    async fn check_command(
+        &self,
+        cmd: &str,
+        from: &str,
+        rcpts: &[String],
+        envelope: &serde_json::Value,
+        raw: &[u8],
+    ) -> Result<Verdict> {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(cmd)
+            .env("SMTP_MAIL_FROM", from)
+            .env("SMTP_RCPT_TO", rcpts.join(","))
+            .env("SMTP_ENVELOPE", envelope.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .context("could not spawn content filter")?;
+
+        let mut stdin = child.stdin.take().context("content filter has no stdin")?;
+        let raw = raw.to_vec();
+        let writer = tokio::spawn(async move {
+            // the filter might not read the whole message, ignore broken pipes
+            if let Err(e) = stdin.write_all(&raw).await {
+                trace!("could not write message to content filter: {}", e);
+            }
+        });
+
+        let output = child.wait_with_output().await?;
+        let _ = writer.await;
+
+        match output.status.code() {
+            Some(0) => Ok(Verdict::Accept),
+            Some(1) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Ok(Verdict::Reject(reject_message(stdout.lines().next())))
+            }
+            Some(2) => Ok(Verdict::Quarantine),
+            status => bail!("content filter failed with status {:?}", status),
+        }
+    }
+
+    /// The endpoint gets the raw message as body and the envelope in the
+    /// `X-Smtp-Envelope` header. An empty 2xx response accepts the message, otherwise a
+    /// JSON object `{"action": "accept|reject|quarantine", "message": "..."}` is expected.
+    async fn check_http(
+        &self,
+        url: &str,
+        envelope: &serde_json::Value,
+        raw: &[u8],
+    ) -> Result<Verdict> {
+        let response = self
+            .http_client
+            .post(url)
+            .header("Content-Type", "message/rfc822")
+            .header("X-Smtp-Envelope", envelope.to_string())
+            .body(raw.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let body = response.bytes().await?;
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Verdict::Accept);
+        }
+
+        let response: HttpResponse = serde_json::from_slice(&body)
+            .map_err(|e| anyhow!("could not parse content filter response: {}", e))?;
+        Ok(match response.action {
+            HttpAction::Accept => Verdict::Accept,
+            HttpAction::Reject => Verdict::Reject(reject_message(response.message.as_deref())),
+            HttpAction::Quarantine => {
+                if let Some(message) = response.message {
+                    warn!("quarantining message: {}", message);
+                }
+                Verdict::Quarantine
+            }
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use crate::db;
+use crate::s3;
+use crate::smtp::Config;
+
+/// Replaces the forgotten address in anonymized rows.
+pub const ANONYMIZED_ADDRESS: &str = "forgotten@invalid";
+
+#[derive(Args, Debug)]
+pub struct ForgetArgs {
+    /// Sender or recipient address whose messages to remove
+    address: String,
+    /// Keep the DB rows without bodies, headers and the address, e.g. for statistics
+    #[arg(long)]
+    anonymize: bool,
+}
+
+/// What was removed for a deletion request.
+#[derive(Debug, Serialize)]
+pub struct Forgotten {
+    pub messages: u64,
+    pub objects: u64,
+    /// messages not stored yet
+    pub queued: u64,
+}
+
+/// Remove the stored objects of all messages from or to `address` and delete or
+/// anonymize their rows, recording the request in the deletion audit table.
+#[instrument(skip(config))]
+pub async fn forget(
+    config: &Config,
+    address: &str,
+    anonymize: bool,
+    requested_via: &str,
+) -> Result<Forgotten> {
+    let address = address.trim().to_lowercase();
+    let stored = db::stored_prefixes(&config.pg_pool, &address).await?;
+
+    // objects first, so a failure leaves the rows to retry with
+    let mut objects = 0;
+    for message in &stored {
+        let bucket = message.bucket.as_deref().unwrap_or(&config.bucket);
+        objects += s3::delete_prefix(&config.s3_config, bucket, &message.s3_prefix).await?;
+        // the day index entry names both addresses
+        let index_keys = Vec::from_iter(message.index_key.clone());
+        objects += s3::delete_objects(&config.s3_config, bucket, &index_keys).await?;
+    }
+    // quarantined messages are only in the buckets
+    let mut buckets: Vec<&str> = stored
+        .iter()
+        .map(|message| message.bucket.as_deref().unwrap_or(&config.bucket))
+        .chain([config.bucket.as_str()])
+        .collect();
+    buckets.sort_unstable();
+    buckets.dedup();
+    for bucket in buckets {
+        objects += s3::delete_quarantined(&config.s3_config, bucket, &address).await?;
+    }
+    // the mirror has copies with the same keys in a single bucket
+    if let Some(mirror) = config.mirror.as_ref() {
+        for message in &stored {
+            let (s3_config, bucket) = (&mirror.s3_config, mirror.bucket.as_str());
+            objects += s3::delete_prefix(s3_config, bucket, &message.s3_prefix).await?;
+            let index_keys = Vec::from_iter(message.index_key.clone());
+            objects += s3::delete_objects(s3_config, bucket, &index_keys).await?;
+        }
+        objects += s3::delete_quarantined(&mirror.s3_config, &mirror.bucket, &address).await?;
+    }
+
+    let messages = if anonymize {
+        db::anonymize_deliveries(&config.pg_pool, &address, ANONYMIZED_ADDRESS).await?;
+        db::anonymize_mails(&config.pg_pool, &address, ANONYMIZED_ADDRESS).await?
+    } else {
+        db::delete_deliveries(&config.pg_pool, &address).await?;
+        db::delete_mails(&config.pg_pool, &address).await?
+    };
+    let queued = db::delete_queued(&config.pg_pool, &address).await?;
+    let forgotten = Forgotten {
+        messages,
+        objects,
+        queued,
+    };
+    db::insert_deletion(
+        &config.pg_pool,
+        &address,
+        anonymize,
+        requested_via,
+        &forgotten,
+    )
+    .await?;
+    info!(
+        messages = forgotten.messages,
+        objects = forgotten.objects,
+        queued = forgotten.queued,
+        "forgot address"
+    );
+    Ok(forgotten)
+}
+
+/// Handle the `forget` command, printing what was removed as JSON.
+#[instrument(skip(config))]
+pub async fn forget_command(args: ForgetArgs, config: &Config) -> Result<()> {
+    let forgotten = forget(config, &args.address, args.anonymize, "cli").await?;
+    println!("{}", serde_json::to_string(&forgotten)?);
+    Ok(())
+}
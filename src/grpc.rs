@@ -0,0 +1,184 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, instrument, warn};
+
+use crate::db;
+use crate::events::feed::{self, Filter};
+use crate::events::MessageStored;
+use crate::http::{authorized, DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT};
+use crate::smtp::SmtpBackend;
+
+pub mod proto {
+    tonic::include_proto!("smtp_s3_dump.v1");
+}
+
+use proto::messages_server::{Messages, MessagesServer};
+
+#[instrument(skip(token, backend))]
+pub async fn start_grpc_server(
+    bind_addr: SocketAddr,
+    token: String,
+    backend: SmtpBackend,
+) -> Result<()> {
+    info!("gRPC API listening on {}", bind_addr);
+    let check_token = move |request: Request<()>| {
+        if authorized(&request.metadata().clone().into_headers(), &token) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("unauthorized"))
+        }
+    };
+    tonic::transport::Server::builder()
+        .add_service(MessagesServer::with_interceptor(
+            Service { backend },
+            check_token,
+        ))
+        .serve(bind_addr)
+        .await?;
+    Ok(())
+}
+
+struct Service {
+    backend: SmtpBackend,
+}
+
+type MessageStream = Pin<Box<dyn Stream<Item = Result<proto::MessageStored, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Messages for Service {
+    type SubscribeStream = MessageStream;
+
+    #[instrument(skip_all)]
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<MessageStream>, Status> {
+        let rcpts = request.into_inner().rcpts;
+        let filter = Filter((!rcpts.is_empty()).then_some(rcpts));
+        info!("gRPC subscriber connected");
+        let events = stream::unfold(
+            (feed::subscribe(), filter),
+            |(mut receiver, filter)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) if filter.matches(&event) => {
+                            return Some((Ok(event.into()), (receiver, filter)));
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("gRPC subscriber missed {} messages", missed);
+                            let status = Status::data_loss(format!("missed {} messages", missed));
+                            return Some((Err(status), (receiver, filter)));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        );
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_message(
+        &self,
+        request: Request<proto::GetMessageRequest>,
+    ) -> Result<Response<proto::Message>, Status> {
+        let request = request.into_inner();
+        let config = self.backend.config.load_full();
+        let mut mail = db::get_mail(
+            &config.pg_pool,
+            &request.message_id,
+            request.rcpt.as_deref(),
+        )
+        .await
+        .map_err(|e| {
+            error!("could not fetch message: {:?}", e);
+            Status::internal("could not fetch message")
+        })?
+        .ok_or_else(|| Status::not_found("message not found"))?;
+
+        if let Some(keys) = config.body_keys.as_ref() {
+            let decrypt = |body: &str| {
+                keys.decrypt(body).map_err(|e| {
+                    error!("could not decrypt message: {:?}", e);
+                    Status::internal("could not decrypt message")
+                })
+            };
+            mail.body_text = decrypt(&mail.body_text)?;
+            mail.body_html = decrypt(&mail.body_html)?;
+        }
+
+        Ok(Response::new(proto::Message {
+            message_id: mail.message_id,
+            rcpt: mail.rcpt,
+            from: mail.from,
+            received_at: mail.received_at.to_rfc3339(),
+            s3_prefix: mail.s3_prefix,
+            body_text: mail.body_text,
+            body_html: mail.body_html,
+            headers_json: mail.headers.to_string(),
+            attachments_json: mail.attachments.to_string(),
+            bucket: mail.bucket,
+            tags: mail.tags.unwrap_or_default(),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_messages(
+        &self,
+        request: Request<proto::ListMessagesRequest>,
+    ) -> Result<Response<proto::ListMessagesResponse>, Status> {
+        let request = request.into_inner();
+        let since = request
+            .since
+            .map(|since| DateTime::parse_from_rfc3339(&since))
+            .transpose()
+            .map_err(|_| Status::invalid_argument("could not parse since"))?
+            .map(|since| since.with_timezone(&Utc));
+        let limit = request
+            .limit
+            .unwrap_or(DEFAULT_LIST_LIMIT)
+            .clamp(1, MAX_LIST_LIMIT);
+        let config = self.backend.config.load_full();
+        let mails = db::list_mails(&config.pg_pool, request.rcpt.as_deref(), since, limit)
+            .await
+            .map_err(|e| {
+                error!("could not list messages: {:?}", e);
+                Status::internal("could not list messages")
+            })?;
+        let messages = mails
+            .into_iter()
+            .map(|mail| proto::MessageSummary {
+                message_id: mail.message_id,
+                rcpt: mail.rcpt,
+                from: mail.from,
+                received_at: mail.received_at.to_rfc3339(),
+                s3_prefix: mail.s3_prefix,
+            })
+            .collect();
+        Ok(Response::new(proto::ListMessagesResponse { messages }))
+    }
+}
+
+impl From<MessageStored> for proto::MessageStored {
+    fn from(event: MessageStored) -> Self {
+        proto::MessageStored {
+            message_id: event.message_id,
+            from: event.from,
+            rcpt: event.rcpt,
+            canonical_rcpt: event.canonical_rcpt,
+            received_at: event.received_at.to_rfc3339(),
+            bucket: event.bucket,
+            s3_prefix: event.s3_prefix,
+            tags: event.tags,
+            attachments_json: event.attachments.to_string(),
+            thread_id: event.thread_id,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::net::IpAddr;
+
+use tracing::warn;
+
+use crate::metrics;
+use crate::rules::glob_match;
+
+/// Checks of the name clients send with `HELO` and `EHLO`.
+#[derive(Debug, Default)]
+pub struct HeloChecks {
+    /// reject address literals like `[192.0.2.1]` and bare addresses
+    pub reject_ip_literals: bool,
+    /// reject names without a dot, e.g. `localhost`
+    pub require_fqdn: bool,
+    /// reject clients claiming to be this server
+    pub reject_own_hostname: bool,
+    /// case-insensitive globs of rejected names, e.g. `*.dynamic.example.net`
+    pub denylist: Vec<String>,
+}
+
+impl HeloChecks {
+    /// Why `helo` is rejected, if it is.
+    pub fn check(&self, helo: &str, own_hostname: &str) -> Option<&'static str> {
+        let name = helo.trim_end_matches('.');
+        let reason = if self.reject_ip_literals && is_ip_literal(name) {
+            "ip_literal"
+        } else if self.require_fqdn && !is_fqdn(name) {
+            "not_fqdn"
+        } else if self.reject_own_hostname && name.eq_ignore_ascii_case(own_hostname) {
+            "own_hostname"
+        } else if self
+            .denylist
+            .iter()
+            .any(|pattern| glob_match(pattern, name))
+        {
+            "denylist"
+        } else {
+            return None;
+        };
+        warn!("rejected HELO {:?}: {}", helo, reason);
+        metrics::HELO_REJECTIONS.with_label_values(&[reason]).inc();
+        Some(reason)
+    }
+}
+
+fn is_ip_literal(name: &str) -> bool {
+    let addr = name
+        .strip_prefix('[')
+        .and_then(|name| name.strip_suffix(']'))
+        .unwrap_or(name);
+    let addr = addr
+        .get(..5)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("IPv6:"))
+        .map_or(addr, |_| &addr[5..]);
+    addr.parse::<IpAddr>().is_ok()
+}
+
+fn is_fqdn(name: &str) -> bool {
+    !name.starts_with('[') && name.contains('.') && name.split('.').all(|l| !l.is_empty())
+}
@@ -0,0 +1,221 @@
+/// Elements that start a new line in the text rendering.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Elements whose content is not text.
+const SKIPPED_ELEMENTS: &[&str] = &["head", "script", "style", "template", "title"];
+
+/// A tag, split into its lower case name and attributes.
+struct Tag<'a> {
+    name: String,
+    closing: bool,
+    attributes: &'a str,
+}
+
+fn parse_tag(tag: &str) -> Tag<'_> {
+    let (closing, tag) = match tag.strip_prefix('/') {
+        Some(tag) => (true, tag),
+        None => (false, tag),
+    };
+    let tag = tag.trim_end_matches('/');
+    let end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+    Tag {
+        name: tag[..end].to_lowercase(),
+        closing,
+        attributes: &tag[end..],
+    }
+}
+
+/// The value of an attribute, e.g. `href`.
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let lower = attributes.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(pos) = lower[from..].find(name) {
+        let start = from + pos;
+        from = start + name.len();
+        let preceded = start == 0 || lower[..start].ends_with(char::is_whitespace);
+        let rest = attributes[from..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value = rest[1..].trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or(""),
+            _ => value.split(char::is_whitespace).next().unwrap_or(""),
+        });
+    }
+    None
+}
+
+/// Decode the common named and all numeric character references.
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        decoded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let replacement = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, replacement) {
+            (Some(entity), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Render HTML as plain text: tags are stripped, block elements start new lines and links
+/// are kept as `text <url>`.
+pub fn to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut skipping: Option<String> = None;
+    // target of the current link and where its text starts
+    let mut link: Option<(String, usize)> = None;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            if skipping.is_none() {
+                push_text(&mut text, rest);
+            }
+            break;
+        };
+        if skipping.is_none() {
+            push_text(&mut text, &rest[..start]);
+        }
+        rest = &rest[start + 1..];
+
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let end = rest.find('>').unwrap_or(rest.len());
+        let tag = parse_tag(&rest[..end]);
+        rest = rest.get(end + 1..).unwrap_or("");
+
+        if let Some(skipped) = skipping.as_ref() {
+            if tag.closing && tag.name == *skipped {
+                skipping = None;
+            }
+            continue;
+        }
+        if SKIPPED_ELEMENTS.contains(&tag.name.as_str()) && !tag.closing {
+            skipping = Some(tag.name);
+            continue;
+        }
+        match (tag.name.as_str(), tag.closing) {
+            ("a", false) => {
+                link = attribute(tag.attributes, "href")
+                    .map(decode_entities)
+                    .filter(|href| !href.starts_with('#'))
+                    .map(|href| (href, text.len()));
+            }
+            ("a", true) => {
+                if let Some((href, start)) = link.take() {
+                    let label = text[start..].trim();
+                    if label != href && label != href.trim_start_matches("mailto:") {
+                        text.push_str(&format!(" <{}>", href));
+                    }
+                }
+            }
+            ("li", false) => text.push_str("\n * "),
+            (name, _) if BLOCK_ELEMENTS.contains(&name) => text.push('\n'),
+            _ => {}
+        }
+    }
+
+    // collapse the blank lines left by nested blocks
+    let mut collapsed = String::with_capacity(text.len());
+    let mut blank = 0;
+    for line in text.lines().map(str::trim_end) {
+        if line.trim().is_empty() {
+            blank += 1;
+            if blank > 1 {
+                continue;
+            }
+        } else {
+            blank = 0;
+        }
+        collapsed.push_str(line);
+        collapsed.push('\n');
+    }
+    collapsed.trim().to_string()
+}
+
+/// Append text content with whitespace collapsed like a browser would.
+fn push_text(text: &mut String, content: &str) {
+    let content = decode_entities(content);
+    for (ix, word) in content.split_whitespace().enumerate() {
+        let line_start = text.is_empty() || text.ends_with(['\n', ' ']);
+        if ix > 0 || (!line_start && content.starts_with(char::is_whitespace)) {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    if content.ends_with(char::is_whitespace) && !content.trim().is_empty() {
+        text.push(' ');
+    }
+}
+
+/// Remove scripts, styles, event handler and other dangerous attributes, and remote images
+/// that could track the reader. Images of the message itself (`cid:`) are kept.
+pub fn sanitize(html: &str) -> String {
+    ammonia::Builder::default()
+        .add_url_schemes(&["cid"])
+        .attribute_filter(|element, attribute, value| {
+            if element == "img" && attribute == "src" && !value.starts_with("cid:") {
+                return None;
+            }
+            Some(value.into())
+        })
+        .clean(html)
+        .to_string()
+}
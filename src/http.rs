@@ -0,0 +1,299 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, instrument, warn};
+
+use crate::db;
+use crate::deliver::{deliver_message, normalize_line_endings, Rejected};
+use crate::forget;
+use crate::metrics;
+use crate::s3;
+use crate::smtp::{SmtpBackend, MAX_MESSAGE_SIZE};
+
+pub mod admin;
+pub mod jmap;
+pub mod live;
+
+pub const DEFAULT_LIST_LIMIT: i64 = 100;
+pub const MAX_LIST_LIMIT: i64 = 1000;
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub backend: SmtpBackend,
+    pub token: Arc<String>,
+    pub admin: Option<Arc<admin::Admin>>,
+}
+
+#[instrument(skip(backend, token, admin))]
+pub async fn start_http_server(
+    bind_addr: SocketAddr,
+    token: String,
+    backend: SmtpBackend,
+    admin: Option<admin::Admin>,
+) -> Result<()> {
+    info!("HTTP API listening on {}", bind_addr);
+    let state = ApiState {
+        backend,
+        token: Arc::new(token),
+        admin: admin.map(Arc::new),
+    };
+
+    let app = Router::new()
+        .route("/v1/messages", post(post_message).get(list_messages))
+        .route("/v1/messages/:message_id", get(get_message))
+        .route("/v1/senders/:address", delete(delete_sender))
+        .route("/v1/live", get(live::live_feed))
+        .route("/metrics", get(get_metrics))
+        .merge(admin::routes())
+        .merge(jmap::routes())
+        .layer(DefaultBodyLimit::max(MAX_MESSAGE_SIZE))
+        .with_state(state);
+
+    axum::Server::bind(&bind_addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Check the `Authorization: Bearer` header in constant time.
+pub fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(given) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+#[instrument]
+async fn get_metrics() -> Response {
+    match metrics::gather() {
+        Ok(metrics) => metrics.into_response(),
+        Err(e) => {
+            error!("could not gather metrics: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not gather metrics",
+            )
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    from: String,
+    /// comma separated recipients
+    rcpt: String,
+}
+
+#[instrument(skip(state, headers, body))]
+async fn post_message(
+    State(state): State<ApiState>,
+    Query(envelope): Query<Envelope>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let rcpts: Vec<String> = envelope
+        .rcpt
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(str::to_string)
+        .collect();
+    if rcpts.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "no recipients given");
+    }
+
+    let mut session = match state.backend.new_session(None) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("could not create session: {:?}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal error");
+        }
+    };
+
+    session.protocol = "HTTP";
+
+    let raw = normalize_line_endings(&body, false);
+    match deliver_message(&mut session, &envelope.from, &rcpts, raw).await {
+        Ok(()) => (
+            StatusCode::CREATED,
+            Json(json!({ "status": "stored", "rcpts": rcpts })),
+        )
+            .into_response(),
+        Err(e) => match e.downcast_ref::<Rejected>() {
+            Some(rejected) if rejected.is_permanent() => {
+                warn!("rejected message: {}", rejected);
+                error_response(StatusCode::UNPROCESSABLE_ENTITY, &rejected.to_string())
+            }
+            Some(rejected) => {
+                error_response(StatusCode::SERVICE_UNAVAILABLE, &rejected.to_string())
+            }
+            None => {
+                error!("could not handle request: {:?}", e);
+                error_response(StatusCode::SERVICE_UNAVAILABLE, "could not handle request")
+            }
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    rcpt: Option<String>,
+    since: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+}
+
+#[instrument(skip(state, headers))]
+async fn list_messages(
+    State(state): State<ApiState>,
+    Query(query): Query<ListQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let config = state.backend.config.load_full();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    match db::list_mails(&config.pg_pool, query.rcpt.as_deref(), query.since, limit).await {
+        Ok(mails) => Json(json!({ "messages": mails })).into_response(),
+        Err(e) => {
+            error!("could not list messages: {:?}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "could not list messages")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetQuery {
+    rcpt: Option<String>,
+    #[serde(default)]
+    presign: bool,
+}
+
+#[instrument(skip(state, headers))]
+async fn get_message(
+    State(state): State<ApiState>,
+    Path(message_id): Path<String>,
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let config = state.backend.config.load_full();
+    let mut mail = match db::get_mail(&config.pg_pool, &message_id, query.rcpt.as_deref()).await {
+        Ok(Some(mail)) => mail,
+        Ok(None) => return error_response(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("could not fetch message: {:?}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "could not fetch message");
+        }
+    };
+
+    if let Some(keys) = config.body_keys.as_ref() {
+        let decrypted = keys
+            .decrypt(&mail.body_text)
+            .and_then(|text| Ok((text, keys.decrypt(&mail.body_html)?)));
+        match decrypted {
+            Ok((body_text, body_html)) => {
+                mail.body_text = body_text;
+                mail.body_html = body_html;
+            }
+            Err(e) => {
+                error!("could not decrypt message: {:?}", e);
+                let status = StatusCode::INTERNAL_SERVER_ERROR;
+                return error_response(status, "could not decrypt message");
+            }
+        }
+    }
+
+    let mut response = json!({ "message": mail });
+    if query.presign {
+        match s3::presigned_message_urls(
+            &config.s3_config,
+            mail.bucket.as_deref().unwrap_or(&config.bucket),
+            &mail,
+            config.presigned_url_expiry,
+        )
+        .await
+        {
+            Ok(urls) => response["urls"] = urls,
+            Err(e) => {
+                error!("could not presign urls: {:?}", e);
+                let status = StatusCode::INTERNAL_SERVER_ERROR;
+                return error_response(status, "could not presign urls");
+            }
+        }
+    }
+    Json(response).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    anonymize: bool,
+}
+
+/// Remove everything stored for a sender or recipient address.
+#[instrument(skip(state, headers))]
+async fn delete_sender(
+    State(state): State<ApiState>,
+    Path(address): Path<String>,
+    Query(query): Query<DeleteQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let config = state.backend.config.load_full();
+    match forget::forget(&config, &address, query.anonymize, "api").await {
+        Ok(forgotten) => {
+            let status = if query.anonymize {
+                "anonymized"
+            } else {
+                "deleted"
+            };
+            Json(json!({ "status": status, "forgotten": forgotten })).into_response()
+        }
+        Err(e) => {
+            error!("could not forget address: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not forget address",
+            )
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, instrument};
+
+use super::{authorized, error_response, ApiState, DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT};
+use crate::db;
+use crate::sessions::Sessions;
+
+/// Reloads the certificate and the configuration.
+pub type Reload = Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Endpoints for incident response, authorized with their own token.
+pub struct Admin {
+    pub token: String,
+    pub sessions: Arc<Sessions>,
+    pub reload: Reload,
+}
+
+pub fn routes() -> Router<ApiState> {
+    Router::new()
+        .route("/v1/admin/sessions", get(list_sessions))
+        .route("/v1/admin/sessions/:id", delete(kick_session))
+        .route("/v1/admin/pause", post(pause))
+        .route("/v1/admin/resume", post(resume))
+        .route("/v1/admin/reload", post(reload))
+        .route("/v1/admin/stuck", get(list_stuck))
+        .route("/v1/admin/dlq", get(list_dead_letters))
+        .route("/v1/admin/dlq/:id", delete(drop_dead_letter))
+        .route("/v1/admin/dlq/:id/retry", post(retry_dead_letter))
+}
+
+/// The admin endpoints, if enabled and the request carries the admin token.
+fn admin<'a>(state: &'a ApiState, headers: &HeaderMap) -> Result<&'a Admin, Box<Response>> {
+    let Some(admin) = state.admin.as_deref() else {
+        return Err(Box::new(error_response(
+            StatusCode::NOT_FOUND,
+            "admin API not enabled",
+        )));
+    };
+    if !authorized(headers, &admin.token) {
+        return Err(Box::new(error_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+        )));
+    }
+    Ok(admin)
+}
+
+#[instrument(skip_all)]
+async fn list_sessions(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    let admin = match admin(&state, &headers) {
+        Ok(admin) => admin,
+        Err(response) => return *response,
+    };
+    Json(json!({
+        "paused": admin.sessions.paused(),
+        "sessions": admin.sessions.list(),
+    }))
+    .into_response()
+}
+
+#[instrument(skip(state, headers))]
+async fn kick_session(
+    State(state): State<ApiState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Response {
+    let admin = match admin(&state, &headers) {
+        Ok(admin) => admin,
+        Err(response) => return *response,
+    };
+    if !admin.sessions.kick(id) {
+        return error_response(StatusCode::NOT_FOUND, "no such session");
+    }
+    info!("closing session {} on request", id);
+    Json(json!({ "status": "closed" })).into_response()
+}
+
+#[instrument(skip_all)]
+async fn pause(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    set_paused(&state, &headers, true)
+}
+
+#[instrument(skip_all)]
+async fn resume(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    set_paused(&state, &headers, false)
+}
+
+fn set_paused(state: &ApiState, headers: &HeaderMap, paused: bool) -> Response {
+    let admin = match admin(state, headers) {
+        Ok(admin) => admin,
+        Err(response) => return *response,
+    };
+    admin.sessions.set_paused(paused);
+    info!(
+        "new SMTP connections {}",
+        if paused { "paused" } else { "resumed" }
+    );
+    Json(json!({ "paused": paused })).into_response()
+}
+
+#[instrument(skip_all)]
+async fn reload(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    let admin = match admin(&state, &headers) {
+        Ok(admin) => admin,
+        Err(response) => return *response,
+    };
+    match (admin.reload)().await {
+        Ok(()) => {
+            info!("reloaded certificate and configuration on request");
+            Json(json!({ "status": "reloaded" })).into_response()
+        }
+        Err(e) => {
+            error!("could not reload: {:?}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "could not reload")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StuckQuery {
+    /// in seconds
+    older_than: Option<u64>,
+    limit: Option<i64>,
+}
+
+/// Messages still received or stored, see `TRACK_STATUS`.
+#[instrument(skip(state, headers))]
+async fn list_stuck(
+    State(state): State<ApiState>,
+    Query(query): Query<StuckQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = admin(&state, &headers) {
+        return *response;
+    }
+    let config = state.backend.config.load_full();
+    let older_than = query.older_than.unwrap_or(600) as f64;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    match db::stuck_deliveries(&config.pg_pool, older_than, limit).await {
+        Ok(stuck) => Json(json!({ "messages": stuck })).into_response(),
+        Err(e) => {
+            error!("could not list stuck messages: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not list stuck messages",
+            )
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeadLetterQuery {
+    limit: Option<i64>,
+}
+
+/// Queued messages whose retries are exhausted or that were refused, see `QUEUE`.
+#[instrument(skip(state, headers))]
+async fn list_dead_letters(
+    State(state): State<ApiState>,
+    Query(query): Query<DeadLetterQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = admin(&state, &headers) {
+        return *response;
+    }
+    let config = state.backend.config.load_full();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    match db::dead_letters(&config.pg_pool, limit).await {
+        Ok(dead) => Json(json!({ "messages": dead })).into_response(),
+        Err(e) => {
+            error!("could not list dead messages: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not list dead messages",
+            )
+        }
+    }
+}
+
+#[instrument(skip(state, headers))]
+async fn retry_dead_letter(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = admin(&state, &headers) {
+        return *response;
+    }
+    let config = state.backend.config.load_full();
+    match db::retry_dead_letter(&config.pg_pool, id).await {
+        Ok(true) => {
+            info!("queued dead message {} again on request", id);
+            Json(json!({ "status": "queued" })).into_response()
+        }
+        Ok(false) => error_response(StatusCode::NOT_FOUND, "no such dead message"),
+        Err(e) => {
+            error!("could not retry dead message: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not retry dead message",
+            )
+        }
+    }
+}
+
+#[instrument(skip(state, headers))]
+async fn drop_dead_letter(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = admin(&state, &headers) {
+        return *response;
+    }
+    let config = state.backend.config.load_full();
+    match db::drop_dead_letter(&config.pg_pool, id).await {
+        Ok(true) => {
+            info!("dropped dead message {} on request", id);
+            Json(json!({ "status": "dropped" })).into_response()
+        }
+        Ok(false) => error_response(StatusCode::NOT_FOUND, "no such dead message"),
+        Err(e) => {
+            error!("could not drop dead message: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not drop dead message",
+            )
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tracing::{error, instrument, warn};
+
+use super::{authorized, error_response, ApiState, DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT};
+use crate::db;
+use crate::s3;
+
+const CORE: &str = "urn:ietf:params:jmap:core";
+const MAIL: &str = "urn:ietf:params:jmap:mail";
+/// The whole archive is a single read-only account.
+const ACCOUNT_ID: &str = "archive";
+/// ... with a single mailbox.
+const MAILBOX_ID: &str = "archive";
+/// Nothing can be changed, so the state never does.
+const STATE: &str = "0";
+const MAX_CALLS: usize = 16;
+const MAX_OBJECTS_IN_GET: usize = 500;
+const PREVIEW_LENGTH: usize = 256;
+
+/// A read-only subset of JMAP Mail (RFC 8621), enough for clients to browse the archive.
+pub fn routes() -> Router<ApiState> {
+    Router::new()
+        .route("/.well-known/jmap", get(session))
+        .route("/jmap/api", post(api))
+        .route("/jmap/download/:account/:blob/:name", get(download))
+}
+
+#[instrument(skip_all)]
+async fn session(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let domain = state.backend.config.load().domain.to_string();
+    Json(json!({
+        "capabilities": {
+            CORE: {
+                "maxSizeUpload": 0,
+                "maxConcurrentUpload": 1,
+                "maxSizeRequest": 1_000_000,
+                "maxConcurrentRequests": 4,
+                "maxCallsInRequest": MAX_CALLS,
+                "maxObjectsInGet": MAX_OBJECTS_IN_GET,
+                "maxObjectsInSet": 0,
+                "collationAlgorithms": [],
+            },
+            MAIL: {},
+        },
+        "accounts": {
+            ACCOUNT_ID: {
+                "name": domain,
+                "isPersonal": false,
+                "isReadOnly": true,
+                "accountCapabilities": {
+                    MAIL: {
+                        "maxMailboxesPerEmail": 1,
+                        "maxMailboxDepth": 1,
+                        "maxSizeMailboxName": 100,
+                        "maxSizeAttachmentsPerEmail": 0,
+                        "emailQuerySortOptions": ["receivedAt"],
+                        "mayCreateTopLevelMailbox": false,
+                    },
+                },
+            },
+        },
+        "primaryAccounts": { MAIL: ACCOUNT_ID },
+        "username": ACCOUNT_ID,
+        "apiUrl": "/jmap/api",
+        "downloadUrl": "/jmap/download/{accountId}/{blobId}/{name}?accept={type}",
+        "uploadUrl": "/jmap/upload/{accountId}/",
+        "eventSourceUrl": "/jmap/eventsource?types={types}&closeafter={closeafter}&ping={ping}",
+        "state": STATE,
+    }))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    using: Vec<String>,
+    method_calls: Vec<(String, Map<String, Value>, String)>,
+}
+
+/// A method level error, see RFC 8620 section 3.6.2.
+struct MethodError(&'static str);
+
+#[instrument(skip_all)]
+async fn api(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<Request>,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    if let Some(unknown) = request
+        .using
+        .iter()
+        .find(|capability| *capability != CORE && *capability != MAIL)
+    {
+        let problem = json!({
+            "type": "urn:ietf:params:jmap:error:unknownCapability",
+            "status": 400,
+            "detail": format!("unknown capability {}", unknown),
+        });
+        return (StatusCode::BAD_REQUEST, Json(problem)).into_response();
+    }
+    if request.method_calls.len() > MAX_CALLS {
+        let problem = json!({
+            "type": "urn:ietf:params:jmap:error:limit",
+            "status": 400,
+            "limit": "maxCallsInRequest",
+        });
+        return (StatusCode::BAD_REQUEST, Json(problem)).into_response();
+    }
+
+    let mut responses: Vec<(String, Value, String)> = vec![];
+    for (method, args, call_id) in request.method_calls {
+        let result = match resolve_references(args, &responses) {
+            Ok(args) => call(&state, &method, args).await,
+            Err(e) => Err(e),
+        };
+        responses.push(match result {
+            Ok(response) => (method, response, call_id),
+            Err(MethodError(kind)) => ("error".to_string(), json!({ "type": kind }), call_id),
+        });
+    }
+    Json(json!({
+        "methodResponses": responses,
+        "sessionState": STATE,
+    }))
+    .into_response()
+}
+
+async fn call(
+    state: &ApiState,
+    method: &str,
+    args: Map<String, Value>,
+) -> Result<Value, MethodError> {
+    if args.get("accountId").and_then(Value::as_str) != Some(ACCOUNT_ID) {
+        return Err(MethodError("accountNotFound"));
+    }
+    match method {
+        "Mailbox/get" => Ok(json!({
+            "accountId": ACCOUNT_ID,
+            "state": STATE,
+            "list": [{
+                "id": MAILBOX_ID,
+                "name": "Archive",
+                "parentId": null,
+                "role": "inbox",
+                "sortOrder": 0,
+                "totalEmails": 0,
+                "unreadEmails": 0,
+                "totalThreads": 0,
+                "unreadThreads": 0,
+                "myRights": {
+                    "mayReadItems": true,
+                    "mayAddItems": false,
+                    "mayRemoveItems": false,
+                    "maySetSeen": false,
+                    "maySetKeywords": false,
+                    "mayCreateChild": false,
+                    "mayRename": false,
+                    "mayDelete": false,
+                    "maySubmit": false,
+                },
+                "isSubscribed": true,
+            }],
+            "notFound": [],
+        })),
+        "Email/query" => email_query(state, args).await,
+        "Email/get" => email_get(state, args).await,
+        _ => Err(MethodError("unknownMethod")),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmailFilter {
+    to: Option<String>,
+    after: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmailQuery {
+    #[serde(default)]
+    filter: Option<EmailFilter>,
+    #[serde(default)]
+    position: i64,
+    limit: Option<i64>,
+}
+
+/// Newest first, filtered by recipient (`to`) and `after`.
+#[instrument(skip(state))]
+async fn email_query(state: &ApiState, args: Map<String, Value>) -> Result<Value, MethodError> {
+    let query: EmailQuery =
+        serde_json::from_value(Value::Object(args)).map_err(|_| MethodError("invalidArguments"))?;
+    if query.position != 0 {
+        return Err(MethodError("invalidArguments"));
+    }
+    let filter = query.filter.unwrap_or_default();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    let config = state.backend.config.load_full();
+    let mails = db::list_mails(&config.pg_pool, filter.to.as_deref(), filter.after, limit)
+        .await
+        .map_err(|e| {
+            error!("could not list messages: {:?}", e);
+            MethodError("serverFail")
+        })?;
+    let ids: Vec<String> = mails
+        .iter()
+        .map(|mail| encode_id(&[&mail.rcpt, &mail.message_id]))
+        .collect();
+    Ok(json!({
+        "accountId": ACCOUNT_ID,
+        "queryState": STATE,
+        "canCalculateChanges": false,
+        "position": 0,
+        "ids": ids,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailGet {
+    ids: Option<Vec<String>>,
+}
+
+/// Every property is returned, bodies from the DB and attachments as blobs in S3.
+#[instrument(skip(state))]
+async fn email_get(state: &ApiState, args: Map<String, Value>) -> Result<Value, MethodError> {
+    let request: EmailGet =
+        serde_json::from_value(Value::Object(args)).map_err(|_| MethodError("invalidArguments"))?;
+    // listing all messages is what Email/query is for
+    let ids = request.ids.ok_or(MethodError("requestTooLarge"))?;
+    if ids.len() > MAX_OBJECTS_IN_GET {
+        return Err(MethodError("requestTooLarge"));
+    }
+
+    let mut list = vec![];
+    let mut not_found = vec![];
+    for id in ids {
+        match email(state, &id).await {
+            Ok(Some(email)) => list.push(email),
+            Ok(None) => not_found.push(id),
+            Err(e) => {
+                error!("could not fetch message: {:?}", e);
+                return Err(MethodError("serverFail"));
+            }
+        }
+    }
+    Ok(json!({
+        "accountId": ACCOUNT_ID,
+        "state": STATE,
+        "list": list,
+        "notFound": not_found,
+    }))
+}
+
+async fn email(state: &ApiState, id: &str) -> Result<Option<Value>> {
+    let Some([rcpt, message_id]) = decode_id(id) else {
+        return Ok(None);
+    };
+    let config = state.backend.config.load_full();
+    let Some(mut mail) = db::get_mail(&config.pg_pool, &message_id, Some(&rcpt)).await? else {
+        return Ok(None);
+    };
+    if let Some(keys) = config.body_keys.as_ref() {
+        mail.body_text = keys.decrypt(&mail.body_text)?;
+        mail.body_html = keys.decrypt(&mail.body_html)?;
+    }
+
+    let header = |name: &str| {
+        mail.headers
+            .as_object()
+            .and_then(|headers| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)))
+            .and_then(|(_, v)| v.as_str())
+    };
+    let attachments: Vec<Value> = mail
+        .attachments
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|attachment| {
+            let index = attachment["index"].as_u64()?;
+            Some(json!({
+                "partId": format!("attachment-{}", index),
+                "blobId": encode_id(&[&rcpt, &message_id, &index.to_string()]),
+                "name": attachment["filename"],
+                "type": attachment["content_type"].as_str().unwrap_or("application/octet-stream"),
+                "disposition": "attachment",
+            }))
+        })
+        .collect();
+    let preview: String = mail.body_text.chars().take(PREVIEW_LENGTH).collect();
+    let preview = preview.split_whitespace().collect::<Vec<_>>().join(" ");
+    let html_body = if mail.body_html.is_empty() {
+        json!([{ "partId": "text", "type": "text/plain" }])
+    } else {
+        json!([{ "partId": "html", "type": "text/html" }])
+    };
+
+    Ok(Some(json!({
+        "id": id,
+        "blobId": null,
+        "threadId": id,
+        "mailboxIds": { MAILBOX_ID: true },
+        "keywords": { "$seen": true },
+        "size": 0,
+        "receivedAt": mail.received_at,
+        "messageId": [mail.message_id.trim_start_matches('<').trim_end_matches('>')],
+        "from": [{ "name": null, "email": mail.from }],
+        "to": [{ "name": null, "email": mail.rcpt }],
+        "subject": header("Subject"),
+        "preview": preview,
+        "hasAttachment": !attachments.is_empty(),
+        "textBody": [{ "partId": "text", "type": "text/plain" }],
+        "htmlBody": html_body,
+        "attachments": attachments,
+        "bodyValues": {
+            "text": { "value": mail.body_text, "isEncodingProblem": false, "isTruncated": false },
+            "html": { "value": mail.body_html, "isEncodingProblem": false, "isTruncated": false },
+        },
+    })))
+}
+
+/// Redirect to a presigned URL of the attachment.
+#[instrument(skip(state, headers))]
+async fn download(
+    State(state): State<ApiState>,
+    Path((account, blob, _name)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    if account != ACCOUNT_ID {
+        return error_response(StatusCode::NOT_FOUND, "account not found");
+    }
+    let Some([rcpt, message_id, index]) = decode_id(&blob) else {
+        return error_response(StatusCode::NOT_FOUND, "blob not found");
+    };
+
+    let config = state.backend.config.load_full();
+    let url = async {
+        let Some(mail) = db::get_mail(&config.pg_pool, &message_id, Some(&rcpt)).await? else {
+            return Ok(None);
+        };
+        let Some(key) = mail
+            .attachments
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|a| a["index"].as_u64().map(|i| i.to_string()).as_ref() == Some(&index))
+            .and_then(|a| a["rel_path"].as_str())
+        else {
+            return Ok(None);
+        };
+        let bucket = mail.bucket.as_deref().unwrap_or(&config.bucket);
+        s3::presigned_object_url(&config.s3_config, bucket, key, config.presigned_url_expiry)
+            .await
+            .map(Some)
+    };
+    match url.await {
+        Ok(Some(url)) => Redirect::temporary(&url).into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "blob not found"),
+        Err(e) => {
+            error!("could not presign attachment: {:?}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "could not fetch blob")
+        }
+    }
+}
+
+/// JMAP ids may only contain `[A-Za-z0-9_-]`, so the parts are joined and base64 encoded.
+fn encode_id(parts: &[&str]) -> String {
+    URL_SAFE_NO_PAD.encode(parts.join("\n"))
+}
+
+fn decode_id<const N: usize>(id: &str) -> Option<[String; N]> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(id).ok()?).ok()?;
+    let parts: Vec<String> = decoded.split('\n').map(str::to_string).collect();
+    parts.try_into().ok()
+}
+
+/// Replace `#name` arguments referencing results of earlier calls, RFC 8620 section 3.7.
+fn resolve_references(
+    args: Map<String, Value>,
+    responses: &[(String, Value, String)],
+) -> Result<Map<String, Value>, MethodError> {
+    let mut resolved = Map::new();
+    for (name, value) in args {
+        let Some(name) = name.strip_prefix('#') else {
+            resolved.insert(name, value);
+            continue;
+        };
+        let reference: ResultReference =
+            serde_json::from_value(value).map_err(|_| MethodError("invalidResultReference"))?;
+        let result = responses
+            .iter()
+            .find(|(method, _, call_id)| {
+                *call_id == reference.result_of && *method == reference.name
+            })
+            .and_then(|(_, response, _)| evaluate_pointer(response, &reference.path))
+            .ok_or(MethodError("invalidResultReference"))?;
+        resolved.insert(name.to_string(), result);
+    }
+    Ok(resolved)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResultReference {
+    result_of: String,
+    name: String,
+    path: String,
+}
+
+/// JSON pointer where `*` maps over arrays and flattens the results.
+fn evaluate_pointer(value: &Value, path: &str) -> Option<Value> {
+    let Some(path) = path.strip_prefix('/') else {
+        return path.is_empty().then(|| value.clone());
+    };
+    let (token, rest) = match path.split_once('/') {
+        Some((token, rest)) => (token, format!("/{}", rest)),
+        None => (path, String::new()),
+    };
+    let token = token.replace("~1", "/").replace("~0", "~");
+    match value {
+        Value::Array(items) if token == "*" => {
+            let mut results = vec![];
+            for item in items {
+                match evaluate_pointer(item, &rest)? {
+                    Value::Array(nested) => results.extend(nested),
+                    other => results.push(other),
+                }
+            }
+            Some(Value::Array(results))
+        }
+        Value::Array(items) => evaluate_pointer(items.get(token.parse::<usize>().ok()?)?, &rest),
+        Value::Object(map) => evaluate_pointer(map.get(&token)?, &rest),
+        _ => {
+            warn!("could not evaluate result reference {}", path);
+            None
+        }
+    }
+}
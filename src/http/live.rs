@@ -0,0 +1,70 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::stream;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, instrument, warn};
+
+use super::{authorized, error_response, ApiState};
+use crate::events::feed::{self, Filter};
+
+#[derive(Debug, Deserialize)]
+pub struct LiveQuery {
+    /// comma separated recipients, all if not given
+    rcpt: Option<String>,
+}
+
+/// Server-Sent Events with a `message` event per stored message. Subscribers too slow
+/// to keep up get a `lagged` event with the number of missed messages.
+#[instrument(skip(state, headers))]
+pub async fn live_feed(
+    State(state): State<ApiState>,
+    Query(query): Query<LiveQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    let filter = Filter(query.rcpt.map(|rcpt| {
+        rcpt.split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(str::to_string)
+            .collect()
+    }));
+    info!("live feed subscribed");
+    let events = stream::unfold(
+        (feed::subscribe(), filter),
+        |(mut receiver, filter)| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) if filter.matches(&event) => {
+                        match Event::default().event("message").json_data(&event) {
+                            Ok(event) => event,
+                            Err(e) => {
+                                warn!("could not serialize event: {:?}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("live feed subscriber missed {} messages", missed);
+                        Event::default().event("lagged").data(missed.to_string())
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok::<_, Infallible>(event), (receiver, filter)));
+            }
+        },
+    );
+    Sse::new(events)
+        .keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+        .into_response()
+}
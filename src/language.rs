@@ -0,0 +1,13 @@
+/// The start of a body is enough to tell its language.
+const MAX_CHARS: usize = 10_000;
+
+/// The ISO 639-3 code of the language of `text`, e.g. `eng` or `deu`, if it is detected
+/// reliably.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let end = text
+        .char_indices()
+        .nth(MAX_CHARS)
+        .map_or(text.len(), |(ix, _)| ix);
+    let info = whatlang::detect(&text[..end])?;
+    info.is_reliable().then(|| info.lang().code())
+}
@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::metrics;
+
+/// Bytes of message content buffered by all sessions of the process.
+static BUFFERED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn buffered_bytes() -> usize {
+    BUFFERED.load(Ordering::Relaxed)
+}
+
+/// A session's share of the buffered bytes, released when dropped.
+#[derive(Debug, Default)]
+pub struct Buffered(usize);
+
+impl Buffered {
+    /// Account for the session's buffer holding `bytes` now.
+    pub fn set(&mut self, bytes: usize) {
+        if bytes >= self.0 {
+            BUFFERED.fetch_add(bytes - self.0, Ordering::Relaxed);
+        } else {
+            BUFFERED.fetch_sub(self.0 - bytes, Ordering::Relaxed);
+        }
+        metrics::BUFFERED_BYTES.add(bytes as i64 - self.0 as i64);
+        self.0 = bytes;
+    }
+}
+
+impl Drop for Buffered {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
@@ -0,0 +1,45 @@
+use url::Url;
+
+/// Characters that end a URL in running text.
+fn ends_url(c: char) -> bool {
+    c.is_whitespace() || "<>\"'`{}|\\^".contains(c)
+}
+
+/// Parse and normalize an HTTP(S) URL, the host is lower case and the fragment removed.
+fn normalize(candidate: &str) -> Option<String> {
+    let mut url = Url::parse(candidate).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return None;
+    }
+    url.set_fragment(None);
+    Some(url.into())
+}
+
+/// The distinct HTTP(S) URLs in the texts, in order of appearance.
+pub fn extract<'a>(texts: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut urls: Vec<String> = vec![];
+    for text in texts {
+        let lower = text.to_ascii_lowercase();
+        let mut from = 0;
+        while let Some(pos) = lower[from..].find("http") {
+            let start = from + pos;
+            let end = text[start..]
+                .find(ends_url)
+                .map_or(text.len(), |end| start + end);
+            from = end.max(start + 4);
+
+            // trailing punctuation most likely belongs to the sentence
+            let candidate = text[start..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+            let scheme = lower[start..].split_once("://").map(|(scheme, _)| scheme);
+            if !matches!(scheme, Some("http" | "https")) {
+                continue;
+            }
+            if let Some(url) = normalize(candidate) {
+                if !urls.contains(&url) {
+                    urls.push(url);
+                }
+            }
+        }
+    }
+    urls
+}
@@ -0,0 +1,70 @@
+use mail_parser::Message;
+use serde::Serialize;
+
+/// Mailing list and automation headers, to tell list and automated mail from mail written
+/// by humans.
+#[derive(Debug, Default, Serialize)]
+pub struct ListInfo {
+    /// identifier of `List-Id` (RFC 2919), without its description, lower case
+    pub list_id: Option<String>,
+    /// URIs of `List-Unsubscribe` (RFC 2369), e.g. `mailto:` and `https:`
+    pub unsubscribe: Vec<String>,
+    /// whether `List-Unsubscribe-Post` allows one-click unsubscribing (RFC 8058)
+    pub one_click: bool,
+    /// keyword of `Auto-Submitted` (RFC 3834), e.g. `auto-generated`
+    pub auto_submitted: Option<String>,
+    /// `Precedence`, e.g. `bulk` or `list`
+    pub precedence: Option<String>,
+}
+
+impl ListInfo {
+    pub fn parse(message: &Message) -> Self {
+        let header = |name: &str| {
+            message
+                .headers_raw()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+        };
+        // the keyword, without parameters or comments
+        let keyword = |value: &str| {
+            let keyword = value.split([';', '(', ' ']).next().unwrap_or("");
+            Some(keyword.trim().to_lowercase()).filter(|k| !k.is_empty())
+        };
+
+        ListInfo {
+            list_id: header("List-Id").and_then(|value| {
+                let id = bracketed(value).next().unwrap_or(value);
+                Some(id.trim().to_lowercase()).filter(|id| !id.is_empty())
+            }),
+            unsubscribe: header("List-Unsubscribe")
+                .map(|value| {
+                    bracketed(value)
+                        .map(|uri| uri.split_whitespace().collect())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            one_click: header("List-Unsubscribe-Post")
+                .is_some_and(|value| value.eq_ignore_ascii_case("List-Unsubscribe=One-Click")),
+            auto_submitted: header("Auto-Submitted").and_then(keyword),
+            precedence: header("Precedence").and_then(keyword),
+        }
+    }
+
+    /// Whether the message comes from a mailing list or was sent automatically.
+    pub fn is_automated(&self) -> bool {
+        self.list_id.is_some()
+            || self.auto_submitted.as_deref().is_some_and(|k| k != "no")
+            || matches!(
+                self.precedence.as_deref(),
+                Some("bulk" | "list" | "junk" | "auto_reply")
+            )
+    }
+}
+
+/// The `<...>` parts of a header value, folding whitespace is left to the caller.
+fn bracketed(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>').map(|(inner, _)| inner))
+}
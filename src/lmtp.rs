@@ -0,0 +1,184 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use rustyknife::behaviour::Intl;
+use rustyknife::rfc5321::{mail_command, rcpt_command};
+use smtpbis::{EnhancedCode, Handler, Reply};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{error, info, instrument, trace, warn};
+
+use crate::smtp::{Delivery, SmtpBackend, SmtpSession, State, MAX_MESSAGE_SIZE};
+
+const MAX_LINE_LENGTH: u64 = 4096;
+
+#[instrument(skip(backend))]
+pub async fn start_lmtp_server(path: String, mode: u32, backend: SmtpBackend) -> Result<()> {
+    info!("listening for LMTP on {}", path);
+    // remove a stale socket from a previous run
+    if fs::metadata(&path).is_ok() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+
+    while let Ok((stream, _addr)) = listener.accept().await {
+        let mut session = backend.new_session(None)?;
+        // LMTP clients have to send LHLO first
+        session.state = State::Connected;
+        tokio::spawn(async move {
+            if let Err(e) = handle_lmtp_connection(stream, session).await {
+                warn!("could not handle LMTP connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Read a line of at most `limit` bytes, returns the number of bytes read.
+async fn read_line<R>(reader: &mut R, line: &mut Vec<u8>, limit: u64) -> Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    Ok((&mut *reader).take(limit).read_until(b'\n', line).await?)
+}
+
+#[instrument(skip_all)]
+async fn handle_lmtp_connection(stream: UnixStream, mut session: SmtpSession) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let domain = session.config.domain.to_string();
+
+    writer
+        .write_all(format!("220 {} LMTP ready\r\n", domain).as_bytes())
+        .await?;
+
+    let mut line = Vec::new();
+    loop {
+        if read_line(&mut reader, &mut line, MAX_LINE_LENGTH).await? == 0 {
+            break;
+        }
+        if !line.ends_with(b"\n") {
+            writer.write_all(b"500 5.5.2 line too long\r\n").await?;
+            continue;
+        }
+        if !line.ends_with(b"\r\n") {
+            line.pop();
+            line.extend(b"\r\n");
+        }
+
+        let verb = String::from_utf8_lossy(line.get(..4).unwrap_or(&line[..])).to_uppercase();
+        trace!("handle LMTP {}", verb);
+        if let Some(reply) = session.state.out_of_sequence(&verb) {
+            writer.write_all(reply.to_string().as_bytes()).await?;
+            continue;
+        }
+        let reply = match verb.as_str() {
+            "LHLO" => {
+                let helo = String::from_utf8_lossy(&line[4..]).trim().to_string();
+                session.rset().await;
+                session.state = session.state.after("LHLO");
+                session.helo = Some(helo);
+                session.protocol = "LMTP";
+                format!(
+                    "250-{}\r\n250-PIPELINING\r\n250-8BITMIME\r\n250-ENHANCEDSTATUSCODES\r\n\
+                     250 SIZE {}\r\n",
+                    domain, MAX_MESSAGE_SIZE
+                )
+            }
+            "MAIL" => match mail_command::<Intl>(&line) {
+                Ok((_, (path, params))) => session
+                    .mail(path, params)
+                    .await
+                    .map_or("250 2.1.0 OK\r\n".to_string(), |r| r.to_string()),
+                Err(_) => "501 5.5.2 syntax error\r\n".to_string(),
+            },
+            "RCPT" => match rcpt_command::<Intl>(&line) {
+                Ok((_, (path, params))) => session
+                    .rcpt(path, params)
+                    .await
+                    .map_or("250 2.1.5 OK\r\n".to_string(), |r| r.to_string()),
+                Err(_) => "501 5.5.2 syntax error\r\n".to_string(),
+            },
+            "DATA" => {
+                writer.write_all(b"354 go ahead\r\n").await?;
+                receive_data(&mut reader, &mut session).await?
+            }
+            "RSET" => {
+                session.rset().await;
+                "250 2.0.0 OK\r\n".to_string()
+            }
+            "NOOP" => "250 2.0.0 OK\r\n".to_string(),
+            "VRFY" => "252 2.5.2 cannot verify\r\n".to_string(),
+            "QUIT" => {
+                writer.write_all(b"221 2.0.0 bye\r\n").await?;
+                break;
+            }
+            _ => "502 5.5.1 command not implemented\r\n".to_string(),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Read the message until the terminating dot and reply once per recipient.
+async fn receive_data<R>(reader: &mut R, session: &mut SmtpSession) -> Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let rcpts = session.rcpts.clone();
+    session.state = session.state.after("DATA");
+    session.data = Vec::new();
+    if let Some(size) = session.declared_size {
+        session.expect_content(size);
+    }
+    let mut too_big = false;
+    let mut line = Vec::new();
+    // long lines are read in parts, only the first may be the end or dot-stuffed
+    let mut line_start = true;
+    loop {
+        if read_line(reader, &mut line, MAX_LINE_LENGTH).await? == 0 {
+            anyhow::bail!("connection closed during DATA");
+        }
+        if line_start && (line == b".\r\n" || line == b".\n") {
+            break;
+        }
+        too_big = too_big || !session.receive_line(&line);
+        line_start = line.ends_with(b"\n");
+    }
+
+    if too_big {
+        session.rset().await;
+        return Ok(rcpts
+            .iter()
+            .map(|_| "552 5.3.4 message exceeds maximum message size\r\n")
+            .collect());
+    }
+
+    let replies = match session.process_message().await {
+        Ok(Delivery::Refused(reply)) => rcpts.iter().map(|_| reply.to_string()).collect(),
+        Ok(Delivery::Delivered(results)) => results
+            .into_iter()
+            .map(|(rcpt, result)| match result {
+                Ok(()) => Reply::new(
+                    250,
+                    Some(EnhancedCode(2, 0, 0)),
+                    format!("<{}> stored", rcpt),
+                )
+                .to_string(),
+                Err(_) => {
+                    let text = format!("<{}> {}", rcpt, session.config.replies.temp_failure);
+                    Reply::new(451, Some(EnhancedCode(4, 3, 0)), text).to_string()
+                }
+            })
+            .collect(),
+        Err(e) => {
+            error!("could not handle request: {}", e);
+            let reply = session.config.replies.temp_failure().to_string();
+            rcpts.iter().map(|_| reply.as_str()).collect()
+        }
+    };
+    Ok(replies)
+}
@@ -1,44 +1,292 @@
+use std::any::Any;
 use std::env;
-use std::net::SocketAddr;
-// use std::time::Duration;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use aws_config::retry::RetryConfig;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::timeout::TimeoutConfig;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_sdk_s3::types::ObjectLockMode;
+use clap::{Parser, Subcommand};
 use futures::{FutureExt, TryFutureExt};
+use ipnet::IpNet;
 use smtpbis::{smtp_server, LoopExit};
-use sqlx::postgres::PgPoolOptions;
-use tokio::io::AsyncWriteExt;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ServerConfig;
 use tokio_rustls::TlsAcceptor;
 use tracing::instrument;
 use tracing::{error, info, trace, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+use crate::commands::{CommandWatch, Commands};
+use crate::probe::Probes;
 use crate::smtp::{SmtpBackend, SmtpSession};
 
+mod allowlist;
+mod archive;
+mod attachments;
+mod auth;
+mod bodies;
+mod calendar;
+mod classify;
+mod commands;
 mod db;
+mod deliver;
+mod directory;
+mod dlq;
+mod doctor;
+mod dsn;
+mod encryption;
+mod events;
+mod export;
+mod extract;
+mod faults;
+mod filter;
+mod forget;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod helo;
+mod html;
+mod http;
+mod language;
+mod limits;
+mod links;
+mod list;
+mod lmtp;
+mod metrics;
+mod milter;
 mod notify;
+mod plugin;
+mod pop3;
+mod probe;
+mod queue;
+mod rdns;
+mod redact;
+mod relay;
+mod replies;
+mod reports;
+mod rules;
 mod s3;
+mod schema;
+mod sender;
+mod sessions;
+mod smime;
 mod smtp;
+mod status;
+mod storage;
+mod tarpit;
+mod tenant;
+mod test_send;
+mod thread;
+mod thumbnail;
 mod tls;
+mod tnef;
+mod trace;
+mod transcript;
+mod uuencode;
+mod vault;
+mod xclient;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the SMTP server (default)
+    Serve,
+    /// Deliver a single message from stdin, like sendmail
+    Deliver(deliver::DeliverArgs),
+    /// Remove all messages from or to an address and their objects
+    Forget(forget::ForgetArgs),
+    /// Re-encrypt or decrypt the bodies stored in the DB
+    Bodies(bodies::BodiesArgs),
+    /// Write the messages stored for a recipient to an mbox or Maildir
+    Export(export::ExportArgs),
+    /// Check the TLS certificate, DNS, S3 access and the DB schema
+    Doctor(doctor::DoctorArgs),
+    /// Send a test message and check that it was stored
+    TestSend(test_send::TestSendArgs),
+    /// List messages still received or stored, i.e. not stored or notified in time
+    Stuck(status::StuckArgs),
+    /// List, retry or drop queued messages whose retries are exhausted
+    Dlq(dlq::DlqArgs),
+}
 
 #[tokio::main]
 #[instrument]
 async fn main() -> Result<()> {
     // install global default tracing subscriber using RUST_LOG env variable
+    // log the busy and idle time of every span when it closes, e.g. of each S3 request
+    let span_events = match env::var("LOG_SPAN_TIMINGS").as_deref() {
+        Ok("true") => FmtSpan::CLOSE,
+        _ => FmtSpan::NONE,
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(
+            fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_span_events(span_events),
+        )
         .with(EnvFilter::from_default_env())
         .init();
 
-    let smtp_bind_addr = env::var("STMP_BIND_ADDR").unwrap_or("0.0.0.0:2525".to_string());
+    let cli = Cli::parse();
+    secrets_from_files()?;
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        Command::Deliver(args) => {
+            let backend = backend_from_env(None).await?;
+            std::process::exit(deliver::deliver(args, backend).await)
+        }
+        Command::Forget(args) => {
+            let backend = backend_from_env(None).await?;
+            forget::forget_command(args, &backend.config.load()).await
+        }
+        Command::Doctor(args) => doctor::doctor_command(args).await,
+        Command::TestSend(args) => {
+            let backend = backend_from_env(None).await?;
+            test_send::test_send_command(args, backend).await
+        }
+        Command::Export(args) => {
+            let backend = backend_from_env(None).await?;
+            export::export_command(args, &backend.config.load()).await
+        }
+        Command::Stuck(args) => {
+            let backend = backend_from_env(None).await?;
+            status::stuck_command(args, &backend.config.load()).await
+        }
+        Command::Dlq(args) => {
+            let backend = backend_from_env(None).await?;
+            dlq::dlq_command(args, &backend.config.load()).await
+        }
+        Command::Bodies(args) => {
+            let database_url =
+                env::var("DATABASE_URL").context("env variable DATABASE_URL not provided")?;
+            let keys = body_keys_from_env()?.context("DB_ENCRYPTION_KEYS not set")?;
+            let pool = pg_pool_from_env(&database_url, 1).await?;
+            bodies::run(args, &pool, &keys).await
+        }
+    }
+}
+
+/// Longest wait between startup retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Variables that may instead be read from a file named by `<name>_FILE`, e.g. a Docker or
+/// Kubernetes secret mount.
+const SECRETS: &[&str] = &[
+    "DATABASE_URL",
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+    "HTTP_API_TOKEN",
+    "RELAY_PASSWORD",
+    "AMQP_URL",
+    "REDIS_URL",
+    "DB_ENCRYPTION_KEYS",
+    "VAULT_TOKEN",
+    "LDAP_BIND_PASSWORD",
+];
+
+/// Set the secrets given as files as environment variables, the variables take precedence.
+fn secrets_from_files() -> Result<()> {
+    for name in SECRETS {
+        let Ok(path) = env::var(format!("{}_FILE", name)) else {
+            continue;
+        };
+        if env::var_os(name).is_some() {
+            warn!("both {} and {}_FILE set, ignoring the file", name, name);
+            continue;
+        }
+        let value = std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read {}_FILE {}", name, path))?;
+        env::set_var(name, value.trim());
+    }
+    Ok(())
+}
+
+/// Retry `f` with exponential backoff until it succeeds or `timeout` has passed.
+async fn with_retries<T, F, Fut>(what: &str, timeout: Duration, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if Instant::now() + backoff < deadline => {
+                warn!("{} not ready, retrying in {:?}: {:#}", what, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => return Err(e.context(format!("{} not ready", what))),
+        }
+    }
+}
+
+/// Connect to the DB, with credentials from Vault if configured.
+async fn pg_pool_from_env(database_url: &str, max_connections: u32) -> Result<PgPool> {
+    if let Ok(path) = env::var("VAULT_DB_CREDS_PATH") {
+        let options = database_url
+            .parse()
+            .context("could not parse DATABASE_URL")?;
+        return vault_from_env()?
+            .connect(path, options, max_connections)
+            .await;
+    }
+
+    let pg_pool = PgPoolOptions::new()
+        .max_connections(max_connections)
+        .connect(database_url)
+        .await?;
+    // rotated credentials are used for new connections
+    if let Ok(path) = env::var("DATABASE_URL_FILE") {
+        notify::watch_database_url(path, pg_pool.clone()).await?;
+    }
+    Ok(pg_pool)
+}
+
+fn vault_from_env() -> Result<vault::Vault> {
+    let addr = env::var("VAULT_ADDR").context("env variable VAULT_ADDR not provided")?;
+    let auth = match env::var("VAULT_K8S_ROLE") {
+        Ok(role) => vault::Auth::Kubernetes {
+            mount: env::var("VAULT_K8S_MOUNT").unwrap_or("kubernetes".to_string()),
+            role,
+        },
+        Err(_) => vault::Auth::Token(
+            env::var("VAULT_TOKEN").context("env variable VAULT_TOKEN not provided")?,
+        ),
+    };
+    Ok(vault::Vault::new(&addr, auth))
+}
+
+/// Set up the storage backend and mail checks shared by all modes.
+async fn backend_from_env(tls_config: Option<Arc<ServerConfig>>) -> Result<SmtpBackend> {
+    Ok(SmtpBackend::new(config_from_env(tls_config).await?))
+}
+
+/// Read the configuration, also when reloading it via the admin API.
+#[instrument(skip_all)]
+async fn config_from_env(tls_config: Option<Arc<ServerConfig>>) -> Result<smtp::Config> {
     let smtp_domain = env::var("SMTP_DOMAIN").context("env variable SMTP_DOMAIN not provided")?;
     let bucket: String =
         env::var("BUCKET_NAME").context("env variable BUCKET_NAME not provided")?;
-    let aws_endpoint_url: Option<String> = env::var("AWS_ENDPOINT_URL").ok();
-    let cert_path =
-        env::var("SMTP_CERT_FILE").context("env variable SMTP_CERT_FILE not provided")?;
-    let key_path = env::var("SMTP_KEY_FILE").context("env variable SMTP_KEY_FILE not provided")?;
     let database_url =
         env::var("DATABASE_URL").context("env variable DATABASE_URL not provided")?;
 
@@ -48,47 +296,1092 @@ async fn main() -> Result<()> {
     let allowed_froms = env::var("ALLOWED_FROMS")
         .map(|s| s.split(',').map(str::to_string).collect())
         .ok();
+    let allowed_pairs_file = env::var("ALLOWED_PAIRS_FILE").ok();
+    let allowed_pairs = env::var("ALLOWED_PAIRS").ok();
+    let allowed_pairs = if allowed_pairs_file.is_some() || allowed_pairs.is_some() {
+        let pairs = parse_key_values(&allowed_pairs.unwrap_or_default());
+        Some(allowlist::PairAllowlist::new(
+            allowed_pairs_file.as_deref(),
+            pairs,
+        )?)
+    } else {
+        None
+    };
+    let null_sender = env::var("NULL_SENDER")
+        .ok()
+        .map(|s| allowlist::NullSender::parse(&s))
+        .transpose()?
+        .unwrap_or_default();
     let check_db: bool = env::var("CHECK_ALLOWED_IN_DB")
         .map(|s| s == "true")
         .unwrap_or(false);
+    let content_filter_timeout = env::var("CONTENT_FILTER_TIMEOUT")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(30))
+        .context("could not parse CONTENT_FILTER_TIMEOUT")?;
+    let content_filter = env::var("CONTENT_FILTER")
+        .ok()
+        .map(|f| filter::ContentFilterHook::new(&f, Duration::from_secs(content_filter_timeout)))
+        .transpose()?;
+    let milter_timeout = env::var("MILTER_TIMEOUT")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(30))
+        .context("could not parse MILTER_TIMEOUT")?;
+    let milter_fail_open: bool = env::var("MILTER_FAIL_OPEN")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let milter = env::var("MILTER")
+        .ok()
+        .map(|m| milter::Milter::new(&m, Duration::from_secs(milter_timeout), milter_fail_open))
+        .transpose()?;
+    let presigned_url_expiry = env::var("PRESIGNED_URL_EXPIRY")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(3600))
+        .context("could not parse PRESIGNED_URL_EXPIRY")?;
+    let presign_on_upload: bool = env::var("PRESIGN_ON_UPLOAD")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let events = events_from_env().await?;
+    let pg_notify_channel = env::var("PG_NOTIFY")
+        .map(|s| s == "true")
+        .unwrap_or(false)
+        .then(|| env::var("PG_NOTIFY_CHANNEL").unwrap_or("smtp_gateway".to_string()));
+    let relay = relay_from_env()?;
+    let bounces = env::var("SEND_BOUNCES")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    if bounces && relay.is_none() {
+        anyhow::bail!("SEND_BOUNCES set, but RELAY_HOST is not configured");
+    }
+    let replies = replies_from_env();
+    let size_limits = parse_key_values(&env::var("SIZE_LIMITS").unwrap_or_default())
+        .into_iter()
+        .map(|(address, size)| Ok((address.to_lowercase(), size.parse()?)))
+        .collect::<Result<_>>()
+        .context("could not parse SIZE_LIMITS")?;
+    let size_limits_in_db: bool = env::var("SIZE_LIMITS_IN_DB")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let metric_labels = metrics::Labels {
+        rcpts: env::var("METRICS_RCPTS")
+            .map(|s| s.split(',').map(|a| a.trim().to_lowercase()).collect())
+            .unwrap_or_default(),
+        sender_domains: env::var("METRICS_SENDER_DOMAINS")
+            .map(|s| s.split(',').map(|a| a.trim().to_lowercase()).collect())
+            .unwrap_or_default(),
+    };
+    let max_recipients = env::var("MAX_RECIPIENTS")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(smtp::MAX_RECIPIENTS))
+        .context("could not parse MAX_RECIPIENTS")?;
+    let max_buffered_bytes = env::var("MAX_BUFFERED_BYTES")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse MAX_BUFFERED_BYTES")?;
+    let tarpit = match env::var("TARPIT_MAX_DELAY") {
+        Ok(max_delay) => {
+            let max_delay = max_delay
+                .parse()
+                .context("could not parse TARPIT_MAX_DELAY")?;
+            let window = env::var("TARPIT_WINDOW")
+                .map(|s| s.parse())
+                .unwrap_or(Ok(3600))
+                .context("could not parse TARPIT_WINDOW")?;
+            Some(tarpit::Tarpit::new(
+                Duration::from_secs(max_delay),
+                Duration::from_secs(window),
+            ))
+        }
+        Err(_) => None,
+    };
+    let aliases = parse_key_values(&env::var("ALIASES").unwrap_or_default())
+        .into_iter()
+        .map(|(alias, canonical)| (alias.to_lowercase(), canonical))
+        .collect();
+    let aliases_in_db: bool = env::var("ALIASES_IN_DB")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let rules = env::var("RULES_FILE")
+        .ok()
+        .map(|path| rules::Rules::load(&path))
+        .transpose()?;
+    let attachment_policy = attachment_policy_from_env()?;
+    let attachment_limits = attachment_limits_from_env()?;
+    let quarantine_encrypted_archives = env::var("QUARANTINE_ENCRYPTED_ARCHIVES")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let transcripts = transcripts_from_env()?;
+    let faults = faults_from_env()?;
+    let thumbnails = thumbnails_from_env()?;
+    let extraction = extraction_from_env()?;
+    let queue = queue_from_env()?;
+    let track_status = env::var("TRACK_STATUS")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let storage = storage::Storage::new(
+        parse_key_values(&env::var("S3_STORAGE_CLASSES").unwrap_or_default()),
+        parse_key_values(&env::var("S3_RETENTION_TAGS").unwrap_or_default()),
+        object_lock_from_env()?,
+    )
+    .context("could not parse S3_STORAGE_CLASSES or S3_RETENTION_TAGS")?;
+    let redactions = env::var("REDACTIONS_FILE")
+        .ok()
+        .map(|path| redact::Redactions::load(&path))
+        .transpose()?;
+    let plugin = plugin_from_env()?;
+    let smime = smime_from_env()?;
+    let encryption = encryption_from_env()?;
+    let decryption = decryption_from_env()?;
+    let body_keys = body_keys_from_env()?;
+    let tenants_in_db: bool = env::var("TENANTS_IN_DB")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let tenants = tenant::Tenants::new(env::var("TENANTS_FILE").ok().as_deref(), tenants_in_db)?;
+    let trusted_relays = nets_from_env("TRUSTED_RELAYS")?;
+    let xclient_trusted = nets_from_env("XCLIENT_TRUSTED")?;
+    let helo_checks = helo::HeloChecks {
+        reject_ip_literals: env::var("HELO_REJECT_IP_LITERALS")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        require_fqdn: env::var("HELO_REQUIRE_FQDN")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        reject_own_hostname: env::var("HELO_REJECT_OWN_HOSTNAME")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        denylist: env::var("HELO_DENYLIST")
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect(),
+    };
+    let rdns_policy = rdns::Policy::parse(&env::var("REQUIRE_RDNS").unwrap_or_default())?;
+    let rdns = match env::var("RDNS_LOOKUP")
+        .map(|s| s == "false")
+        .unwrap_or(false)
+    {
+        true if rdns_policy != rdns::Policy::Any => {
+            anyhow::bail!("REQUIRE_RDNS set, but RDNS_LOOKUP disabled")
+        }
+        true => None,
+        false => Some(rdns::ReverseDns::from_system_conf(rdns_policy)?),
+    };
+    let dmarc_rua = env::var("DMARC_RUA_ADDRESSES")
+        .map(|s| s.split(',').map(|a| a.trim().to_lowercase()).collect())
+        .unwrap_or_default();
+    let tls_rua = env::var("TLSRPT_ADDRESSES")
+        .map(|s| s.split(',').map(|a| a.trim().to_lowercase()).collect())
+        .unwrap_or_default();
+    let sanitize_html: bool = env::var("SANITIZE_HTML")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let quotas: bool = env::var("CHECK_QUOTAS")
+        .map(|s| s == "true")
+        .unwrap_or(false);
 
-    let resolver = tls::CertificateResolver::new(&cert_path, &key_path)?;
-    // start certificate change watcher
-    notify::watch_certs(resolver.clone()).await?;
-    let tls_config = tls::safe_tls_config(resolver)?;
+    let s3_config = s3_config_from_env().await?;
+    let detect_language = env::var("DETECT_LANGUAGE")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let day_index = env::var("S3_DAY_INDEX")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let mirror = env::var("MIRROR_BUCKET").ok().map(|bucket| {
+        let mut builder = s3_config.to_builder();
+        if let Ok(region) = env::var("MIRROR_AWS_REGION") {
+            builder = builder.region(aws_sdk_s3::config::Region::new(region));
+        }
+        if let Ok(endpoint) = env::var("MIRROR_ENDPOINT_URL") {
+            builder = builder.endpoint_url(endpoint);
+        }
+        s3::Mirror {
+            s3_config: builder.build(),
+            bucket,
+        }
+    });
+
+    // wait for the DB and S3 to be ready, e.g. when starting alongside them
+    let startup_timeout = env::var("STARTUP_TIMEOUT")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(0))
+        .context("could not parse STARTUP_TIMEOUT")?;
+    let startup_timeout = Duration::from_secs(startup_timeout);
+    let pg_pool =
+        with_retries("DB", startup_timeout, || pg_pool_from_env(&database_url, 2)).await?;
+    let authenticator = authenticator_from_env(&pg_pool, startup_timeout).await?;
+    let directory = directory_from_env(&smtp_domain)?;
+    let create_bucket: bool = env::var("CREATE_BUCKET_IF_MISSING")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    if create_bucket {
+        let new_bucket = s3::NewBucket {
+            versioning: env::var("BUCKET_VERSIONING")
+                .map(|s| s == "true")
+                .unwrap_or(false),
+            encryption: env::var("BUCKET_ENCRYPTION").ok(),
+            kms_key_id: env::var("BUCKET_KMS_KEY_ID").ok(),
+        };
+        with_retries("S3 bucket", startup_timeout, || {
+            s3::create_bucket_if_missing(&s3_config, &bucket, &new_bucket)
+        })
+        .await?;
+    } else if !startup_timeout.is_zero() {
+        with_retries("S3 bucket", startup_timeout, || {
+            s3::check_bucket(&s3_config, &bucket)
+        })
+        .await?;
+    }
+
+    Ok(smtp::Config {
+        s3_config,
+        pg_pool,
+        tls_config,
+        domain: smtp::parse_domain(&smtp_domain)?,
+        bucket,
+        allowed_rcpts,
+        allowed_froms,
+        allowed_pairs,
+        null_sender,
+        check_db,
+        content_filter,
+        milter,
+        presigned_url_expiry: Duration::from_secs(presigned_url_expiry),
+        presign_on_upload,
+        events,
+        pg_notify_channel,
+        relay,
+        bounces,
+        replies,
+        size_limits,
+        size_limits_in_db,
+        max_recipients,
+        max_buffered_bytes,
+        metric_labels,
+        tarpit,
+        aliases,
+        aliases_in_db,
+        rules,
+        attachment_policy,
+        attachment_limits,
+        quarantine_encrypted_archives,
+        plugin,
+        tenants,
+        quotas,
+        trusted_relays,
+        xclient_trusted,
+        helo_checks,
+        rdns,
+        authenticator,
+        directory,
+        dmarc_rua,
+        tls_rua,
+        sanitize_html,
+        smime,
+        encryption,
+        decryption,
+        body_keys,
+        redactions,
+        transcripts,
+        faults,
+        storage,
+        mirror,
+        day_index,
+        detect_language,
+        thumbnails,
+        extraction,
+        queue,
+        track_status,
+    })
+}
 
+/// The S3 client configuration, from the usual AWS variables and `AWS_ENDPOINT_URL`.
+async fn s3_config_from_env() -> Result<aws_sdk_s3::Config> {
     let aws_config = aws_config::from_env();
     // remove once https://github.com/awslabs/smithy-rs/issues/2863 lands
-    let aws_config = if let Some(endpoint) = aws_endpoint_url {
+    let aws_config = if let Ok(endpoint) = env::var("AWS_ENDPOINT_URL") {
         aws_config.endpoint_url(endpoint)
     } else {
         aws_config
     };
     let aws_config = aws_config.load().await;
 
-    let s3_config = aws_sdk_s3::config::Builder::from(&aws_config)
-        .force_path_style(true)
-        .build();
+    let mut builder = aws_sdk_s3::config::Builder::from(&aws_config).force_path_style(true);
+    if let Some(credentials) = s3_role_from_env(&aws_config)? {
+        builder = builder.credentials_provider(credentials);
+    }
+    if let Some(timeout_config) = s3_timeouts_from_env()? {
+        builder = builder.timeout_config(timeout_config);
+    }
+    if let Some(retry_config) = s3_retries_from_env()? {
+        builder = builder.retry_config(retry_config);
+    }
+    Ok(builder.build())
+}
 
-    let pg_pool = PgPoolOptions::new()
-        .max_connections(2)
-        .connect(&database_url)
-        .await?;
+/// Object Lock retention with `S3_OBJECT_LOCK_MODE` (`GOVERNANCE` or `COMPLIANCE`) for
+/// `S3_OBJECT_LOCK_DAYS` or the days in `S3_OBJECT_LOCK_RCPT_DAYS` by recipient.
+fn object_lock_from_env() -> Result<Option<storage::ObjectLock>> {
+    let Ok(mode) = env::var("S3_OBJECT_LOCK_MODE") else {
+        return Ok(None);
+    };
+    if !ObjectLockMode::values().contains(&mode.as_str()) {
+        anyhow::bail!("unknown S3_OBJECT_LOCK_MODE {}", mode);
+    }
+    let days = env::var("S3_OBJECT_LOCK_DAYS")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse S3_OBJECT_LOCK_DAYS")?;
+    let rcpt_days = parse_key_values(&env::var("S3_OBJECT_LOCK_RCPT_DAYS").unwrap_or_default())
+        .into_iter()
+        .map(|(address, days)| Ok((address.to_lowercase(), days.parse()?)))
+        .collect::<Result<_>>()
+        .context("could not parse S3_OBJECT_LOCK_RCPT_DAYS")?;
+    Ok(Some(storage::ObjectLock {
+        mode: ObjectLockMode::from(mode.as_str()),
+        days,
+        rcpt_days,
+    }))
+}
 
-    let backend = SmtpBackend::new(
-        s3_config,
-        pg_pool,
-        tls_config,
-        &smtp_domain,
-        &bucket,
-        allowed_rcpts,
-        allowed_froms,
-        check_db,
-    )?;
+/// Credentials of the roles in `S3_ROLE_ARN`, assumed in turn starting with the ambient
+/// credentials, e.g. of IRSA, for buckets in other AWS accounts. They are refreshed by the
+/// SDK's credentials cache before they expire.
+fn s3_role_from_env(
+    aws_config: &aws_config::SdkConfig,
+) -> Result<Option<SharedCredentialsProvider>> {
+    let Ok(role_arns) = env::var("S3_ROLE_ARN") else {
+        return Ok(None);
+    };
+    let roles: Vec<&str> = role_arns
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .collect();
+    let session_name =
+        env::var("S3_ROLE_SESSION_NAME").unwrap_or_else(|_| "smtp-s3-dump".to_string());
+    let external_id = env::var("S3_ROLE_EXTERNAL_ID").ok();
+    let session_length = env::var("S3_ROLE_SESSION_DURATION")
+        .ok()
+        .map(|s| s.parse().map(Duration::from_secs))
+        .transpose()
+        .context("could not parse S3_ROLE_SESSION_DURATION")?;
+
+    let mut credentials = aws_config
+        .credentials_provider()
+        .context("no AWS credentials to assume S3_ROLE_ARN with")?;
+    for (ix, role_arn) in roles.iter().enumerate() {
+        info!("assuming role {} for S3", role_arn);
+        let mut provider = AssumeRoleProvider::builder(*role_arn).session_name(&session_name);
+        if let Some(region) = aws_config.region() {
+            provider = provider.region(region.clone());
+        }
+        // the external id is expected by the account granting access, i.e. the last role
+        if let Some(external_id) = external_id.as_deref().filter(|_| ix == roles.len() - 1) {
+            provider = provider.external_id(external_id);
+        }
+        if let Some(session_length) = session_length {
+            provider = provider.session_length(session_length);
+        }
+        credentials = SharedCredentialsProvider::new(provider.build(credentials));
+    }
+    Ok(Some(credentials))
+}
+
+/// Timeouts of S3 requests in seconds, shorter than the SDK's defaults so uploads fail before
+/// the SMTP client gives up.
+fn s3_timeouts_from_env() -> Result<Option<TimeoutConfig>> {
+    let timeout = |name: &str| -> Result<Option<Duration>> {
+        env::var(name)
+            .ok()
+            .map(|s| s.parse().map(Duration::from_secs_f64))
+            .transpose()
+            .with_context(|| format!("could not parse {}", name))
+    };
+    let connect = timeout("S3_CONNECT_TIMEOUT")?;
+    let read = timeout("S3_READ_TIMEOUT")?;
+    let attempt = timeout("S3_ATTEMPT_TIMEOUT")?;
+    let operation = timeout("S3_OPERATION_TIMEOUT")?;
+    if connect.is_none() && read.is_none() && attempt.is_none() && operation.is_none() {
+        return Ok(None);
+    }
+    let mut timeout_config = TimeoutConfig::builder();
+    timeout_config
+        .set_connect_timeout(connect)
+        .set_read_timeout(read)
+        .set_operation_attempt_timeout(attempt)
+        .set_operation_timeout(operation);
+    Ok(Some(timeout_config.build()))
+}
+
+/// `S3_MAX_ATTEMPTS` and `S3_RETRY_MODE` (`standard` or `adaptive`), overriding the SDK's
+/// `AWS_MAX_ATTEMPTS` and `AWS_RETRY_MODE` for S3.
+fn s3_retries_from_env() -> Result<Option<RetryConfig>> {
+    let mode = env::var("S3_RETRY_MODE").ok();
+    let max_attempts = env::var("S3_MAX_ATTEMPTS")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse S3_MAX_ATTEMPTS")?;
+    if mode.is_none() && max_attempts.is_none() {
+        return Ok(None);
+    }
+    let retry_config = match mode.as_deref() {
+        None | Some("standard") => RetryConfig::standard(),
+        Some("adaptive") => RetryConfig::adaptive(),
+        Some(mode) => anyhow::bail!("unknown S3_RETRY_MODE {}", mode),
+    };
+    Ok(Some(match max_attempts {
+        Some(max_attempts) => retry_config.with_max_attempts(max_attempts),
+        None => retry_config,
+    }))
+}
+
+/// Credential checks for the submission listener.
+async fn authenticator_from_env(
+    pg_pool: &PgPool,
+    startup_timeout: Duration,
+) -> Result<Option<Arc<dyn auth::Authenticator>>> {
+    if env::var("SUBMISSION_BIND_ADDR").is_err() {
+        return Ok(None);
+    }
+    match env::var("AUTH_BACKEND").as_deref().unwrap_or("db") {
+        "db" => Ok(Some(Arc::new(auth::DbAuthenticator::new(pg_pool.clone())))),
+        "oidc" => {
+            let issuer =
+                env::var("OIDC_ISSUER").context("env variable OIDC_ISSUER not provided")?;
+            let audience =
+                env::var("OIDC_AUDIENCE").context("env variable OIDC_AUDIENCE not provided")?;
+            let claim = env::var("OIDC_USERNAME_CLAIM").unwrap_or("sub".to_string());
+            #[cfg(feature = "oidc")]
+            {
+                let authenticator = with_retries("OIDC issuer", startup_timeout, || {
+                    auth::oidc::OidcAuthenticator::new(&issuer, &audience, &claim)
+                })
+                .await?;
+                Ok(Some(Arc::new(authenticator)))
+            }
+            #[cfg(not(feature = "oidc"))]
+            {
+                let _ = (issuer, audience, claim, startup_timeout);
+                anyhow::bail!("AUTH_BACKEND=oidc set, but compiled without oidc support");
+            }
+        }
+        "ldap" => {
+            #[cfg(feature = "ldap")]
+            {
+                Ok(Some(Arc::new(ldap_from_env()?)))
+            }
+            #[cfg(not(feature = "ldap"))]
+            {
+                anyhow::bail!("AUTH_BACKEND=ldap set, but compiled without ldap support");
+            }
+        }
+        other => anyhow::bail!("unknown AUTH_BACKEND {}", other),
+    }
+}
+
+/// Recipient lookups as an alternative to the `is_valid_rcpt` DB function.
+fn directory_from_env(smtp_domain: &str) -> Result<Option<Arc<dyn directory::Directory>>> {
+    let check_ldap: bool = env::var("CHECK_RCPT_IN_LDAP")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    let callout_server = env::var("CALLOUT_SERVER").ok();
+    if let Some(server) = callout_server {
+        if check_ldap {
+            anyhow::bail!("CALLOUT_SERVER and CHECK_RCPT_IN_LDAP cannot be combined");
+        }
+        let secs = |name: &str, default: u64| {
+            env::var(name)
+                .map(|s| s.parse())
+                .unwrap_or(Ok(default))
+                .with_context(|| format!("could not parse {}", name))
+                .map(Duration::from_secs)
+        };
+        return Ok(Some(Arc::new(directory::callout::Callout::new(
+            &server,
+            smtp_domain,
+            secs("CALLOUT_TIMEOUT", 30)?,
+            secs("CALLOUT_POSITIVE_TTL", 3600)?,
+            secs("CALLOUT_NEGATIVE_TTL", 600)?,
+        ))));
+    }
+    if !check_ldap {
+        return Ok(None);
+    }
+    #[cfg(feature = "ldap")]
+    {
+        Ok(Some(Arc::new(ldap_from_env()?)))
+    }
+    #[cfg(not(feature = "ldap"))]
+    {
+        anyhow::bail!("CHECK_RCPT_IN_LDAP set, but compiled without ldap support");
+    }
+}
+
+#[cfg(feature = "ldap")]
+fn ldap_from_env() -> Result<directory::ldap::LdapDirectory> {
+    let url = env::var("LDAP_URL").context("env variable LDAP_URL not provided")?;
+    let base_dn = env::var("LDAP_BASE_DN").context("env variable LDAP_BASE_DN not provided")?;
+    let bind_dn = env::var("LDAP_BIND_DN").unwrap_or_default();
+    let bind_password = env::var("LDAP_BIND_PASSWORD").unwrap_or_default();
+    let user_filter = env::var("LDAP_USER_FILTER").unwrap_or("(uid={username})".to_string());
+    let rcpt_filter = env::var("LDAP_RCPT_FILTER").unwrap_or("(mail={rcpt})".to_string());
+    Ok(directory::ldap::LdapDirectory::new(
+        &url,
+        &bind_dn,
+        &bind_password,
+        &base_dn,
+        &user_filter,
+        &rcpt_filter,
+    ))
+}
+
+/// Where to write transcripts of failed sessions, `TRANSCRIPT_DIR` or `TRANSCRIPT_S3_PREFIX`.
+fn transcripts_from_env() -> Result<Option<transcript::Transcripts>> {
+    let sink = match (env::var("TRANSCRIPT_DIR"), env::var("TRANSCRIPT_S3_PREFIX")) {
+        (Ok(_), Ok(_)) => {
+            anyhow::bail!("only one of TRANSCRIPT_DIR and TRANSCRIPT_S3_PREFIX may be set")
+        }
+        (Ok(dir), Err(_)) => transcript::Sink::Dir(dir.into()),
+        (Err(_), Ok(prefix)) => transcript::Sink::S3(prefix),
+        (Err(_), Err(_)) => return Ok(None),
+    };
+    let on_reject = env::var("TRANSCRIPT_ON_REJECT")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    Ok(Some(transcript::Transcripts { sink, on_reject }))
+}
+
+/// Artificial failures for resilience testing, only with `FAULT_INJECTION=true`.
+fn faults_from_env() -> Result<Option<faults::Faults>> {
+    if env::var("FAULT_INJECTION").map_or(true, |s| s != "true") {
+        return Ok(None);
+    }
+    let fault = |kind: &str| -> Result<faults::Fault> {
+        let rate = env::var(format!("FAULT_{}_RATE", kind))
+            .map(|s| s.parse())
+            .unwrap_or(Ok(0.0))
+            .with_context(|| format!("could not parse FAULT_{}_RATE", kind))?;
+        let latency = env::var(format!("FAULT_{}_LATENCY_MS", kind))
+            .map(|s| s.parse())
+            .unwrap_or(Ok(0))
+            .with_context(|| format!("could not parse FAULT_{}_LATENCY_MS", kind))?;
+        Ok(faults::Fault {
+            rate,
+            latency: Duration::from_millis(latency),
+        })
+    };
+    let faults = faults::Faults {
+        s3: fault("S3")?,
+        db: fault("DB")?,
+        client: fault("CLIENT")?,
+    };
+    warn!("fault injection enabled: {:?}", faults);
+    Ok(Some(faults))
+}
+
+/// The certificate's private key, in `SMTP_KEY_FILE` or the AWS KMS key `SMTP_KEY_KMS_ID`.
+#[instrument]
+async fn tls_key_from_env() -> Result<tls::KeySource> {
+    let Ok(key_id) = env::var("SMTP_KEY_KMS_ID") else {
+        let key_path =
+            env::var("SMTP_KEY_FILE").context("env variable SMTP_KEY_FILE not provided")?;
+        return Ok(tls::KeySource::File(key_path));
+    };
+
+    #[cfg(feature = "kms")]
+    {
+        let aws_config = aws_config::from_env().load().await;
+        let key = tls::kms::KmsSigningKey::new(&aws_config, &key_id).await?;
+        Ok(tls::KeySource::Remote(Arc::new(key)))
+    }
+    #[cfg(not(feature = "kms"))]
+    {
+        let _ = key_id;
+        anyhow::bail!("SMTP_KEY_KMS_ID set, but compiled without kms support");
+    }
+}
+
+#[instrument]
+fn plugin_from_env() -> Result<Option<Box<dyn plugin::Plugin>>> {
+    let Ok(path) = env::var("WASM_PLUGIN") else {
+        return Ok(None);
+    };
+
+    #[cfg(feature = "wasm")]
+    {
+        let fuel = env::var("WASM_PLUGIN_FUEL")
+            .map(|s| s.parse())
+            .unwrap_or(Ok(1_000_000_000))
+            .context("could not parse WASM_PLUGIN_FUEL")?;
+        let plugin = plugin::wasm::WasmPlugin::new(&path, fuel)?;
+        Ok(Some(Box::new(Arc::new(plugin))))
+    }
+    #[cfg(not(feature = "wasm"))]
+    {
+        let _ = path;
+        anyhow::bail!("WASM_PLUGIN set, but compiled without wasm support");
+    }
+}
+
+#[instrument]
+/// Thumbnails of image attachments at most `THUMBNAIL_SIZE` pixels wide and high.
+fn thumbnails_from_env() -> Result<Option<thumbnail::Thumbnails>> {
+    let Ok(size) = env::var("THUMBNAIL_SIZE") else {
+        return Ok(None);
+    };
+    let size = size.parse().context("could not parse THUMBNAIL_SIZE")?;
+
+    #[cfg(feature = "thumbnails")]
+    {
+        Ok(Some(thumbnail::Thumbnails { size }))
+    }
+    #[cfg(not(feature = "thumbnails"))]
+    {
+        let _: u32 = size;
+        anyhow::bail!("THUMBNAIL_SIZE set, but compiled without thumbnails support");
+    }
+}
+
+fn attachment_policy_from_env() -> Result<Option<attachments::AttachmentPolicy>> {
+    let block = env::var("ATTACHMENT_BLOCK").unwrap_or_default();
+    let allow = env::var("ATTACHMENT_ALLOW").unwrap_or_default();
+    if block.is_empty() && allow.is_empty() {
+        return Ok(None);
+    }
+    let action = env::var("ATTACHMENT_POLICY_ACTION")
+        .map(|s| attachments::PolicyAction::parse(&s))
+        .unwrap_or(Ok(attachments::PolicyAction::Drop))?;
+    Ok(Some(attachments::AttachmentPolicy::new(
+        &block, &allow, action,
+    )))
+}
+
+fn attachment_limits_from_env() -> Result<Option<attachments::AttachmentLimits>> {
+    let max_count = env::var("MAX_ATTACHMENTS")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse MAX_ATTACHMENTS")?;
+    let max_size = env::var("MAX_ATTACHMENT_SIZE")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse MAX_ATTACHMENT_SIZE")?;
+    if max_count.is_none() && max_size.is_none() {
+        return Ok(None);
+    }
+    let reject = match env::var("ATTACHMENT_LIMIT_ACTION").as_deref() {
+        Ok("reject") => true,
+        Ok("skip") | Err(_) => false,
+        Ok(action) => anyhow::bail!("unknown ATTACHMENT_LIMIT_ACTION {}", action),
+    };
+    Ok(Some(attachments::AttachmentLimits {
+        max_count,
+        max_size,
+        reject,
+    }))
+}
+
+fn extraction_from_env() -> Result<Option<extract::Extraction>> {
+    let enabled = env::var("ATTACHMENT_TEXT")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+    let max_bytes = env::var("ATTACHMENT_TEXT_MAX_BYTES")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse ATTACHMENT_TEXT_MAX_BYTES")?
+        .unwrap_or(extract::DEFAULT_MAX_BYTES);
+    Ok(Some(extract::Extraction { max_bytes }))
+}
+
+fn queue_from_env() -> Result<Option<Arc<queue::Queue>>> {
+    let enabled = env::var("QUEUE").map(|s| s == "true").unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+    let workers = env::var("QUEUE_WORKERS")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(4))
+        .context("could not parse QUEUE_WORKERS")?;
+    let max_attempts = env::var("QUEUE_MAX_ATTEMPTS")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(10))
+        .context("could not parse QUEUE_MAX_ATTEMPTS")?;
+    let retry_delay = env::var("QUEUE_RETRY_DELAY")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(60))
+        .context("could not parse QUEUE_RETRY_DELAY")?;
+    Ok(Some(Arc::new(queue::Queue {
+        workers,
+        max_attempts,
+        retry_delay: Duration::from_secs(retry_delay),
+    })))
+}
+
+fn smime_from_env() -> Result<Option<Box<dyn smime::Smime>>> {
+    let Ok(keys) = env::var("SMIME_KEYS") else {
+        return Ok(None);
+    };
+
+    #[cfg(feature = "smime")]
+    {
+        let ca_file = env::var("SMIME_CA_FILE").ok();
+        let smime =
+            smime::openssl::OpensslSmime::new(&parse_key_values(&keys), ca_file.as_deref())?;
+        Ok(Some(Box::new(smime)))
+    }
+    #[cfg(not(feature = "smime"))]
+    {
+        let _ = keys;
+        anyhow::bail!("SMIME_KEYS set, but compiled without smime support");
+    }
+}
+
+#[instrument]
+fn encryption_from_env() -> Result<Option<Box<dyn encryption::Encryption>>> {
+    let age_recipients = env::var("ENCRYPT_AGE_RECIPIENTS").ok();
+    let pgp_key = env::var("ENCRYPT_PGP_KEY").ok();
+    match (age_recipients, pgp_key) {
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => {
+            anyhow::bail!("only one of ENCRYPT_AGE_RECIPIENTS and ENCRYPT_PGP_KEY may be set")
+        }
+        #[cfg(feature = "age")]
+        (Some(recipients), None) => {
+            let recipients: Vec<String> = recipients
+                .split(',')
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty())
+                .collect();
+            let encryption = encryption::age::AgeEncryption::new(&recipients)?;
+            Ok(Some(Box::new(encryption)))
+        }
+        #[cfg(not(feature = "age"))]
+        (Some(_), None) => {
+            anyhow::bail!("ENCRYPT_AGE_RECIPIENTS set, but compiled without age support")
+        }
+        #[cfg(feature = "pgp")]
+        (None, Some(path)) => Ok(Some(Box::new(encryption::pgp::PgpEncryption::new(&path)?))),
+        #[cfg(not(feature = "pgp"))]
+        (None, Some(_)) => anyhow::bail!("ENCRYPT_PGP_KEY set, but compiled without pgp support"),
+    }
+}
+
+#[instrument]
+fn decryption_from_env() -> Result<Option<Box<dyn encryption::Decryption>>> {
+    let age_identity = env::var("DECRYPT_AGE_IDENTITY").ok();
+    let pgp_key = env::var("DECRYPT_PGP_KEY").ok();
+    match (age_identity, pgp_key) {
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => {
+            anyhow::bail!("only one of DECRYPT_AGE_IDENTITY and DECRYPT_PGP_KEY may be set")
+        }
+        #[cfg(feature = "age")]
+        (Some(path), None) => Ok(Some(Box::new(encryption::age::AgeDecryption::new(&path)?))),
+        #[cfg(not(feature = "age"))]
+        (Some(_), None) => {
+            anyhow::bail!("DECRYPT_AGE_IDENTITY set, but compiled without age support")
+        }
+        #[cfg(feature = "pgp")]
+        (None, Some(path)) => {
+            let passphrase = env::var("DECRYPT_PGP_PASSPHRASE").ok();
+            let decryption = encryption::pgp::PgpDecryption::new(&path, passphrase)?;
+            Ok(Some(Box::new(decryption)))
+        }
+        #[cfg(not(feature = "pgp"))]
+        (None, Some(_)) => anyhow::bail!("DECRYPT_PGP_KEY set, but compiled without pgp support"),
+    }
+}
+
+fn body_keys_from_env() -> Result<Option<bodies::BodyKeys>> {
+    let Ok(keys) = env::var("DB_ENCRYPTION_KEYS") else {
+        return Ok(None);
+    };
+    let current = env::var("DB_ENCRYPTION_KEY_ID").ok();
+    let keys = bodies::BodyKeys::new(&parse_key_values(&keys), current.as_deref())
+        .context("could not parse DB_ENCRYPTION_KEYS")?;
+    Ok(Some(keys))
+}
+
+/// Read reply text templates, keeping the defaults for unset ones.
+fn replies_from_env() -> replies::ReplyTexts {
+    let defaults = replies::ReplyTexts::default();
+    replies::ReplyTexts {
+        banner: env::var("REPLY_BANNER").ok(),
+        ehlo: env::var("REPLY_EHLO").unwrap_or(defaults.ehlo),
+        accepted: env::var("REPLY_ACCEPTED").unwrap_or(defaults.accepted),
+        rejected: env::var("REPLY_REJECTED").unwrap_or(defaults.rejected),
+        temp_failure: env::var("REPLY_TEMP_FAILURE").unwrap_or(defaults.temp_failure),
+    }
+}
+
+/// Configure the optional smart host accepted mail is copied to.
+#[instrument]
+fn relay_from_env() -> Result<Option<relay::Relay>> {
+    let Ok(host) = env::var("RELAY_HOST") else {
+        return Ok(None);
+    };
+    let port = env::var("RELAY_PORT")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse RELAY_PORT")?;
+    let tls = env::var("RELAY_TLS").unwrap_or("starttls".to_string());
+    let credentials = env::var("RELAY_USERNAME")
+        .ok()
+        .zip(env::var("RELAY_PASSWORD").ok());
+    let required = env::var("RELAY_REQUIRED")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+
+    Ok(Some(relay::Relay::new(
+        &host,
+        port,
+        &tls,
+        credentials,
+        required,
+    )?))
+}
 
-    let server = start_smtp_server(smtp_bind_addr, backend);
+/// Configure the event backends notified about stored messages.
+#[instrument]
+async fn events_from_env() -> Result<events::Events> {
+    #[allow(unused_mut)]
+    let mut events = events::Events::default();
+
+    if let Ok(brokers) = env::var("KAFKA_BROKERS") {
+        #[cfg(feature = "kafka")]
+        {
+            let topic = env::var("KAFKA_TOPIC").unwrap_or("smtp-s3-dump".to_string());
+            let properties = env::var("KAFKA_PROPERTIES")
+                .map(|s| parse_key_values(&s))
+                .unwrap_or_default();
+            let timeout = env::var("KAFKA_TIMEOUT")
+                .map(|s| s.parse())
+                .unwrap_or(Ok(30))
+                .context("could not parse KAFKA_TIMEOUT")?;
+            let wait_for_ack = env::var("KAFKA_WAIT_FOR_ACK")
+                .map(|s| s == "true")
+                .unwrap_or(true);
+            events.add(events::kafka::KafkaSink::new(
+                &brokers,
+                &topic,
+                &properties,
+                Duration::from_secs(timeout),
+                wait_for_ack,
+            )?);
+        }
+        #[cfg(not(feature = "kafka"))]
+        anyhow::bail!(
+            "KAFKA_BROKERS={} set, but compiled without kafka support",
+            brokers
+        );
+    }
+
+    if let Ok(url) = env::var("AMQP_URL") {
+        #[cfg(feature = "amqp")]
+        {
+            let exchange = env::var("AMQP_EXCHANGE").unwrap_or("smtp-s3-dump".to_string());
+            let wait_for_confirm = env::var("AMQP_WAIT_FOR_CONFIRM")
+                .map(|s| s == "true")
+                .unwrap_or(true);
+            events.add(events::amqp::AmqpSink::new(
+                &url,
+                &exchange,
+                wait_for_confirm,
+            ));
+        }
+        #[cfg(not(feature = "amqp"))]
+        {
+            let _ = url;
+            anyhow::bail!("AMQP_URL set, but compiled without amqp support");
+        }
+    }
+
+    if let Ok(url) = env::var("REDIS_URL") {
+        #[cfg(feature = "redis")]
+        {
+            let channel = env::var("REDIS_CHANNEL").ok();
+            let stream = env::var("REDIS_STREAM").ok();
+            let stream_maxlen = env::var("REDIS_STREAM_MAXLEN")
+                .ok()
+                .map(|s| s.parse())
+                .transpose()
+                .context("could not parse REDIS_STREAM_MAXLEN")?;
+            let wait_for_ack = env::var("REDIS_WAIT_FOR_ACK")
+                .map(|s| s == "true")
+                .unwrap_or(false);
+            // publish to the default channel if nothing is configured
+            let channel = if channel.is_none() && stream.is_none() {
+                Some("smtp-s3-dump".to_string())
+            } else {
+                channel
+            };
+            events.add(
+                events::redis::RedisSink::new(&url, channel, stream, stream_maxlen, wait_for_ack)
+                    .await?,
+            );
+        }
+        #[cfg(not(feature = "redis"))]
+        {
+            let _ = url;
+            anyhow::bail!("REDIS_URL set, but compiled without redis support");
+        }
+    }
+
+    Ok(events)
+}
+
+/// smtpbis settings of a listener, read from `<prefix>_STARTTLS`, `<prefix>_SMTPUTF8` and
+/// `<prefix>_CHUNKING`; all extensions are advertised by default.
+fn listener_config_from_env(prefix: &str) -> smtpbis::Config {
+    let flag = |name: &str| {
+        env::var(format!("{}_{}", prefix, name))
+            .map(|s| s != "false")
+            .unwrap_or(true)
+    };
+    smtpbis::Config {
+        enable_starttls: flag("STARTTLS"),
+        enable_smtputf8: flag("SMTPUTF8"),
+        enable_chunking: flag("CHUNKING"),
+    }
+}
+
+/// Parse a comma separated list of addresses or networks, e.g. `10.0.0.0/8,192.0.2.25`.
+fn nets_from_env(name: &str) -> Result<Vec<IpNet>> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        })
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("could not parse {}", name))
+}
+
+/// Parse `key=value,key=value` lists.
+fn parse_key_values(s: &str) -> Vec<(String, String)> {
+    s.split(',')
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+#[instrument]
+async fn serve() -> Result<()> {
+    let smtp_bind_addr = env::var("STMP_BIND_ADDR").unwrap_or("0.0.0.0:2525".to_string());
+    let submission_bind_addr = env::var("SUBMISSION_BIND_ADDR").ok();
+    let lmtp_socket = env::var("LMTP_SOCKET").ok();
+    let lmtp_socket_mode = env::var("LMTP_SOCKET_MODE")
+        .map(|s| u32::from_str_radix(&s, 8))
+        .unwrap_or(Ok(0o660))
+        .context("could not parse LMTP_SOCKET_MODE")?;
+    let http_bind_addr: Option<SocketAddr> = env::var("HTTP_BIND_ADDR")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse HTTP_BIND_ADDR")?;
+    let http_api_token = http_bind_addr
+        .map(|_| env::var("HTTP_API_TOKEN").context("env variable HTTP_API_TOKEN not provided"))
+        .transpose()?;
+    let cert_path =
+        env::var("SMTP_CERT_FILE").context("env variable SMTP_CERT_FILE not provided")?;
+    let key = tls_key_from_env().await?;
+    let provider = tls::crypto_provider(&env::var("TLS_CRYPTO_PROVIDER").unwrap_or_default())
+        .context("could not parse TLS_CRYPTO_PROVIDER")?;
+
+    let resolver = tls::CertificateResolver::new(&cert_path, key, provider)?;
+    // start certificate change watcher
+    notify::watch_certs(resolver.clone()).await?;
+    let tls_config = tls::safe_tls_config(resolver.clone())?;
+
+    let backend = backend_from_env(Some(tls_config.clone())).await?;
+    let max_connections = env::var("MAX_CONNECTIONS")
+        .ok()
+        .map(|s| s.parse())
+        .transpose()
+        .context("could not parse MAX_CONNECTIONS")?;
+    let sessions = Arc::new(sessions::Sessions::new(max_connections));
+
+    let lmtp_handler = lmtp_socket.map(|path| {
+        tokio::spawn(lmtp::start_lmtp_server(
+            path,
+            lmtp_socket_mode,
+            backend.clone(),
+        ))
+    });
+
+    let admin = env::var("ADMIN_API_TOKEN").ok().map(|token| {
+        let (config, resolver, tls_config) =
+            (backend.config.clone(), resolver.clone(), tls_config.clone());
+        let reload: http::admin::Reload = Box::new(move || {
+            let (config, resolver, tls_config) =
+                (config.clone(), resolver.clone(), tls_config.clone());
+            async move {
+                resolver.refresh().await?;
+                config.store(Arc::new(config_from_env(Some(tls_config)).await?));
+                Ok(())
+            }
+            .boxed()
+        });
+        http::admin::Admin {
+            token,
+            sessions: sessions.clone(),
+            reload,
+        }
+    });
+    let http_handler = http_bind_addr.zip(http_api_token).map(|(addr, token)| {
+        tokio::spawn(http::start_http_server(addr, token, backend.clone(), admin))
+    });
+
+    let max_unknown_commands = env::var("MAX_UNKNOWN_COMMANDS")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(10))
+        .context("could not parse MAX_UNKNOWN_COMMANDS")?;
+    let smtp_listener = Listener {
+        bind_addr: smtp_bind_addr,
+        smtp_config: listener_config_from_env("SMTP"),
+        max_unknown_commands,
+        submission: false,
+    };
+    let submission_handler = submission_bind_addr.map(|bind_addr| {
+        let listener = Listener {
+            bind_addr,
+            smtp_config: listener_config_from_env("SUBMISSION"),
+            max_unknown_commands,
+            submission: true,
+        };
+        tokio::spawn(start_smtp_server(
+            listener,
+            backend.clone(),
+            sessions.clone(),
+        ))
+    });
 
-    let smtp_handler = tokio::spawn(server);
+    let grpc_handler = grpc_from_env(&backend)?;
+
+    let queue_handler = backend
+        .config
+        .load()
+        .queue
+        .clone()
+        .map(|queue| tokio::spawn(queue::run_workers(backend.clone(), queue)));
+
+    let pop3_handler = pop3_from_env(&tls_config)?
+        .map(|pop3| tokio::spawn(pop3::start_pop3_server(pop3, backend.clone())));
+
+    let smtp_handler = tokio::spawn(start_smtp_server(smtp_listener, backend, sessions));
 
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -107,55 +1400,265 @@ async fn main() -> Result<()> {
         _ = ctrl_c => {},
         _ = terminate => {},
         _ = smtp_handler => {},
+        _ = optional_task(submission_handler) => {},
+        _ = optional_task(lmtp_handler) => {},
+        _ = optional_task(http_handler) => {},
+        _ = optional_task(grpc_handler) => {},
+        _ = optional_task(pop3_handler) => {},
+        _ = optional_task(queue_handler) => {},
     }
     tracing::info!("shutting down");
 
     Ok(())
 }
 
-#[instrument(skip_all)]
-async fn start_smtp_server(smtp_bind_addr: String, smtp_backend: SmtpBackend) -> Result<()> {
-    info!("listening on {}", smtp_bind_addr);
-    let listener = TcpListener::bind(smtp_bind_addr).await?;
+/// Start the gRPC API if `GRPC_BIND_ADDR` is set.
+fn grpc_from_env(backend: &SmtpBackend) -> Result<Option<JoinHandle<Result<()>>>> {
+    let Ok(bind_addr) = env::var("GRPC_BIND_ADDR") else {
+        return Ok(None);
+    };
+    #[cfg(feature = "grpc")]
+    {
+        let bind_addr = bind_addr
+            .parse()
+            .context("could not parse GRPC_BIND_ADDR")?;
+        let token =
+            env::var("GRPC_API_TOKEN").context("env variable GRPC_API_TOKEN not provided")?;
+        Ok(Some(tokio::spawn(grpc::start_grpc_server(
+            bind_addr,
+            token,
+            backend.clone(),
+        ))))
+    }
+    #[cfg(not(feature = "grpc"))]
+    {
+        let _ = backend;
+        anyhow::bail!(
+            "GRPC_BIND_ADDR={} set, but compiled without grpc support",
+            bind_addr
+        );
+    }
+}
+
+/// The read-only POP3 listener, if `POP3_BIND_ADDR` is set.
+fn pop3_from_env(tls_config: &Arc<ServerConfig>) -> Result<Option<pop3::Pop3>> {
+    let Ok(bind_addr) = env::var("POP3_BIND_ADDR") else {
+        return Ok(None);
+    };
+    let tls = env::var("POP3_TLS").map(|s| s != "false").unwrap_or(true);
+    let max_messages = env::var("POP3_MAX_MESSAGES")
+        .map(|s| s.parse())
+        .unwrap_or(Ok(1000))
+        .context("could not parse POP3_MAX_MESSAGES")?;
+    Ok(Some(pop3::Pop3 {
+        bind_addr,
+        tls_config: tls.then(|| tls_config.clone()),
+        max_messages,
+    }))
+}
+
+/// Wait for an optional spawned task, never completes without one.
+async fn optional_task(handle: Option<JoinHandle<Result<()>>>) {
+    match handle {
+        Some(handle) => {
+            let _ = handle.await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Settings of an SMTP or submission listener.
+struct Listener {
+    bind_addr: String,
+    smtp_config: smtpbis::Config,
+    max_unknown_commands: usize,
+    /// require AUTH before MAIL
+    submission: bool,
+}
+
+#[instrument(skip_all, fields(bind_addr = listener.bind_addr))]
+async fn start_smtp_server(
+    listener: Listener,
+    smtp_backend: SmtpBackend,
+    sessions: Arc<sessions::Sessions>,
+) -> Result<()> {
+    info!("listening on {}", listener.bind_addr);
+    let Listener {
+        bind_addr,
+        smtp_config,
+        max_unknown_commands,
+        submission,
+    } = listener;
+    let listener = TcpListener::bind(bind_addr).await?;
 
     // ignore smtpbis' shutdown
     let (_shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
     let shutdown_rx = shutdown_rx.map_err(|_| ()).shared();
 
     while let Ok((socket, addr)) = listener.accept().await {
-        let session = smtp_backend.new_session()?;
+        if sessions.paused() {
+            tokio::spawn(turn_away(socket, PAUSED));
+            continue;
+        }
+        if sessions.full() {
+            warn!("refused connection from {} at the connection limit", addr);
+            metrics::RESOURCE_LIMITS
+                .with_label_values(&["connections"])
+                .inc();
+            tokio::spawn(turn_away(socket, TOO_MANY_CONNECTIONS));
+            continue;
+        }
+        let registration = sessions.register(addr, submission);
+        let mut session = smtp_backend.new_session(Some(addr))?;
+        session.submission = submission;
         let mut shutdown_rx = shutdown_rx.clone();
+        // smtpbis' Config is not Clone
+        let smtp_config = smtpbis::Config {
+            enable_starttls: smtp_config.enable_starttls,
+            enable_smtputf8: smtp_config.enable_smtputf8,
+            enable_chunking: smtp_config.enable_chunking,
+        };
+        let config = session.config.clone();
+        let transcript = config
+            .transcripts
+            .as_ref()
+            .map(|_| transcript::Transcript::new(addr));
+        let commands = Commands::new(
+            Probes::new(addr, max_unknown_commands),
+            session.proxy(),
+            session.sasl(),
+            transcript.clone(),
+            registration.activity.clone(),
+        );
+        let socket = CommandWatch::new(socket, commands);
         tokio::spawn(async move {
-            if let Err(e) = handle_smtp_connection(socket, addr, session, &mut shutdown_rx).await {
+            let connection = handle_smtp_connection(socket, session, smtp_config, &mut shutdown_rx);
+            let result = tokio::select! {
+                result = connection => result,
+                _ = registration.activity.kicked() => {
+                    info!("closed connection from {} on request", addr);
+                    Ok(())
+                }
+            };
+            drop(registration);
+            if let Err(e) = result {
                 warn!("could not handle connection: {}", e);
+                if let Some(transcript) = transcript.as_ref() {
+                    transcript.lock().unwrap().error(&e);
+                }
+            }
+            if let Some((transcripts, transcript)) = config.transcripts.as_ref().zip(transcript) {
+                transcripts.finish(&config, &transcript).await;
             }
         });
     }
     Ok(())
 }
 
-#[instrument(skip_all)]
+#[instrument(skip_all, fields(peer = ?session.peer))]
 async fn handle_smtp_connection(
-    mut socket: TcpStream,
-    _addr: SocketAddr,
+    mut socket: CommandWatch<TcpStream>,
     mut session: SmtpSession,
+    mut smtp_config: smtpbis::Config,
     shutdown: &mut smtpbis::ShutdownSignal,
 ) -> Result<()> {
-    let mut smtp_config = smtpbis::Config::default();
-    match smtp_server(&mut socket, &mut session, &smtp_config, shutdown, true).await {
-        Ok(LoopExit::Done) => trace!("session done"),
-        Ok(LoopExit::STARTTLS(tls_config)) => {
+    // send the configured banner instead of smtpbis' default
+    let banner = session.config.replies.banner.as_ref().map(|banner| {
+        let domain = session.config.domain.to_string();
+        replies::render(banner, &[("domain", &domain)])
+    });
+    if let Some(banner) = banner.as_ref() {
+        socket
+            .write_all(format!("220 {}\r\n", banner).as_bytes())
+            .await?;
+    }
+    let send_banner = banner.is_none();
+    // a panic must not take the connection down without an answer
+    let served = AssertUnwindSafe(smtp_server(
+        &mut socket,
+        &mut session,
+        &smtp_config,
+        shutdown,
+        send_banner,
+    ))
+    .catch_unwind()
+    .await;
+    match served {
+        Ok(Ok(LoopExit::Done)) => trace!("session done"),
+        Ok(Ok(LoopExit::STARTTLS(tls_config))) => {
             let acceptor = TlsAcceptor::from(tls_config);
-            let mut tls_socket = acceptor.accept(socket).await?;
+            let (socket, commands) = socket.into_parts();
+            let mut tls_socket = CommandWatch::new(acceptor.accept(socket).await?, commands);
+            tls_socket.tls_started();
             smtp_config.enable_starttls = false;
-            // handler.tls_started(tls_socket.get_ref().1).await;
-            match smtp_server(&mut tls_socket, &mut session, &smtp_config, shutdown, false).await {
-                Ok(_) => trace!("TLS session done"),
-                Err(e) => error!("TLS session error: {:?}", e),
+            session.tls_started(tls_socket.get_ref().get_ref().1);
+            let served = AssertUnwindSafe(smtp_server(
+                &mut tls_socket,
+                &mut session,
+                &smtp_config,
+                shutdown,
+                false,
+            ))
+            .catch_unwind()
+            .await;
+            match served {
+                Ok(Ok(_)) => trace!("TLS session done"),
+                Ok(Err(_)) if tls_socket.exceeded() => disconnect_prober(&mut tls_socket).await?,
+                Ok(Err(e)) => {
+                    error!("TLS session error: {:?}", e);
+                    tls_socket.record_error(format!("{:?}", e));
+                }
+                Err(panic) => crashed(&mut tls_socket, &session, panic).await?,
             }
             tls_socket.shutdown().await?;
         }
-        Err(_e) => {}
+        Ok(Err(_)) if socket.exceeded() => disconnect_prober(&mut socket).await?,
+        Ok(Err(e)) => socket.record_error(format!("{:?}", e)),
+        Err(panic) => crashed(&mut socket, &session, panic).await?,
     }
     Ok(())
 }
+
+/// Log and count a session that panicked, e.g. while parsing or uploading a message, and
+/// tell the client to try again later.
+async fn crashed<S: AsyncWrite + Unpin>(
+    socket: &mut CommandWatch<S>,
+    session: &SmtpSession,
+    panic: Box<dyn Any + Send>,
+) -> Result<()> {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    metrics::SESSION_PANICS.inc();
+    error!(
+        from = ?session.from,
+        rcpts = ?session.rcpts,
+        "session panicked: {}",
+        message
+    );
+    socket.record_error(format!("panic: {}", message));
+    socket
+        .write_all(b"451 4.3.0 local error in processing, try again later\r\n")
+        .await?;
+    Ok(())
+}
+
+const PAUSED: &[u8] = b"421 4.3.2 service not accepting connections, try again later\r\n";
+const TOO_MANY_CONNECTIONS: &[u8] = b"421 4.7.0 too many connections, try again later\r\n";
+
+/// Tell a client to come back later, e.g. while new connections are paused.
+async fn turn_away(mut socket: TcpStream, reply: &'static [u8]) -> Result<()> {
+    socket.write_all(reply).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Tell a client sending too many unknown commands that the connection is closed.
+async fn disconnect_prober<S: AsyncWrite + Unpin>(socket: &mut CommandWatch<S>) -> Result<()> {
+    socket
+        .write_all(b"421 4.7.0 too many unknown commands, closing connection\r\n")
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+pub static MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_messages_total",
+        "Messages by recipient, sender domain and result",
+        &["rcpt", "sender_domain", "result"]
+    )
+    .unwrap()
+});
+
+pub static QUOTA_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_quota_messages_total",
+        "Messages counted against a quota",
+        &["key"]
+    )
+    .unwrap()
+});
+
+pub static QUOTA_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_quota_bytes_total",
+        "Bytes counted against a quota",
+        &["key"]
+    )
+    .unwrap()
+});
+
+pub static QUOTA_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_quota_rejections_total",
+        "Recipients refused for being over quota",
+        &["key"]
+    )
+    .unwrap()
+});
+
+pub static TLS_SESSIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_tls_sessions_total",
+        "Negotiated STARTTLS sessions",
+        &["version", "cipher"]
+    )
+    .unwrap()
+});
+
+pub static PROBE_COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_probe_commands_total",
+        "Unknown or forbidden SMTP commands, e.g. VRFY, AUTH or HTTP requests",
+        &["command"]
+    )
+    .unwrap()
+});
+
+pub static PROBE_DISCONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "smtp_probe_disconnects_total",
+        "Connections closed after too many unknown commands"
+    )
+    .unwrap()
+});
+
+pub static TLS_CERT_NOT_AFTER: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "smtp_tls_certificate_not_after_seconds",
+        "Expiry of the active TLS certificate as a Unix timestamp"
+    )
+    .unwrap()
+});
+
+pub static TLS_CERT_RELOADS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_tls_certificate_reloads_total",
+        "Reloads of the TLS certificate and key by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static TLS_CERT_LAST_RELOAD: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "smtp_tls_certificate_last_reload_seconds",
+        "Time of the last successful TLS certificate reload as a Unix timestamp"
+    )
+    .unwrap()
+});
+
+pub static TLS_CERT_WATCHER_UP: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "smtp_tls_certificate_watcher_up",
+        "Whether the TLS certificate and key files are watched for changes"
+    )
+    .unwrap()
+});
+
+pub static TLS_CERT_WATCHER_RESTARTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "smtp_tls_certificate_watcher_restarts_total",
+        "Restarts of the TLS certificate watcher after it stopped"
+    )
+    .unwrap()
+});
+
+pub static HELO_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_helo_rejections_total",
+        "Rejected HELO and EHLO names by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static RDNS_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_rdns_lookups_total",
+        "Reverse DNS lookups of clients by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static BLOCKED_ATTACHMENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_blocked_attachments_total",
+        "Messages with attachments of blocked types by action",
+        &["action"]
+    )
+    .unwrap()
+});
+
+pub static ATTACHMENT_LIMITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_attachment_limits_total",
+        "Messages over the attachment limits by action",
+        &["action"]
+    )
+    .unwrap()
+});
+
+pub static ENCRYPTED_ARCHIVES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "smtp_encrypted_archives_total",
+        "Messages with password-protected archive attachments"
+    )
+    .unwrap()
+});
+
+pub static QUEUE_ATTEMPTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_queue_attempts_total",
+        "Attempts to store queued messages by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static MIRROR_WRITES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_mirror_writes_total",
+        "Messages written to the mirror bucket by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static SESSION_PANICS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "smtp_session_panics_total",
+        "SMTP sessions aborted by a panic"
+    )
+    .unwrap()
+});
+
+pub static BUFFERED_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "smtp_buffered_bytes",
+        "Bytes of message content buffered by all sessions"
+    )
+    .unwrap()
+});
+
+pub static RESOURCE_LIMITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_resource_limit_rejections_total",
+        "Connections and messages refused at a process-wide limit",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+pub static TARPIT_DELAYS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "smtp_tarpit_delays_total",
+        "Replies delayed for clients with recent rejections"
+    )
+    .unwrap()
+});
+
+pub static AUTH_ATTEMPTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_auth_attempts_total",
+        "AUTH attempts of submission clients",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static POP3_LOGINS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_pop3_logins_total",
+        "Login attempts of POP3 clients",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static FAULTS_INJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "smtp_faults_injected_total",
+        "Failures and stalls injected for resilience testing",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+/// Recipients and sender domains used as label values, all others are counted as `other`
+/// to bound the number of time series.
+#[derive(Debug, Default)]
+pub struct Labels {
+    pub rcpts: HashSet<String>,
+    pub sender_domains: HashSet<String>,
+}
+
+impl Labels {
+    pub fn rcpt<'a>(&self, rcpt: &'a str) -> &'a str {
+        match self.rcpts.contains(&rcpt.to_lowercase()) {
+            true => rcpt,
+            false => "other",
+        }
+    }
+
+    /// The domain of `from`, `<>` for the null sender.
+    pub fn sender_domain<'a>(&self, from: Option<&'a str>) -> &'a str {
+        let Some(from) = from.filter(|from| !from.is_empty()) else {
+            return "<>";
+        };
+        let domain = from.rsplit_once('@').map_or(from, |(_, domain)| domain);
+        match self.sender_domains.contains(domain) {
+            true => domain,
+            false => "other",
+        }
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub fn gather() -> Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
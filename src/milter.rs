@@ -0,0 +1,476 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use smtpbis::{EnhancedCode, Reply};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tracing::{instrument, trace, warn};
+
+const MILTER_VERSION: u32 = 6;
+const MAX_BODY_CHUNK: usize = 65535;
+const MAX_PACKET_SIZE: u32 = 1024 * 1024;
+
+// actions we allow the milter to take
+const SMFIF_ADDHDRS: u32 = 0x01;
+const SMFIF_QUARANTINE: u32 = 0x20;
+
+// protocol steps the milter may disable or not reply to
+const SMFIP_NOCONNECT: u32 = 0x01;
+const SMFIP_NOHELO: u32 = 0x02;
+const SMFIP_NOMAIL: u32 = 0x04;
+const SMFIP_NORCPT: u32 = 0x08;
+const SMFIP_NOBODY: u32 = 0x10;
+const SMFIP_NOHDRS: u32 = 0x20;
+const SMFIP_NOEOH: u32 = 0x40;
+const SMFIP_NR_HDR: u32 = 0x80;
+const SMFIP_NODATA: u32 = 0x200;
+const SMFIP_NR_CONN: u32 = 0x1000;
+const SMFIP_NR_HELO: u32 = 0x2000;
+const SMFIP_NR_MAIL: u32 = 0x4000;
+const SMFIP_NR_RCPT: u32 = 0x8000;
+const SMFIP_NR_DATA: u32 = 0x10000;
+const SMFIP_NR_EOH: u32 = 0x40000;
+const SMFIP_NR_BODY: u32 = 0x80000;
+
+const SUPPORTED_PROTOCOL: u32 = SMFIP_NOCONNECT
+    | SMFIP_NOHELO
+    | SMFIP_NOMAIL
+    | SMFIP_NORCPT
+    | SMFIP_NOBODY
+    | SMFIP_NOHDRS
+    | SMFIP_NOEOH
+    | SMFIP_NR_HDR
+    | SMFIP_NODATA
+    | SMFIP_NR_CONN
+    | SMFIP_NR_HELO
+    | SMFIP_NR_MAIL
+    | SMFIP_NR_RCPT
+    | SMFIP_NR_DATA
+    | SMFIP_NR_EOH
+    | SMFIP_NR_BODY;
+
+trait MilterStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> MilterStream for T {}
+
+/// Outcome of a milter protocol step.
+pub enum MilterResult {
+    Continue,
+    /// Accept without consulting the milter any further.
+    Accept,
+    /// Accept, but silently drop the message.
+    Discard,
+    Reply(Reply),
+}
+
+/// Modifications the milter requested at end of message.
+#[derive(Debug, Default)]
+pub struct Modifications {
+    pub add_headers: Vec<(String, String)>,
+    pub quarantine: Option<String>,
+}
+
+impl Modifications {
+    /// Prepend added headers to the raw message.
+    pub fn apply(&self, data: &mut Vec<u8>) {
+        if self.add_headers.is_empty() {
+            return;
+        }
+        let mut headers: Vec<u8> = self
+            .add_headers
+            .iter()
+            .flat_map(|(k, v)| format!("{}: {}\r\n", k, v).into_bytes())
+            .collect();
+        headers.append(data);
+        *data = headers;
+    }
+}
+
+/// Milter configuration, e.g. `inet:8891@localhost` or `unix:/run/opendkim/opendkim.sock`.
+#[derive(Debug)]
+pub struct Milter {
+    pub socket: String,
+    pub timeout: Duration,
+    pub fail_open: bool,
+}
+
+impl Milter {
+    #[instrument]
+    pub fn new(socket: &str, timeout: Duration, fail_open: bool) -> Result<Self> {
+        if !(socket.starts_with("unix:") || socket.starts_with("inet:")) {
+            bail!("milter socket has to start with unix: or inet:");
+        }
+        Ok(Self {
+            socket: socket.to_string(),
+            timeout,
+            fail_open,
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn connect(&self) -> Result<MilterSession> {
+        trace!("connecting to milter");
+        let stream: Box<dyn MilterStream> = if let Some(path) = self.socket.strip_prefix("unix:") {
+            Box::new(UnixStream::connect(path).await?)
+        } else {
+            let inet = self.socket.trim_start_matches("inet:");
+            let (port, host) = inet.split_once('@').unwrap_or((inet, "localhost"));
+            Box::new(TcpStream::connect((host, port.parse::<u16>()?)).await?)
+        };
+
+        let mut session = MilterSession {
+            stream,
+            timeout: self.timeout,
+            protocol: 0,
+            accepted: false,
+        };
+        tokio::time::timeout(self.timeout, session.negotiate())
+            .await
+            .context("milter negotiation timed out")??;
+        Ok(session)
+    }
+}
+
+pub struct MilterSession {
+    stream: Box<dyn MilterStream>,
+    timeout: Duration,
+    protocol: u32,
+    accepted: bool,
+}
+
+impl MilterSession {
+    async fn negotiate(&mut self) -> Result<()> {
+        let mut data = Vec::with_capacity(12);
+        data.extend(MILTER_VERSION.to_be_bytes());
+        data.extend((SMFIF_ADDHDRS | SMFIF_QUARANTINE).to_be_bytes());
+        data.extend(SUPPORTED_PROTOCOL.to_be_bytes());
+        self.write_packet(b'O', &data).await?;
+
+        let (cmd, data) = self.read_packet().await?;
+        if cmd != b'O' || data.len() < 12 {
+            bail!("invalid milter option negotiation response");
+        }
+        let protocol = u32::from_be_bytes(data[8..12].try_into()?);
+        self.protocol = protocol & SUPPORTED_PROTOCOL;
+        trace!("negotiated milter protocol {:#x}", self.protocol);
+        Ok(())
+    }
+
+    async fn write_packet(&mut self, cmd: u8, data: &[u8]) -> Result<()> {
+        let len = u32::try_from(data.len() + 1)?;
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend(len.to_be_bytes());
+        packet.push(cmd);
+        packet.extend(data);
+        self.stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    async fn read_packet(&mut self) -> Result<(u8, Vec<u8>)> {
+        let len = self.stream.read_u32().await?;
+        if len == 0 || len > MAX_PACKET_SIZE {
+            bail!("invalid milter packet length {}", len);
+        }
+        let cmd = self.stream.read_u8().await?;
+        let mut data = vec![0; len as usize - 1];
+        self.stream.read_exact(&mut data).await?;
+        Ok((cmd, data))
+    }
+
+    /// Send a command and wait for the milter's decision, unless the step is disabled
+    /// (`skip_flag`) or the milter asked not to reply (`noreply_flag`).
+    async fn step(
+        &mut self,
+        cmd: u8,
+        data: &[u8],
+        skip_flag: u32,
+        noreply_flag: u32,
+    ) -> Result<MilterResult> {
+        if self.accepted || self.protocol & skip_flag != 0 {
+            return Ok(MilterResult::Continue);
+        }
+        self.write_packet(cmd, data).await?;
+        if self.protocol & noreply_flag != 0 {
+            return Ok(MilterResult::Continue);
+        }
+        tokio::time::timeout(self.timeout, self.read_response(None))
+            .await
+            .context("milter timed out")?
+    }
+
+    async fn read_response(
+        &mut self,
+        mut modifications: Option<&mut Modifications>,
+    ) -> Result<MilterResult> {
+        loop {
+            let (cmd, data) = self.read_packet().await?;
+            trace!("got milter response {}", cmd as char);
+            let result = match cmd {
+                b'c' | b's' => MilterResult::Continue,
+                b'a' => {
+                    self.accepted = true;
+                    MilterResult::Accept
+                }
+                b'd' => MilterResult::Discard,
+                b'r' => MilterResult::Reply(Reply::new(
+                    550,
+                    Some(EnhancedCode(5, 7, 1)),
+                    "Command rejected",
+                )),
+                b't' => MilterResult::Reply(Reply::new(
+                    451,
+                    Some(EnhancedCode(4, 7, 1)),
+                    "Service unavailable - try again later",
+                )),
+                b'y' => MilterResult::Reply(parse_reply(&data)?),
+                // progress, keep waiting
+                b'p' => continue,
+                b'h' | b'i' | b'q' => {
+                    let Some(modifications) = modifications.as_deref_mut() else {
+                        bail!("unexpected milter modification {}", cmd as char);
+                    };
+                    match cmd {
+                        // insert header carries an index we ignore, headers are always prepended
+                        b'i' if data.len() > 4 => {
+                            modifications.add_headers.push(parse_header(&data[4..])?)
+                        }
+                        b'h' => modifications.add_headers.push(parse_header(&data)?),
+                        b'q' => modifications.quarantine = Some(c_strings(&data).join(" ")),
+                        _ => bail!("invalid milter modification"),
+                    }
+                    continue;
+                }
+                _ => bail!("unexpected milter response {}", cmd as char),
+            };
+            return Ok(result);
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn connect(
+        &mut self,
+        hostname: &str,
+        peer: Option<SocketAddr>,
+        domain: &str,
+    ) -> Result<MilterResult> {
+        // macro `j` is the MTA's hostname, for signing milters
+        let mut macros = vec![b'C'];
+        push_c_string(&mut macros, "j");
+        push_c_string(&mut macros, domain);
+        self.write_packet(b'D', &macros).await?;
+
+        let mut data = vec![];
+        push_c_string(&mut data, hostname);
+        match peer {
+            Some(SocketAddr::V4(addr)) => {
+                data.push(b'4');
+                data.extend(addr.port().to_be_bytes());
+                push_c_string(&mut data, &addr.ip().to_string());
+            }
+            Some(SocketAddr::V6(addr)) => {
+                data.push(b'6');
+                data.extend(addr.port().to_be_bytes());
+                push_c_string(&mut data, &addr.ip().to_string());
+            }
+            None => data.push(b'U'),
+        }
+        self.step(b'C', &data, SMFIP_NOCONNECT, SMFIP_NR_CONN).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn helo(&mut self, helo: &str) -> Result<MilterResult> {
+        let mut data = vec![];
+        push_c_string(&mut data, helo);
+        self.step(b'H', &data, SMFIP_NOHELO, SMFIP_NR_HELO).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn mail(&mut self, from: &str) -> Result<MilterResult> {
+        let mut data = vec![];
+        push_c_string(&mut data, &format!("<{}>", from));
+        self.step(b'M', &data, SMFIP_NOMAIL, SMFIP_NR_MAIL).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn rcpt(&mut self, rcpt: &str) -> Result<MilterResult> {
+        let mut data = vec![];
+        push_c_string(&mut data, &format!("<{}>", rcpt));
+        self.step(b'R', &data, SMFIP_NORCPT, SMFIP_NR_RCPT).await
+    }
+
+    /// Run the DATA, header, body and end-of-message steps.
+    #[instrument(skip_all)]
+    pub async fn message(&mut self, raw: &[u8]) -> Result<(MilterResult, Modifications)> {
+        let mut modifications = Modifications::default();
+
+        let result = self.step(b'T', &[], SMFIP_NODATA, SMFIP_NR_DATA).await?;
+        if !matches!(result, MilterResult::Continue) {
+            return Ok((result, modifications));
+        }
+
+        let (headers, body) = split_message(raw);
+        for (name, value) in headers {
+            let mut data = vec![];
+            push_c_string(&mut data, &name);
+            push_c_string(&mut data, &value);
+            let result = self.step(b'L', &data, SMFIP_NOHDRS, SMFIP_NR_HDR).await?;
+            if !matches!(result, MilterResult::Continue) {
+                return Ok((result, modifications));
+            }
+        }
+
+        let result = self.step(b'N', &[], SMFIP_NOEOH, SMFIP_NR_EOH).await?;
+        if !matches!(result, MilterResult::Continue) {
+            return Ok((result, modifications));
+        }
+
+        for chunk in body.chunks(MAX_BODY_CHUNK) {
+            let result = self.step(b'B', chunk, SMFIP_NOBODY, SMFIP_NR_BODY).await?;
+            if !matches!(result, MilterResult::Continue) {
+                return Ok((result, modifications));
+            }
+        }
+
+        if self.accepted {
+            return Ok((MilterResult::Accept, modifications));
+        }
+        self.write_packet(b'E', &[]).await?;
+        let result =
+            tokio::time::timeout(self.timeout, self.read_response(Some(&mut modifications)))
+                .await
+                .context("milter timed out")??;
+        Ok((result, modifications))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn quit(mut self) {
+        if let Err(e) = self.write_packet(b'Q', &[]).await {
+            warn!("could not quit milter session: {}", e);
+        }
+    }
+}
+
+fn push_c_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(s.as_bytes());
+    buf.push(0);
+}
+
+fn c_strings(data: &[u8]) -> Vec<String> {
+    data.split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+fn parse_header(data: &[u8]) -> Result<(String, String)> {
+    let mut strings = c_strings(data).into_iter();
+    let name = strings.next().context("milter header without name")?;
+    let value = strings.next().unwrap_or_default();
+    Ok((name, value))
+}
+
+/// Parse a `y` response like `550 5.7.1 Message rejected`.
+fn parse_reply(data: &[u8]) -> Result<Reply> {
+    let text = c_strings(data).join(" ");
+    let (code, text) = text.split_once(' ').unwrap_or((&text, ""));
+    let code: u16 = code.parse().context("invalid milter reply code")?;
+    if !(400..600).contains(&code) {
+        bail!("invalid milter reply code {}", code);
+    }
+    let text = text.trim();
+    let (first, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let (ecode, text) = match parse_enhanced_code(first, code) {
+        Some(ecode) => (Some(ecode), rest.trim()),
+        None => (None, text),
+    };
+    // replies cannot span lines, nor be empty
+    let text = match text.replace(['\r', '\n'], " ") {
+        text if text.is_empty() => "message rejected by milter".to_string(),
+        text => text,
+    };
+    Ok(Reply::new(code, ecode, text))
+}
+
+/// Parse an enhanced status code whose class matches the reply code.
+fn parse_enhanced_code(ecode: &str, code: u16) -> Option<EnhancedCode> {
+    let mut parts = ecode.split('.');
+    let class: u8 = parts.next()?.parse().ok()?;
+    let subject: u16 = parts.next()?.parse().ok()?;
+    let detail: u16 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || u16::from(class) != code / 100 {
+        return None;
+    }
+    Some(EnhancedCode(class, subject, detail))
+}
+
+/// Split a raw message into unfolded headers and the body.
+fn split_message(raw: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+    let (header_block, body) = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(ix) => (&raw[..ix + 2], &raw[ix + 4..]),
+        None => (raw, &[][..]),
+    };
+
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in String::from_utf8_lossy(header_block).split("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push('\n');
+                value.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_string(), value.trim_start().to_string()));
+        }
+    }
+    (headers, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(data: &[u8]) -> String {
+        parse_reply(data).unwrap().to_string()
+    }
+
+    #[test]
+    fn replies() {
+        assert_eq!(
+            reply(b"550 5.7.1 Message rejected\0"),
+            "550 5.7.1 Message rejected\r\n"
+        );
+        assert_eq!(reply(b"451 try again later\0"), "451 try again later\r\n");
+        // the class of the enhanced code has to match
+        assert_eq!(reply(b"550 4.7.1 rejected\0"), "550 4.7.1 rejected\r\n");
+        assert_eq!(
+            reply(b"554 5.7.1\0"),
+            "554 5.7.1 message rejected by milter\r\n"
+        );
+        assert_eq!(reply(b"421\0"), "421 message rejected by milter\r\n");
+        assert_eq!(reply(b"550 5.7.1 a\r\nb\0"), "550 5.7.1 a  b\r\n");
+    }
+
+    #[test]
+    fn invalid_replies() {
+        assert!(parse_reply(b"250 2.0.0 OK\0").is_err());
+        assert!(parse_reply(b"rejected\0").is_err());
+        assert!(parse_reply(b"").is_err());
+    }
+
+    #[test]
+    fn split() {
+        let (headers, body) =
+            split_message(b"Subject: a\r\n b\r\nTo:x@example.com\r\n\r\nbody\r\n\r\nmore");
+        assert_eq!(
+            headers,
+            [
+                ("Subject".to_string(), "a\n b".to_string()),
+                ("To".to_string(), "x@example.com".to_string())
+            ]
+        );
+        assert_eq!(body, b"body\r\n\r\nmore");
+
+        let (headers, body) = split_message(b"Subject: only headers\r\n");
+        assert_eq!(headers.len(), 1);
+        assert!(body.is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
-use std::path::Path;
+use sqlx::postgres::{PgConnectOptions, PgPool};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::{spawn, sync::mpsc::Receiver};
@@ -9,36 +10,127 @@ use notify_debouncer_mini::{
     notify::{RecommendedWatcher, RecursiveMode},
     DebounceEventResult, Debouncer,
 };
-use tracing::{error, info, instrument, trace};
+use tracing::{error, info, instrument, trace, warn};
 
+use crate::metrics;
 use crate::tls;
 
+/// Wait before setting up the certificate watcher again after it stopped.
+const WATCHER_RESTART_DELAY: Duration = Duration::from_secs(10);
+
 #[instrument(skip_all)]
 pub async fn watch_certs(resolver: Arc<tls::CertificateResolver>) -> Result<()> {
-    let (mut debouncer, mut rx) = setup_watcher()?;
-
-    let binding = [&resolver.cert_path, &resolver.key_path];
+    let binding = [Some(resolver.cert_path.as_str()), resolver.key.path()];
     let mut dirs = binding
         .iter()
-        .map(|p| Path::new(p).parent().context("path has no parent"))
-        .collect::<Result<Vec<&Path>>>()?;
+        .flatten()
+        .map(|p| {
+            let dir = Path::new(p).parent().context("path has no parent")?;
+            Ok(dir.to_path_buf())
+        })
+        .collect::<Result<Vec<PathBuf>>>()?;
     dirs.dedup();
 
+    // failing to watch at startup is fatal, later it is retried
+    let mut watcher = Some(watch_dirs(&dirs)?);
+    spawn(async move {
+        loop {
+            let (debouncer, mut rx) = match watcher.take() {
+                Some(watcher) => watcher,
+                None => match watch_dirs(&dirs) {
+                    Ok(watcher) => {
+                        // changes may have been missed in the meantime
+                        reload_certs(&resolver).await;
+                        watcher
+                    }
+                    Err(e) => {
+                        error!("could not restart certificate watcher: {:?}", e);
+                        tokio::time::sleep(WATCHER_RESTART_DELAY).await;
+                        continue;
+                    }
+                },
+            };
+            metrics::TLS_CERT_WATCHER_UP.set(1);
+            // keep watching as long as the debouncer's channel is open
+            let _debouncer = debouncer;
+            while let Some(res) = rx.recv().await {
+                match res {
+                    Ok(event) => {
+                        trace!("got inotify event {:?}", event);
+                        reload_certs(&resolver).await;
+                    }
+                    Err(e) => {
+                        error!("inotify error: {:?}", e);
+                    }
+                }
+            }
+            metrics::TLS_CERT_WATCHER_UP.set(0);
+            metrics::TLS_CERT_WATCHER_RESTARTS.inc();
+            warn!(
+                "certificate watcher stopped, restarting in {:?}",
+                WATCHER_RESTART_DELAY
+            );
+            tokio::time::sleep(WATCHER_RESTART_DELAY).await;
+        }
+    });
+    Ok(())
+}
+
+fn watch_dirs(
+    dirs: &[PathBuf],
+) -> Result<(Debouncer<RecommendedWatcher>, Receiver<DebounceEventResult>)> {
+    let (mut debouncer, rx) = setup_watcher()?;
     for dir in dirs {
         debouncer
             .watcher()
             .watch(dir, RecursiveMode::NonRecursive)?;
     }
+    Ok((debouncer, rx))
+}
+
+#[instrument(skip_all, fields(cert_path = %resolver.cert_path))]
+async fn reload_certs(resolver: &tls::CertificateResolver) {
+    match resolver.refresh().await {
+        Ok(s) => {
+            info!("refreshed certificates successfully. {:?}", s);
+            metrics::TLS_CERT_RELOADS
+                .with_label_values(&["success"])
+                .inc();
+            metrics::TLS_CERT_LAST_RELOAD.set(chrono::Utc::now().timestamp());
+        }
+        Err(e) => {
+            error!("could not refresh certificates: {:?}", e);
+            metrics::TLS_CERT_RELOADS
+                .with_label_values(&["failure"])
+                .inc();
+        }
+    };
+}
+
+/// Reconnect with the URL in `path` when it changes, e.g. after a password rotation.
+/// Established connections are kept.
+#[instrument(skip(pool))]
+pub async fn watch_database_url(path: String, pool: PgPool) -> Result<()> {
+    let (mut debouncer, mut rx) = setup_watcher()?;
+    let dir = Path::new(&path).parent().context("path has no parent")?;
+    debouncer
+        .watcher()
+        .watch(dir, RecursiveMode::NonRecursive)?;
 
     spawn(async move {
+        // keep watching as long as the task runs
+        let _debouncer = debouncer;
         while let Some(res) = rx.recv().await {
             match res {
                 Ok(event) => {
                     trace!("got inotify event {:?}", event);
-                    match resolver.refresh().await {
-                        Ok(s) => info!("refreshed certificates successfully. {:?}", s),
-                        Err(e) => error!("could not refresh certificates: {:?}", e),
-                    };
+                    match read_database_url(&path) {
+                        Ok(options) => {
+                            pool.set_connect_options(options);
+                            info!("reloaded database URL");
+                        }
+                        Err(e) => error!("could not reload database URL: {:?}", e),
+                    }
                 }
                 Err(e) => {
                     error!("inotify error: {:?}", e);
@@ -49,6 +141,11 @@ pub async fn watch_certs(resolver: Arc<tls::CertificateResolver>) -> Result<()>
     Ok(())
 }
 
+fn read_database_url(path: &str) -> Result<PgConnectOptions> {
+    let url = std::fs::read_to_string(path)?;
+    Ok(url.trim().parse()?)
+}
+
 #[instrument]
 pub fn setup_watcher() -> Result<(Debouncer<RecommendedWatcher>, Receiver<DebounceEventResult>)> {
     let (tx, rx) = tokio::sync::mpsc::channel(1);
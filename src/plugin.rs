@@ -0,0 +1,57 @@
+use std::borrow::Cow;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// What a plugin gets to see of a message.
+#[derive(Debug, Serialize)]
+pub struct PluginInput<'a> {
+    pub from: &'a str,
+    pub rcpts: &'a [String],
+    pub size: usize,
+    pub message_id: Option<&'a str>,
+    pub subject: Option<&'a str>,
+    pub headers: Vec<(&'a str, &'a str)>,
+    pub body_text: Option<Cow<'a, str>>,
+    pub attachments: Vec<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginAction {
+    #[default]
+    Accept,
+    Reject,
+    Quarantine,
+}
+
+/// An additional object to store next to the message.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PluginObject {
+    /// relative to the message's S3 prefix
+    pub name: String,
+    pub content: Option<String>,
+    pub content_base64: Option<String>,
+}
+
+/// A plugin's answer, every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PluginOutput {
+    pub action: PluginAction,
+    pub message: Option<String>,
+    pub bucket: Option<String>,
+    pub prefix: Option<String>,
+    pub tags: Vec<String>,
+    pub objects: Vec<PluginObject>,
+}
+
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    async fn process(&self, input: &PluginInput<'_>) -> Result<PluginOutput>;
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use tracing::{instrument, trace};
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasmtime::{Engine, Linker, Module, Store};
+use wasmtime_wasi::sync::WasiCtxBuilder;
+use wasmtime_wasi::{I32Exit, WasiCtx};
+
+use super::{Plugin, PluginInput, PluginOutput};
+
+/// A WASI command module getting the `PluginInput` as JSON on stdin and answering with
+/// a `PluginOutput` as JSON on stdout. It has no access to the file system or network.
+pub struct WasmPlugin {
+    engine: Engine,
+    module: Module,
+    linker: Arc<Linker<WasiCtx>>,
+    /// limits the instructions a single run may execute
+    fuel: u64,
+}
+
+impl WasmPlugin {
+    #[instrument]
+    pub fn new(path: &str, fuel: u64) -> Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::from_file(&engine, path).context("could not load WASM plugin")?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |ctx| ctx)?;
+
+        Ok(Self {
+            engine,
+            module,
+            linker: Arc::new(linker),
+            fuel,
+        })
+    }
+
+    fn run(&self, input: Vec<u8>) -> Result<Vec<u8>> {
+        let stdout = WritePipe::new_in_memory();
+        let wasi = WasiCtxBuilder::new()
+            .stdin(Box::new(ReadPipe::from(input)))
+            .stdout(Box::new(stdout.clone()))
+            .inherit_stderr()
+            .build();
+        let mut store = Store::new(&self.engine, wasi);
+        store.add_fuel(self.fuel)?;
+
+        self.linker.module(&mut store, "", &self.module)?;
+        let start = self
+            .linker
+            .get_default(&mut store, "")?
+            .typed::<(), ()>(&store)?;
+        if let Err(e) = start.call(&mut store, ()) {
+            match e.downcast_ref::<I32Exit>() {
+                Some(I32Exit(0)) => {}
+                Some(I32Exit(status)) => bail!("WASM plugin exited with status {}", status),
+                None => return Err(e.context("WASM plugin failed")),
+            }
+        }
+        drop(store);
+
+        Ok(stdout
+            .try_into_inner()
+            .map_err(|_| anyhow!("WASM plugin output still in use"))?
+            .into_inner())
+    }
+}
+
+#[async_trait]
+impl Plugin for Arc<WasmPlugin> {
+    #[instrument(skip_all)]
+    async fn process(&self, input: &PluginInput<'_>) -> Result<PluginOutput> {
+        let input = serde_json::to_vec(input)?;
+        let plugin = self.clone();
+        let output = tokio::task::spawn_blocking(move || plugin.run(input)).await??;
+        trace!("WASM plugin returned {} bytes", output.len());
+
+        if output.iter().all(u8::is_ascii_whitespace) {
+            return Ok(PluginOutput::default());
+        }
+        serde_json::from_slice(&output).context("could not parse WASM plugin output")
+    }
+}
@@ -0,0 +1,305 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, instrument, trace, warn};
+
+use crate::db::{self, StoredObject};
+use crate::encryption;
+use crate::export;
+use crate::metrics;
+use crate::s3;
+use crate::smtp::{Config, SmtpBackend};
+
+/// Commands are at most 255 bytes (RFC 2449), with some slack.
+const MAX_LINE_LENGTH: u64 = 512;
+const MAX_LOGIN_FAILURES: usize = 3;
+
+/// Read-only POP3 access (RFC 1939) to the messages stored for a recipient, for tools
+/// that cannot use the HTTP API.
+#[derive(Debug)]
+pub struct Pop3 {
+    pub bind_addr: String,
+    /// implicit TLS like on port 995, `None` for plain text
+    pub tls_config: Option<Arc<ServerConfig>>,
+    /// the newest messages in a maildrop
+    pub max_messages: i64,
+}
+
+#[instrument(skip_all, fields(bind_addr = pop3.bind_addr))]
+pub async fn start_pop3_server(pop3: Pop3, backend: SmtpBackend) -> Result<()> {
+    info!("listening for POP3 on {}", pop3.bind_addr);
+    let listener = TcpListener::bind(&pop3.bind_addr).await?;
+    let acceptor = pop3.tls_config.map(TlsAcceptor::from);
+
+    while let Ok((stream, addr)) = listener.accept().await {
+        let config = backend.config.load_full();
+        let acceptor = acceptor.clone();
+        let max_messages = pop3.max_messages;
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_pop3_connection(stream, config, max_messages).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle_pop3_connection(stream, config, max_messages).await,
+            };
+            if let Err(e) = result {
+                warn!("could not handle POP3 connection from {}: {}", addr, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Read a line of at most `limit` bytes, returns the number of bytes read.
+async fn read_line<R>(reader: &mut R, line: &mut Vec<u8>, limit: u64) -> Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    Ok((&mut *reader).take(limit).read_until(b'\n', line).await?)
+}
+
+enum State {
+    Authorization {
+        user: Option<String>,
+        failures: usize,
+    },
+    Transaction(Vec<StoredObject>),
+}
+
+#[instrument(skip_all)]
+async fn handle_pop3_connection<S>(stream: S, config: Arc<Config>, max_messages: i64) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    writer.write_all(b"+OK POP3 archive ready\r\n").await?;
+
+    let mut state = State::Authorization {
+        user: None,
+        failures: 0,
+    };
+    let mut line = Vec::new();
+    loop {
+        let mut next = None;
+        if read_line(&mut reader, &mut line, MAX_LINE_LENGTH).await? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end();
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let command = command.to_ascii_uppercase();
+        // passwords are not logged
+        trace!("POP3 command {}", command);
+
+        let reply: Vec<u8> = match (&mut state, command.as_str()) {
+            (_, "QUIT") => {
+                writer.write_all(b"+OK bye\r\n").await?;
+                break;
+            }
+            (_, "CAPA") => "+OK\r\nUSER\r\nUIDL\r\nTOP\r\n.\r\n".into(),
+            (_, "NOOP") => "+OK\r\n".into(),
+            (State::Authorization { user, .. }, "USER") if !args.is_empty() => {
+                *user = Some(args.to_string());
+                "+OK\r\n".into()
+            }
+            (State::Authorization { user, failures }, "PASS") => {
+                let Some(username) = user.take() else {
+                    writer.write_all(b"-ERR USER first\r\n").await?;
+                    continue;
+                };
+                match login(&config, &username, args, max_messages).await {
+                    Ok(Some(maildrop)) => {
+                        metrics::POP3_LOGINS.with_label_values(&["success"]).inc();
+                        info!("POP3 login of {}", username);
+                        let reply = format!("+OK {} messages\r\n", maildrop.len());
+                        next = Some(State::Transaction(maildrop));
+                        reply.into()
+                    }
+                    Ok(None) => {
+                        metrics::POP3_LOGINS.with_label_values(&["failure"]).inc();
+                        warn!("POP3 login of {} failed", username);
+                        *failures += 1;
+                        if *failures >= MAX_LOGIN_FAILURES {
+                            writer.write_all(b"-ERR too many failures\r\n").await?;
+                            break;
+                        }
+                        "-ERR invalid credentials\r\n".into()
+                    }
+                    Err(e) => {
+                        metrics::POP3_LOGINS.with_label_values(&["error"]).inc();
+                        error!("could not check POP3 login: {:?}", e);
+                        "-ERR [SYS/TEMP] could not check credentials\r\n".into()
+                    }
+                }
+            }
+            (State::Transaction(maildrop), "STAT") => {
+                let total: i64 = maildrop.iter().map(size).sum();
+                format!("+OK {} {}\r\n", maildrop.len(), total).into()
+            }
+            (State::Transaction(maildrop), "LIST") => {
+                scan_listing(maildrop, args, |m| size(m).to_string()).into()
+            }
+            (State::Transaction(maildrop), "UIDL") => {
+                scan_listing(maildrop, args, unique_id).into()
+            }
+            (State::Transaction(maildrop), "RETR") => match message(maildrop, args) {
+                Some(m) => retrieve(&config, m, None).await,
+                None => "-ERR no such message\r\n".into(),
+            },
+            (State::Transaction(maildrop), "TOP") => {
+                let (number, lines) = args.split_once(' ').unwrap_or((args, ""));
+                match (message(maildrop, number), lines.parse()) {
+                    (Some(m), Ok(lines)) => retrieve(&config, m, Some(lines)).await,
+                    _ => "-ERR no such message\r\n".into(),
+                }
+            }
+            (State::Transaction(_), "DELE") => "-ERR the archive is read-only\r\n".into(),
+            (State::Transaction(_), "RSET") => "+OK\r\n".into(),
+            _ => "-ERR unknown command\r\n".into(),
+        };
+        writer.write_all(&reply).await?;
+        if let Some(next) = next {
+            state = next;
+        }
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// The maildrop of `username` for valid credentials.
+async fn login(
+    config: &Config,
+    username: &str,
+    password: &str,
+    max_messages: i64,
+) -> Result<Option<Vec<StoredObject>>> {
+    if !db::check_login(&config.pg_pool, username, password).await? {
+        return Ok(None);
+    }
+    let maildrop = db::list_objects(&config.pg_pool, username, None, max_messages).await?;
+    Ok(Some(maildrop))
+}
+
+fn size(message: &StoredObject) -> i64 {
+    message.size.unwrap_or(0)
+}
+
+/// The message with the 1-based `number`.
+fn message<'a>(maildrop: &'a [StoredObject], number: &str) -> Option<&'a StoredObject> {
+    let number: usize = number.trim().parse().ok()?;
+    maildrop.get(number.checked_sub(1)?)
+}
+
+/// The reply to `LIST` or `UIDL`, for a single message if given.
+fn scan_listing(
+    maildrop: &[StoredObject],
+    args: &str,
+    value: impl Fn(&StoredObject) -> String,
+) -> String {
+    if !args.is_empty() {
+        return match message(maildrop, args) {
+            Some(m) => format!("+OK {} {}\r\n", args.trim(), value(m)),
+            None => "-ERR no such message\r\n".to_string(),
+        };
+    }
+    let mut reply = format!("+OK {} messages\r\n", maildrop.len());
+    for (ix, m) in maildrop.iter().enumerate() {
+        reply.push_str(&format!("{} {}\r\n", ix + 1, value(m)));
+    }
+    reply.push_str(".\r\n");
+    reply
+}
+
+/// Stable across sessions, as FNV-1a hash of the S3 prefix.
+fn unique_id(message: &StoredObject) -> String {
+    let prefix = message.s3_prefix.as_deref().unwrap_or(&message.message_id);
+    let hash = prefix.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// The reply to `RETR`, or to `TOP` with the number of body `lines`.
+async fn retrieve(config: &Config, message: &StoredObject, lines: Option<usize>) -> Vec<u8> {
+    let Some(prefix) = message.s3_prefix.as_deref() else {
+        return b"-ERR message not stored\r\n".to_vec();
+    };
+    let method = match export::object_encryption(config, message).await {
+        Ok(method) => method,
+        Err(e) => {
+            error!(
+                "could not fetch manifest of {}: {:?}",
+                message.message_id, e
+            );
+            return b"-ERR [SYS/TEMP] could not fetch message\r\n".to_vec();
+        }
+    };
+    let bucket = message.bucket.as_deref().unwrap_or(&config.bucket);
+    let raw = match s3::get_raw_message(&config.s3_config, bucket, prefix).await {
+        Ok(Some(raw)) => raw,
+        Ok(None) => return b"-ERR message not stored in raw form\r\n".to_vec(),
+        Err(e) => {
+            error!("could not fetch message {}: {:?}", message.message_id, e);
+            return b"-ERR [SYS/TEMP] could not fetch message\r\n".to_vec();
+        }
+    };
+    // fails without a matching key, e.g. DECRYPT_AGE_IDENTITY
+    let raw = match encryption::decrypt(config.decryption.as_deref(), method.as_deref(), raw) {
+        Ok(raw) => raw,
+        Err(e) => {
+            error!("could not decrypt message {}: {:?}", message.message_id, e);
+            return b"-ERR could not decrypt message\r\n".to_vec();
+        }
+    };
+    let raw = match lines {
+        Some(lines) => top(&raw, lines),
+        None => &raw,
+    };
+    let mut reply = format!("+OK {} octets\r\n", raw.len()).into_bytes();
+    reply.extend(multiline(raw));
+    reply
+}
+
+/// The header and the first `lines` lines of the body.
+fn top(raw: &[u8], lines: usize) -> &[u8] {
+    let mut end = 0;
+    let mut in_body = false;
+    let mut body_lines = 0;
+    for line in raw.split_inclusive(|b| *b == b'\n') {
+        if in_body {
+            if body_lines == lines {
+                break;
+            }
+            body_lines += 1;
+        } else if line == b"\r\n" || line == b"\n" {
+            in_body = true;
+        }
+        end += line.len();
+    }
+    &raw[..end]
+}
+
+/// Byte-stuff lines starting with `.`, end them with CRLF and terminate the multi-line
+/// response, RFC 1939 section 3.
+fn multiline(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5);
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            out.push(b'.');
+        }
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b".\r\n");
+    out
+}
@@ -0,0 +1,53 @@
+use std::net::SocketAddr;
+
+use tracing::{info, warn};
+
+use crate::metrics;
+
+/// Requests of clients mistaking the SMTP port for a web server.
+const HTTP_METHODS: &[&str] = &["GET", "POST", "HEAD", "PUT", "OPTIONS", "CONNECT"];
+
+/// Unknown and forbidden commands of one connection, e.g. `VRFY` floods, `AUTH` attempts or
+/// HTTP requests.
+#[derive(Debug)]
+pub struct Probes {
+    peer: SocketAddr,
+    max: usize,
+    count: usize,
+}
+
+impl Probes {
+    /// Allow `max` unknown commands before disconnecting, `0` allows any number.
+    pub fn new(peer: SocketAddr, max: usize) -> Self {
+        Self {
+            peer,
+            max,
+            count: 0,
+        }
+    }
+
+    /// Whether the client sent more unknown commands than allowed.
+    pub fn exceeded(&self) -> bool {
+        self.max > 0 && self.count > self.max
+    }
+
+    /// Count a command the server does not handle.
+    pub fn probe(&mut self, verb: &str) {
+        let kind = match verb {
+            "VRFY" | "EXPN" | "AUTH" => verb.to_ascii_lowercase(),
+            _ if HTTP_METHODS.contains(&verb) => "http".to_string(),
+            _ => "unknown".to_string(),
+        };
+        // arguments are not logged, they may contain credentials
+        info!("{} sent {} command {:?}", self.peer, kind, verb);
+        metrics::PROBE_COMMANDS.with_label_values(&[&kind]).inc();
+        self.count += 1;
+        if self.exceeded() {
+            warn!(
+                "disconnecting {} after {} unknown commands",
+                self.peer, self.count
+            );
+            metrics::PROBE_DISCONNECTS.inc();
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::Notify;
+use tracing::{error, info, instrument, trace, warn};
+
+use crate::db;
+use crate::dsn::{self, EnvelopeDsn, Failure, RcptDsn};
+use crate::metrics;
+use crate::rdns::Rdns;
+use crate::smtp::{Delivery, SmtpBackend};
+use crate::tls::TlsInfo;
+
+/// Idle workers look for due retries this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// A message is retried if its worker did not finish in time, e.g. as it died.
+const LEASE: Duration = Duration::from_secs(600);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Wakes an idle worker after queueing a message.
+static ENQUEUED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Messages are queued in the DB after DATA and stored by a pool of workers, so slow S3 or
+/// DB writes do not delay the SMTP replies.
+#[derive(Debug)]
+pub struct Queue {
+    /// workers started by this instance, queued messages are stored by any instance
+    pub workers: usize,
+    /// messages failing this often are kept as dead
+    pub max_attempts: i32,
+    /// before the first retry, doubled for every further one
+    pub retry_delay: Duration,
+}
+
+/// What storing a queued message needs of the SMTP session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuedEnvelope {
+    pub from: String,
+    /// not stored yet
+    pub rcpts: Vec<String>,
+    pub dsn: EnvelopeDsn,
+    pub rcpt_dsn: HashMap<String, RcptDsn>,
+    pub declared_size: Option<u64>,
+    pub aliases: HashMap<String, String>,
+    pub tls: Option<TlsInfo>,
+    pub rdns: Option<Rdns>,
+    pub helo: Option<String>,
+    pub submitter: Option<String>,
+    /// set by the milter
+    pub quarantine: bool,
+    /// rows tracking the status by recipient
+    #[serde(default)]
+    pub deliveries: HashMap<String, i64>,
+}
+
+impl Queue {
+    #[instrument(skip_all)]
+    pub async fn enqueue(
+        &self,
+        pool: &PgPool,
+        envelope: &QueuedEnvelope,
+        data: &[u8],
+    ) -> Result<()> {
+        let id = db::enqueue(pool, &serde_json::to_value(envelope)?, data).await?;
+        trace!(id, "queued message");
+        ENQUEUED.notify_one();
+        Ok(())
+    }
+
+    /// Wait before the next attempt after `attempts` failed ones.
+    fn retry_delay(&self, attempts: i32) -> Duration {
+        let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+        self.retry_delay
+            .saturating_mul(2u32.pow(exponent))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Store queued messages with `queue.workers` workers, its settings are not reloaded.
+pub async fn run_workers(backend: SmtpBackend, queue: Arc<Queue>) -> Result<()> {
+    info!("starting {} queue workers", queue.workers);
+    let handles: Vec<_> = (0..queue.workers)
+        .map(|_| tokio::spawn(work(backend.clone(), queue.clone())))
+        .collect();
+    futures::future::join_all(handles).await;
+    Ok(())
+}
+
+async fn work(backend: SmtpBackend, queue: Arc<Queue>) {
+    loop {
+        let config = backend.config.load_full();
+        match db::claim_queued(&config.pg_pool, LEASE.as_secs_f64()).await {
+            Ok(Some(row)) => process(&backend, &queue, row).await,
+            Ok(None) => {
+                tokio::select! {
+                    _ = ENQUEUED.notified() => {},
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {},
+                }
+            }
+            Err(e) => {
+                error!("could not fetch queued message: {:?}", e);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Store the message for its remaining recipients, retrying the ones that failed. The sender
+/// gets a bounce for the recipients of dead messages if bounces are enabled.
+#[instrument(skip_all, fields(id = row.id, attempts = row.attempts))]
+async fn process(backend: &SmtpBackend, queue: &Queue, row: db::QueuedRow) {
+    let pool = backend.config.load().pg_pool.clone();
+    let mut envelope: QueuedEnvelope = match serde_json::from_value(row.envelope.clone()) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            error!("could not parse queued envelope: {:?}", e);
+            let error = format!("could not parse envelope: {}", e);
+            if let Err(e) = db::requeue(&pool, row.id, &row.envelope, &error, 0.0, true).await {
+                error!("could not update queued message: {:?}", e);
+            }
+            return;
+        }
+    };
+
+    let mut data = row.data;
+    let result = match backend.new_session(None) {
+        Ok(mut session) => {
+            let result = session
+                .process_queued(&envelope, data, row.attempts > 1)
+                .await;
+            data = std::mem::take(&mut session.data);
+            result
+        }
+        Err(e) => Err(e),
+    };
+    let every_rcpt = |error: &str| {
+        envelope
+            .rcpts
+            .iter()
+            .map(|rcpt| (rcpt.clone(), anyhow!("{}", error)))
+            .collect()
+    };
+    let (error, refused, failed): (_, _, Vec<_>) = match result {
+        Ok(Delivery::Delivered(results)) => {
+            let failed: Vec<_> = results
+                .into_iter()
+                .filter_map(|(rcpt, result)| result.err().map(|e| (rcpt, e)))
+                .collect();
+            let Some((_, e)) = failed.first() else {
+                metrics::QUEUE_ATTEMPTS.with_label_values(&["stored"]).inc();
+                if let Err(e) = db::dequeue(&pool, row.id).await {
+                    error!("could not remove stored message from queue: {:?}", e);
+                }
+                return;
+            };
+            (format!("{:#}", e), false, failed)
+        }
+        // refused after accepting it, e.g. by the content filter, retrying will not help
+        Ok(Delivery::Refused(reply)) => {
+            let error = reply.to_string().trim_end().to_string();
+            let failed = every_rcpt(&error);
+            (error, true, failed)
+        }
+        Err(e) => {
+            let error = format!("{:#}", e);
+            let failed = every_rcpt(&error);
+            (error, false, failed)
+        }
+    };
+    envelope.rcpts = failed.iter().map(|(rcpt, _)| rcpt.clone()).collect();
+
+    let dead = refused || row.attempts >= queue.max_attempts;
+    let delay = queue.retry_delay(row.attempts);
+    if dead {
+        metrics::QUEUE_ATTEMPTS.with_label_values(&["dead"]).inc();
+        error!("giving up on queued message: {}", error);
+        let config = backend.config.load_full();
+        if config.bounces {
+            let failed = Failure::notified(&envelope.rcpt_dsn, failed);
+            let from = &envelope.from;
+            if let Err(e) = dsn::send_bounce(&config, from, &envelope.dsn, &failed, &data).await {
+                error!("could not send bounce to {}: {:?}", from, e);
+            }
+        }
+    } else {
+        metrics::QUEUE_ATTEMPTS.with_label_values(&["retry"]).inc();
+        warn!(
+            "could not store queued message, retrying in {:?}: {}",
+            delay, error
+        );
+    }
+    let envelope = match serde_json::to_value(&envelope) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            error!("could not serialize queued envelope: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = db::requeue(&pool, row.id, &envelope, &error, delay.as_secs_f64(), dead).await {
+        error!("could not update queued message: {:?}", e);
+    }
+}
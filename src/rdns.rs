@@ -0,0 +1,117 @@
+use std::net::IpAddr;
+
+use anyhow::{bail, Result};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, trace};
+
+use crate::metrics;
+
+/// PTR names tried when forward-confirming.
+const MAX_NAMES: usize = 10;
+
+/// The client's reverse DNS name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rdns {
+    pub addr: IpAddr,
+    /// `None` without a PTR record
+    pub name: Option<String>,
+    /// the name resolves back to the address
+    pub confirmed: bool,
+}
+
+/// Which clients to accept mail from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// only record the name
+    Any,
+    /// clients need a PTR record
+    Ptr,
+    /// clients need a forward-confirmed PTR record
+    Confirmed,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "" | "none" => Ok(Policy::Any),
+            "ptr" => Ok(Policy::Ptr),
+            "confirmed" => Ok(Policy::Confirmed),
+            _ => bail!("unknown reverse DNS policy {:?}", s),
+        }
+    }
+
+    pub fn allows(&self, rdns: &Rdns) -> bool {
+        match self {
+            Policy::Any => true,
+            Policy::Ptr => rdns.name.is_some(),
+            Policy::Confirmed => rdns.confirmed,
+        }
+    }
+}
+
+/// Looks up and forward-confirms the PTR records of clients.
+pub struct ReverseDns {
+    resolver: TokioAsyncResolver,
+    pub policy: Policy,
+}
+
+impl ReverseDns {
+    /// Use the system's resolver configuration, i.e. `/etc/resolv.conf`.
+    pub fn from_system_conf(policy: Policy) -> Result<Self> {
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+            policy,
+        })
+    }
+
+    /// The first PTR name of `addr` resolving back to it, or the first one if none does.
+    #[instrument(skip(self))]
+    pub async fn lookup(&self, addr: IpAddr) -> Result<Rdns> {
+        let result = self.lookup_inner(addr).await;
+        let label = match &result {
+            Ok(Rdns {
+                confirmed: true, ..
+            }) => "confirmed",
+            Ok(Rdns { name: Some(_), .. }) => "unconfirmed",
+            Ok(Rdns { name: None, .. }) => "none",
+            Err(_) => "error",
+        };
+        metrics::RDNS_LOOKUPS.with_label_values(&[label]).inc();
+        result
+    }
+
+    async fn lookup_inner(&self, addr: IpAddr) -> Result<Rdns> {
+        let names: Vec<_> = match self.resolver.reverse_lookup(addr).await {
+            Ok(lookup) => lookup
+                .iter()
+                .take(MAX_NAMES)
+                .map(|name| name.to_string().trim_end_matches('.').to_string())
+                .collect(),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        for name in &names {
+            let confirmed = match self.resolver.lookup_ip(format!("{}.", name)).await {
+                Ok(ips) => ips.iter().any(|ip| ip == addr),
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => false,
+                Err(e) => return Err(e.into()),
+            };
+            if confirmed {
+                trace!("{} is forward-confirmed {}", addr, name);
+                return Ok(Rdns {
+                    addr,
+                    name: Some(name.clone()),
+                    confirmed,
+                });
+            }
+        }
+        trace!("{} has unconfirmed reverse DNS {:?}", addr, names);
+        Ok(Rdns {
+            addr,
+            name: names.into_iter().next(),
+            confirmed: false,
+        })
+    }
+}
@@ -0,0 +1,119 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer};
+use tracing::instrument;
+
+/// Redaction rules for personal data in bodies and headers, read from a YAML file like
+///
+/// ```yaml
+/// redactions:
+///   - name: credit-card
+///     pattern: '\b(?:\d[ -]?){12,18}\d\b'
+///     luhn: true
+///   - name: iban
+///     pattern: '\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,3})?\b'
+///     replacement: "[IBAN]"
+/// ```
+///
+/// Patterns use the `regex` crate's syntax, matches are replaced with `replacement`
+/// (default `[REDACTED]`). With `luhn` set, only matches with a valid Luhn checksum are
+/// replaced, to not redact every long number.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Redactions {
+    redactions: Vec<Redaction>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Redaction {
+    name: String,
+    #[serde(deserialize_with = "regex")]
+    pattern: Regex,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+    #[serde(default)]
+    luhn: bool,
+}
+
+/// Number of replacements by redaction name.
+pub type Counts = BTreeMap<String, u64>;
+
+fn default_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// Whether the digits of `number` have a valid Luhn checksum, as credit card numbers do.
+fn luhn(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    if digits.len() < 12 {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(ix, d)| match (ix % 2, d * 2) {
+            (0, _) => *d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+impl Redactions {
+    #[instrument]
+    pub fn load(path: &str) -> Result<Self> {
+        let redactions = fs::read_to_string(path).context("could not read redactions")?;
+        serde_yaml::from_str(&redactions).context("could not parse redactions")
+    }
+
+    /// Apply all redactions to `text`, adding the replacements to `counts`.
+    pub fn apply<'a>(&self, text: &'a str, counts: &mut Counts) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for redaction in &self.redactions {
+            let mut count = 0;
+            let replaced = redaction.pattern.replace_all(&text, |captures: &Captures| {
+                let matched = &captures[0];
+                if redaction.luhn && !luhn(matched) {
+                    return matched.to_string();
+                }
+                count += 1;
+                redaction.replacement.clone()
+            });
+            if count > 0 {
+                let replaced = replaced.into_owned();
+                text = Cow::Owned(replaced);
+                *counts.entry(redaction.name.clone()).or_default() += count;
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn luhn_checksum() {
+        assert!(luhn("4111 1111 1111 1111"));
+        assert!(luhn("5555-5555-5555-4444"));
+        assert!(luhn("378282246310005"));
+        assert!(!luhn("4111 1111 1111 1112"));
+        assert!(!luhn("378282246310006"));
+        // valid, but too short for a card number
+        assert!(!luhn("79927398713"));
+        assert!(!luhn(""));
+    }
+}
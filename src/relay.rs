@@ -0,0 +1,62 @@
+use anyhow::{bail, Result};
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::AsyncSmtpTransport;
+use lettre::{Address, AsyncTransport, Tokio1Executor};
+use tracing::{instrument, trace};
+
+/// Smart host every accepted message is copied to.
+pub struct Relay {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// refuse the message when relaying fails
+    pub required: bool,
+}
+
+impl Relay {
+    /// `tls` is one of `starttls` (default), `tls` (implicit TLS) or `none`.
+    #[instrument(skip(credentials))]
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        tls: &str,
+        credentials: Option<(String, String)>,
+        required: bool,
+    ) -> Result<Self> {
+        let mut builder = match tls {
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            _ => bail!("invalid relay TLS mode {}", tls),
+        };
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            required,
+        })
+    }
+
+    /// Send the raw message, an empty `from` is the null reverse-path.
+    #[instrument(skip(self, raw))]
+    pub async fn send(&self, from: &str, rcpts: &[String], raw: &[u8]) -> Result<()> {
+        let from: Option<Address> = if from.is_empty() {
+            None
+        } else {
+            Some(from.parse()?)
+        };
+        let rcpts = rcpts
+            .iter()
+            .map(|r| r.parse())
+            .collect::<Result<Vec<Address>, _>>()?;
+        let envelope = Envelope::new(from, rcpts)?;
+
+        let response = self.transport.send_raw(&envelope, raw).await?;
+        trace!("relayed message: {:?}", response.code());
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use smtpbis::{EnhancedCode, Reply};
+
+/// Operator configurable reply texts.
+///
+/// `{name}` placeholders are replaced when rendering, unknown placeholders are kept as is.
+pub struct ReplyTexts {
+    /// greeting banner without the code, smtpbis' default when unset; `{domain}`
+    pub banner: Option<String>,
+    /// first line of the EHLO response; `{domain}`, `{helo}`
+    pub ehlo: String,
+    /// reply after DATA; `{domain}`, `{bytes}`, `{lines}`
+    pub accepted: String,
+    /// permanent rejection of a sender or recipient
+    pub rejected: String,
+    /// temporary failures
+    pub temp_failure: String,
+}
+
+impl Default for ReplyTexts {
+    fn default() -> Self {
+        Self {
+            banner: None,
+            ehlo: "hello {helo}".to_string(),
+            accepted: "Received {bytes} bytes in {lines} lines.".to_string(),
+            rejected: "mailbox unavailable".to_string(),
+            temp_failure: "could not handle request".to_string(),
+        }
+    }
+}
+
+/// Replace `{name}` placeholders in `template`.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+impl ReplyTexts {
+    pub fn rejected(&self, ecode: EnhancedCode) -> Reply {
+        Reply::new(550, Some(ecode), self.rejected.clone())
+    }
+
+    pub fn temp_failure(&self) -> Reply {
+        Reply::new(451, Some(EnhancedCode(4, 3, 0)), self.temp_failure.clone())
+    }
+}
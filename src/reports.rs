@@ -0,0 +1,205 @@
+use std::io::{Cursor, Read};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use mail_parser::{Message, MessagePart, MimeHeaders};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{instrument, trace};
+
+/// Limit of a decompressed report, to not be fooled by compression bombs.
+const MAX_REPORT_SIZE: u64 = 50 * 1024 * 1024;
+
+/// A DMARC aggregate report (RFC 7489 appendix C).
+#[derive(Debug, Deserialize)]
+pub struct Feedback {
+    pub report_metadata: ReportMetadata,
+    pub policy_published: PolicyPublished,
+    #[serde(default, rename = "record")]
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportMetadata {
+    pub org_name: String,
+    pub email: Option<String>,
+    pub report_id: String,
+    pub date_range: DateRange,
+}
+
+/// Seconds since the epoch.
+#[derive(Debug, Deserialize)]
+pub struct DateRange {
+    pub begin: i64,
+    pub end: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyPublished {
+    pub domain: String,
+    pub p: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    pub row: Row,
+    pub identifiers: Identifiers,
+    #[serde(default)]
+    pub auth_results: AuthResults,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Row {
+    pub source_ip: String,
+    pub count: i64,
+    pub policy_evaluated: PolicyEvaluated,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyEvaluated {
+    pub disposition: String,
+    pub dkim: Option<String>,
+    pub spf: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Identifiers {
+    pub header_from: String,
+    pub envelope_from: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AuthResults {
+    #[serde(default)]
+    pub dkim: Vec<AuthResult>,
+    #[serde(default)]
+    pub spf: Vec<AuthResult>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthResult {
+    pub domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+    pub result: String,
+}
+
+/// An SMTP TLS report (RFC 8460 section 4).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsReport {
+    pub organization_name: String,
+    pub date_range: TlsDateRange,
+    pub contact_info: Option<String>,
+    pub report_id: String,
+    #[serde(default)]
+    pub policies: Vec<TlsPolicyResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsDateRange {
+    pub start_datetime: DateTime<Utc>,
+    pub end_datetime: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsPolicyResult {
+    /// `policy-type`, `policy-domain`, `policy-string` and `mx-host`
+    pub policy: Value,
+    pub summary: TlsSummary,
+    #[serde(default)]
+    pub failure_details: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsSummary {
+    pub total_successful_session_count: i64,
+    pub total_failure_session_count: i64,
+}
+
+/// The DMARC aggregate reports attached to the message as XML, gzipped or zipped XML.
+#[instrument(skip_all)]
+pub fn dmarc_reports(message: &Message) -> Result<Vec<Feedback>> {
+    documents(message, ".xml")?
+        .iter()
+        .map(|document| {
+            let document = std::str::from_utf8(document)?;
+            quick_xml::de::from_str(document).context("could not parse DMARC report")
+        })
+        .collect()
+}
+
+/// The SMTP TLS reports attached to the message as JSON or gzipped JSON.
+#[instrument(skip_all)]
+pub fn tls_reports(message: &Message) -> Result<Vec<TlsReport>> {
+    documents(message, ".json")?
+        .iter()
+        .map(|document| serde_json::from_slice(document).context("could not parse TLS report"))
+        .collect()
+}
+
+/// The decompressed attachments whose name, without `.gz` or within a zip file, ends with
+/// `extension`.
+fn documents(message: &Message, extension: &str) -> Result<Vec<Vec<u8>>> {
+    let mut documents = vec![];
+    for part in message.attachments() {
+        let name = attachment_name(part);
+        let body = part.contents();
+        if name.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(Cursor::new(body))?;
+            for ix in 0..archive.len() {
+                let file = archive.by_index(ix)?;
+                if file.name().to_lowercase().ends_with(extension) {
+                    documents.push(read_limited(file)?);
+                }
+            }
+        } else if name
+            .strip_suffix(".gz")
+            .is_some_and(|n| n.ends_with(extension))
+        {
+            documents.push(read_limited(GzDecoder::new(body))?);
+        } else if name.ends_with(extension) {
+            documents.push(body.to_vec());
+        } else {
+            trace!("skipping attachment {}", name);
+        }
+    }
+    Ok(documents)
+}
+
+/// The lower case attachment name, with an extension guessed from the content type when
+/// there is none.
+fn attachment_name(part: &MessagePart) -> String {
+    if let Some(name) = part.attachment_name() {
+        return name.to_lowercase();
+    }
+    let content_type = part
+        .content_type()
+        .map(|ct| format!("{}/{}", ct.ctype(), ct.subtype().unwrap_or("")))
+        .unwrap_or_default()
+        .to_lowercase();
+    match content_type.as_str() {
+        "application/zip" => ".zip",
+        "application/gzip" | "application/x-gzip" => ".xml.gz",
+        "application/xml" | "text/xml" => ".xml",
+        "application/tlsrpt+gzip" => ".json.gz",
+        "application/tlsrpt+json" | "application/json" => ".json",
+        _ => "",
+    }
+    .to_string()
+}
+
+fn read_limited(reader: impl Read) -> Result<Vec<u8>> {
+    let mut document = vec![];
+    reader
+        .take(MAX_REPORT_SIZE + 1)
+        .read_to_end(&mut document)?;
+    if document.len() as u64 > MAX_REPORT_SIZE {
+        anyhow::bail!("report exceeds {} bytes", MAX_REPORT_SIZE);
+    }
+    Ok(document)
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{Context, Result};
+use mail_parser::Message;
+use serde::Deserialize;
+use tracing::{instrument, trace};
+
+use crate::plugin::PluginObject;
+use crate::sender::Sender;
+use crate::trace::Origin;
+
+/// Filtering and routing rules, read from a YAML file like
+///
+/// ```yaml
+/// rules:
+///   - name: invoices
+///     match:
+///       rcpt: "invoices@*"
+///       headers:
+///         Subject: "*invoice*"
+///     actions:
+///       - set-prefix: "invoices/"
+///       - tag: invoice
+///       - drop-attachment: "*.exe"
+///     stop: true
+/// ```
+///
+/// All conditions of a rule have to match, patterns are case-insensitive globs with `*`
+/// and `?`. The actions of every matching rule are applied in order, until a matching rule
+/// has `stop` set.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    name: Option<String>,
+    #[serde(default, rename = "match")]
+    conditions: Conditions,
+    /// `- tag: name` instead of YAML tags like `- !tag name`
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    actions: Vec<Action>,
+    #[serde(default)]
+    stop: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Conditions {
+    from: Option<String>,
+    rcpt: Option<String>,
+    /// header name to pattern on the raw value
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    size_over: Option<usize>,
+    size_under: Option<usize>,
+    /// address of the originating client, see `trace::origin`
+    origin_ip: Option<String>,
+    /// HELO name of the originating client
+    origin_host: Option<String>,
+    /// addresses of the `From` and `Reply-To` headers
+    header_from: Option<String>,
+    reply_to: Option<String>,
+    /// pattern on any of the mismatches of `sender::Sender`, e.g. `*` or `display-name`
+    from_mismatch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Action {
+    Reject(String),
+    Quarantine,
+    SetBucket(String),
+    SetPrefix(String),
+    Tag(String),
+    DropAttachment(String),
+}
+
+/// The combined result of all matching rules for a recipient.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub reject: Option<String>,
+    pub quarantine: bool,
+    pub bucket: Option<String>,
+    /// prepended to the S3 prefix
+    pub prefix: Option<String>,
+    pub tags: Vec<String>,
+    /// patterns of attachment file names not to store
+    pub drop_attachments: Vec<String>,
+    /// additional objects from the plugin
+    pub objects: Vec<PluginObject>,
+}
+
+impl Outcome {
+    pub fn drops_attachment(&self, filename: &str) -> bool {
+        self.drop_attachments
+            .iter()
+            .any(|pattern| glob_match(pattern, filename))
+    }
+}
+
+impl Rules {
+    #[instrument]
+    pub fn load(path: &str) -> Result<Self> {
+        let rules = fs::read_to_string(path).context("could not read rules")?;
+        serde_yaml::from_str(&rules).context("could not parse rules")
+    }
+
+    #[instrument(skip(self, message))]
+    pub fn evaluate(
+        &self,
+        from: &str,
+        rcpt: &str,
+        message: &Message,
+        size: usize,
+        origin: Option<&Origin>,
+    ) -> Outcome {
+        let mut outcome = Outcome::default();
+        for rule in &self.rules {
+            if !rule.conditions.matches(from, rcpt, message, size, origin) {
+                continue;
+            }
+            trace!("rule {:?} matched", rule.name);
+
+            for action in &rule.actions {
+                match action {
+                    Action::Reject(text) => outcome.reject = Some(text.clone()),
+                    Action::Quarantine => outcome.quarantine = true,
+                    Action::SetBucket(bucket) => outcome.bucket = Some(bucket.clone()),
+                    Action::SetPrefix(prefix) => outcome.prefix = Some(prefix.clone()),
+                    Action::Tag(tag) => outcome.tags.push(tag.clone()),
+                    Action::DropAttachment(pattern) => {
+                        outcome.drop_attachments.push(pattern.clone())
+                    }
+                }
+            }
+            if rule.stop {
+                break;
+            }
+        }
+        outcome
+    }
+}
+
+impl Conditions {
+    fn matches(
+        &self,
+        from: &str,
+        rcpt: &str,
+        message: &Message,
+        size: usize,
+        origin: Option<&Origin>,
+    ) -> bool {
+        let matches = |pattern: &Option<String>, text: &str| {
+            pattern.as_ref().is_none_or(|p| glob_match(p, text))
+        };
+        let origin_ip = origin.map(|o| o.ip.to_string()).unwrap_or_default();
+        let origin_host = origin.and_then(|o| o.host.as_deref()).unwrap_or("");
+        let sender = if self.header_from.is_some()
+            || self.reply_to.is_some()
+            || self.from_mismatch.is_some()
+        {
+            Sender::parse(from, message)
+        } else {
+            Sender::default()
+        };
+
+        matches(&self.from, from)
+            && matches(&self.rcpt, rcpt)
+            && matches(&self.origin_ip, &origin_ip)
+            && matches(&self.origin_host, origin_host)
+            && matches(
+                &self.header_from,
+                sender.header_from.as_deref().unwrap_or(""),
+            )
+            && matches(&self.reply_to, sender.reply_to.as_deref().unwrap_or(""))
+            && self
+                .from_mismatch
+                .as_ref()
+                .is_none_or(|pattern| sender.mismatches.iter().any(|m| glob_match(pattern, m)))
+            && self.size_over.is_none_or(|limit| size > limit)
+            && self.size_under.is_none_or(|limit| size < limit)
+            && self.headers.iter().all(|(name, pattern)| {
+                message
+                    .headers_raw()
+                    .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .any(|(_, value)| glob_match(pattern, value.trim()))
+            })
+    }
+}
+
+/// Case-insensitive glob match supporting `*` and `?`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("invoices@*", "invoices@example.com"));
+        assert!(glob_match("*@example.com", "a@example.com"));
+        assert!(glob_match("*invoice*", "Your invoice 42"));
+        assert!(glob_match("a*b*c", "axxbyybc"));
+        assert!(!glob_match("a*b*c", "axxbyybd"));
+        assert!(glob_match("?", "a"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("?", "ab"));
+        assert!(glob_match("file.???", "file.pdf"));
+        assert!(!glob_match("file.???", "file.docx"));
+        assert!(!glob_match("invoices@*", "sales@example.com"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn glob_case_and_unicode() {
+        assert!(glob_match("*.EXE", "setup.exe"));
+        assert!(glob_match("Invoices@*", "INVOICES@example.com"));
+        assert!(glob_match("rechnung-?.pdf", "Rechnung-ä.PDF"));
+        assert!(glob_match("*ÜBER*", "darüber"));
+        assert!(glob_match("?", "日"));
+        assert!(!glob_match("??", "日"));
+    }
+
+    #[test]
+    fn stop_ends_evaluation() {
+        let rules: Rules = serde_yaml::from_str(
+            r#"
+rules:
+  - match:
+      rcpt: "invoices@*"
+    actions:
+      - tag: first
+  - match:
+      headers:
+        Subject: "*invoice*"
+    actions:
+      - tag: second
+      - set-prefix: "invoices/"
+    stop: true
+  - actions:
+      - tag: third
+      - quarantine
+"#,
+        )
+        .unwrap();
+        let message = MessageParser::default()
+            .parse(&b"Subject: Your invoice\r\n\r\nbody\r\n"[..])
+            .unwrap();
+
+        let outcome = rules.evaluate("a@example.com", "invoices@example.com", &message, 10, None);
+        assert_eq!(outcome.tags, ["first", "second"]);
+        assert_eq!(outcome.prefix.as_deref(), Some("invoices/"));
+        assert!(!outcome.quarantine);
+
+        // rules after a stopping rule apply when it does not match
+        let message = MessageParser::default()
+            .parse(&b"Subject: Hello\r\n\r\nbody\r\n"[..])
+            .unwrap();
+        let outcome = rules.evaluate("a@example.com", "invoices@example.com", &message, 10, None);
+        assert_eq!(outcome.tags, ["first", "third"]);
+        assert_eq!(outcome.prefix, None);
+        assert!(outcome.quarantine);
+    }
+}
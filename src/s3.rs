@@ -1,133 +1,1244 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use aws_sdk_s3::primitives::ByteStream;
+use anyhow::{bail, Context, Result};
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::{ByteStream, DateTime};
+use aws_sdk_s3::types::{
+    BucketLocationConstraint, BucketVersioningStatus, ChecksumAlgorithm, CreateBucketConfiguration,
+    ServerSideEncryption, ServerSideEncryptionByDefault, ServerSideEncryptionConfiguration,
+    ServerSideEncryptionRule, VersioningConfiguration,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::Utc;
 use futures::future::try_join_all;
-use mail_parser::{Message, MessagePart, MimeHeaders};
-use serde_json::json;
-use sqlx::PgPool;
-use tracing::{instrument, trace};
+use futures::StreamExt;
+use mail_parser::{Message, MessagePart, MimeHeaders, PartType};
+use serde_json::{json, Value};
+use tracing::{error, info, instrument, trace, warn};
 
+use crate::archive;
+use crate::attachments::{self, PolicyAction};
+use crate::calendar;
+use crate::classify::classify;
 use crate::db;
+use crate::encryption::{self, Encryption};
+use crate::events::MessageStored;
+use crate::html;
+use crate::language;
+use crate::links;
+use crate::list::ListInfo;
+use crate::metrics;
+use crate::rdns::Rdns;
+use crate::redact;
+use crate::rules;
+use crate::schema;
+use crate::sender::Sender;
+use crate::smime::SmimeInfo;
+use crate::smtp::Config;
+use crate::storage::{ObjectKind, Placement};
+use crate::tenant::Tenant;
+use crate::thread;
+use crate::tls::TlsInfo;
+use crate::tnef;
+use crate::trace::{self, Origin};
+use crate::uuencode;
 
-#[instrument(skip(s3_config, message, pg_pool), fields(message_id = message.message_id()))]
+/// Percent-encode characters that would split the key or that S3 recommends to avoid,
+/// UTF-8 is kept as is.
+fn key_component(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_control() || "/\\%{}^`[]\"<>~#|".contains(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+/// A second bucket, possibly at another endpoint or in another region, written after the
+/// primary one, for disaster recovery without bucket replication.
+#[derive(Debug)]
+pub struct Mirror {
+    pub s3_config: aws_sdk_s3::Config,
+    pub bucket: String,
+}
+
+/// Envelope information stored with a message.
+#[derive(Debug)]
+pub struct Envelope<'a> {
+    pub from: &'a str,
+    pub rcpt: &'a str,
+    /// DSN parameters, see `dsn::to_json`
+    pub dsn: Option<Value>,
+    /// `SIZE` parameter of the MAIL command
+    pub declared_size: Option<u64>,
+    pub tenant: Option<&'a Tenant>,
+    /// storage identity the recipient is an alias of, used instead of it in the key
+    pub canonical_rcpt: Option<&'a str>,
+    /// originating client, see `trace::origin`
+    pub origin: Option<&'a Origin>,
+    /// outcome of S/MIME decryption and verification
+    pub smime: Option<&'a SmimeInfo>,
+    /// TLS session the message was received in
+    pub tls: Option<&'a TlsInfo>,
+    /// reverse DNS name of the client
+    pub rdns: Option<&'a Rdns>,
+    /// HELO name of the client
+    pub helo: Option<&'a str>,
+    /// account of an authenticated submission
+    pub submitter: Option<&'a str>,
+}
+
+#[instrument(skip(config, message), fields(message_id = message.message_id()))]
 pub async fn upload_message(
-    s3_config: &aws_sdk_s3::Config,
-    pg_pool: &PgPool,
-    bucket: &str,
-    from: &str,
-    rcpt: &str,
-    message: Message<'_>,
-) -> Result<()> {
+    config: &Config,
+    envelope: Envelope<'_>,
+    message: &Message<'_>,
+    outcome: &rules::Outcome,
+) -> Result<Option<MessageStored>> {
     trace!("uploading message");
+    if let Some(faults) = config.faults.as_ref() {
+        faults.s3().await?;
+    }
+    let Envelope {
+        from,
+        rcpt,
+        dsn,
+        declared_size,
+        tenant,
+        canonical_rcpt,
+        origin,
+        smime,
+        tls,
+        rdns,
+        helo,
+        submitter,
+    } = envelope;
+    let tenant_prefix = tenant.and_then(|t| t.prefix.as_deref());
+    let retention = config.storage.object_lock(rcpt);
+    let tenant_bucket = tenant.and_then(|t| t.bucket.as_deref());
 
     let message_id = message.message_id().context("mail has no message id")?;
     let date = message.date().context("mail has no date")?.to_rfc3339();
-    let base_path = format!("{}/{}/{}-{}/", rcpt.to_lowercase(), from, date, message_id);
+    let identity = match canonical_rcpt {
+        // canonical identities may span several path segments
+        Some(canonical) => canonical
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(key_component)
+            .collect::<Vec<_>>()
+            .join("/"),
+        None => key_component(&rcpt.to_lowercase()),
+    };
+    let base_path = format!(
+        "{}{}/{}/{}-{}/",
+        outcome.prefix.as_deref().or(tenant_prefix).unwrap_or(""),
+        identity,
+        key_component(from),
+        date,
+        key_component(message_id)
+    );
+    // quarantined mail is kept out of the normal prefixes and the DB
+    let base_path = if outcome.quarantine {
+        format!("quarantine/{}", base_path)
+    } else {
+        base_path
+    };
 
-    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    let bucket = outcome
+        .bucket
+        .as_deref()
+        .or(tenant_bucket)
+        .unwrap_or(&config.bucket);
+    let s3_client = aws_sdk_s3::Client::from_conf(config.s3_config.clone());
+    // headers.json and manifest.json stay readable to find messages
+    let encryption = config.encryption.as_deref();
+
+    let files = attachment_files(message);
 
     // attachments uploads
+    let mut redactions = redact::Counts::new();
     let mut attachments_metadata = vec![];
-    let mut uploads = message
-        .attachments()
+    let mut skipped_attachments = vec![];
+    let mut attachment_texts = vec![];
+    let mut derived_uploads = vec![];
+    let mut uploads = files
+        .iter()
         .enumerate()
-        .map(|(ix, attachment)| {
-            let attachment_name = attachment
-                .attachment_name()
-                .context("attachment has no name")?;
-            let body = attachment.contents();
+        .filter(|(_, AttachmentFile { name, .. })| {
+            let dropped = name
+                .as_deref()
+                .is_some_and(|name| outcome.drops_attachment(name));
+            if dropped {
+                trace!("dropping attachment {:?}", name);
+            }
+            !dropped
+        })
+        .filter(|(ix, file)| {
+            let exceeded = config
+                .attachment_limits
+                .as_ref()
+                .and_then(|limits| limits.exceeded(*ix, file.body.len()));
+            if let Some(reason) = exceeded {
+                trace!(
+                    "skipping attachment {:?} over the {} limit",
+                    file.name,
+                    reason
+                );
+                skipped_attachments.push(schema::SkippedAttachment {
+                    index: *ix,
+                    filename: file.name.as_deref().map(str::to_string),
+                    size: file.body.len(),
+                    reason,
+                });
+            }
+            exceeded.is_none()
+        })
+        .map(|(ix, file)| {
+            let AttachmentFile {
+                name,
+                content_type: declared,
+                body,
+            } = file;
+            let attachment_name = name.as_deref().context("attachment has no name")?;
             let path = format!("{}attachments/{:02}-{}", base_path, ix, attachment_name);
 
-            let metadata = json!({
-                "index": ix,
-                "filename": attachment_name,
-                "rel_path": path,
-                "content_type": mime_guess::from_path(&path).first_raw(),
+            let content_type = mime_guess::from_path(&path).first_raw();
+            let blocked = config.attachment_policy.as_ref().and_then(|policy| {
+                Some((
+                    policy.action,
+                    policy.blocked(name.as_deref(), declared.as_deref())?,
+                ))
             });
+            if let Some((PolicyAction::Drop, reason)) = blocked {
+                trace!("replacing blocked attachment {:?}", name);
+                let placeholder_path = format!("{}attachments/{:02}.removed.txt", base_path, ix);
+                attachments_metadata.push(schema::Attachment {
+                    index: ix,
+                    filename: attachment_name.to_string(),
+                    rel_path: placeholder_path.clone(),
+                    content_type: Some("text/plain".to_string()),
+                    thumbnail: None,
+                    text: None,
+                    blocked: Some(reason.to_string()),
+                    encrypted: false,
+                });
+                return Ok(upload_file(
+                    &s3_client,
+                    bucket,
+                    placeholder_path,
+                    attachments::placeholder(attachment_name, body.len(), reason).into_bytes(),
+                    encryption,
+                    config
+                        .storage
+                        .placement(ObjectKind::Attachment, retention.as_ref()),
+                ));
+            }
+            let thumbnail = config
+                .thumbnails
+                .as_ref()
+                .filter(|_| content_type.is_some_and(|ct| ct.starts_with("image/")))
+                .and_then(|thumbnails| thumbnails.render(body))
+                .map(|thumbnail| {
+                    let thumbnail_path =
+                        format!("{}thumbnails/{:02}-{}.jpg", base_path, ix, attachment_name);
+                    derived_uploads.push(upload_file(
+                        &s3_client,
+                        bucket,
+                        thumbnail_path.clone(),
+                        thumbnail,
+                        encryption,
+                        config
+                            .storage
+                            .placement(ObjectKind::Thumbnail, retention.as_ref()),
+                    ));
+                    thumbnail_path
+                });
+            let text = config
+                .extraction
+                .as_ref()
+                .and_then(|extraction| extraction.extract(content_type, body))
+                .map(|text| {
+                    let text = redacted(config, Cow::Owned(text), &mut redactions).into_owned();
+                    let text_path = format!("{}attachments/{:02}.txt", base_path, ix);
+                    derived_uploads.push(upload_file(
+                        &s3_client,
+                        bucket,
+                        text_path.clone(),
+                        text.as_bytes().to_vec(),
+                        encryption,
+                        config
+                            .storage
+                            .placement(ObjectKind::Text, retention.as_ref()),
+                    ));
+                    attachment_texts.push(text);
+                    text_path
+                });
 
-            attachments_metadata.push(metadata);
+            attachments_metadata.push(schema::Attachment {
+                index: ix,
+                filename: attachment_name.to_string(),
+                rel_path: path.clone(),
+                content_type: content_type.map(str::to_string),
+                thumbnail,
+                text,
+                blocked: blocked.map(|(_, reason)| reason.to_string()),
+                encrypted: archive::is_encrypted(body),
+            });
 
-            Ok(upload_file(&s3_client, bucket, path, body.to_vec()))
+            Ok(upload_file(
+                &s3_client,
+                bucket,
+                path,
+                body.to_vec(),
+                encryption,
+                config
+                    .storage
+                    .placement(ObjectKind::Attachment, retention.as_ref()),
+            ))
         })
         .collect::<Result<Vec<_>>>()?;
+    uploads.extend(derived_uploads);
 
-    let headers_map: HashMap<&str, &str> =
-        message.headers_raw().map(|(k, v)| (k, v.trim())).collect();
-    let headers_json = serde_json::to_vec_pretty(&headers_map)?;
+    let headers_map: BTreeMap<&str, Cow<str>> = message
+        .headers_raw()
+        .map(|(k, v)| {
+            (
+                k,
+                redacted(config, Cow::Borrowed(v.trim()), &mut redactions),
+            )
+        })
+        .collect();
+    let headers_json = headers_json(&headers_map)?;
     let headers_path = format!("{}headers.json", base_path);
-    uploads.push(upload_file(&s3_client, bucket, headers_path, headers_json));
+    uploads.push(upload_file(
+        &s3_client,
+        bucket,
+        headers_path,
+        headers_json,
+        None,
+        config
+            .storage
+            .placement(ObjectKind::Headers, retention.as_ref()),
+    ));
+
+    // the message as received, including the added Received header; it is left out when
+    // redacting, as encoded parts cannot be redacted reliably
+    if config.redactions.is_none() {
+        let raw_path = format!("{}raw.eml", base_path);
+        uploads.push(upload_file(
+            &s3_client,
+            bucket,
+            raw_path,
+            message.raw_message().to_vec(),
+            encryption,
+            config
+                .storage
+                .placement(ObjectKind::Raw, retention.as_ref()),
+        ));
+    }
 
     // this selects only the first part
-    let body_text = message.text_bodies().next();
-    if let Some(body_text) = body_text {
+    let html_part = message.html_bodies().next();
+
+    // this selects only the first part, HTML-only messages get a plain text rendering
+    let text_part = message
+        .text_bodies()
+        .find(|part| matches!(part.body, PartType::Text(_)));
+    let body_text = match text_part {
+        Some(part) => part.text_contents().map(Cow::Borrowed),
+        None => html_part
+            .and_then(MessagePart::text_contents)
+            .map(|html| Cow::Owned(html::to_text(html))),
+    };
+    // uuencoded files are stored as attachments instead, see `attachment_files`
+    let body_text = body_text.map(|text| match uuencode::strip(&text) {
+        Some(stripped) => Cow::Owned(stripped),
+        None => text,
+    });
+    let body_text = body_text.map(|text| redacted(config, text, &mut redactions));
+    if let Some(body_text) = body_text.as_ref() {
         let body_text_path = format!("{}body.txt", base_path);
         uploads.push(upload_file(
             &s3_client,
             bucket,
             body_text_path,
-            body_text.contents().to_vec(),
+            body_text.as_bytes().to_vec(),
+            encryption,
+            config
+                .storage
+                .placement(ObjectKind::Body, retention.as_ref()),
         ));
     }
 
-    // this selects only the first part
-    let body_html = message.html_bodies().next();
-    if let Some(body_html) = body_html {
+    // the unsanitized original is still in raw.eml
+    let body_html = html_part.and_then(|part| {
+        let html = part.text_contents()?;
+        Some(match part.body {
+            PartType::Html(_) if config.sanitize_html => Cow::Owned(html::sanitize(html)),
+            _ => Cow::Borrowed(html),
+        })
+    });
+    let body_html = body_html.map(|html| redacted(config, html, &mut redactions));
+    if let Some(body_html) = body_html.as_ref() {
         let body_html_path = format!("{}body.html", base_path);
         uploads.push(upload_file(
             &s3_client,
             bucket,
             body_html_path,
-            body_html.contents().to_vec(),
+            body_html.as_bytes().to_vec(),
+            encryption,
+            config
+                .storage
+                .placement(ObjectKind::Body, retention.as_ref()),
         ));
     }
 
+    let calendar_events = calendar::events(message);
+    let calendar = if calendar_events.is_empty() {
+        None
+    } else {
+        let calendar = serde_json::to_value(&calendar_events)?;
+        let calendar_path = format!("{}calendar.json", base_path);
+        let calendar_json = serde_json::to_vec_pretty(&calendar)?;
+        uploads.push(upload_file(
+            &s3_client,
+            bucket,
+            calendar_path,
+            calendar_json,
+            encryption,
+            config
+                .storage
+                .placement(ObjectKind::Calendar, retention.as_ref()),
+        ));
+        Some(calendar)
+    };
+
+    // objects added by the plugin
+    for object in &outcome.objects {
+        if object.name.split('/').any(|s| s.is_empty() || s == "..") {
+            bail!("invalid plugin object name {}", object.name);
+        }
+        let body = match (&object.content, &object.content_base64) {
+            (Some(content), _) => content.as_bytes().to_vec(),
+            (None, Some(encoded)) => STANDARD.decode(encoded)?,
+            (None, None) => vec![],
+        };
+        let path = format!("{}plugin/{}", base_path, object.name);
+        uploads.push(upload_file(
+            &s3_client,
+            bucket,
+            path,
+            body,
+            encryption,
+            config
+                .storage
+                .placement(ObjectKind::Plugin, retention.as_ref()),
+        ));
+    }
+
+    let thread_id = thread::thread_id(message);
+    let list_info = ListInfo::parse(message);
+    let sender = Sender::parse(from, message);
+    let from_mismatch: Vec<String> = sender.mismatches.iter().map(|m| m.to_string()).collect();
+    let language = body_text
+        .as_deref()
+        .filter(|_| config.detect_language)
+        .and_then(language::detect);
+
+    // bodies are decoded to UTF-8, keep what they were declared as
+    let charsets = json!({
+        "body_text": text_part.and_then(charset),
+        "body_html": html_part.and_then(charset),
+    });
+
+    // rendered as text to decode entities in link targets
+    let html_text = html_part
+        .filter(|part| matches!(part.body, PartType::Html(_)))
+        .and(body_html.as_deref())
+        .map(html::to_text);
+    let links = links::extract(body_text.as_deref().into_iter().chain(html_text.as_deref()));
+    let manifest = json!({
+        "schema_version": schema::SCHEMA_VERSION,
+        "message_id": message_id,
+        "from": from,
+        "rcpt": rcpt,
+        "date": date,
+        "subject": message
+            .subject()
+            .map(|subject| redacted(config, Cow::Borrowed(subject), &mut redactions)),
+        "bucket": bucket,
+        "s3_prefix": base_path,
+        "attachments": attachments_metadata,
+        "skipped_attachments": skipped_attachments,
+        "links": links,
+        "tags": outcome.tags,
+        "charsets": charsets,
+        "language": language,
+        "thread_id": thread_id,
+        "list": list_info,
+        "sender": sender,
+        "smime": smime,
+        "tls": tls,
+        "rdns": rdns,
+        "helo": helo,
+        "submitter": submitter,
+        "encryption": encryption.map(encryption::to_json),
+        "redactions": config.redactions.as_ref().map(|_| &redactions),
+        "object_lock": retention.as_ref().map(|r| json!({
+            "mode": r.mode.as_str(),
+            "retain_until": r.until,
+        })),
+    });
+    let manifest_path = format!("{}manifest.json", base_path);
+    uploads.push(upload_file(
+        &s3_client,
+        bucket,
+        manifest_path,
+        serde_json::to_vec_pretty(&manifest)?,
+        None,
+        config
+            .storage
+            .placement(ObjectKind::Manifest, retention.as_ref()),
+    ));
+
     // run upload futures
-    try_join_all(uploads).await?;
+    let mut uploaded = try_join_all(uploads).await?;
+    let received_at = Utc::now();
+    let mut index_key = None;
+    // only once the message is complete, so entries never point to missing objects
+    if config.day_index && !outcome.quarantine {
+        let entry = schema::IndexEntry {
+            schema_version: schema::SCHEMA_VERSION,
+            message_id: message_id.to_string(),
+            from: from.to_string(),
+            rcpt: rcpt.to_string(),
+            received_at,
+            bucket: bucket.to_string(),
+            s3_prefix: base_path.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // a key per message, as concurrent uploads would lose appends to a shared object
+        let index_path = format!(
+            "{}index/{}/{}-{}-{}.json",
+            outcome.prefix.as_deref().or(tenant_prefix).unwrap_or(""),
+            received_at.format("%Y-%m-%d"),
+            received_at.format("%H%M%S%.6f"),
+            key_component(&rcpt.to_lowercase()),
+            key_component(message_id)
+        );
+        index_key = Some(index_path.clone());
+        uploaded.push(
+            upload_file(
+                &s3_client,
+                bucket,
+                index_path,
+                line,
+                None,
+                config.storage.placement(ObjectKind::Index, None),
+            )
+            .await?,
+        );
+    }
+    // the primary is authoritative, diverging mirrors are recorded in the DB
+    let mirrored = match config.mirror.as_ref() {
+        Some(mirror) => Some(mirror_objects(mirror, &uploaded).await),
+        None => None,
+    };
+    // the uploaded bodies are not needed anymore
+    drop(uploaded);
+
+    if outcome.quarantine {
+        return Ok(None);
+    }
+
+    let urls = if config.presign_on_upload {
+        let attachment_paths: Vec<&str> = attachments_metadata
+            .iter()
+            .map(|a| a.rel_path.as_str())
+            .collect();
+        Some(
+            presigned_urls(
+                &s3_client,
+                bucket,
+                &base_path,
+                body_text.is_some(),
+                body_html.is_some(),
+                &attachment_paths,
+                config.presigned_url_expiry,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let encrypted_archive = attachments_metadata.iter().any(|a| a.encrypted);
+    let attachments = serde_json::to_value(attachments_metadata)?;
+    let trace = serde_json::to_value(trace::chain(message))?;
+    let (kind, report) = classify(message);
+    let event = MessageStored {
+        message_id: message_id.to_string(),
+        from: from.to_string(),
+        rcpt: rcpt.to_string(),
+        received_at,
+        bucket: bucket.to_string(),
+        s3_prefix: base_path.clone(),
+        attachments: attachments.clone(),
+        urls: urls.clone(),
+        tags: outcome.tags.clone(),
+        canonical_rcpt: canonical_rcpt.map(str::to_string),
+        origin: origin.cloned(),
+        thread_id: thread_id.clone(),
+    };
+
+    let body_text = body_text.as_deref().unwrap_or("").trim();
+    let body_html = body_html.as_deref().unwrap_or("").trim();
+    // not stored encrypted, so left out when the bodies are
+    let attachment_text = Some(attachment_texts.join("\n\n"))
+        .filter(|text| !text.is_empty() && config.body_keys.is_none());
+    let (body_text, body_html) = match config.body_keys.as_ref() {
+        Some(keys) => (
+            Cow::Owned(keys.encrypt(body_text)?),
+            Cow::Owned(keys.encrypt(body_html)?),
+        ),
+        None => (Cow::Borrowed(body_text), Cow::Borrowed(body_html)),
+    };
 
     // afterwards, when complete, insert into DB
+    if let Some(faults) = config.faults.as_ref() {
+        faults.db().await?;
+    }
     db::insert_mail(
-        pg_pool,
-        message_id,
-        rcpt,
-        from,
-        body_text
-            .and_then(MessagePart::text_contents)
-            .unwrap_or("")
-            .trim(),
-        body_html
-            .and_then(MessagePart::text_contents)
-            .unwrap_or("")
-            .trim(),
-        serde_json::to_value(headers_map)?,
-        serde_json::to_value(attachments_metadata)?,
+        &config.pg_pool,
+        &db::NewMail {
+            message_id,
+            rcpt,
+            canonical_rcpt,
+            from,
+            body_text: &body_text,
+            body_html: &body_html,
+            attachment_text: attachment_text.as_deref(),
+            headers: serde_json::to_value(headers_map)?,
+            trace,
+            kind: kind.as_str(),
+            report,
+            calendar,
+            links: &links,
+            charsets,
+            redactions: config
+                .redactions
+                .as_ref()
+                .map(|_| serde_json::to_value(&redactions))
+                .transpose()?,
+            smime: smime.map(serde_json::to_value).transpose()?,
+            tls: tls.map(serde_json::to_value).transpose()?,
+            rdns: rdns.map(serde_json::to_value).transpose()?,
+            helo,
+            submitter,
+            origin_ip: origin.map(|o| o.ip.to_string()),
+            origin_host: origin.and_then(|o| o.host.as_deref()),
+            attachments,
+            s3_prefix: &base_path,
+            index_key: index_key.as_deref(),
+            urls,
+            dsn,
+            size: message.raw_message().len() as i64,
+            bucket,
+            tags: &outcome.tags,
+            tenant: tenant.map(|t| t.domain.as_str()),
+            expires_at: tenant
+                .and_then(|t| t.retention_days)
+                .map(|days| Utc::now() + chrono::Duration::days(days.into())),
+            declared_size: declared_size.map(|size| size as i64),
+            mirrored,
+            encrypted_archive,
+            language,
+            thread_id: thread_id.as_deref(),
+            list_id: list_info.list_id.as_deref(),
+            list_unsubscribe: &list_info.unsubscribe,
+            auto_submitted: list_info.auto_submitted.as_deref(),
+            precedence: list_info.precedence.as_deref(),
+            automated: list_info.is_automated(),
+            header_from: sender.header_from.as_deref(),
+            reply_to: sender.reply_to.as_deref(),
+            from_mismatch: &from_mismatch,
+        },
+        config.pg_notify_channel.as_deref(),
     )
     .await?;
-    Ok(())
+    Ok(Some(event))
+}
+
+/// An attachment as stored, TNEF containers (winmail.dat) are replaced by the files they
+/// wrap and uuencoded files are taken out of the bodies.
+pub struct AttachmentFile<'a> {
+    pub name: Option<Cow<'a, str>>,
+    /// declared content type, `None` for files unpacked from TNEF
+    pub content_type: Option<String>,
+    pub body: Cow<'a, [u8]>,
+}
+
+pub fn attachment_files<'a>(message: &'a Message<'_>) -> Vec<AttachmentFile<'a>> {
+    let mut files = vec![];
+    for attachment in message.attachments() {
+        if tnef::is_tnef(attachment) {
+            match tnef::attachments(attachment.contents()) {
+                Ok(wrapped) => {
+                    files.extend(wrapped.into_iter().map(|file| AttachmentFile {
+                        name: Some(Cow::Owned(file.name)),
+                        content_type: None,
+                        body: Cow::Owned(file.data),
+                    }));
+                    continue;
+                }
+                Err(e) => warn!("could not unpack TNEF attachment: {:?}", e),
+            }
+        }
+        files.push(AttachmentFile {
+            name: attachment.attachment_name().map(Cow::Borrowed),
+            content_type: attachment
+                .content_type()
+                .map(|ct| format!("{}/{}", ct.ctype(), ct.subtype().unwrap_or(""))),
+            body: Cow::Borrowed(attachment.contents()),
+        });
+    }
+    // files uuencoded in plain text bodies follow the MIME attachments
+    let text_bodies = message
+        .text_bodies()
+        .filter(|part| matches!(part.body, PartType::Text(_)))
+        .filter_map(MessagePart::text_contents);
+    for text in text_bodies {
+        files.extend(
+            uuencode::files(text)
+                .into_iter()
+                .map(|file| AttachmentFile {
+                    name: Some(Cow::Owned(file.name)),
+                    content_type: None,
+                    body: Cow::Owned(file.data),
+                }),
+        );
+    }
+    files
+}
+
+/// The content type of an object, the decoded bodies, extracted texts and placeholders are
+/// labeled as UTF-8.
+fn content_type(path: &str) -> Option<String> {
+    let content_type = mime_guess::from_path(path).first_raw()?;
+    // `attachments/NN.txt` and `attachments/NN.removed.txt`, the attachments themselves
+    // always have a name after the index
+    let extracted = path
+        .rsplit_once("/attachments/")
+        .and_then(|(_, name)| name.strip_suffix(".txt"))
+        .map(|name| name.strip_suffix(".removed").unwrap_or(name))
+        .is_some_and(|ix| ix.bytes().all(|b| b.is_ascii_digit()));
+    if extracted || path.ends_with("/body.txt") || path.ends_with("/body.html") {
+        return Some(format!("{}; charset=utf-8", content_type));
+    }
+    Some(content_type.to_string())
+}
+
+/// Apply the configured redactions, adding the replacements to `counts`.
+fn redacted<'a>(config: &Config, text: Cow<'a, str>, counts: &mut redact::Counts) -> Cow<'a, str> {
+    let Some(redactions) = config.redactions.as_ref() else {
+        return text;
+    };
+    match text {
+        Cow::Borrowed(text) => redactions.apply(text, counts),
+        Cow::Owned(text) => Cow::Owned(redactions.apply(&text, counts).into_owned()),
+    }
+}
+
+/// `headers.json`, see `schema::Headers`.
+fn headers_json(headers: &BTreeMap<&str, Cow<str>>) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(&schema::Headers::new(headers))?)
 }
 
-#[instrument(skip(s3_client, body))]
+/// The lower case charset the part was declared in.
+fn charset(part: &MessagePart) -> Option<String> {
+    part.content_type()?
+        .attribute("charset")
+        .map(str::to_lowercase)
+}
+
+/// An object as uploaded, to write it to the mirror as well.
+struct Uploaded {
+    path: String,
+    body: Bytes,
+    content_type: Option<String>,
+    placement: Placement,
+}
+
+#[instrument(skip(s3_client, body, encryption))]
 async fn upload_file(
     s3_client: &aws_sdk_s3::Client,
     bucket: &str,
     path: String,
     body: Vec<u8>,
-) -> Result<()> {
-    let content_type = mime_guess::from_path(&path).first_raw();
+    encryption: Option<&dyn Encryption>,
+    placement: Placement,
+) -> Result<Uploaded> {
+    let (body, content_type) = match encryption {
+        Some(encryption) => (
+            encryption.encrypt(&body)?,
+            Some("application/octet-stream".to_string()),
+        ),
+        None => (body, content_type(&path)),
+    };
 
     trace!(
         "uploading file path={} content_type={}",
         path,
-        content_type.unwrap_or("")
+        content_type.as_deref().unwrap_or("")
     );
 
+    let uploaded = Uploaded {
+        path,
+        body: Bytes::from(body),
+        content_type,
+        placement,
+    };
+    put_object(s3_client, bucket, &uploaded).await?;
+    Ok(uploaded)
+}
+
+async fn put_object(s3_client: &aws_sdk_s3::Client, bucket: &str, object: &Uploaded) -> Result<()> {
+    let placement = &object.placement;
     let s3_req = s3_client
         .put_object()
         .bucket(bucket)
-        .body(ByteStream::from(body))
-        .set_content_type(content_type.map(str::to_string))
-        .key(path);
+        .body(ByteStream::from(object.body.clone()))
+        .set_content_type(object.content_type.clone())
+        .set_storage_class(placement.storage_class.clone())
+        .set_tagging(placement.tagging.clone())
+        .key(&object.path);
+    // S3 requires a checksum for uploads with Object Lock parameters
+    let s3_req = match placement.retention.as_ref() {
+        Some(retention) => s3_req
+            .object_lock_mode(retention.mode.clone())
+            .object_lock_retain_until_date(DateTime::from_secs(retention.until.timestamp()))
+            .checksum_algorithm(ChecksumAlgorithm::Crc32),
+        None => s3_req,
+    };
 
     s3_req.send().await.map_err(aws_sdk_s3::Error::from)?;
     Ok(())
 }
+
+/// Write the objects of a message to the mirror, returns whether all were written.
+#[instrument(skip_all, fields(bucket = mirror.bucket))]
+async fn mirror_objects(mirror: &Mirror, objects: &[Uploaded]) -> bool {
+    let s3_client = aws_sdk_s3::Client::from_conf(mirror.s3_config.clone());
+    let puts = objects
+        .iter()
+        .map(|object| put_object(&s3_client, &mirror.bucket, object));
+    match try_join_all(puts).await {
+        Ok(_) => {
+            metrics::MIRROR_WRITES.with_label_values(&["success"]).inc();
+            true
+        }
+        Err(e) => {
+            error!(
+                "could not write to mirror bucket {}: {:?}",
+                mirror.bucket, e
+            );
+            metrics::MIRROR_WRITES.with_label_values(&["failure"]).inc();
+            false
+        }
+    }
+}
+
+#[instrument(skip(s3_client))]
+async fn presigned_url(
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    expires_in: Duration,
+) -> Result<String> {
+    let request = s3_client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .presigned(PresigningConfig::expires_in(expires_in)?)
+        .await?;
+    Ok(request.uri().to_string())
+}
+
+/// Presigned GET URLs for the stored objects of a message.
+#[instrument(skip(s3_client, attachment_paths))]
+async fn presigned_urls(
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+    base_path: &str,
+    has_body_text: bool,
+    has_body_html: bool,
+    attachment_paths: &[&str],
+    expires_in: Duration,
+) -> Result<Value> {
+    let headers_path = format!("{}headers.json", base_path);
+    let mut urls = json!({
+        "headers": presigned_url(s3_client, bucket, &headers_path, expires_in).await?,
+    });
+    if has_body_text {
+        let path = format!("{}body.txt", base_path);
+        urls["body_text"] = presigned_url(s3_client, bucket, &path, expires_in)
+            .await?
+            .into();
+    }
+    if has_body_html {
+        let path = format!("{}body.html", base_path);
+        urls["body_html"] = presigned_url(s3_client, bucket, &path, expires_in)
+            .await?
+            .into();
+    }
+
+    let mut attachments = vec![];
+    for path in attachment_paths {
+        attachments.push(presigned_url(s3_client, bucket, path, expires_in).await?);
+    }
+    urls["attachments"] = attachments.into();
+
+    Ok(urls)
+}
+
+/// Upload the transcript of a failed session, see `transcript::Transcripts`.
+#[instrument(skip(config, body))]
+pub async fn upload_transcript(config: &Config, key: String, body: Vec<u8>) -> Result<()> {
+    let s3_client = aws_sdk_s3::Client::from_conf(config.s3_config.clone());
+    upload_file(
+        &s3_client,
+        &config.bucket,
+        key,
+        body,
+        config.encryption.as_deref(),
+        config.storage.placement(ObjectKind::Transcript, None),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Check that the bucket exists and is accessible.
+#[instrument(skip(s3_config))]
+pub async fn check_bucket(s3_config: &aws_sdk_s3::Config, bucket: &str) -> Result<()> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    s3_client
+        .head_bucket()
+        .bucket(bucket)
+        .send()
+        .await
+        .map_err(aws_sdk_s3::Error::from)?;
+    Ok(())
+}
+
+/// Check that objects can be written to and deleted from the bucket.
+#[instrument(skip(s3_config))]
+pub async fn probe_bucket(s3_config: &aws_sdk_s3::Config, bucket: &str) -> Result<()> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    let key = format!(".smtp-s3-dump-probe-{}", Utc::now().timestamp_micros());
+    s3_client
+        .put_object()
+        .bucket(bucket)
+        .key(&key)
+        .body(ByteStream::from_static(b"probe"))
+        .send()
+        .await
+        .map_err(aws_sdk_s3::Error::from)
+        .context("could not put probe object")?;
+    s3_client
+        .delete_object()
+        .bucket(bucket)
+        .key(&key)
+        .send()
+        .await
+        .map_err(aws_sdk_s3::Error::from)
+        .context("could not delete probe object")?;
+    Ok(())
+}
+
+/// Settings of a bucket created at startup.
+#[derive(Debug, Default)]
+pub struct NewBucket {
+    pub versioning: bool,
+    /// default server-side encryption, `AES256` or `aws:kms`
+    pub encryption: Option<String>,
+    pub kms_key_id: Option<String>,
+}
+
+/// Create the bucket in the configured region, unless it exists.
+#[instrument(skip(s3_config))]
+pub async fn create_bucket_if_missing(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    settings: &NewBucket,
+) -> Result<()> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    if s3_client.head_bucket().bucket(bucket).send().await.is_ok() {
+        trace!("bucket exists");
+        return Ok(());
+    }
+
+    info!("creating bucket {}", bucket);
+    let mut request = s3_client.create_bucket().bucket(bucket);
+    // us-east-1 is the default and must not be given as location
+    if let Some(region) = s3_config.region().filter(|r| r.as_ref() != "us-east-1") {
+        request = request.create_bucket_configuration(
+            CreateBucketConfiguration::builder()
+                .location_constraint(BucketLocationConstraint::from(region.as_ref()))
+                .build(),
+        );
+    }
+    request.send().await.map_err(aws_sdk_s3::Error::from)?;
+
+    if settings.versioning {
+        s3_client
+            .put_bucket_versioning()
+            .bucket(bucket)
+            .versioning_configuration(
+                VersioningConfiguration::builder()
+                    .status(BucketVersioningStatus::Enabled)
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+    }
+    if let Some(algorithm) = settings.encryption.as_deref() {
+        let default = ServerSideEncryptionByDefault::builder()
+            .sse_algorithm(ServerSideEncryption::from(algorithm))
+            .set_kms_master_key_id(settings.kms_key_id.clone())
+            .build();
+        let rule = ServerSideEncryptionRule::builder()
+            .apply_server_side_encryption_by_default(default)
+            .build();
+        s3_client
+            .put_bucket_encryption()
+            .bucket(bucket)
+            .server_side_encryption_configuration(
+                ServerSideEncryptionConfiguration::builder()
+                    .rules(rule)
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+    }
+    Ok(())
+}
+
+/// Delete all objects below `prefix`, returns their number.
+#[instrument(skip(s3_config))]
+pub async fn delete_prefix(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    prefix: &str,
+) -> Result<u64> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    let keys = list_keys(&s3_client, bucket, prefix).await?;
+    delete_keys(&s3_client, bucket, &keys).await
+}
+
+/// Delete the objects with `keys`, returns their number.
+#[instrument(skip(s3_config))]
+pub async fn delete_objects(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    keys: &[String],
+) -> Result<u64> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    delete_keys(&s3_client, bucket, keys).await
+}
+
+/// Delete the objects of quarantined messages from or to `address`, which are kept out of
+/// the DB, returns their number.
+#[instrument(skip(s3_config))]
+pub async fn delete_quarantined(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    address: &str,
+) -> Result<u64> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    // the sender or recipient is a path segment below a rule or tenant prefix
+    let address = key_component(&address.to_lowercase());
+    let keys: Vec<String> = list_keys(&s3_client, bucket, "quarantine/")
+        .await?
+        .into_iter()
+        .filter(|key| {
+            key.split('/')
+                .any(|segment| segment.to_lowercase() == address)
+        })
+        .collect();
+    delete_keys(&s3_client, bucket, &keys).await
+}
+
+async fn list_keys(
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<String>> {
+    let mut keys = vec![];
+    let mut pages = s3_client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(aws_sdk_s3::Error::from)?;
+        keys.extend(
+            page.contents()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|object| object.key().map(str::to_string)),
+        );
+    }
+    Ok(keys)
+}
+
+async fn delete_keys(s3_client: &aws_sdk_s3::Client, bucket: &str, keys: &[String]) -> Result<u64> {
+    trace!("deleting {} objects", keys.len());
+    try_join_all(
+        keys.iter()
+            .map(|key| s3_client.delete_object().bucket(bucket).key(key).send()),
+    )
+    .await
+    .map_err(aws_sdk_s3::Error::from)?;
+    Ok(keys.len() as u64)
+}
+
+/// The message as received, `None` if it was not stored, e.g. when redacting.
+pub async fn get_raw_message(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    s3_prefix: &str,
+) -> Result<Option<Vec<u8>>> {
+    get_object(s3_config, bucket, &format!("{}raw.eml", s3_prefix)).await
+}
+
+/// The content of an object, `None` if it does not exist.
+#[instrument(skip(s3_config))]
+pub async fn get_object(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<Vec<u8>>> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    let result = s3_client.get_object().bucket(bucket).key(key).send().await;
+    match result {
+        Ok(object) => Ok(Some(object.body.collect().await?.into_bytes().to_vec())),
+        Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+        Err(e) => Err(aws_sdk_s3::Error::from(e).into()),
+    }
+}
+
+/// Presigned GET URL for a single object, e.g. an attachment.
+#[instrument(skip(s3_config))]
+pub async fn presigned_object_url(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    key: &str,
+    expires_in: Duration,
+) -> Result<String> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    presigned_url(&s3_client, bucket, key, expires_in).await
+}
+
+/// Presigned GET URLs for a message stored in the DB.
+#[instrument(skip(s3_config, mail), fields(message_id = mail.message_id))]
+pub async fn presigned_message_urls(
+    s3_config: &aws_sdk_s3::Config,
+    bucket: &str,
+    mail: &db::StoredMail,
+    expires_in: Duration,
+) -> Result<Value> {
+    let s3_client = aws_sdk_s3::Client::from_conf(s3_config.clone());
+    let base_path = mail
+        .s3_prefix
+        .as_deref()
+        .context("message has no stored S3 prefix")?;
+    let attachment_paths: Vec<&str> = mail
+        .attachments
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|a| a["rel_path"].as_str())
+        .collect();
+
+    presigned_urls(
+        &s3_client,
+        bucket,
+        base_path,
+        !mail.body_text.is_empty(),
+        !mail.body_html.is_empty(),
+        &attachment_paths,
+        expires_in,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: a@example.com\r\nTo: b@example.com\r\nSubject:  Hi \r\n\
+        Received: first\r\nReceived: second\r\n\r\nbody\r\n";
+
+    fn headers_map<'a>(message: &'a Message<'a>) -> BTreeMap<&'a str, Cow<'a, str>> {
+        message
+            .headers_raw()
+            .map(|(k, v)| (k, Cow::Borrowed(v.trim())))
+            .collect()
+    }
+
+    #[test]
+    fn headers_json_golden() {
+        let message = MessageParser::default().parse(MESSAGE).unwrap();
+        let json = headers_json(&headers_map(&message)).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{
+  "schema_version": 1,
+  "headers": {
+    "From": "a@example.com",
+    "Received": "second",
+    "Subject": "Hi",
+    "To": "b@example.com"
+  }
+}"#
+        );
+    }
+
+    /// Before version 1, `headers.json` was the plain map, now nested as `headers`.
+    #[test]
+    fn headers_json_nests_unversioned_map() {
+        let message = MessageParser::default().parse(MESSAGE).unwrap();
+        let headers = headers_map(&message);
+        let unversioned: Value = serde_json::to_value(&headers).unwrap();
+        assert_eq!(
+            unversioned,
+            json!({
+                "From": "a@example.com",
+                "Received": "second",
+                "Subject": "Hi",
+                "To": "b@example.com",
+            })
+        );
+
+        let json: Value = serde_json::from_slice(&headers_json(&headers).unwrap()).unwrap();
+        assert_eq!(json["schema_version"], schema::SCHEMA_VERSION);
+        assert_eq!(json["headers"], unversioned);
+    }
+}
@@ -0,0 +1,134 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Version of the JSON objects stored with a message, increased on incompatible changes.
+/// Within a version, fields are only added, never renamed or removed, so consumers should
+/// ignore fields they do not know.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// `headers.json`, with the headers sorted by name for a stable output.
+#[derive(Debug, Serialize)]
+pub struct Headers<'a> {
+    pub schema_version: u32,
+    /// raw header values by name, the last one of repeated headers
+    pub headers: &'a BTreeMap<&'a str, Cow<'a, str>>,
+}
+
+impl<'a> Headers<'a> {
+    pub fn new(headers: &'a BTreeMap<&'a str, Cow<'a, str>>) -> Self {
+        Headers {
+            schema_version: SCHEMA_VERSION,
+            headers,
+        }
+    }
+}
+
+/// An object in `index/YYYY-MM-DD/` per stored message, a single line so the objects of a
+/// day can be concatenated to JSON lines.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub schema_version: u32,
+    pub message_id: String,
+    pub from: String,
+    pub rcpt: String,
+    pub received_at: DateTime<Utc>,
+    pub bucket: String,
+    pub s3_prefix: String,
+}
+
+/// An attachment not stored for being over the attachment limits, in `manifest.json`.
+#[derive(Debug, Serialize)]
+pub struct SkippedAttachment {
+    pub index: usize,
+    pub filename: Option<String>,
+    pub size: usize,
+    /// `count` or `size`, see `attachments::AttachmentLimits`
+    pub reason: &'static str,
+}
+
+/// An attachment in `manifest.json` and the `attachments` column.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    /// position among the message's attachments, also in `rel_path`
+    pub index: usize,
+    pub filename: String,
+    /// key of the attachment's object
+    pub rel_path: String,
+    /// guessed from the filename
+    pub content_type: Option<String>,
+    /// key of the JPEG thumbnail of an image
+    pub thumbnail: Option<String>,
+    /// key of the text extracted from a PDF or DOCX document
+    pub text: Option<String>,
+    /// why the attachment's type is blocked, see `attachments::AttachmentPolicy`; dropped
+    /// attachments are replaced by a text placeholder at `rel_path`
+    pub blocked: Option<String>,
+    /// password-protected ZIP, 7z or RAR archive, see `archive::is_encrypted`
+    pub encrypted: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// How consumers read `headers.json` back.
+    #[derive(Debug, Deserialize)]
+    struct StoredHeaders {
+        schema_version: u32,
+        headers: BTreeMap<String, String>,
+    }
+
+    fn headers() -> BTreeMap<&'static str, Cow<'static, str>> {
+        BTreeMap::from([
+            ("To", Cow::Borrowed("b@example.com")),
+            ("From", Cow::Borrowed("a@example.com")),
+            ("Subject", Cow::Owned("Grüße".to_string())),
+        ])
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let headers = headers();
+        let json = serde_json::to_vec(&Headers::new(&headers)).unwrap();
+        let stored: StoredHeaders = serde_json::from_slice(&json).unwrap();
+        assert_eq!(stored.schema_version, SCHEMA_VERSION);
+        let expected: BTreeMap<_, _> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(stored.headers, expected);
+    }
+
+    #[test]
+    fn headers_golden() {
+        let headers = headers();
+        assert_eq!(
+            serde_json::to_string(&Headers::new(&headers)).unwrap(),
+            concat!(
+                r#"{"schema_version":1,"headers":"#,
+                r#"{"From":"a@example.com","Subject":"Grüße","To":"b@example.com"}}"#
+            )
+        );
+    }
+
+    /// Unversioned objects, stored before version 1, are the plain map of headers and
+    /// thus fail to parse as the current version.
+    #[test]
+    fn headers_unversioned_shape() {
+        let headers = headers();
+        let unversioned = serde_json::to_value(&headers).unwrap();
+        assert_eq!(
+            unversioned,
+            json!({ "From": "a@example.com", "Subject": "Grüße", "To": "b@example.com" })
+        );
+        assert!(serde_json::from_value::<StoredHeaders>(unversioned.clone()).is_err());
+
+        let versioned = serde_json::to_value(Headers::new(&headers)).unwrap();
+        assert_eq!(versioned["headers"], unversioned);
+    }
+}
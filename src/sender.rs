@@ -0,0 +1,72 @@
+use mail_parser::Message;
+use serde::Serialize;
+
+/// The header senders of a message and how they disagree with each other and the envelope,
+/// for phishing triage.
+#[derive(Debug, Default, Serialize)]
+pub struct Sender {
+    /// address of the first `From` mailbox
+    pub header_from: Option<String>,
+    /// address of the first `Reply-To` mailbox
+    pub reply_to: Option<String>,
+    /// `envelope` if the domain of the envelope sender is not aligned with the one of
+    /// `From`, `reply-to` if the one of `Reply-To` is not, and `display-name` if the display
+    /// name of `From` contains another address
+    pub mismatches: Vec<&'static str>,
+}
+
+impl Sender {
+    pub fn parse(envelope_from: &str, message: &Message) -> Self {
+        let from = message.from().and_then(|addrs| addrs.iter().next());
+        let header_from = from.and_then(|addr| addr.address()).map(str::to_lowercase);
+        let reply_to = message
+            .reply_to()
+            .and_then(|addrs| addrs.iter().next())
+            .and_then(|addr| addr.address())
+            .map(str::to_lowercase);
+
+        let mut mismatches = vec![];
+        if let Some(header_from) = header_from.as_deref() {
+            // bounces have no envelope sender to compare
+            if !envelope_from.is_empty() && !aligned(envelope_from, header_from) {
+                mismatches.push("envelope");
+            }
+            if reply_to
+                .as_deref()
+                .is_some_and(|reply_to| !aligned(reply_to, header_from))
+            {
+                mismatches.push("reply-to");
+            }
+            let name = from.and_then(|addr| addr.name()).unwrap_or("");
+            let spoofed = name
+                .split(|c: char| c.is_whitespace() || "<>\"'()".contains(c))
+                .any(|word| word.contains('@') && !word.eq_ignore_ascii_case(header_from));
+            if spoofed {
+                mismatches.push("display-name");
+            }
+        }
+
+        Sender {
+            header_from,
+            reply_to,
+            mismatches,
+        }
+    }
+}
+
+/// Whether the domains of both addresses are the same, or one a subdomain of the other
+/// (like relaxed DMARC alignment, without the public suffix list).
+fn aligned(a: &str, b: &str) -> bool {
+    let domain = |address: &str| {
+        let domain = address
+            .rsplit_once('@')
+            .map_or(address, |(_, domain)| domain);
+        domain.trim_end_matches('.').to_lowercase()
+    };
+    let (a, b) = (domain(a), domain(b));
+    let subdomain = |sub: &str, parent: &str| {
+        sub.strip_suffix(parent)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+    };
+    a == b || subdomain(&a, &b) || subdomain(&b, &a)
+}
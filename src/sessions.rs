@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+/// What an active SMTP connection is doing, shared with the admin API.
+#[derive(Debug)]
+pub struct Activity {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub submission: bool,
+    pub started: DateTime<Utc>,
+    /// bytes received so far
+    bytes: AtomicU64,
+    /// last command, e.g. `RCPT`
+    command: Mutex<&'static str>,
+    kick: Notify,
+}
+
+impl Activity {
+    pub fn received(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn command(&self, command: &'static str) {
+        *self.command.lock().unwrap() = command;
+    }
+
+    /// Resolves once the connection is to be closed.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub submission: bool,
+    pub started: DateTime<Utc>,
+    pub bytes: u64,
+    pub command: &'static str,
+}
+
+/// The active SMTP connections and whether new ones are accepted.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<Activity>>>,
+    paused: AtomicBool,
+    /// new connections are refused beyond this many
+    max_connections: Option<usize>,
+}
+
+impl Sessions {
+    pub fn new(max_connections: Option<usize>) -> Self {
+        Self {
+            max_connections,
+            ..Default::default()
+        }
+    }
+
+    /// Track a new connection until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, peer: SocketAddr, submission: bool) -> Registration {
+        let activity = Arc::new(Activity {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            peer,
+            submission,
+            started: Utc::now(),
+            bytes: AtomicU64::new(0),
+            command: Mutex::new("CONNECT"),
+            kick: Notify::new(),
+        });
+        self.active
+            .lock()
+            .unwrap()
+            .insert(activity.id, activity.clone());
+        Registration {
+            sessions: self.clone(),
+            activity,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|a| SessionInfo {
+                id: a.id,
+                peer: a.peer,
+                submission: a.submission,
+                started: a.started,
+                bytes: a.bytes.load(Ordering::Relaxed),
+                command: *a.command.lock().unwrap(),
+            })
+            .collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    /// Close the connection `id`, returns whether it exists.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.active.lock().unwrap().get(&id) {
+            Some(activity) => {
+                activity.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Turn new connections away, e.g. to drain the instance before shutting it down.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connection limit is reached.
+    pub fn full(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max| self.active.lock().unwrap().len() >= max)
+    }
+}
+
+/// Removes the connection from the active ones when dropped.
+pub struct Registration {
+    sessions: Arc<Sessions>,
+    pub activity: Arc<Activity>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.sessions
+            .active
+            .lock()
+            .unwrap()
+            .remove(&self.activity.id);
+    }
+}
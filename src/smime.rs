@@ -0,0 +1,150 @@
+use std::borrow::Cow;
+
+use anyhow::Result;
+use mail_parser::{MessageParser, MimeHeaders};
+use serde::Serialize;
+use tracing::{instrument, warn};
+
+#[cfg(feature = "smime")]
+pub mod openssl;
+
+/// What S/MIME processing found out about a message, stored with it.
+#[derive(Debug, Default, Serialize)]
+pub struct SmimeInfo {
+    pub encrypted: bool,
+    /// the message could be decrypted with one of the configured keys
+    pub decrypted: bool,
+    pub signed: bool,
+    /// the signature is valid and the signer's certificate trusted
+    pub verified: bool,
+    /// addresses, or common names, of the signing certificates
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The result of checking a signature.
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub valid: bool,
+    pub signers: Vec<String>,
+    pub error: Option<String>,
+    /// the signed entity of opaque (`signed-data`) messages
+    pub content: Option<Vec<u8>>,
+}
+
+pub trait Smime: Send + Sync {
+    /// Decrypt an `enveloped-data` message to the MIME entity it wraps.
+    fn decrypt(&self, message: &[u8]) -> Result<Vec<u8>>;
+    /// Check the signature of a `multipart/signed` or `signed-data` message.
+    fn verify(&self, message: &[u8]) -> Result<Verification>;
+}
+
+#[derive(Debug, PartialEq)]
+enum Wrapping {
+    Enveloped,
+    Signed,
+}
+
+/// How the message is protected, judging by its top level content type.
+fn wrapping(parser: &MessageParser, message: &[u8]) -> Option<Wrapping> {
+    let message = parser.parse(message)?;
+    let content_type = message.root_part().content_type()?;
+    let ctype = content_type.ctype().to_ascii_lowercase();
+    let subtype = content_type.subtype().unwrap_or("").to_ascii_lowercase();
+    match (ctype.as_str(), subtype.as_str()) {
+        ("multipart", "signed") => content_type
+            .attribute("protocol")
+            .is_some_and(|p| p.to_ascii_lowercase().ends_with("pkcs7-signature"))
+            .then_some(Wrapping::Signed),
+        ("application", "pkcs7-mime" | "x-pkcs7-mime") => {
+            match content_type.attribute("smime-type") {
+                Some(t) if t.eq_ignore_ascii_case("signed-data") => Some(Wrapping::Signed),
+                // older clients do not set smime-type
+                _ => Some(Wrapping::Enveloped),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Replace the content headers and body of the message with `entity`, keeping the other
+/// headers like `From` or `Subject`.
+fn replace_content(message: &[u8], entity: &[u8]) -> Vec<u8> {
+    let mut replaced = Vec::with_capacity(message.len().max(entity.len()));
+    let mut keep = true;
+    for line in message.split_inclusive(|b| *b == b'\n') {
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        // continuation lines belong to the previous header
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name = line
+                .split(|b| *b == b':')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            keep = !name.starts_with(b"content-") && name != b"mime-version";
+        }
+        if keep {
+            replaced.extend_from_slice(line);
+        }
+    }
+    replaced.extend_from_slice(b"MIME-Version: 1.0\r\n");
+    replaced.extend_from_slice(entity);
+    replaced
+}
+
+/// Decrypt the message and check its signatures, returning the unwrapped message to store
+/// while `data` is kept as received, e.g. for relaying. Messages that are signed, then
+/// encrypted are unwrapped in both steps. Returns `None` for messages without S/MIME.
+#[instrument(skip_all)]
+pub fn unwrap<'a>(
+    smime: &dyn Smime,
+    parser: &MessageParser,
+    data: &'a [u8],
+) -> Option<(SmimeInfo, Cow<'a, [u8]>)> {
+    let mut info = SmimeInfo::default();
+    let mut data = Cow::Borrowed(data);
+    loop {
+        match wrapping(parser, &data) {
+            Some(Wrapping::Enveloped) if !info.encrypted => {
+                info.encrypted = true;
+                match smime.decrypt(&data) {
+                    Ok(entity) => {
+                        data = Cow::Owned(replace_content(&data, &entity));
+                        info.decrypted = true;
+                    }
+                    Err(e) => {
+                        warn!("could not decrypt message: {:#}", e);
+                        info.error = Some(format!("{:#}", e));
+                        break;
+                    }
+                }
+            }
+            Some(Wrapping::Signed) if !info.signed => {
+                info.signed = true;
+                match smime.verify(&data) {
+                    Ok(verification) => {
+                        info.verified = verification.valid;
+                        info.signers = verification.signers;
+                        info.error = verification.error;
+                        // detached signatures leave the message as it is
+                        let Some(content) = verification.content else {
+                            break;
+                        };
+                        data = Cow::Owned(replace_content(&data, &content));
+                    }
+                    Err(e) => {
+                        warn!("could not verify signature: {:#}", e);
+                        info.error = Some(format!("{:#}", e));
+                        break;
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+    (info.encrypted || info.signed).then_some((info, data))
+}
@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use openssl::nid::Nid;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509Ref, X509};
+use tracing::{instrument, trace};
+
+use super::{Smime, Verification};
+
+/// Decrypts with the configured recipient keys and verifies signatures against the system
+/// trust store and additional CA certificates.
+pub struct OpensslSmime {
+    keys: Vec<(X509, PKey<Private>)>,
+    store: X509Store,
+}
+
+impl OpensslSmime {
+    /// `keys` are pairs of PEM encoded certificate and private key files.
+    #[instrument]
+    pub fn new(keys: &[(String, String)], ca_file: Option<&str>) -> Result<Self> {
+        let keys = keys
+            .iter()
+            .map(|(cert, key)| {
+                let cert_pem =
+                    std::fs::read(cert).with_context(|| format!("could not read {}", cert))?;
+                let key_pem =
+                    std::fs::read(key).with_context(|| format!("could not read {}", key))?;
+                Ok((
+                    X509::from_pem(&cert_pem)?,
+                    PKey::private_key_from_pem(&key_pem)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut store = X509StoreBuilder::new()?;
+        store.set_default_paths()?;
+        if let Some(ca_file) = ca_file {
+            let pem =
+                std::fs::read(ca_file).with_context(|| format!("could not read {}", ca_file))?;
+            for cert in X509::stack_from_pem(&pem)? {
+                store.add_cert(cert)?;
+            }
+        }
+        Ok(Self {
+            keys,
+            store: store.build(),
+        })
+    }
+}
+
+/// The addresses of the certificate, or its common name if it has none.
+fn signer_name(cert: &X509Ref) -> String {
+    let mut addresses: Vec<String> = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.email().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    if addresses.is_empty() {
+        let subject = cert.subject_name();
+        addresses.extend(
+            subject
+                .entries_by_nid(Nid::PKCS9_EMAILADDRESS)
+                .chain(subject.entries_by_nid(Nid::COMMONNAME))
+                .filter_map(|entry| entry.data().as_utf8().ok())
+                .map(|s| s.to_string())
+                .take(1),
+        );
+    }
+    addresses.join(", ")
+}
+
+impl Smime for OpensslSmime {
+    fn decrypt(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let (pkcs7, _) = Pkcs7::from_smime(message).context("could not parse S/MIME message")?;
+        for (cert, key) in &self.keys {
+            match pkcs7.decrypt(key, cert, Pkcs7Flags::empty()) {
+                Ok(entity) => return Ok(entity),
+                Err(e) => trace!("could not decrypt with {}: {}", signer_name(cert), e),
+            }
+        }
+        bail!("message is not encrypted to any configured certificate")
+    }
+
+    fn verify(&self, message: &[u8]) -> Result<Verification> {
+        let (pkcs7, detached) =
+            Pkcs7::from_smime(message).context("could not parse S/MIME message")?;
+        // only the certificates included in the message are used
+        let certs = Stack::<X509>::new()?;
+        let signers = pkcs7
+            .signers(&certs, Pkcs7Flags::empty())?
+            .iter()
+            .map(signer_name)
+            .collect();
+
+        let mut content = vec![];
+        let (valid, error) = match pkcs7.verify(
+            &certs,
+            &self.store,
+            detached.as_deref(),
+            Some(&mut content),
+            Pkcs7Flags::empty(),
+        ) {
+            Ok(()) => (true, None),
+            Err(e) => {
+                // the content of opaque messages is still extracted
+                content.clear();
+                if detached.is_none() {
+                    pkcs7.verify(
+                        &certs,
+                        &self.store,
+                        None,
+                        Some(&mut content),
+                        Pkcs7Flags::NOVERIFY | Pkcs7Flags::NOSIGS,
+                    )?;
+                }
+                (false, Some(e.to_string()))
+            }
+        };
+        Ok(Verification {
+            valid,
+            signers,
+            error,
+            content: detached.is_none().then_some(content),
+        })
+    }
+}
@@ -1,67 +1,152 @@
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use bytes::BytesMut;
+use chrono::Utc;
 use futures::{Stream, TryStreamExt};
-use mail_parser::MessageParser;
+use ipnet::IpNet;
+use mail_parser::{Message, MessageParser, MimeHeaders};
 use rustyknife::rfc5321::{ForwardPath, Param, ReversePath};
 use rustyknife::types::{Domain, DomainPart, Mailbox};
-use smtpbis::{EhloKeywords, Reply};
+use serde_json::json;
+use smtpbis::{EhloKeywords, EnhancedCode, Reply};
 use sqlx::PgPool;
-use tokio_rustls::rustls::ServerConfig;
-use tracing::{error, instrument, trace, warn};
+use tokio_rustls::rustls::{ServerConfig, ServerConnection};
+use tracing::{error, info, instrument, trace, warn, Span};
+use unicode_normalization::UnicodeNormalization;
 
+use crate::allowlist::{NullSender, PairAllowlist};
+use crate::archive;
+use crate::attachments::{AttachmentLimits, AttachmentPolicy, PolicyAction};
+use crate::auth::{Authenticator, Sasl, SharedLogin};
+use crate::bodies::BodyKeys;
 use crate::db;
+use crate::directory::Directory;
+use crate::dsn::{self, EnvelopeDsn, Failure, RcptDsn};
+use crate::encryption::{Decryption, Encryption};
+use crate::events::Events;
+use crate::extract::Extraction;
+use crate::faults::Faults;
+use crate::filter::{ContentFilterHook, Verdict};
+use crate::helo::HeloChecks;
+use crate::limits::{self, Buffered};
+use crate::metrics;
+use crate::milter::{Milter, MilterResult, MilterSession};
+use crate::plugin::{Plugin, PluginAction, PluginInput};
+use crate::queue::{Queue, QueuedEnvelope};
+use crate::rdns::{Policy, Rdns, ReverseDns};
+use crate::redact::Redactions;
+use crate::relay::Relay;
+use crate::replies::{self, ReplyTexts};
+use crate::reports;
+use crate::rules::{self, Rules};
 use crate::s3;
+use crate::smime::{self, Smime};
+use crate::status::Status;
+use crate::storage::Storage;
+use crate::tarpit::Tarpit;
+use crate::tenant::{Tenant, Tenants};
+use crate::thumbnail::Thumbnails;
+use crate::tls::TlsInfo;
+use crate::trace::{self};
+use crate::transcript::Transcripts;
+use crate::xclient::{Proxy, SharedForwarding};
 
+pub const MAX_MESSAGE_SIZE: usize = 100_000_000;
+/// RFC 5321 requires accepting at least 100 recipients.
+pub const MAX_RECIPIENTS: usize = 100;
+
+/// Outcome of processing a message.
+pub enum Delivery {
+    /// The message was refused for all recipients.
+    Refused(Reply),
+    /// Per recipient results of storing the message.
+    Delivered(Vec<(String, Result<()>)>),
+}
+
+#[derive(Clone)]
 pub struct SmtpBackend {
     pub config: Arc<ArcSwap<Config>>,
 }
 
+/// Parse the domain used for unqualified recipients.
+pub fn parse_domain(domain: &str) -> Result<DomainPart> {
+    DomainPart::from_smtp(domain.as_bytes())
+        .map_err(|e| anyhow!("could not parse SMTP_DOMAIN: {}", e))
+}
+
+/// Parse the `SIZE` parameter (RFC 1870) of a MAIL command.
+fn size_param(params: &[Param]) -> Result<Option<u64>, Reply> {
+    let Some(Param(_, value)) = params
+        .iter()
+        .find(|Param(keyword, _)| keyword.to_string().eq_ignore_ascii_case("SIZE"))
+    else {
+        return Ok(None);
+    };
+    value
+        .as_ref()
+        .and_then(|v| v.to_string().parse().ok())
+        .map(Some)
+        .ok_or_else(|| Reply::new(501, Some(EnhancedCode(5, 5, 4)), "invalid SIZE parameter"))
+}
+
+/// Format an address in NFC with a lower case domain, so that equivalent UTF-8 addresses
+/// end up in the same S3 prefix and DB rows.
+pub fn normalize_address(mailbox: impl Display, domain: impl Display) -> String {
+    let mailbox: String = mailbox.to_string().nfc().collect();
+    let domain: String = domain.to_string().nfc().collect();
+    format!("{}@{}", mailbox, domain.to_lowercase())
+}
+
 impl SmtpBackend {
-    #[allow(clippy::too_many_arguments)]
-    #[instrument(skip(s3_config, pg_pool, tls_config))]
-    pub fn new(
-        s3_config: aws_sdk_s3::Config,
-        pg_pool: PgPool,
-        tls_config: Arc<ServerConfig>,
-        domain: &str,
-        bucket: &str,
-        allowed_rcpts: Option<HashSet<String>>,
-        allowed_froms: Option<HashSet<String>>,
-        check_db: bool,
-    ) -> Result<SmtpBackend> {
-        let bucket = bucket.to_string();
-        let domain: DomainPart = DomainPart::from_smtp(domain.as_bytes())
-            .map_err(|e| anyhow!("could not parse SMTP_DOMAIN: {}", e))?;
-
-        let config = Arc::new(ArcSwap::from_pointee(Config {
-            s3_config,
-            pg_pool,
-            tls_config,
-            domain,
-            bucket,
-            allowed_rcpts,
-            allowed_froms,
-            check_db,
-        }));
+    #[instrument(skip_all)]
+    pub fn new(config: Config) -> SmtpBackend {
+        let config = Arc::new(ArcSwap::from_pointee(config));
         trace!("got config");
-        Ok(SmtpBackend { config })
+        SmtpBackend { config }
     }
 
     #[instrument(skip_all)]
-    pub fn new_session(&self) -> Result<SmtpSession> {
+    pub fn new_session(&self, peer: Option<SocketAddr>) -> Result<SmtpSession> {
         let message_parser = MessageParser::default();
         let config = self.config.load_full();
         Ok(SmtpSession {
             message_parser,
             config,
-            rcpt: None,
+            peer,
+            helo: None,
+            protocol: "SMTP",
+            tls: None,
+            rcpts: vec![],
+            // local sessions, e.g. of the HTTP API, do not greet
+            state: if peer.is_some() {
+                State::Connected
+            } else {
+                State::Idle
+            },
             from: None,
             data: vec![],
+            buffered: Buffered::default(),
+            milter: None,
+            discard: false,
+            dsn: EnvelopeDsn::default(),
+            rcpt_dsn: HashMap::new(),
+            declared_size: None,
+            max_size: MAX_MESSAGE_SIZE,
+            tenants: HashMap::new(),
+            aliases: HashMap::new(),
+            deliveries: HashMap::new(),
+            forwarding: SharedForwarding::default(),
+            rdns: None,
+            submission: false,
+            login: SharedLogin::default(),
         })
     }
 }
@@ -69,55 +154,1208 @@ impl SmtpBackend {
 pub struct Config {
     pub s3_config: aws_sdk_s3::Config,
     pub pg_pool: PgPool,
-    pub tls_config: Arc<ServerConfig>,
+    pub tls_config: Option<Arc<ServerConfig>>,
     pub domain: DomainPart,
     pub bucket: String,
     pub allowed_rcpts: Option<HashSet<String>>,
     pub allowed_froms: Option<HashSet<String>>,
+    pub allowed_pairs: Option<PairAllowlist>,
+    /// whether bounces pass the sender allowlists
+    pub null_sender: NullSender,
     pub check_db: bool,
+    /// looks up whether recipients exist, e.g. in LDAP
+    pub directory: Option<Arc<dyn Directory>>,
+    pub content_filter: Option<ContentFilterHook>,
+    pub milter: Option<Milter>,
+    pub presigned_url_expiry: Duration,
+    /// record presigned URLs with every stored message
+    pub presign_on_upload: bool,
+    pub events: Events,
+    /// `pg_notify` this channel after inserting a message
+    pub pg_notify_channel: Option<String>,
+    pub relay: Option<Relay>,
+    /// bounce recipients that could not be stored instead of refusing the message
+    pub bounces: bool,
+    pub replies: ReplyTexts,
+    /// maximum message size by recipient address or domain
+    pub size_limits: HashMap<String, usize>,
+    pub size_limits_in_db: bool,
+    /// recipients accepted per transaction
+    pub max_recipients: usize,
+    /// message content buffered by all sessions before new messages are refused
+    pub max_buffered_bytes: Option<usize>,
+    /// recipients and sender domains counted individually in the metrics
+    pub metric_labels: metrics::Labels,
+    /// delays replies to clients with recent rejections
+    pub tarpit: Option<Tarpit>,
+    /// only for resilience testing
+    pub faults: Option<Faults>,
+    /// canonical storage identities keyed by address or local part, e.g. `sales@`
+    pub aliases: HashMap<String, String>,
+    pub aliases_in_db: bool,
+    pub rules: Option<Rules>,
+    /// attachment types to refuse, drop or quarantine
+    pub attachment_policy: Option<AttachmentPolicy>,
+    pub attachment_limits: Option<AttachmentLimits>,
+    /// quarantine messages with password-protected archives
+    pub quarantine_encrypted_archives: bool,
+    pub plugin: Option<Box<dyn Plugin>>,
+    pub tenants: Tenants,
+    /// enforce and count quotas in the DB
+    pub quotas: bool,
+    /// relays skipped when determining the originating client from the `Received` headers
+    pub trusted_relays: Vec<IpNet>,
+    /// proxies allowed to send `XCLIENT` and `XFORWARD`
+    pub xclient_trusted: Vec<IpNet>,
+    /// rejects HELO names, e.g. bare IP literals
+    pub helo_checks: HeloChecks,
+    /// looks up and optionally requires the client's reverse DNS name
+    pub rdns: Option<ReverseDns>,
+    /// checks the credentials of submission clients
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// recipients whose DMARC aggregate reports are inserted into the DB
+    pub dmarc_rua: HashSet<String>,
+    /// recipients whose SMTP TLS reports are inserted into the DB
+    pub tls_rua: HashSet<String>,
+    /// remove scripts, remote images and dangerous attributes from `body.html`
+    pub sanitize_html: bool,
+    /// decrypts S/MIME messages and verifies their signatures
+    pub smime: Option<Box<dyn Smime>>,
+    /// encrypts the message, bodies and attachments before uploading them
+    pub encryption: Option<Box<dyn Encryption>>,
+    /// decrypts objects encrypted by `encryption`, e.g. to export messages
+    pub decryption: Option<Box<dyn Decryption>>,
+    /// encrypts `body_text` and `body_html` in the DB
+    pub body_keys: Option<BodyKeys>,
+    /// replaces personal data in bodies and headers before storing them
+    pub redactions: Option<Redactions>,
+    /// writes transcripts of failed sessions
+    pub transcripts: Option<Transcripts>,
+    /// storage classes and lifecycle tags by object kind
+    pub storage: Storage,
+    pub mirror: Option<s3::Mirror>,
+    /// write `index/YYYY-MM-DD/` entries for every message
+    pub day_index: bool,
+    /// store the language of `body_text`
+    pub detect_language: bool,
+    pub thumbnails: Option<Thumbnails>,
+    /// extracts the text of PDF and DOCX attachments
+    pub extraction: Option<Extraction>,
+    /// store messages in the background after replying to DATA
+    pub queue: Option<Arc<Queue>>,
+    /// record the status of every message in the DB
+    pub track_status: bool,
+}
+
+/// A line of LMTP `DATA` as it is stored after the content `received` so far: dot-unstuffed
+/// if it starts a line and with a trailing bare LF as CRLF. Parts of long lines are kept as
+/// they are.
+pub fn data_line<'a>(received: &[u8], line: &'a [u8]) -> Cow<'a, [u8]> {
+    let line_start = received.is_empty() || received.ends_with(b"\n");
+    let line = match line.strip_prefix(b".") {
+        Some(unstuffed) if line_start => unstuffed,
+        _ => line,
+    };
+    match line.strip_suffix(b"\n") {
+        // the LF of a CRLF split between reads
+        Some(b"") if received.ends_with(b"\r") => Cow::Borrowed(line),
+        Some(content) if !content.ends_with(b"\r") => {
+            let mut line = content.to_vec();
+            line.extend_from_slice(b"\r\n");
+            Cow::Owned(line)
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
+/// Read the lines of SMTP `DATA` into `receive`, as they are: smtpbis already unstuffs dots.
+/// Returns the number of lines and whether `receive` refused any, e.g. because it is too big.
+async fn read_data<S>(
+    stream: &mut S,
+    mut receive: impl FnMut(&[u8]) -> bool,
+) -> Result<(usize, bool), smtpbis::ServerError>
+where
+    S: Stream<Item = Result<BytesMut, smtpbis::LineError>> + Unpin + Send,
+{
+    let mut nb_lines: usize = 0;
+    let mut refused = false;
+    while let Some(line) = stream.try_next().await? {
+        refused = refused || !receive(&line);
+        nb_lines += 1
+    }
+    Ok((nb_lines, refused))
+}
+
+/// Where the session is in a mail transaction, commands out of sequence get a 503.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum State {
+    /// SMTP clients have to send HELO or EHLO first
+    Connected,
+    /// no transaction, e.g. after HELO, RSET or a delivered message
+    #[default]
+    Idle,
+    /// after MAIL
+    Mail,
+    /// after at least one accepted RCPT
+    Rcpt,
+    /// receiving the content, possibly in several BDAT chunks
+    Data,
+}
+
+impl State {
+    /// The reply refusing `command`, e.g. `RCPT`, if it is not valid in this state. Other
+    /// commands than MAIL, RCPT, DATA and BDAT are always valid.
+    pub fn out_of_sequence(self, command: &str) -> Option<Reply> {
+        let text = match (command, self) {
+            ("MAIL", State::Idle)
+            | ("RCPT", State::Mail | State::Rcpt)
+            | ("DATA" | "BDAT", State::Rcpt | State::Data) => return None,
+            ("MAIL", State::Connected) => "send HELO or EHLO first",
+            ("MAIL", _) => "nested MAIL command",
+            ("RCPT" | "DATA" | "BDAT", State::Connected | State::Idle) => "need MAIL command",
+            ("DATA" | "BDAT", State::Mail) => "need RCPT command",
+            ("RCPT", State::Data) => "bad sequence of commands",
+            _ => return None,
+        };
+        Some(Reply::new(503, Some(EnhancedCode(5, 5, 1)), text))
+    }
+
+    /// The state after `command` succeeded. RSET ends the transaction, as does a delivered
+    /// message, but does not replace HELO.
+    pub fn after(self, command: &str) -> State {
+        match (command, self) {
+            ("HELO" | "EHLO" | "LHLO", _) => State::Idle,
+            ("RSET", State::Connected) => State::Connected,
+            ("RSET", _) => State::Idle,
+            ("MAIL", _) => State::Mail,
+            ("RCPT", _) => State::Rcpt,
+            ("DATA" | "BDAT", _) => State::Data,
+            _ => self,
+        }
+    }
 }
 
 pub struct SmtpSession {
     pub config: Arc<Config>,
     pub message_parser: MessageParser,
-    pub rcpt: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub helo: Option<String>,
+    /// protocol for the `Received` header, e.g. `ESMTP`
+    pub protocol: &'static str,
+    /// negotiated TLS version, cipher and server name
+    pub tls: Option<TlsInfo>,
+    pub rcpts: Vec<String>,
+    pub state: State,
+    /// empty for the null reverse-path `<>`
     pub from: Option<String>,
     pub data: Vec<u8>,
+    /// `data`'s share of the process-wide buffered bytes
+    pub buffered: Buffered,
+    pub milter: Option<MilterSession>,
+    /// accept the message, but do not store it
+    pub discard: bool,
+    pub dsn: EnvelopeDsn,
+    pub rcpt_dsn: HashMap<String, RcptDsn>,
+    /// `SIZE` parameter of the MAIL command
+    pub declared_size: Option<u64>,
+    /// smallest maximum message size of the accepted recipients
+    pub max_size: usize,
+    /// tenants of the recipients
+    pub tenants: HashMap<String, Arc<Tenant>>,
+    /// canonical storage identities of aliased recipients
+    pub aliases: HashMap<String, String>,
+    /// the original client, as told by a trusted proxy
+    pub forwarding: SharedForwarding,
+    /// reverse DNS name of the client
+    pub rdns: Option<Rdns>,
+    /// require AUTH before MAIL
+    pub submission: bool,
+    /// account the client authenticated as
+    pub login: SharedLogin,
+    /// rows tracking the status of the message by recipient
+    pub deliveries: HashMap<String, i64>,
 }
 
 impl SmtpSession {
     #[instrument(skip(self))]
     fn reset(&mut self) {
         trace!("resetting session");
+        self.state = self.state.after("RSET");
         self.from = None;
-        self.rcpt = None;
+        self.rcpts = vec![];
         self.data = vec![];
+        self.buffered.set(0);
+        self.milter = None;
+        self.discard = false;
+        self.dsn = EnvelopeDsn::default();
+        self.rcpt_dsn.clear();
+        self.declared_size = None;
+        self.max_size = MAX_MESSAGE_SIZE;
+        self.tenants.clear();
+        self.aliases.clear();
+        self.deliveries.clear();
+        self.forwarding.lock().unwrap().end_transaction();
     }
 
-    async fn handle_data(&mut self) -> Result<()> {
-        let from = self.from.take().unwrap();
-        let rcpt = self.rcpt.take().unwrap();
-        let message = self
+    /// Start over as after connecting if `XCLIENT` was sent, the client has to send HELO or
+    /// EHLO again.
+    fn restart_after_xclient(&mut self) {
+        if self.forwarding.lock().unwrap().take_restart() {
+            trace!("restarting session after XCLIENT");
+            self.reset();
+            self.state = State::Connected;
+            self.helo = None;
+        }
+    }
+
+    /// Whether the peer may send `XCLIENT` and `XFORWARD`.
+    fn trusted_proxy(&self) -> bool {
+        self.peer.is_some_and(|peer| {
+            self.config
+                .xclient_trusted
+                .iter()
+                .any(|net| net.contains(&peer.ip()))
+        })
+    }
+
+    /// Handles `XCLIENT` and `XFORWARD` of a trusted proxy.
+    pub fn proxy(&self) -> Option<Proxy> {
+        if !self.trusted_proxy() {
+            return None;
+        }
+        let domain = self.config.domain.to_string();
+        let greeting = match self.config.replies.banner.as_ref() {
+            Some(banner) => replies::render(banner, &[("domain", &domain)]),
+            None => format!("{} ESMTP", domain),
+        };
+        Some(Proxy::new(self.forwarding.clone(), greeting))
+    }
+
+    /// Handles `AUTH` of submission clients.
+    pub fn sasl(&self) -> Option<Sasl> {
+        if !self.submission {
+            return None;
+        }
+        let authenticator = self.config.authenticator.clone()?;
+        Some(Sasl::new(authenticator, self.login.clone()))
+    }
+
+    /// Reject a recipient, slowing down further replies to the client.
+    fn rejected(&self, ecode: EnhancedCode) -> Reply {
+        if let Some((tarpit, client)) = self.config.tarpit.as_ref().zip(self.client_addr()) {
+            tarpit.rejected(client.ip());
+        }
+        self.config.replies.rejected(ecode)
+    }
+
+    /// Wait before replying to clients with recent rejections.
+    async fn tarpit(&self) {
+        let Some((tarpit, client)) = self.config.tarpit.as_ref().zip(self.client_addr()) else {
+            return;
+        };
+        let delay = tarpit.delay(client.ip());
+        if !delay.is_zero() {
+            info!("delaying reply to {} by {:?}", client.ip(), delay);
+            metrics::TARPIT_DELAYS.inc();
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// The client's address, as told by a trusted proxy or the peer's.
+    fn client_addr(&self) -> Option<SocketAddr> {
+        let forwarding = self.forwarding.lock().unwrap();
+        match forwarding.addr() {
+            Some(ip) => Some(SocketAddr::new(ip, forwarding.port().unwrap_or(0))),
+            None => self.peer,
+        }
+    }
+
+    /// The client's reverse DNS name, as told by a trusted proxy or looked up once per
+    /// address.
+    async fn client_rdns(&self, reverse_dns: &ReverseDns) -> Result<Option<Rdns>> {
+        let Some(addr) = self.client_addr().map(|c| c.ip()) else {
+            return Ok(None);
+        };
+        // proxies only forward verified names
+        if let Some(name) = self.forwarding.lock().unwrap().name() {
+            return Ok(Some(Rdns {
+                addr,
+                name: Some(name),
+                confirmed: true,
+            }));
+        }
+        if let Some(rdns) = self.rdns.as_ref().filter(|rdns| rdns.addr == addr) {
+            return Ok(Some(rdns.clone()));
+        }
+        reverse_dns.lookup(addr).await.map(Some)
+    }
+
+    /// Record the client's reverse DNS name, returns the rejection if it does not satisfy
+    /// `REQUIRE_RDNS`. Authenticated clients are not rejected.
+    async fn check_rdns(&mut self) -> Option<Reply> {
+        let config = self.config.clone();
+        let reverse_dns = config.rdns.as_ref()?;
+        let enforce = self.login.lock().unwrap().is_none();
+        match self.client_rdns(reverse_dns).await {
+            Ok(rdns) => {
+                let rdns = rdns?;
+                let allowed = reverse_dns.policy.allows(&rdns);
+                self.rdns = Some(rdns);
+                if enforce && !allowed {
+                    warn!("rejected client with reverse DNS {:?}", self.rdns);
+                    return Some(Reply::new(
+                        550,
+                        Some(EnhancedCode(5, 7, 25)),
+                        "reverse DNS validation failed",
+                    ));
+                }
+            }
+            Err(e) => {
+                warn!("could not look up reverse DNS: {:?}", e);
+                if enforce && reverse_dns.policy != Policy::Any {
+                    return Some(Reply::new(
+                        451,
+                        Some(EnhancedCode(4, 7, 25)),
+                        "reverse DNS lookup failed, try again later",
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// The rejection of a HELO name failing the configured checks, names of trusted proxies
+    /// and submission clients are not checked.
+    fn check_helo(&self, helo: &str) -> Option<Reply> {
+        if self.submission || self.trusted_proxy() {
+            return None;
+        }
+        let domain = self.config.domain.to_string();
+        self.config.helo_checks.check(helo, &domain)?;
+        Some(Reply::new(
+            550,
+            Some(EnhancedCode(5, 7, 1)),
+            "HELO name rejected",
+        ))
+    }
+
+    /// The client's HELO name, as told by a trusted proxy or sent by the peer.
+    fn client_helo(&self) -> Option<String> {
+        let forwarded = self.forwarding.lock().unwrap().helo();
+        forwarded.or_else(|| self.helo.clone())
+    }
+
+    /// The canonical storage identity `rcpt` is an alias of, configured by address or by
+    /// local part in any domain, e.g. `sales@`.
+    async fn resolve_alias(&self, rcpt: &str) -> Result<Option<String>> {
+        let rcpt = rcpt.to_lowercase();
+        let local_part = match rcpt.rsplit_once('@') {
+            Some((local_part, _)) => format!("{}@", local_part),
+            None => format!("{}@", rcpt),
+        };
+        let aliases = &self.config.aliases;
+        if let Some(canonical) = aliases.get(&rcpt).or_else(|| aliases.get(&local_part)) {
+            return Ok(Some(canonical.clone()));
+        }
+        if self.config.aliases_in_db {
+            return db::get_alias(&self.config.pg_pool, &rcpt, &local_part).await;
+        }
+        Ok(None)
+    }
+
+    /// The maximum message size for `rcpt`, from the configured limits, its tenant or the
+    /// DB.
+    async fn rcpt_max_size(&self, rcpt: &str, tenant: Option<&Tenant>) -> Result<usize> {
+        let domain = rcpt.rsplit_once('@').map_or(rcpt, |(_, domain)| domain);
+        let limits = &self.config.size_limits;
+        if let Some(limit) = limits.get(rcpt).or_else(|| limits.get(domain)) {
+            return Ok(MAX_MESSAGE_SIZE.min(*limit));
+        }
+        if let Some(limit) = tenant.and_then(|t| t.max_size) {
+            return Ok(MAX_MESSAGE_SIZE.min(limit));
+        }
+        if self.config.size_limits_in_db {
+            if let Some(limit) = db::max_message_size(&self.config.pg_pool, rcpt, domain).await? {
+                return Ok(MAX_MESSAGE_SIZE.min(usize::try_from(limit).unwrap_or(0)));
+            }
+        }
+        Ok(MAX_MESSAGE_SIZE)
+    }
+
+    /// The quota keys of a recipient, its address and its tenant's domain.
+    fn quota_keys(&self, rcpt: &str) -> Vec<String> {
+        let mut keys = vec![rcpt.to_lowercase()];
+        if let Some(tenant) = self.tenants.get(rcpt) {
+            keys.push(tenant.domain.clone());
+        }
+        keys
+    }
+
+    /// The first quota of the recipient that is exhausted, if any.
+    async fn exceeded_quota(&self, rcpt: &str) -> Result<Option<String>> {
+        let size = self.declared_size.unwrap_or(0) as i64;
+        let usage = db::quota_usage(&self.config.pg_pool, &self.quota_keys(rcpt)).await?;
+        Ok(usage
+            .into_iter()
+            .find(|u| {
+                u.max_messages.is_some_and(|max| u.messages >= max)
+                    || u.max_bytes.is_some_and(|max| u.bytes + size > max)
+            })
+            .map(|u| u.key))
+    }
+
+    /// Count the stored message against the recipient's quotas, failures are only logged.
+    async fn record_quota_usage(&self, rcpt: &str) {
+        let size = self.data.len() as i64;
+        match db::record_usage(&self.config.pg_pool, &self.quota_keys(rcpt), size).await {
+            Ok(keys) => {
+                for key in keys {
+                    metrics::QUOTA_MESSAGES.with_label_values(&[&key]).inc();
+                    metrics::QUOTA_BYTES
+                        .with_label_values(&[&key])
+                        .inc_by(size as u64);
+                }
+            }
+            Err(e) => error!("could not record quota usage: {:?}", e),
+        }
+    }
+
+    /// Preallocate the buffer for a message of `size` bytes, as declared with `SIZE` or
+    /// `BDAT`, so large messages are not copied again with every reallocation.
+    pub fn expect_content(&mut self, size: u64) {
+        let size = size.min(self.max_size as u64) as usize;
+        self.data.reserve(size.saturating_sub(self.data.len()));
+        self.buffered.set(self.data.capacity());
+    }
+
+    /// Append received content, returns `false` if the message would exceed the maximum
+    /// size; nothing is appended then.
+    pub fn receive_content(&mut self, content: &[u8]) -> bool {
+        if self.data.len().saturating_add(content.len()) > self.max_size {
+            return false;
+        }
+        self.data.extend_from_slice(content);
+        self.buffered.set(self.data.capacity());
+        true
+    }
+
+    /// Append a line of LMTP `DATA` as returned by `data_line`, so the stored message is what
+    /// the client sent, e.g. for DKIM signatures.
+    pub fn receive_line(&mut self, line: &[u8]) -> bool {
+        let line = data_line(&self.data, line);
+        self.receive_content(&line)
+    }
+
+    /// Refuse new messages while too much content is buffered, instead of risking the OOM
+    /// killer.
+    fn over_buffer_limit(&self) -> Option<Reply> {
+        let max = self.config.max_buffered_bytes?;
+        let buffered = limits::buffered_bytes();
+        if buffered < max {
+            return None;
+        }
+        warn!("refused message with {} bytes buffered", buffered);
+        metrics::RESOURCE_LIMITS
+            .with_label_values(&["buffered_bytes"])
+            .inc();
+        Some(Reply::new(
+            452,
+            Some(EnhancedCode(4, 3, 1)),
+            "insufficient system resources, try again later",
+        ))
+    }
+
+    pub fn too_big(&self) -> Reply {
+        Reply::new(
+            552,
+            Some(EnhancedCode(5, 3, 4)),
+            "message exceeds maximum message size",
+        )
+    }
+
+    /// Map a milter step's result to a reply, if the command should be refused.
+    fn milter_outcome(&mut self, result: Result<MilterResult>) -> Option<Reply> {
+        match result {
+            Ok(MilterResult::Continue) | Ok(MilterResult::Accept) => None,
+            Ok(MilterResult::Discard) => {
+                warn!("discarding mail due to milter");
+                self.discard = true;
+                None
+            }
+            Ok(MilterResult::Reply(reply)) => {
+                warn!("rejected mail due to milter");
+                Some(reply)
+            }
+            Err(e) => {
+                error!("milter failed: {:?}", e);
+                self.milter = None;
+                if self.config.milter.as_ref().is_some_and(|m| m.fail_open) {
+                    None
+                } else {
+                    Some(self.config.replies.temp_failure())
+                }
+            }
+        }
+    }
+
+    /// Start a milter session and run the connect, HELO and MAIL steps.
+    async fn milter_mail(&mut self, milter: &Milter, from: &str) -> Result<MilterResult> {
+        let mut session = milter.connect().await?;
+        let client = self.client_addr();
+        let forwarded_name = self.forwarding.lock().unwrap().name();
+        let hostname = forwarded_name
+            .or_else(|| client.map(|c| c.ip().to_string()))
+            .unwrap_or_else(|| "localhost".to_string());
+        let domain = self.config.domain.to_string();
+
+        let mut result = session.connect(&hostname, client, &domain).await?;
+        if matches!(result, MilterResult::Continue) {
+            let helo = self.client_helo().unwrap_or_else(|| hostname.clone());
+            result = session.helo(&helo).await?;
+        }
+        if matches!(result, MilterResult::Continue) {
+            result = session.mail(from).await?;
+        }
+        self.milter = Some(session);
+        Ok(result)
+    }
+
+    /// Returns a reply when the message was not accepted.
+    ///
+    /// With bounces enabled, a message stored for some recipients is accepted and the
+    /// remaining recipients are bounced, as there is only a single reply after DATA.
+    async fn handle_data(&mut self) -> Result<Option<Reply>> {
+        let from = self.from.clone();
+        let result = self.process_message_counted().await;
+        // the bounce returns the content, so reset afterwards
+        let reply = self.reply_or_bounce(from, result).await;
+        self.reset();
+        reply
+    }
+
+    /// The reply to the message, failed recipients are bounced if the message was stored for
+    /// others, so the client does not send it again.
+    async fn reply_or_bounce(
+        &self,
+        from: Option<String>,
+        result: Result<Delivery>,
+    ) -> Result<Option<Reply>> {
+        let (stored, failed): (Vec<_>, Vec<_>) = match result? {
+            Delivery::Refused(reply) => return Ok(Some(reply)),
+            Delivery::Delivered(results) => {
+                results.into_iter().partition(|(_, result)| result.is_ok())
+            }
+        };
+        let bounce_to = from.filter(|from| self.config.bounces && !from.is_empty());
+        let Some(from) = bounce_to.filter(|_| !stored.is_empty()) else {
+            for (_, result) in failed {
+                result?;
+            }
+            return Ok(None);
+        };
+
+        let failed = failed
+            .into_iter()
+            .filter_map(|(rcpt, result)| result.err().map(|e| (rcpt, e)));
+        let failed = Failure::notified(&self.rcpt_dsn, failed);
+        // the stored recipients are accepted anyway
+        if let Err(e) = dsn::send_bounce(&self.config, &from, &self.dsn, &failed, &self.data).await
+        {
+            error!("could not send bounce to {}: {:?}", from, e);
+        }
+        Ok(None)
+    }
+
+    /// Insert the attached DMARC aggregate reports into the DB, failures are only logged as
+    /// the message is stored anyway.
+    async fn ingest_dmarc_reports(&self, message: &Message<'_>) {
+        let message_id = message.message_id().unwrap_or_default();
+        let reports = match reports::dmarc_reports(message) {
+            Ok(reports) => reports,
+            Err(e) => {
+                error!("could not read DMARC reports: {:?}", e);
+                return;
+            }
+        };
+        for report in reports {
+            if let Err(e) = db::insert_dmarc_report(&self.config.pg_pool, message_id, &report).await
+            {
+                error!("could not insert DMARC report: {:?}", e);
+            }
+        }
+    }
+
+    /// Insert the attached SMTP TLS reports into the DB, failures are only logged as the
+    /// message is stored anyway.
+    async fn ingest_tls_reports(&self, message: &Message<'_>) {
+        let message_id = message.message_id().unwrap_or_default();
+        let reports = match reports::tls_reports(message) {
+            Ok(reports) => reports,
+            Err(e) => {
+                error!("could not read TLS reports: {:?}", e);
+                return;
+            }
+        };
+        for report in reports {
+            if let Err(e) = db::insert_tls_report(&self.config.pg_pool, message_id, &report).await {
+                error!("could not insert TLS report: {:?}", e);
+            }
+        }
+    }
+
+    /// Record the negotiated TLS parameters for the `Received` header and storage.
+    pub fn tls_started(&mut self, connection: &ServerConnection) {
+        let tls = TlsInfo::from_connection(connection);
+        trace!("TLS started {:?}", tls);
+        metrics::TLS_SESSIONS
+            .with_label_values(&[
+                tls.version.as_deref().unwrap_or(""),
+                tls.cipher.as_deref().unwrap_or(""),
+            ])
+            .inc();
+        self.tls = Some(tls);
+    }
+
+    /// The `Received` header to prepend to the message (RFC 5321 section 4.4).
+    fn received_header(&self, rcpts: &[String]) -> String {
+        let now = Utc::now();
+        let ip = self.client_addr().map(|c| c.ip());
+        // only forward-confirmed names, like other MTAs
+        let name = self
+            .rdns
+            .as_ref()
+            .filter(|rdns| rdns.confirmed && Some(rdns.addr) == ip)
+            .and_then(|rdns| rdns.name.as_deref());
+        let mut header = match (self.client_helo().as_deref(), ip) {
+            (Some(helo), Some(ip)) => match name {
+                Some(name) => format!("Received: from {} ({} [{}])\r\n\t", helo, name, ip),
+                None => format!("Received: from {} ([{}])\r\n\t", helo, ip),
+            },
+            (None, Some(ip)) => format!("Received: from [{}]\r\n\t", ip),
+            (Some(helo), None) => format!("Received: from {}\r\n\t", helo),
+            (None, None) => "Received: ".to_string(),
+        };
+        if let Some(tls) = self.tls.as_ref() {
+            header.push_str(&format!("({})\r\n\t", tls));
+        }
+        let mut protocol = match self.tls {
+            Some(_) => format!("{}S", self.protocol),
+            None => self.protocol.to_string(),
+        };
+        // RFC 3848
+        if self.login.lock().unwrap().is_some() {
+            protocol.push('A');
+        }
+        header.push_str(&format!(
+            "by {} with {} id {:x}",
+            self.config.domain,
+            protocol,
+            now.timestamp_nanos_opt().unwrap_or_default()
+        ));
+        // like other MTAs, only name a single recipient
+        if let [rcpt] = rcpts {
+            header.push_str(&format!("\r\n\tfor <{}>", rcpt));
+        }
+        header.push_str(&format!(";\r\n\t{}\r\n", now.to_rfc2822()));
+        header
+    }
+
+    /// Run the milter and content filter, then store the message for every recipient.
+    #[instrument(skip_all, fields(from = self.from, message_id))]
+    pub async fn process_message(&mut self) -> Result<Delivery> {
+        let result = self.process_message_counted().await;
+        self.reset();
+        result
+    }
+
+    /// Process the message without resetting the session, which still holds the content.
+    async fn process_message_counted(&mut self) -> Result<Delivery> {
+        let from = self.from.clone();
+        let rcpts = self.rcpts.clone();
+        let result = self.process_message_inner().await;
+        self.count_messages(from.as_deref(), &rcpts, &result);
+        result
+    }
+
+    /// Count the message by recipient, sender domain and whether it was stored.
+    fn count_messages(&self, from: Option<&str>, rcpts: &[String], result: &Result<Delivery>) {
+        let labels = &self.config.metric_labels;
+        let sender_domain = labels.sender_domain(from);
+        for rcpt in rcpts {
+            let outcome = match result {
+                Ok(Delivery::Refused(_)) => "refused",
+                Ok(Delivery::Delivered(results)) => match results.iter().find(|(r, _)| r == rcpt) {
+                    Some((_, Ok(()))) if self.config.queue.is_some() => "queued",
+                    Some((_, Ok(()))) => "stored",
+                    _ => "failed",
+                },
+                Err(_) => "failed",
+            };
+            metrics::MESSAGES
+                .with_label_values(&[labels.rcpt(rcpt), sender_domain, outcome])
+                .inc();
+        }
+    }
+
+    async fn process_message_inner(&mut self) -> Result<Delivery> {
+        if let Some(reply) = self.state.out_of_sequence("DATA") {
+            return Ok(Delivery::Refused(reply));
+        }
+        let from = self.from.take().ok_or_else(|| anyhow!("no sender"))?;
+        let rcpts = std::mem::take(&mut self.rcpts);
+        let received = self.received_header(&rcpts);
+        self.data.splice(0..0, received.into_bytes());
+
+        let mut quarantine = false;
+        if let Some(mut milter) = self.milter.take() {
+            let result = milter
+                .message(&self.data)
+                .await
+                .map(|(result, modifications)| {
+                    modifications.apply(&mut self.data);
+                    if let Some(reason) = modifications.quarantine {
+                        warn!("quarantining mail due to milter: {}", reason);
+                        quarantine = true;
+                    }
+                    result
+                });
+            milter.quit().await;
+            if let Some(reply) = self.milter_outcome(result) {
+                return Ok(Delivery::Refused(reply));
+            }
+        }
+        if self.discard {
+            return Ok(Delivery::Delivered(
+                rcpts.into_iter().map(|rcpt| (rcpt, Ok(()))).collect(),
+            ));
+        }
+
+        if self.config.track_status {
+            let message_id = self
+                .message_parser
+                .parse(&self.data)
+                .and_then(|message| message.message_id().map(str::to_string));
+            let received = Status::Received.as_str();
+            match db::insert_deliveries(
+                &self.config.pg_pool,
+                message_id.as_deref(),
+                &from,
+                &rcpts,
+                received,
+            )
+            .await
+            {
+                Ok(deliveries) => self.deliveries = deliveries.into_iter().collect(),
+                Err(e) => error!("could not track message status: {:?}", e),
+            }
+        }
+
+        if let Some(queue) = self.config.queue.as_ref() {
+            let envelope = QueuedEnvelope {
+                from,
+                rcpts: rcpts.clone(),
+                dsn: self.dsn.clone(),
+                rcpt_dsn: self.rcpt_dsn.clone(),
+                declared_size: self.declared_size,
+                aliases: self.aliases.clone(),
+                tls: self.tls.clone(),
+                rdns: self.rdns.clone(),
+                helo: self.client_helo(),
+                submitter: self.login.lock().unwrap().clone(),
+                quarantine,
+                deliveries: self.deliveries.clone(),
+            };
+            queue
+                .enqueue(&self.config.pg_pool, &envelope, &self.data)
+                .await?;
+            return Ok(Delivery::Delivered(
+                rcpts.into_iter().map(|rcpt| (rcpt, Ok(()))).collect(),
+            ));
+        }
+        self.store(from, rcpts, quarantine).await
+    }
+
+    /// Store a message queued by `process_message` of another session. When `retry`ing, the
+    /// recipients an earlier attempt stored it for are skipped. The session keeps the message,
+    /// e.g. for a bounce.
+    pub async fn process_queued(
+        &mut self,
+        envelope: &QueuedEnvelope,
+        data: Vec<u8>,
+        retry: bool,
+    ) -> Result<Delivery> {
+        for rcpt in &envelope.rcpts {
+            if let Some(tenant) = self
+                .config
+                .tenants
+                .resolve(&self.config.pg_pool, rcpt)
+                .await?
+            {
+                self.tenants.insert(rcpt.clone(), tenant);
+            }
+        }
+        self.dsn = envelope.dsn.clone();
+        self.rcpt_dsn = envelope.rcpt_dsn.clone();
+        self.declared_size = envelope.declared_size;
+        self.aliases = envelope.aliases.clone();
+        self.tls = envelope.tls.clone();
+        self.rdns = envelope.rdns.clone();
+        self.helo = envelope.helo.clone();
+        *self.login.lock().unwrap() = envelope.submitter.clone();
+        self.deliveries = envelope.deliveries.clone();
+        self.data = data;
+
+        let mut rcpts = envelope.rcpts.clone();
+        if retry {
+            rcpts = self.unstored(rcpts).await?;
+            if rcpts.is_empty() {
+                return Ok(Delivery::Delivered(vec![]));
+            }
+        }
+        self.store(envelope.from.clone(), rcpts, envelope.quarantine)
+            .await
+    }
+
+    /// The recipients the message is not in the DB for, e.g. as storing it failed after the
+    /// insert or its worker died.
+    async fn unstored(&self, rcpts: Vec<String>) -> Result<Vec<String>> {
+        let message_id = self
             .message_parser
             .parse(&self.data)
+            .and_then(|message| message.message_id().map(str::to_string));
+        let Some(message_id) = message_id else {
+            return Ok(rcpts);
+        };
+        let mut unstored = Vec::with_capacity(rcpts.len());
+        for rcpt in rcpts {
+            if db::mail_stored(&self.config.pg_pool, &message_id, &rcpt).await? {
+                trace!(rcpt, "message stored already");
+            } else {
+                unstored.push(rcpt);
+            }
+        }
+        Ok(unstored)
+    }
+
+    /// Filter the message and store it for every recipient.
+    async fn store(
+        &mut self,
+        from: String,
+        rcpts: Vec<String>,
+        quarantine: bool,
+    ) -> Result<Delivery> {
+        let result = self.store_inner(from, rcpts.clone(), quarantine).await;
+        // the results per recipient are tracked while storing
+        let error = match &result {
+            Ok(Delivery::Refused(reply)) => Some(reply.to_string().trim_end().to_string()),
+            Ok(Delivery::Delivered(_)) => None,
+            Err(e) => Some(format!("{:#}", e)),
+        };
+        if let Some(error) = error {
+            for rcpt in &rcpts {
+                self.track(rcpt, Status::Failed, None, Some(&error)).await;
+            }
+        }
+        result
+    }
+
+    /// Record the status of the message for `rcpt`, failures are only logged.
+    async fn track(
+        &self,
+        rcpt: &str,
+        status: Status,
+        s3_prefix: Option<&str>,
+        error: Option<&str>,
+    ) {
+        let Some(id) = self.deliveries.get(rcpt) else {
+            return;
+        };
+        let updated =
+            db::update_delivery(&self.config.pg_pool, *id, status.as_str(), s3_prefix, error);
+        if let Err(e) = updated.await {
+            error!("could not track message status: {:?}", e);
+        }
+    }
+
+    async fn store_inner(
+        &mut self,
+        from: String,
+        rcpts: Vec<String>,
+        mut quarantine: bool,
+    ) -> Result<Delivery> {
+        if let Some(filter) = self.config.content_filter.as_ref() {
+            match filter.check(&from, &rcpts, &self.data).await? {
+                Verdict::Accept => {}
+                Verdict::Reject(message) => {
+                    warn!("rejected mail due to content filter");
+                    return Ok(Delivery::Refused(Reply::new(
+                        550,
+                        Some(EnhancedCode(5, 7, 1)),
+                        message,
+                    )));
+                }
+                Verdict::Quarantine => {
+                    warn!("quarantining mail due to content filter");
+                    quarantine = true;
+                }
+            }
+        }
+
+        // stored decrypted, but relayed and bounced as received
+        let unwrapped = self
+            .config
+            .smime
+            .as_deref()
+            .and_then(|smime| smime::unwrap(smime, &self.message_parser, &self.data));
+        let (smime_info, unwrapped) = match unwrapped {
+            Some((info, content)) => (Some(info), Some(content)),
+            None => (None, None),
+        };
+        let content = unwrapped.as_deref().unwrap_or(self.data.as_slice());
+        // parsed once for all checks and recipients
+        let message = self
+            .message_parser
+            .parse(content)
             .ok_or_else(|| anyhow!("Cannot parse message"))?;
 
-        s3::upload_message(
-            &self.config.s3_config,
-            &self.config.pg_pool,
-            &self.config.bucket,
-            &from,
-            &rcpt,
-            message,
-        )
-        .await
-        .map_err(|e| {
-            error!("upload to s3 bucket failed: {:?}", e);
-            e
-        })?;
+        let mut plugin_output = None;
+        if let Some(plugin) = self.config.plugin.as_ref() {
+            let input = PluginInput {
+                from: &from,
+                rcpts: &rcpts,
+                size: content.len(),
+                message_id: message.message_id(),
+                subject: message.subject(),
+                headers: message.headers_raw().map(|(k, v)| (k, v.trim())).collect(),
+                body_text: message.body_text(0),
+                attachments: message
+                    .attachments()
+                    .map(|a| json!({ "filename": a.attachment_name(), "size": a.contents().len() }))
+                    .collect(),
+            };
+            let output = plugin.process(&input).await?;
+            match output.action {
+                PluginAction::Accept => {}
+                PluginAction::Reject => {
+                    warn!("rejected mail due to plugin");
+                    // replies cannot span lines
+                    let text = output.message.map_or("message rejected".to_string(), |m| {
+                        m.replace(['\r', '\n'], " ")
+                    });
+                    return Ok(Delivery::Refused(Reply::new(
+                        550,
+                        Some(EnhancedCode(5, 7, 1)),
+                        text,
+                    )));
+                }
+                PluginAction::Quarantine => {
+                    warn!("quarantining mail due to plugin");
+                    quarantine = true;
+                }
+            }
+            plugin_output = Some(output);
+        }
 
-        self.reset();
-        Ok(())
+        if let Some(policy) = self.config.attachment_policy.as_ref() {
+            let blocked = s3::attachment_files(&message).iter().find_map(|file| {
+                policy.blocked(file.name.as_deref(), file.content_type.as_deref())
+            });
+            if let Some(reason) = blocked {
+                metrics::BLOCKED_ATTACHMENTS
+                    .with_label_values(&[policy.action.as_str()])
+                    .inc();
+                match policy.action {
+                    PolicyAction::Reject => {
+                        warn!(reason, "rejected mail due to attachment policy");
+                        return Ok(Delivery::Refused(Reply::new(
+                            550,
+                            Some(EnhancedCode(5, 7, 1)),
+                            "attachment type not allowed",
+                        )));
+                    }
+                    PolicyAction::Quarantine => {
+                        warn!(reason, "quarantining mail due to attachment policy");
+                        quarantine = true;
+                    }
+                    PolicyAction::Drop => trace!(reason, "dropping blocked attachments"),
+                }
+            }
+        }
+
+        if let Some(limits) = self.config.attachment_limits.as_ref() {
+            let exceeded = s3::attachment_files(&message)
+                .iter()
+                .enumerate()
+                .find_map(|(ix, file)| limits.exceeded(ix, file.body.len()));
+            if let Some(reason) = exceeded {
+                let action = if limits.reject { "reject" } else { "skip" };
+                metrics::ATTACHMENT_LIMITS
+                    .with_label_values(&[action])
+                    .inc();
+                if limits.reject {
+                    warn!(reason, "rejected mail over the attachment limits");
+                    return Ok(Delivery::Refused(Reply::new(
+                        552,
+                        Some(EnhancedCode(5, 3, 4)),
+                        "message exceeds attachment limits",
+                    )));
+                }
+                trace!(reason, "skipping attachments over the limits");
+            }
+        }
+
+        let encrypted_archive = s3::attachment_files(&message)
+            .iter()
+            .any(|file| archive::is_encrypted(&file.body));
+        if encrypted_archive {
+            metrics::ENCRYPTED_ARCHIVES.inc();
+            if self.config.quarantine_encrypted_archives {
+                warn!("quarantining mail with password-protected archive");
+                quarantine = true;
+            }
+        }
+
+        if let Some(declared_size) = self.declared_size {
+            if self.data.len() as u64 > declared_size {
+                warn!(
+                    declared_size,
+                    size = self.data.len(),
+                    "message larger than declared"
+                );
+            }
+        }
+
+        let origin = trace::origin(&trace::chain(&message), &self.config.trusted_relays);
+        if let Some(origin) = origin.as_ref() {
+            trace!("message originates from {:?}", origin);
+        }
+
+        // evaluate the rules for all recipients before storing anything
+        let mut outcomes = Vec::with_capacity(rcpts.len());
+        for rcpt in &rcpts {
+            let mut outcome = match self.config.rules.as_ref() {
+                Some(rules) => {
+                    rules.evaluate(&from, rcpt, &message, content.len(), origin.as_ref())
+                }
+                None => rules::Outcome::default(),
+            };
+            if let Some(text) = outcome.reject {
+                warn!("rejected mail due to rules");
+                return Ok(Delivery::Refused(Reply::new(
+                    550,
+                    Some(EnhancedCode(5, 7, 1)),
+                    text,
+                )));
+            }
+            outcome.quarantine |= quarantine;
+            if let Some(output) = plugin_output.as_ref() {
+                outcome.bucket = output.bucket.clone().or(outcome.bucket);
+                outcome.prefix = output.prefix.clone().or(outcome.prefix);
+                outcome.tags.extend(output.tags.iter().cloned());
+                outcome.objects = output.objects.clone();
+            }
+            outcomes.push(outcome);
+        }
+
+        // a required relay comes first, so the client retrying a failure does not store the
+        // message again
+        let relay = self.config.relay.as_ref();
+        if let Some(relay) = relay.filter(|relay| relay.required) {
+            let relay_rcpts: Vec<_> = rcpts
+                .iter()
+                .zip(&outcomes)
+                .filter(|(_, outcome)| !outcome.quarantine)
+                .map(|(rcpt, _)| rcpt.clone())
+                .collect();
+            if !relay_rcpts.is_empty() {
+                if let Err(e) = relay.send(&from, &relay_rcpts, &self.data).await {
+                    error!("relaying message failed: {:?}", e);
+                    return Err(e);
+                }
+            }
+        }
+
+        let submitter = self.login.lock().unwrap().clone();
+        let helo = self.client_helo();
+        let mut results = Vec::with_capacity(rcpts.len());
+        // quarantined mail is not relayed
+        let mut relay_rcpts = vec![];
+        for (rcpt, outcome) in rcpts.into_iter().zip(outcomes) {
+            // shown with the S3 requests and DB queries of the message
+            Span::current().record("message_id", message.message_id());
+
+            let envelope = s3::Envelope {
+                from: &from,
+                rcpt: &rcpt,
+                dsn: dsn::to_json(&self.dsn, self.rcpt_dsn.get(&rcpt)),
+                declared_size: self.declared_size,
+                tenant: self.tenants.get(&rcpt).map(|t| t.as_ref()),
+                canonical_rcpt: self.aliases.get(&rcpt).map(String::as_str),
+                origin: origin.as_ref(),
+                smime: smime_info.as_ref(),
+                tls: self.tls.as_ref(),
+                rdns: self.rdns.as_ref(),
+                helo: helo.as_deref(),
+                submitter: submitter.as_deref(),
+            };
+            let result = match s3::upload_message(&self.config, envelope, &message, &outcome).await
+            {
+                Ok(Some(event)) => {
+                    self.track(&rcpt, Status::Stored, Some(&event.s3_prefix), None)
+                        .await;
+                    if self.config.quotas {
+                        self.record_quota_usage(&rcpt).await;
+                    }
+                    if let Some(tenant) = self.tenants.get(&rcpt) {
+                        self.config.tenants.notify(tenant, &event);
+                    }
+                    let published = self.config.events.publish(&event).await;
+                    match published.as_ref() {
+                        Ok(()) => self.track(&rcpt, Status::Notified, None, None).await,
+                        // stays stored, so it is listed as stuck
+                        Err(e) => {
+                            let error = format!("{:#}", e);
+                            self.track(&rcpt, Status::Stored, None, Some(&error)).await
+                        }
+                    }
+                    published
+                }
+                Ok(None) => {
+                    self.track(&rcpt, Status::Quarantined, None, None).await;
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("upload to s3 bucket failed: {:?}", e);
+                    let error = format!("{:#}", e);
+                    self.track(&rcpt, Status::Failed, None, Some(&error)).await;
+                    Err(e)
+                }
+            };
+            if result.is_ok() && !outcome.quarantine {
+                relay_rcpts.push(rcpt.clone());
+            }
+            results.push((rcpt, result));
+        }
+
+        if relay_rcpts
+            .iter()
+            .any(|r| self.config.dmarc_rua.contains(r))
+        {
+            self.ingest_dmarc_reports(&message).await;
+        }
+        if relay_rcpts.iter().any(|r| self.config.tls_rua.contains(r)) {
+            self.ingest_tls_reports(&message).await;
+        }
+
+        if let Some(relay) = relay.filter(|relay| !relay.required) {
+            if !relay_rcpts.is_empty() {
+                if let Err(e) = relay.send(&from, &relay_rcpts, &self.data).await {
+                    error!("relaying message failed: {:?}", e);
+                }
+            }
+        }
+
+        Ok(Delivery::Delivered(results))
     }
 
     #[instrument(skip_all, fields(addr))]
@@ -136,7 +1374,7 @@ impl smtpbis::Handler for SmtpSession {
 
     #[instrument(skip_all)]
     async fn tls_request(&mut self) -> Option<Self::TlsConfig> {
-        Some(self.config.tls_config.clone())
+        self.config.tls_config.clone()
     }
 
     #[instrument(skip_all)]
@@ -146,42 +1384,150 @@ impl smtpbis::Handler for SmtpSession {
         mut initial_keywords: EhloKeywords,
     ) -> Result<(String, EhloKeywords), Reply> {
         trace!("handle EHLO");
-        let max_message_size = 100_000_000;
+        self.restart_after_xclient();
+        if let Some(reply) = self.check_helo(&domain.to_string()) {
+            return Err(reply);
+        }
         initial_keywords.insert("DSN".into(), None);
+        initial_keywords.insert("ENHANCEDSTATUSCODES".into(), None);
         initial_keywords.insert("8BITMIME".into(), None);
-        initial_keywords.insert("SIZE".into(), Some(max_message_size.to_string()));
+        initial_keywords.insert("SMTPUTF8".into(), None);
+        initial_keywords.insert("SIZE".into(), Some(MAX_MESSAGE_SIZE.to_string()));
+        // only offered over TLS, as credentials are sent in plain text
+        if let Some(authenticator) = self.config.authenticator.as_ref() {
+            if self.submission && self.tls.is_some() {
+                let mechanisms = authenticator.mechanisms().join(" ");
+                initial_keywords.insert("AUTH".into(), Some(mechanisms));
+            }
+        }
+        if self.trusted_proxy() {
+            initial_keywords.insert("XCLIENT".into(), Some("NAME ADDR PORT HELO".into()));
+            initial_keywords.insert("XFORWARD".into(), Some("NAME ADDR PORT HELO".into()));
+        }
 
-        let greet = format!("hello {}", domain);
+        let greet = replies::render(
+            &self.config.replies.ehlo,
+            &[
+                ("domain", &self.config.domain.to_string()),
+                ("helo", &domain.to_string()),
+            ],
+        );
         self.reset();
+        self.state = self.state.after("EHLO");
+        self.helo = Some(domain.to_string());
+        self.protocol = "ESMTP";
 
         Ok((greet, initial_keywords))
     }
 
     #[instrument(skip(self))]
-    async fn helo(&mut self, _domain: Domain) -> Option<Reply> {
+    async fn helo(&mut self, domain: Domain) -> Option<Reply> {
+        self.restart_after_xclient();
+        if let Some(reply) = self.check_helo(&domain.to_string()) {
+            return Some(reply);
+        }
         self.reset();
+        self.state = self.state.after("HELO");
+        self.helo = Some(domain.to_string());
+        self.protocol = "SMTP";
         None
     }
 
     #[instrument(skip_all)]
-    async fn mail(&mut self, from: ReversePath, _params: Vec<Param>) -> Option<Reply> {
+    async fn mail(&mut self, from: ReversePath, params: Vec<Param>) -> Option<Reply> {
         trace!("handle MAIL");
+        self.restart_after_xclient();
+        if let Some(reply) = self.state.out_of_sequence("MAIL") {
+            return Some(reply);
+        }
+        if self.submission && self.login.lock().unwrap().is_none() {
+            warn!("rejected unauthenticated submission");
+            return Some(Reply::new(
+                530,
+                Some(EnhancedCode(5, 7, 0)),
+                "authentication required",
+            ));
+        }
+        if let Some(reply) = self.check_rdns().await {
+            return Some(reply);
+        }
+        let dsn = match EnvelopeDsn::from_params(&params) {
+            Ok(dsn) => dsn,
+            Err(reply) => return Some(reply),
+        };
+        let declared_size = match size_param(&params) {
+            Ok(size) => size,
+            Err(reply) => return Some(reply),
+        };
+        if declared_size.is_some_and(|size| size > MAX_MESSAGE_SIZE as u64) {
+            warn!("rejected mail with declared size {:?}", declared_size);
+            return Some(self.too_big());
+        }
 
-        if let Some((mailbox, domain)) =
-            std::convert::Into::<Option<Mailbox>>::into(from).map(Mailbox::into_parts)
-        {
-            let from = format!("{}@{}", mailbox, domain);
-            self.from = Some(from);
+        let from = match std::convert::Into::<Option<Mailbox>>::into(from) {
+            Some(mailbox) => {
+                let (mailbox, domain) = mailbox.into_parts();
+                normalize_address(mailbox, domain)
+            }
+            None if self.config.null_sender == NullSender::Reject => {
+                warn!("rejected mail with null sender");
+                return Some(self.rejected(EnhancedCode(5, 7, 1)));
+            }
+            None => String::new(),
+        };
+
+        let config = self.config.clone();
+        if let Some(milter) = config.milter.as_ref() {
+            let result = self.milter_mail(milter, &from).await;
+            if let Some(reply) = self.milter_outcome(result) {
+                return Some(reply);
+            }
         }
+
+        self.state = self.state.after("MAIL");
+        self.forwarding.lock().unwrap().start_transaction();
+        self.from = Some(from);
+        self.dsn = dsn;
+        self.declared_size = declared_size;
         None
     }
 
     #[instrument(skip_all, fields(from=self.from))]
-    async fn rcpt(&mut self, rcpt: ForwardPath, _params: Vec<Param>) -> Option<Reply> {
+    async fn rcpt(&mut self, rcpt: ForwardPath, params: Vec<Param>) -> Option<Reply> {
         trace!("handle RCPT");
+        if let Some(reply) = self.state.out_of_sequence("RCPT") {
+            return Some(reply);
+        }
+        self.tarpit().await;
+        if let Some(faults) = self.config.faults.as_ref() {
+            faults.client().await;
+        }
+        let dsn = match RcptDsn::from_params(&params) {
+            Ok(dsn) => dsn,
+            Err(reply) => return Some(reply),
+        };
+        if self.rcpts.len() >= self.config.max_recipients {
+            warn!(
+                "rejected RCPT exceeding {} recipients",
+                self.config.max_recipients
+            );
+            return Some(Reply::new(
+                452,
+                Some(EnhancedCode(4, 5, 3)),
+                "too many recipients",
+            ));
+        }
         let (mailbox, domain) = rcpt.into_mailbox(&self.config.domain).into_parts();
-        let rcpt = format!("{}@{}", mailbox, domain);
-        let from = self.from.as_ref().unwrap();
+        let rcpt = normalize_address(mailbox, domain);
+        let from = self.from.as_deref().unwrap_or_default();
+        // the null sender is named `<>` in the allowlists, or skips them
+        let listed_from = if !from.is_empty() {
+            Some(from)
+        } else if self.config.null_sender == NullSender::Check {
+            Some("<>")
+        } else {
+            None
+        };
 
         if self
             .config
@@ -190,83 +1536,243 @@ impl smtpbis::Handler for SmtpSession {
             .is_some_and(|c| !c.contains(&rcpt))
         {
             warn!("rejected mail due to RCPT address");
-            return Some(Reply::new(550, None, "mailbox unavailable"));
+            return Some(self.rejected(EnhancedCode(5, 1, 1)));
         };
 
-        if !self.check_address(&self.config.allowed_froms, from) {
+        if listed_from.is_some_and(|f| !self.check_address(&self.config.allowed_froms, f)) {
             warn!("rejected mail due to FROM address");
-            return Some(Reply::new(550, None, "mailbox unavailable"));
+            return Some(self.rejected(EnhancedCode(5, 7, 1)));
+        };
+
+        if self
+            .config
+            .allowed_pairs
+            .as_ref()
+            .zip(listed_from)
+            .is_some_and(|(p, f)| !p.allows(f, &rcpt))
+        {
+            warn!("rejected mail due to FROM address for RCPT");
+            return Some(self.rejected(EnhancedCode(5, 7, 1)));
         };
 
         if self.config.check_db {
-            match db::check_address(&self.config.pg_pool, from, &rcpt).await {
+            let checked = async {
+                if let Some(faults) = self.config.faults.as_ref() {
+                    faults.db().await?;
+                }
+                db::check_address(&self.config.pg_pool, from, &rcpt).await
+            };
+            match checked.await {
                 Ok(res) => {
                     if !res {
                         warn!("rejected mail due to DB check");
-                        return Some(Reply::new(550, None, "mailbox unavailable"));
+                        return Some(self.rejected(EnhancedCode(5, 1, 1)));
                     }
                 }
                 Err(e) => {
                     error!("could not handle request: {}", e);
-                    return Some(Reply::new(451, None, "could not handle request"));
+                    return Some(self.config.replies.temp_failure());
+                }
+            }
+        }
+
+        if let Some(directory) = self.config.directory.as_ref() {
+            match directory.is_valid_rcpt(&rcpt).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("rejected mail due to directory lookup");
+                    return Some(self.rejected(EnhancedCode(5, 1, 1)));
+                }
+                Err(e) => {
+                    error!("could not handle request: {}", e);
+                    return Some(self.config.replies.temp_failure());
+                }
+            }
+        }
+
+        if let Some(milter) = self.milter.as_mut() {
+            let result = milter.rcpt(&rcpt).await;
+            if let Some(reply) = self.milter_outcome(result) {
+                return Some(reply);
+            }
+        }
+
+        let alias = match self.resolve_alias(&rcpt).await {
+            Ok(alias) => alias,
+            Err(e) => {
+                error!("could not handle request: {}", e);
+                return Some(self.config.replies.temp_failure());
+            }
+        };
+        if let Some(canonical) = alias.as_ref() {
+            trace!("{} is an alias of {}", rcpt, canonical);
+        }
+
+        let tenant = match self
+            .config
+            .tenants
+            .resolve(&self.config.pg_pool, &rcpt)
+            .await
+        {
+            Ok(tenant) => tenant,
+            Err(e) => {
+                error!("could not handle request: {}", e);
+                return Some(self.config.replies.temp_failure());
+            }
+        };
+        let max_size = match self.rcpt_max_size(&rcpt, tenant.as_deref()).await {
+            Ok(max_size) => max_size,
+            Err(e) => {
+                error!("could not handle request: {}", e);
+                return Some(self.config.replies.temp_failure());
+            }
+        };
+        if self
+            .declared_size
+            .is_some_and(|size| size > max_size as u64)
+        {
+            warn!("rejected mail exceeding maximum message size for {}", rcpt);
+            return Some(self.too_big());
+        }
+
+        if let Some(tenant) = tenant {
+            self.tenants.insert(rcpt.clone(), tenant);
+        }
+        if self.config.quotas {
+            match self.exceeded_quota(&rcpt).await {
+                Ok(None) => {}
+                Ok(Some(key)) => {
+                    warn!("rejected mail due to quota of {}", key);
+                    metrics::QUOTA_REJECTIONS.with_label_values(&[&key]).inc();
+                    self.tenants.remove(&rcpt);
+                    return Some(Reply::new(
+                        452,
+                        Some(EnhancedCode(4, 2, 2)),
+                        "mailbox over quota",
+                    ));
+                }
+                Err(e) => {
+                    error!("could not handle request: {}", e);
+                    self.tenants.remove(&rcpt);
+                    return Some(self.config.replies.temp_failure());
                 }
             }
         }
 
-        self.rcpt = Some(rcpt);
+        self.max_size = self.max_size.min(max_size);
+        self.rcpt_dsn.insert(rcpt.clone(), dsn);
+        if let Some(canonical) = alias {
+            self.aliases.insert(rcpt.clone(), canonical);
+        }
+        self.rcpts.push(rcpt);
+        self.state = self.state.after("RCPT");
         None
     }
 
     #[instrument(skip_all)]
     async fn data_start(&mut self) -> Option<Reply> {
-        None
+        if let Some(reply) = self.state.out_of_sequence("DATA") {
+            return Some(reply);
+        }
+        if let Some(faults) = self.config.faults.as_ref() {
+            faults.client().await;
+        }
+        self.over_buffer_limit()
     }
 
-    #[instrument(skip_all, fields(from=self.from, rcpt=self.rcpt))]
+    #[instrument(skip_all, fields(from=self.from, rcpts=?self.rcpts))]
     async fn data<S>(&mut self, stream: &mut S) -> Result<Option<Reply>, smtpbis::ServerError>
     where
         S: Stream<Item = Result<BytesMut, smtpbis::LineError>> + Unpin + Send,
     {
         trace!("handle DATA");
-
-        let mut nb_lines: usize = 0;
+        if let Some(reply) = self.state.out_of_sequence("DATA") {
+            while stream.try_next().await?.is_some() {}
+            return Ok(Some(reply));
+        }
+        self.state = self.state.after("DATA");
 
         self.data = Vec::new();
-        while let Some(line) = stream.try_next().await? {
-            self.data.extend(line);
-            nb_lines += 1
+        if let Some(size) = self.declared_size {
+            self.expect_content(size);
+        }
+        let (nb_lines, too_big) = read_data(stream, |line| self.receive_content(line)).await?;
+        if too_big {
+            warn!("rejected mail exceeding maximum message size");
+            self.reset();
+            return Ok(Some(self.too_big()));
         }
 
-        let reply_txt = format!("Received {} bytes in {} lines.", self.data.len(), nb_lines);
+        let reply_txt = replies::render(
+            &self.config.replies.accepted,
+            &[
+                ("domain", &self.config.domain.to_string()),
+                ("bytes", &self.data.len().to_string()),
+                ("lines", &nb_lines.to_string()),
+            ],
+        );
 
         match self.handle_data().await {
-            Ok(_) => Ok(Some(Reply::new(250, None, reply_txt))),
+            Ok(None) => Ok(Some(Reply::new(
+                250,
+                Some(EnhancedCode(2, 0, 0)),
+                reply_txt,
+            ))),
+            Ok(Some(reply)) => Ok(Some(reply)),
             Err(e) => {
                 error!("could not handle request: {}", e);
-                Ok(Some(Reply::new(451, None, "could not handle request")))
+                Ok(Some(self.config.replies.temp_failure()))
             }
         }
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip(self, stream))]
     async fn bdat<S>(
         &mut self,
         stream: &mut S,
-        _size: u64,
+        size: u64,
         last: bool,
     ) -> Result<Option<Reply>, smtpbis::ServerError>
     where
         S: Stream<Item = Result<BytesMut, smtpbis::LineError>> + Unpin + Send,
     {
+        if let Some(reply) = self.state.out_of_sequence("BDAT") {
+            while stream.try_next().await?.is_some() {}
+            return Ok(Some(reply));
+        }
+        self.state = self.state.after("BDAT");
+
+        if self.data.is_empty() {
+            if let Some(reply) = self.over_buffer_limit() {
+                while stream.try_next().await?.is_some() {}
+                self.reset();
+                return Ok(Some(reply));
+            }
+        }
+
+        // check the declared size before buffering anything, a size overflowing is too big
+        let total = (self.data.len() as u64)
+            .checked_add(size)
+            .filter(|total| *total <= self.max_size as u64);
+        let mut too_big = total.is_none();
+        if let Some(total) = total {
+            self.expect_content(total);
+        }
         while let Some(chunk) = stream.try_next().await? {
-            self.data.extend(chunk)
+            too_big = too_big || !self.receive_content(&chunk);
+        }
+        if too_big {
+            warn!("rejected mail exceeding maximum message size");
+            self.reset();
+            return Ok(Some(self.too_big()));
         }
+
         if last {
             match self.handle_data().await {
-                Ok(_) => Ok(None),
+                Ok(reply) => Ok(reply),
                 Err(e) => {
                     error!("could not handle request: {}", e);
-                    Ok(Some(Reply::new(451, None, "could not handle request")))
+                    Ok(Some(self.config.replies.temp_failure()))
                 }
             }
         } else {
@@ -279,3 +1785,203 @@ impl smtpbis::Handler for SmtpSession {
         self.reset();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [State; 5] = [
+        State::Connected,
+        State::Idle,
+        State::Mail,
+        State::Rcpt,
+        State::Data,
+    ];
+
+    /// Run `commands` as a client would, returning the first refusal.
+    fn walk(mut state: State, commands: &[&str]) -> Result<State, String> {
+        for command in commands {
+            if let Some(reply) = state.out_of_sequence(command) {
+                return Err(reply.to_string());
+            }
+            state = state.after(command);
+        }
+        Ok(state)
+    }
+
+    #[test]
+    fn every_state_and_command() {
+        let expected = |state, command| match (state, command) {
+            (State::Connected, "MAIL") => Some("send HELO or EHLO first"),
+            (State::Mail | State::Rcpt | State::Data, "MAIL") => Some("nested MAIL command"),
+            (State::Connected | State::Idle, "RCPT" | "DATA" | "BDAT") => Some("need MAIL command"),
+            (State::Mail, "DATA" | "BDAT") => Some("need RCPT command"),
+            (State::Data, "RCPT") => Some("bad sequence of commands"),
+            _ => None,
+        };
+        for state in STATES {
+            for command in [
+                "HELO", "EHLO", "MAIL", "RCPT", "DATA", "BDAT", "RSET", "NOOP",
+            ] {
+                assert_eq!(
+                    state
+                        .out_of_sequence(command)
+                        .map(|reply| reply.to_string()),
+                    expected(state, command).map(|text| format!("503 5.5.1 {}\r\n", text)),
+                    "{} in {:?}",
+                    command,
+                    state
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mail_before_helo() {
+        assert_eq!(
+            walk(State::Connected, &["MAIL"]),
+            Err("503 5.5.1 send HELO or EHLO first\r\n".to_string())
+        );
+        assert_eq!(walk(State::Connected, &["EHLO", "MAIL"]), Ok(State::Mail));
+        assert_eq!(walk(State::Connected, &["HELO", "MAIL"]), Ok(State::Mail));
+    }
+
+    #[test]
+    fn mail_before_lhlo() {
+        assert_eq!(
+            walk(State::Connected, &["MAIL"]),
+            Err("503 5.5.1 send HELO or EHLO first\r\n".to_string())
+        );
+        assert_eq!(walk(State::Connected, &["LHLO", "MAIL"]), Ok(State::Mail));
+        assert_eq!(
+            walk(State::Connected, &["LHLO", "MAIL", "RCPT", "DATA"]),
+            Ok(State::Data)
+        );
+    }
+
+    #[test]
+    fn rcpt_before_mail() {
+        assert_eq!(
+            walk(State::Connected, &["EHLO", "RCPT"]),
+            Err("503 5.5.1 need MAIL command\r\n".to_string())
+        );
+    }
+
+    #[test]
+    fn data_before_rcpt() {
+        for data in ["DATA", "BDAT"] {
+            assert_eq!(
+                walk(State::Idle, &["MAIL", data]),
+                Err("503 5.5.1 need RCPT command\r\n".to_string())
+            );
+        }
+        assert_eq!(
+            walk(State::Idle, &["MAIL", "RCPT", "RCPT", "DATA"]),
+            Ok(State::Data)
+        );
+    }
+
+    #[test]
+    fn rset_and_helo_reset() {
+        for reset in ["RSET", "HELO", "EHLO"] {
+            assert_eq!(
+                walk(State::Connected, &["EHLO", "MAIL", "RCPT", reset]),
+                Ok(State::Idle)
+            );
+            assert_eq!(
+                walk(State::Idle, &["MAIL", "RCPT", reset, "RCPT"]),
+                Err("503 5.5.1 need MAIL command\r\n".to_string())
+            );
+        }
+        // RSET does not replace the greeting
+        assert_eq!(walk(State::Connected, &["RSET"]), Ok(State::Connected));
+    }
+
+    #[test]
+    fn bdat_after_data() {
+        // a delivered message resets the session like RSET
+        assert_eq!(
+            walk(State::Idle, &["MAIL", "RCPT", "DATA", "RSET", "BDAT"]),
+            Err("503 5.5.1 need MAIL command\r\n".to_string())
+        );
+        assert_eq!(
+            walk(State::Idle, &["MAIL", "RCPT", "BDAT", "BDAT"]),
+            Ok(State::Data)
+        );
+        assert_eq!(
+            walk(State::Idle, &["MAIL", "RCPT", "BDAT", "RCPT"]),
+            Err("503 5.5.1 bad sequence of commands\r\n".to_string())
+        );
+        assert_eq!(
+            walk(State::Idle, &["MAIL", "RCPT", "BDAT", "MAIL"]),
+            Err("503 5.5.1 nested MAIL command\r\n".to_string())
+        );
+    }
+
+    /// Store `lines` as read from the client.
+    fn receive(lines: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![];
+        for line in lines {
+            let line = data_line(&data, line).into_owned();
+            data.extend(line);
+        }
+        data
+    }
+
+    #[test]
+    fn data_line_keeps_crlf() {
+        assert_eq!(
+            receive(&[b"Subject: test\r\n", b"\r\n", b"body\r\n"]),
+            b"Subject: test\r\n\r\nbody\r\n"
+        );
+    }
+
+    #[test]
+    fn data_line_normalizes_trailing_bare_lf() {
+        assert_eq!(receive(&[b"a\n", b"b\r\n", b"\n"]), b"a\r\nb\r\n\r\n");
+        // only the line ending, bare CRs and LFs within lines are kept
+        assert_eq!(receive(&[b"a\rb\r\n"]), b"a\rb\r\n");
+    }
+
+    #[test]
+    fn data_line_keeps_partial_lines() {
+        assert_eq!(receive(&[b"long "]), b"long ");
+        assert_eq!(receive(&[b"long ", b"line\r\n"]), b"long line\r\n");
+        // a CRLF split between reads is not doubled
+        assert_eq!(
+            receive(&[b"line\r", b"\n", b"next\r\n"]),
+            b"line\r\nnext\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_data_keeps_unstuffed_lines() {
+        // smtpbis passes `..` from the client as `.`, and `...` as `..`
+        let lines: Vec<Result<BytesMut, smtpbis::LineError>> = [
+            &b"Subject: dots\r\n"[..],
+            b"\r\n",
+            b".\r\n",
+            b"..\r\n",
+            b".a\r\n",
+        ]
+        .into_iter()
+        .map(|line| Ok(BytesMut::from(line)))
+        .collect();
+        let mut data = vec![];
+        let result = read_data(&mut futures::stream::iter(lines), |line| {
+            data.extend_from_slice(line);
+            true
+        })
+        .await;
+        assert!(matches!(result, Ok((5, false))));
+        assert_eq!(data, b"Subject: dots\r\n\r\n.\r\n..\r\n.a\r\n");
+    }
+
+    #[test]
+    fn data_line_unstuffs_dots() {
+        assert_eq!(receive(&[b"..\r\n", b".a.\r\n"]), b".\r\na.\r\n");
+        assert_eq!(receive(&[b"...\n"]), b"..\r\n");
+        // only at the start of a line
+        assert_eq!(receive(&[b"a.\r\n", b"b", b".c\r\n"]), b"a.\r\nb.c\r\n");
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use clap::Args;
+use tracing::instrument;
+
+use crate::db;
+use crate::smtp::Config;
+
+/// Where a message is in the pipeline for a recipient, tracked with `TRACK_STATUS=true`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// accepted after DATA, possibly queued
+    Received,
+    /// uploaded and inserted into the DB
+    Stored,
+    /// the events were published
+    Notified,
+    /// refused after DATA or not stored, the last error is recorded
+    Failed,
+    /// stored under `quarantine/`
+    Quarantined,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Received => "received",
+            Status::Stored => "stored",
+            Status::Notified => "notified",
+            Status::Failed => "failed",
+            Status::Quarantined => "quarantined",
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct StuckArgs {
+    /// Only messages received longer ago, in seconds
+    #[arg(long, default_value_t = 600)]
+    older_than: u64,
+    #[arg(long, default_value_t = 100)]
+    limit: i64,
+}
+
+/// Handle the `stuck` command, printing a JSON line per message still received or stored.
+#[instrument(skip(config))]
+pub async fn stuck_command(args: StuckArgs, config: &Config) -> Result<()> {
+    let stuck = db::stuck_deliveries(&config.pg_pool, args.older_than as f64, args.limit).await?;
+    for delivery in stuck {
+        println!("{}", serde_json::to_string(&delivery)?);
+    }
+    Ok(())
+}
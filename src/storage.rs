@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use aws_sdk_s3::types::{ObjectLockMode, StorageClass};
+use chrono::{DateTime, Duration, Utc};
+
+/// Kinds of objects stored for a message, to choose storage classes and lifecycle tags by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Headers,
+    /// `body.txt` and `body.html`
+    Body,
+    /// `raw.eml`
+    Raw,
+    Attachment,
+    /// `thumbnails/` of image attachments
+    Thumbnail,
+    /// `attachments/NN.txt` extracted from documents
+    Text,
+    Manifest,
+    Calendar,
+    /// objects added by the WASM plugin
+    Plugin,
+    Transcript,
+    /// entries in `index/`
+    Index,
+}
+
+impl ObjectKind {
+    const ALL: &'static [ObjectKind] = &[
+        ObjectKind::Headers,
+        ObjectKind::Body,
+        ObjectKind::Raw,
+        ObjectKind::Attachment,
+        ObjectKind::Thumbnail,
+        ObjectKind::Text,
+        ObjectKind::Manifest,
+        ObjectKind::Calendar,
+        ObjectKind::Plugin,
+        ObjectKind::Transcript,
+        ObjectKind::Index,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectKind::Headers => "headers",
+            ObjectKind::Body => "body",
+            ObjectKind::Raw => "raw",
+            ObjectKind::Attachment => "attachment",
+            ObjectKind::Thumbnail => "thumbnail",
+            ObjectKind::Text => "text",
+            ObjectKind::Manifest => "manifest",
+            ObjectKind::Calendar => "calendar",
+            ObjectKind::Plugin => "plugin",
+            ObjectKind::Transcript => "transcript",
+            ObjectKind::Index => "index",
+        }
+    }
+
+    fn parse(name: &str) -> Result<Self> {
+        match Self::ALL.iter().find(|kind| kind.name() == name) {
+            Some(kind) => Ok(*kind),
+            None => bail!("unknown object kind {}", name),
+        }
+    }
+}
+
+/// Storage classes and `retention` tags by object kind, so bucket lifecycle rules can
+/// transition and expire them differently.
+#[derive(Debug, Default)]
+pub struct Storage {
+    classes: HashMap<ObjectKind, StorageClass>,
+    retention: HashMap<ObjectKind, String>,
+    object_lock: Option<ObjectLock>,
+}
+
+/// Object Lock retention of a message's objects, for buckets with Object Lock enabled.
+#[derive(Debug)]
+pub struct ObjectLock {
+    pub mode: ObjectLockMode,
+    /// days to retain messages of recipients without their own
+    pub days: Option<u32>,
+    /// days by recipient address or domain
+    pub rcpt_days: HashMap<String, u32>,
+}
+
+/// Objects may not be deleted or overwritten before `until`.
+#[derive(Clone, Debug)]
+pub struct Retention {
+    pub mode: ObjectLockMode,
+    pub until: DateTime<Utc>,
+}
+
+/// How an object is stored.
+#[derive(Clone, Debug, Default)]
+pub struct Placement {
+    pub storage_class: Option<StorageClass>,
+    /// URL encoded tags, as `put_object` expects them
+    pub tagging: Option<String>,
+    pub retention: Option<Retention>,
+}
+
+impl ObjectLock {
+    /// The retention of messages stored now for `rcpt`, if any.
+    pub fn retention(&self, rcpt: &str) -> Option<Retention> {
+        let rcpt = rcpt.to_lowercase();
+        let domain = rcpt
+            .rsplit_once('@')
+            .map_or(rcpt.as_str(), |(_, domain)| domain);
+        let days = self
+            .rcpt_days
+            .get(&rcpt)
+            .or_else(|| self.rcpt_days.get(domain))
+            .or(self.days.as_ref())?;
+        Some(Retention {
+            mode: self.mode.clone(),
+            until: Utc::now() + Duration::days(i64::from(*days)),
+        })
+    }
+}
+
+impl Storage {
+    /// From pairs of object kind and storage class, e.g. `attachment` and `STANDARD_IA`,
+    /// and of object kind and `retention` tag value, e.g. `raw` and `10y`.
+    pub fn new(
+        classes: Vec<(String, String)>,
+        retention: Vec<(String, String)>,
+        object_lock: Option<ObjectLock>,
+    ) -> Result<Self> {
+        let classes = classes
+            .into_iter()
+            .map(|(kind, class)| {
+                if !StorageClass::values().contains(&class.as_str()) {
+                    bail!("unknown storage class {}", class);
+                }
+                Ok((
+                    ObjectKind::parse(&kind)?,
+                    StorageClass::from(class.as_str()),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let retention = retention
+            .into_iter()
+            .map(|(kind, value)| {
+                // tag values may contain more, but lifecycle filters are easier without
+                if value.is_empty()
+                    || !value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                {
+                    bail!("invalid retention tag value {:?}", value);
+                }
+                Ok((ObjectKind::parse(&kind)?, value))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Storage {
+            classes,
+            retention,
+            object_lock,
+        })
+    }
+
+    /// The retention of messages stored now for `rcpt`, see `ObjectLock`.
+    pub fn object_lock(&self, rcpt: &str) -> Option<Retention> {
+        self.object_lock.as_ref()?.retention(rcpt)
+    }
+
+    pub fn placement(&self, kind: ObjectKind, retention: Option<&Retention>) -> Placement {
+        Placement {
+            storage_class: self.classes.get(&kind).cloned(),
+            tagging: self
+                .retention
+                .get(&kind)
+                .map(|value| format!("retention={}", value)),
+            retention: retention.cloned(),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// expired offenders are removed once this many are tracked
+const MAX_OFFENDERS: usize = 10_000;
+
+/// Delays the replies to clients with recent rejections, doubling the delay with every
+/// rejection up to `max_delay`, to slow down dictionary attacks against recipients.
+#[derive(Debug)]
+pub struct Tarpit {
+    max_delay: Duration,
+    /// rejections older than this are forgotten
+    window: Duration,
+    offenders: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl Tarpit {
+    pub fn new(max_delay: Duration, window: Duration) -> Self {
+        Self {
+            max_delay,
+            window,
+            offenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a rejection of `ip`.
+    pub fn rejected(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut offenders = self.offenders.lock().unwrap();
+        if offenders.len() >= MAX_OFFENDERS {
+            offenders.retain(|_, (_, last)| now.duration_since(*last) < self.window);
+        }
+        let (count, last) = offenders.entry(ip).or_insert((0, now));
+        if now.duration_since(*last) >= self.window {
+            *count = 0;
+        }
+        *count += 1;
+        *last = now;
+    }
+
+    /// How long to wait before replying to `ip`: a second after the first recent rejection,
+    /// doubling with every further one.
+    pub fn delay(&self, ip: IpAddr) -> Duration {
+        let offenders = self.offenders.lock().unwrap();
+        match offenders.get(&ip) {
+            Some((count, last)) if last.elapsed() < self.window => {
+                let factor = 1u32.checked_shl(count - 1).unwrap_or(u32::MAX);
+                Duration::from_secs(1)
+                    .saturating_mul(factor)
+                    .min(self.max_delay)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::{error, instrument, trace};
+
+use crate::db;
+use crate::events::MessageStored;
+
+/// Settings for all recipients of a domain.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Tenant {
+    #[serde(skip)]
+    pub domain: String,
+    pub bucket: Option<String>,
+    /// prepended to the S3 prefix
+    pub prefix: Option<String>,
+    /// gets every `MessageStored` event POSTed as JSON
+    pub webhook_url: Option<String>,
+    pub max_size: Option<usize>,
+    /// recorded as `expires_at` with every message
+    pub retention_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantsFile {
+    tenants: HashMap<String, Tenant>,
+}
+
+/// Tenants from a YAML file, keyed by recipient domain, and optionally the DB.
+pub struct Tenants {
+    configured: HashMap<String, Arc<Tenant>>,
+    in_db: bool,
+    http_client: reqwest::Client,
+}
+
+impl Tenants {
+    #[instrument]
+    pub fn new(path: Option<&str>, in_db: bool) -> Result<Self> {
+        let mut configured = HashMap::new();
+        if let Some(path) = path {
+            let file = fs::read_to_string(path).context("could not read tenants")?;
+            let file: TenantsFile =
+                serde_yaml::from_str(&file).context("could not parse tenants")?;
+            for (domain, mut tenant) in file.tenants {
+                let domain = domain.to_lowercase();
+                tenant.domain = domain.clone();
+                configured.insert(domain, Arc::new(tenant));
+            }
+        }
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Self {
+            configured,
+            in_db,
+            http_client,
+        })
+    }
+
+    /// The tenant of the recipient's domain, configured tenants take precedence.
+    #[instrument(skip(self, pool))]
+    pub async fn resolve(&self, pool: &PgPool, rcpt: &str) -> Result<Option<Arc<Tenant>>> {
+        let domain = rcpt.rsplit_once('@').map_or(rcpt, |(_, domain)| domain);
+        if let Some(tenant) = self.configured.get(domain) {
+            return Ok(Some(tenant.clone()));
+        }
+        if !self.in_db {
+            return Ok(None);
+        }
+
+        let Some(row) = db::get_tenant(pool, domain).await? else {
+            return Ok(None);
+        };
+        trace!("found tenant in DB");
+        Ok(Some(Arc::new(Tenant {
+            domain: domain.to_string(),
+            bucket: row.bucket,
+            prefix: row.prefix,
+            webhook_url: row.webhook_url,
+            max_size: row.max_size.and_then(|s| usize::try_from(s).ok()),
+            retention_days: row.retention_days.and_then(|d| u32::try_from(d).ok()),
+        })))
+    }
+
+    /// POST the event to the tenant's webhook in the background, failures are logged.
+    pub fn notify(&self, tenant: &Tenant, event: &MessageStored) {
+        let Some(url) = tenant.webhook_url.clone() else {
+            return;
+        };
+        let request = self.http_client.post(url).json(event);
+        let domain = tenant.domain.clone();
+        tokio::spawn(async move {
+            let result = request.send().await.and_then(|r| r.error_for_status());
+            if let Err(e) = result {
+                error!("could not call webhook of tenant {}: {:?}", domain, e);
+            }
+        });
+    }
+}
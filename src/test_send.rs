@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use clap::Args;
+use lettre::address::Envelope;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::transport::smtp::AsyncSmtpTransport;
+use lettre::{Address, AsyncTransport, Tokio1Executor};
+use serde_json::json;
+use tracing::{info, instrument, trace};
+
+use crate::db;
+use crate::deliver::deliver_message;
+use crate::export::part;
+use crate::s3;
+use crate::smtp::SmtpBackend;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Args, Debug)]
+pub struct TestSendArgs {
+    /// Recipient of the test message
+    #[arg(long)]
+    to: String,
+    /// Sender, `test-send@<SMTP_DOMAIN>` by default
+    #[arg(long)]
+    from: Option<String>,
+    /// Files to attach
+    #[arg(long)]
+    attach: Vec<PathBuf>,
+    /// SMTP server to send to; its certificate is not verified
+    #[arg(long, default_value = "localhost:2525")]
+    server: String,
+    /// Run the message through the pipeline in this process instead of sending it
+    #[arg(long)]
+    in_process: bool,
+    /// Seconds to wait for the message to be stored
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+}
+
+/// Send a synthetic message and verify that its DB row and S3 objects appear, as smoke test
+/// of a deployment.
+#[instrument(skip(backend))]
+pub async fn test_send_command(args: TestSendArgs, backend: SmtpBackend) -> Result<()> {
+    let config = backend.config.load_full();
+    let domain = config.domain.to_string();
+    let from = args
+        .from
+        .clone()
+        .unwrap_or_else(|| format!("test-send@{}", domain));
+    let message_id = format!("test-send-{}@{}", Utc::now().timestamp_micros(), domain);
+    let raw = message(&from, &args.to, &message_id, &args.attach)?;
+
+    if args.in_process {
+        let mut session = backend.new_session(None)?;
+        session.protocol = "local";
+        deliver_message(&mut session, &from, std::slice::from_ref(&args.to), raw).await?;
+    } else {
+        send(&args.server, &from, &args.to, &raw).await?;
+    }
+    info!("sent test message {}", message_id);
+
+    let deadline = Instant::now() + Duration::from_secs(args.timeout);
+    let mail = loop {
+        if let Some(mail) = db::get_mail(&config.pg_pool, &message_id, Some(&args.to)).await? {
+            break mail;
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "message {} was not stored within {}s",
+                message_id,
+                args.timeout
+            );
+        }
+        trace!("waiting for the message to be stored");
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+
+    let prefix = mail
+        .s3_prefix
+        .as_deref()
+        .context("stored message has no S3 prefix")?;
+    let bucket = mail.bucket.as_deref().unwrap_or(&config.bucket);
+    let headers = format!("{}headers.json", prefix);
+    if s3::get_object(&config.s3_config, bucket, &headers)
+        .await?
+        .is_none()
+    {
+        bail!("object {} is missing", headers);
+    }
+    let attachments = mail.attachments.as_array().map_or(0, Vec::len);
+    if attachments != args.attach.len() {
+        bail!(
+            "stored {} attachments instead of {}",
+            attachments,
+            args.attach.len()
+        );
+    }
+
+    println!(
+        "{}",
+        json!({
+            "message_id": message_id,
+            "bucket": bucket,
+            "s3_prefix": prefix,
+            "attachments": attachments,
+        })
+    );
+    Ok(())
+}
+
+fn message(from: &str, to: &str, message_id: &str, attach: &[PathBuf]) -> Result<Vec<u8>> {
+    let boundary = format!("test-send-{}", Utc::now().timestamp_micros());
+    let mut out = format!(
+        "From: <{}>\r\nTo: <{}>\r\nSubject: smtp-s3-dump test message\r\nDate: {}\r\n\
+        Message-ID: <{}>\r\nMIME-Version: 1.0\r\n\
+        Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n",
+        from,
+        to,
+        Utc::now().to_rfc2822(),
+        message_id,
+        boundary
+    );
+    out.push_str(&format!("--{}\r\n", boundary));
+    out.push_str(&part(
+        "text/plain; charset=utf-8",
+        None,
+        b"This message was sent by smtp-s3-dump test-send.\r\n",
+    ));
+    for path in attach {
+        let content =
+            std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+        let filename = path
+            .file_name()
+            .context("attachment has no file name")?
+            .to_string_lossy();
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        out.push_str(&format!("--{}\r\n", boundary));
+        out.push_str(&part(content_type.as_ref(), Some(&filename), &content));
+    }
+    out.push_str(&format!("--{}--\r\n", boundary));
+    Ok(out.into_bytes())
+}
+
+/// Send via SMTP, with STARTTLS if offered.
+async fn send(server: &str, from: &str, to: &str, raw: &[u8]) -> Result<()> {
+    let (host, port) = server.rsplit_once(':').context("server is not host:port")?;
+    let port = port.parse().context("could not parse server port")?;
+    let tls = TlsParameters::builder(host.to_string())
+        .dangerous_accept_invalid_certs(true)
+        .dangerous_accept_invalid_hostnames(true)
+        .build_rustls()?;
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        .port(port)
+        .tls(Tls::Opportunistic(tls))
+        .build();
+    let envelope = Envelope::new(Some(from.parse()?), vec![to.parse::<Address>()?])?;
+    let response = transport.send_raw(&envelope, raw).await?;
+    trace!("sent test message: {:?}", response.code());
+    Ok(())
+}
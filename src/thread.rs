@@ -0,0 +1,33 @@
+use mail_parser::Message;
+
+/// The thread a message belongs to, i.e. the normalized message ID of its root: the first
+/// of `References`, else `In-Reply-To`, else the message's own ID.
+pub fn thread_id(message: &Message) -> Option<String> {
+    let header = |name: &str| {
+        message
+            .headers_raw()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    };
+    header("References")
+        .and_then(first_id)
+        .or_else(|| header("In-Reply-To").and_then(first_id))
+        .or_else(|| message.message_id().map(normalize))
+        .filter(|id| !id.is_empty())
+}
+
+/// The first `<...>` message ID of a header value.
+fn first_id(value: &str) -> Option<String> {
+    let start = value.find('<')? + 1;
+    let end = start + value[start..].find('>')?;
+    Some(normalize(&value[start..end]))
+}
+
+/// Without the whitespace folding may have left and in lower case, as some clients change
+/// the case of the domain when replying.
+fn normalize(id: &str) -> String {
+    id.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
@@ -0,0 +1,54 @@
+/// Small JPEG renderings of image attachments, so UIs listing archived mail do not need to
+/// download the originals.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "thumbnails"), allow(dead_code))]
+pub struct Thumbnails {
+    /// maximum width and height in pixels
+    pub size: u32,
+}
+
+/// Decoding stops beyond this, images are untrusted input.
+#[cfg(feature = "thumbnails")]
+const MAX_DECODE_ALLOC: u64 = 64 * 1024 * 1024;
+#[cfg(feature = "thumbnails")]
+const JPEG_QUALITY: u8 = 80;
+
+impl Thumbnails {
+    /// The thumbnail of an image in a format the `image` crate decodes, keeping its aspect
+    /// ratio; `None` for anything else.
+    #[cfg(feature = "thumbnails")]
+    pub fn render(&self, content: &[u8]) -> Option<Vec<u8>> {
+        use std::io::Cursor;
+
+        use image::io::{Limits, Reader};
+        use image::{DynamicImage, ImageOutputFormat};
+
+        let mut reader = Reader::new(Cursor::new(content))
+            .with_guessed_format()
+            .ok()?;
+        reader.format()?;
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(MAX_DECODE_ALLOC);
+        reader.limits(limits);
+        let image = match reader.decode() {
+            Ok(image) => image,
+            Err(e) => {
+                tracing::warn!("could not decode image attachment: {}", e);
+                return None;
+            }
+        };
+        // JPEG has no alpha channel
+        let thumbnail = DynamicImage::ImageRgb8(image.thumbnail(self.size, self.size).to_rgb8());
+        let mut out = Cursor::new(vec![]);
+        thumbnail
+            .write_to(&mut out, ImageOutputFormat::Jpeg(JPEG_QUALITY))
+            .ok()?;
+        Some(out.into_inner())
+    }
+
+    /// Never constructed without the `thumbnails` feature.
+    #[cfg(not(feature = "thumbnails"))]
+    pub fn render(&self, _content: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
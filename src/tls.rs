@@ -1,65 +1,164 @@
 use std::{fs::File, io::BufReader, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use arc_swap::ArcSwap;
 // use tokio::{fs::File, io::AsyncReadExt, try_join};
+use serde::{Deserialize, Serialize};
 use tokio_rustls::rustls::{
+    crypto::{self, CryptoProvider},
+    pki_types::CertificateDer,
     server::{ClientHello, ResolvesServerCert},
-    sign::{self, CertifiedKey},
-    Certificate, PrivateKey, ServerConfig,
+    sign::{CertifiedKey, SigningKey},
+    ProtocolVersion, ServerConfig, ServerConnection,
 };
 use tracing::{instrument, trace};
 
+use crate::metrics;
+
+#[cfg(feature = "kms")]
+pub mod kms;
+
+/// Parameters of a TLS session, stored with the messages received in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// e.g. `TLSv1.3`
+    pub version: Option<String>,
+    /// e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher: Option<String>,
+    /// server name the client asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+}
+
+impl TlsInfo {
+    pub fn from_connection(connection: &ServerConnection) -> Self {
+        let version = connection.protocol_version().map(|version| match version {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            other => format!("{:?}", other),
+        });
+        TlsInfo {
+            version,
+            cipher: connection
+                .negotiated_cipher_suite()
+                .map(|c| format!("{:?}", c.suite())),
+            sni: connection.server_name().map(str::to_string),
+        }
+    }
+}
+
+impl std::fmt::Display for TlsInfo {
+    /// The comment of the `Received` header.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "using {} with cipher {}",
+            self.version.as_deref().unwrap_or("unknown"),
+            self.cipher.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+/// The crypto provider `name`d `ring` (default), `aws-lc-rs` or `fips`, i.e. aws-lc-rs in
+/// FIPS mode.
+pub fn crypto_provider(name: &str) -> Result<Arc<CryptoProvider>> {
+    let provider = match name {
+        "" | "ring" => crypto::ring::default_provider(),
+        #[cfg(feature = "aws-lc-rs")]
+        "aws-lc-rs" | "fips" => crypto::aws_lc_rs::default_provider(),
+        #[cfg(not(feature = "aws-lc-rs"))]
+        "aws-lc-rs" | "fips" => bail!(
+            "{} crypto provider requested, but compiled without aws-lc-rs support",
+            name
+        ),
+        other => bail!("unknown crypto provider {}", other),
+    };
+    if name == "fips" && !provider.fips() {
+        bail!("fips crypto provider requested, but compiled without fips support");
+    }
+    Ok(Arc::new(provider))
+}
+
 #[instrument(skip_all)]
 pub fn safe_tls_config(resolver: Arc<CertificateResolver>) -> Result<Arc<ServerConfig>> {
     Ok(Arc::new(
-        ServerConfig::builder()
-            .with_safe_defaults()
+        ServerConfig::builder_with_provider(resolver.provider.clone())
+            .with_safe_default_protocol_versions()?
             .with_no_client_auth()
             .with_cert_resolver(resolver),
     ))
 }
 
+/// Where the certificate's private key is.
+#[derive(Debug)]
+pub enum KeySource {
+    /// PEM file, reloaded with the certificate
+    File(String),
+    /// key kept outside the container, e.g. in AWS KMS
+    #[cfg_attr(not(feature = "kms"), allow(dead_code))]
+    Remote(Arc<dyn SigningKey>),
+}
+
+impl KeySource {
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            KeySource::File(path) => Some(path),
+            KeySource::Remote(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct CertificateResolver {
     pub cert_path: String,
-    pub key_path: String,
+    pub key: KeySource,
+    /// loads key files and runs the handshakes
+    pub provider: Arc<CryptoProvider>,
     pub certified_key: Arc<ArcSwap<CertifiedKey>>,
 }
 
 impl CertificateResolver {
-    #[instrument]
-    fn load_certs_and_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey> {
+    #[instrument(skip(provider))]
+    fn load_certs_and_key(
+        cert_path: &str,
+        key: &KeySource,
+        provider: &CryptoProvider,
+    ) -> Result<CertifiedKey> {
         trace!("loading certs from files");
 
-        let certs: Vec<Certificate> =
-            rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
-                .into_iter()
-                .map(Certificate)
-                .collect();
-        let key = sign::any_supported_type(
-            &rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))?
-                .into_iter()
-                .map(PrivateKey)
-                .next()
-                .context("no private key found")?,
-        )?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = match key {
+            KeySource::File(key_path) => {
+                let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+                    .context("no private key found")?;
+                provider.key_provider.load_private_key(key)?
+            }
+            KeySource::Remote(key) => key.clone(),
+        };
+        let not_after = not_after(certs.first().context("no certificate found")?)?;
         let certified_key = CertifiedKey::new(certs, key);
         trace!("got certs from files");
+        metrics::TLS_CERT_NOT_AFTER.set(not_after);
 
         Ok(certified_key)
     }
 
-    #[instrument]
-    pub fn new(cert_path: &str, key_path: &str) -> Result<Arc<Self>> {
+    #[instrument(skip(provider))]
+    pub fn new(
+        cert_path: &str,
+        key: KeySource,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<Self>> {
         let certified_key = Arc::new(ArcSwap::from_pointee(Self::load_certs_and_key(
-            cert_path, key_path,
+            cert_path, &key, &provider,
         )?));
 
         let cert_path = cert_path.to_string();
-        let key_path = key_path.to_string();
         Ok(Arc::new(Self {
             cert_path,
-            key_path,
+            key,
+            provider,
             certified_key,
         }))
     }
@@ -67,16 +166,23 @@ impl CertificateResolver {
     #[instrument(skip_all)]
     pub async fn refresh(&self) -> Result<()> {
         trace!("refreshing certificates");
-        let certified_key = Self::load_certs_and_key(&self.cert_path, &self.key_path)?;
+        let certified_key = Self::load_certs_and_key(&self.cert_path, &self.key, &self.provider)?;
 
         self.certified_key.store(Arc::new(certified_key));
         Ok(())
     }
 }
 
+/// Expiry of a certificate as a Unix timestamp.
+pub fn not_after(cert: &CertificateDer) -> Result<i64> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| anyhow::anyhow!("could not parse certificate: {}", e))?;
+    Ok(cert.validity().not_after.timestamp())
+}
+
 impl ResolvesServerCert for CertificateResolver {
     #[instrument(skip_all)]
-    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
         trace!("loading certificate");
         Some(arc_swap::Guard::into_inner(self.certified_key.load()))
     }
@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use aws_sdk_kms::primitives::Blob;
+use aws_sdk_kms::types::{KeySpec, MessageType, SigningAlgorithmSpec};
+use aws_sdk_kms::Client;
+use tokio::runtime::Handle;
+use tokio_rustls::rustls::sign::{Signer, SigningKey};
+use tokio_rustls::rustls::{Error, SignatureAlgorithm, SignatureScheme};
+use tracing::{error, info, instrument};
+
+/// TLS signature schemes and the KMS signing algorithms implementing them, in order of
+/// preference.
+const SCHEMES: &[(SignatureScheme, SigningAlgorithmSpec)] = &[
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SigningAlgorithmSpec::EcdsaSha384,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SigningAlgorithmSpec::EcdsaSha256,
+    ),
+    (
+        SignatureScheme::RSA_PSS_SHA512,
+        SigningAlgorithmSpec::RsassaPssSha512,
+    ),
+    (
+        SignatureScheme::RSA_PSS_SHA384,
+        SigningAlgorithmSpec::RsassaPssSha384,
+    ),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        SigningAlgorithmSpec::RsassaPssSha256,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA512,
+        SigningAlgorithmSpec::RsassaPkcs1V15Sha512,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA384,
+        SigningAlgorithmSpec::RsassaPkcs1V15Sha384,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        SigningAlgorithmSpec::RsassaPkcs1V15Sha256,
+    ),
+];
+
+/// Signs TLS handshakes with an asymmetric AWS KMS key, so the private key never exists
+/// in the container. CloudHSM backed keys are used via a KMS custom key store.
+#[derive(Debug)]
+pub struct KmsSigningKey {
+    client: Client,
+    key_id: String,
+    algorithm: SignatureAlgorithm,
+    /// signing algorithms the key supports
+    algorithms: Vec<SigningAlgorithmSpec>,
+}
+
+impl KmsSigningKey {
+    #[instrument(skip(config))]
+    pub async fn new(config: &aws_config::SdkConfig, key_id: &str) -> Result<Self> {
+        let client = Client::new(config);
+        let public_key = client
+            .get_public_key()
+            .key_id(key_id)
+            .send()
+            .await
+            .with_context(|| format!("could not get KMS key {}", key_id))?;
+        let algorithm = match public_key.key_spec().context("KMS key has no key spec")? {
+            KeySpec::Rsa2048 | KeySpec::Rsa3072 | KeySpec::Rsa4096 => SignatureAlgorithm::RSA,
+            KeySpec::EccNistP256 | KeySpec::EccNistP384 => SignatureAlgorithm::ECDSA,
+            other => anyhow::bail!("KMS key {} has unsupported key spec {:?}", key_id, other),
+        };
+        let algorithms = public_key.signing_algorithms().unwrap_or_default().to_vec();
+        info!("signing TLS handshakes with KMS key {}", key_id);
+        Ok(Self {
+            client,
+            key_id: key_id.to_string(),
+            algorithm,
+            algorithms,
+        })
+    }
+}
+
+impl SigningKey for KmsSigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let (scheme, algorithm) = SCHEMES.iter().find(|(scheme, algorithm)| {
+            offered.contains(scheme) && self.algorithms.contains(algorithm)
+        })?;
+        Some(Box::new(KmsSigner {
+            client: self.client.clone(),
+            key_id: self.key_id.clone(),
+            scheme: *scheme,
+            algorithm: algorithm.clone(),
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+}
+
+#[derive(Debug)]
+struct KmsSigner {
+    client: Client,
+    key_id: String,
+    scheme: SignatureScheme,
+    algorithm: SigningAlgorithmSpec,
+}
+
+impl Signer for KmsSigner {
+    /// Blocks the handshake's worker thread for the KMS request.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let request = self
+            .client
+            .sign()
+            .key_id(&self.key_id)
+            .message(Blob::new(message))
+            .message_type(MessageType::Raw)
+            .signing_algorithm(self.algorithm.clone());
+        let response = tokio::task::block_in_place(|| Handle::current().block_on(request.send()));
+        let response = response.map_err(|e| {
+            error!("could not sign with KMS key {}: {:?}", self.key_id, e);
+            Error::General("KMS signing failed".to_string())
+        })?;
+        let signature = response
+            .signature()
+            .ok_or_else(|| Error::General("KMS returned no signature".to_string()))?;
+        Ok(signature.as_ref().to_vec())
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
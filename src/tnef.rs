@@ -0,0 +1,139 @@
+use anyhow::{bail, Context, Result};
+use mail_parser::{MessagePart, MimeHeaders};
+
+const TNEF_SIGNATURE: u32 = 0x223e_9f78;
+const LEVEL_ATTACHMENT: u8 = 2;
+/// starts a new attachment
+const ATT_ATTACH_REND_DATA: u16 = 0x9002;
+const ATT_ATTACH_TITLE: u16 = 0x8010;
+const ATT_ATTACH_DATA: u16 = 0x800f;
+
+/// A file wrapped in a TNEF container.
+#[derive(Debug, Default)]
+pub struct TnefAttachment {
+    /// `attachment-N` if the container has no title for it
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Whether the part is a TNEF container, usually called `winmail.dat`.
+pub fn is_tnef(part: &MessagePart) -> bool {
+    let content_type = part.content_type().is_some_and(|ct| {
+        ct.ctype().eq_ignore_ascii_case("application")
+            && ct.subtype().is_some_and(|s| {
+                s.eq_ignore_ascii_case("ms-tnef") || s.eq_ignore_ascii_case("vnd.ms-tnef")
+            })
+    });
+    content_type
+        || part
+            .attachment_name()
+            .is_some_and(|name| name.eq_ignore_ascii_case("winmail.dat"))
+}
+
+/// Read `N` bytes at `pos` and advance it.
+fn take<const N: usize>(data: &[u8], pos: &mut usize) -> Result<[u8; N]> {
+    let bytes = data.get(*pos..*pos + N).context("truncated TNEF data")?;
+    *pos += N;
+    Ok(bytes.try_into()?)
+}
+
+/// The attachments of a TNEF container (MS-OXTNEF), the message attributes are ignored.
+pub fn attachments(data: &[u8]) -> Result<Vec<TnefAttachment>> {
+    let mut attachments = attachment_attributes(data)?;
+    for (ix, attachment) in attachments.iter_mut().enumerate() {
+        if attachment.name.is_empty() {
+            attachment.name = format!("attachment-{}", ix + 1);
+        }
+    }
+    Ok(attachments)
+}
+
+fn attachment_attributes(data: &[u8]) -> Result<Vec<TnefAttachment>> {
+    let mut pos = 0;
+    if u32::from_le_bytes(take(data, &mut pos)?) != TNEF_SIGNATURE {
+        bail!("not a TNEF container");
+    }
+    // legacy key
+    take::<2>(data, &mut pos)?;
+
+    let mut attachments: Vec<TnefAttachment> = vec![];
+    while pos < data.len() {
+        let [level] = take(data, &mut pos)?;
+        let attribute = u32::from_le_bytes(take(data, &mut pos)?);
+        let length = u32::from_le_bytes(take(data, &mut pos)?) as usize;
+        let value = data
+            .get(pos..pos + length)
+            .context("truncated TNEF attribute")?;
+        pos += length;
+        // checksum
+        take::<2>(data, &mut pos)?;
+
+        if level != LEVEL_ATTACHMENT {
+            continue;
+        }
+        match (attribute & 0xffff) as u16 {
+            ATT_ATTACH_REND_DATA => attachments.push(TnefAttachment::default()),
+            ATT_ATTACH_TITLE => {
+                let name = value.split(|b| *b == 0).next().unwrap_or_default();
+                if let Some(attachment) = attachments.last_mut() {
+                    attachment.name = String::from_utf8_lossy(name).into_owned();
+                }
+            }
+            ATT_ATTACH_DATA => {
+                if let Some(attachment) = attachments.last_mut() {
+                    attachment.data = value.to_vec();
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(tnef: &mut Vec<u8>, id: u16, value: &[u8]) {
+        tnef.push(LEVEL_ATTACHMENT);
+        tnef.extend_from_slice(&(id as u32).to_le_bytes());
+        tnef.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        tnef.extend_from_slice(value);
+        // checksums are not verified
+        tnef.extend_from_slice(&[0, 0]);
+    }
+
+    #[test]
+    fn attachment_names() {
+        let mut tnef = TNEF_SIGNATURE.to_le_bytes().to_vec();
+        tnef.extend_from_slice(&[1, 0]);
+        attribute(&mut tnef, ATT_ATTACH_REND_DATA, &[0; 14]);
+        attribute(&mut tnef, ATT_ATTACH_TITLE, b"report.txt\0");
+        attribute(&mut tnef, ATT_ATTACH_DATA, b"first");
+        attribute(&mut tnef, ATT_ATTACH_REND_DATA, &[0; 14]);
+        attribute(&mut tnef, ATT_ATTACH_DATA, b"second");
+
+        let attachments = attachments(&tnef).unwrap();
+        let files: Vec<_> = attachments
+            .iter()
+            .map(|a| (a.name.as_str(), a.data.as_slice()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("report.txt", &b"first"[..]),
+                ("attachment-2", &b"second"[..])
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let mut tnef = TNEF_SIGNATURE.to_le_bytes().to_vec();
+        tnef.extend_from_slice(&[1, 0]);
+        attribute(&mut tnef, ATT_ATTACH_DATA, b"data");
+        tnef.truncate(tnef.len() - 4);
+        assert!(attachments(&tnef).is_err());
+        assert!(attachments(b"winmail").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use mail_parser::Message;
+use serde::Serialize;
+
+/// The clauses of a `Received` header (RFC 5321 section 4.4).
+#[derive(Debug, Default, Serialize)]
+pub struct Received {
+    pub from: Option<String>,
+    /// address literal of the `from` clause or its comment
+    pub from_ip: Option<IpAddr>,
+    pub by: Option<String>,
+    pub via: Option<String>,
+    pub with: Option<String>,
+    pub id: Option<String>,
+    #[serde(rename = "for")]
+    pub for_: Option<String>,
+    pub date: Option<String>,
+}
+
+/// The client that submitted the message.
+#[derive(Clone, Debug, Serialize)]
+pub struct Origin {
+    pub ip: IpAddr,
+    /// HELO name
+    pub host: Option<String>,
+}
+
+/// The first address literal like `[192.0.2.1]` or `[IPv6:2001:db8::1]` in `s`.
+fn address_literal(s: &str) -> Option<IpAddr> {
+    let start = s.find('[')? + 1;
+    let end = start + s[start..].find(']')?;
+    let literal = &s[start..end];
+    literal
+        .strip_prefix("IPv6:")
+        .unwrap_or(literal)
+        .parse()
+        .ok()
+}
+
+/// Parse a `Received` header value leniently, clauses that are not understood are ignored.
+pub fn parse_received(value: &str) -> Received {
+    let (clauses, date) = match value.rsplit_once(';') {
+        Some((clauses, date)) => {
+            let date = date.split_whitespace().collect::<Vec<_>>().join(" ");
+            (clauses, Some(date))
+        }
+        None => (value, None),
+    };
+    let mut received = Received {
+        date,
+        ..Default::default()
+    };
+
+    let mut clause = None;
+    let mut depth = 0usize;
+    for word in clauses.split_whitespace() {
+        let in_comment = depth > 0 || word.starts_with('(');
+        depth = (depth + word.matches('(').count()).saturating_sub(word.matches(')').count());
+        if in_comment {
+            if clause.as_deref() == Some("from") && received.from_ip.is_none() {
+                received.from_ip = address_literal(word);
+            }
+            continue;
+        }
+
+        let keyword = word.to_lowercase();
+        if matches!(
+            keyword.as_str(),
+            "from" | "by" | "via" | "with" | "id" | "for"
+        ) {
+            clause = Some(keyword);
+            continue;
+        }
+        let field = match clause.as_deref() {
+            Some("from") => {
+                if received.from_ip.is_none() {
+                    received.from_ip = address_literal(word);
+                }
+                &mut received.from
+            }
+            Some("by") => &mut received.by,
+            Some("via") => &mut received.via,
+            Some("with") => &mut received.with,
+            Some("id") => &mut received.id,
+            Some(_) => &mut received.for_,
+            None => continue,
+        };
+        if field.is_none() {
+            *field = Some(word.trim_matches(|c| c == '<' || c == '>').to_string());
+        }
+    }
+    received
+}
+
+/// The parsed `Received` headers of the message, the most recent first.
+pub fn chain(message: &Message) -> Vec<Received> {
+    message
+        .headers_raw()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Received"))
+        .map(|(_, value)| parse_received(value))
+        .collect()
+}
+
+/// The originating client, from the most recent hop whose client is not a trusted relay.
+/// When all clients are trusted, the oldest one is returned.
+pub fn origin(chain: &[Received], trusted: &[IpNet]) -> Option<Origin> {
+    let mut origin = None;
+    for hop in chain {
+        let Some(ip) = hop.from_ip else {
+            continue;
+        };
+        origin = Some(Origin {
+            ip,
+            host: hop.from.clone(),
+        });
+        if !trusted.iter().any(|net| net.contains(&ip)) {
+            break;
+        }
+    }
+    origin
+}
@@ -0,0 +1,173 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, info, instrument};
+
+use crate::s3;
+use crate::smtp::Config;
+
+/// Lines are truncated to this many bytes.
+const MAX_LINE: usize = 512;
+/// Lines after this many are only counted.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    /// milliseconds since the connection was accepted
+    ms: u128,
+    /// `C` for the client, `S` for the server, `E` for errors
+    dir: &'static str,
+    line: String,
+}
+
+/// Commands and replies of one connection. Message content, SASL responses and `AUTH`
+/// arguments are left out.
+#[derive(Debug)]
+pub struct Transcript {
+    peer: SocketAddr,
+    started: DateTime<Utc>,
+    start: Instant,
+    entries: Vec<Entry>,
+    dropped: usize,
+    /// incomplete reply line
+    reply: Vec<u8>,
+    error: bool,
+    /// the server replied with a 4xx or 5xx code
+    rejected: bool,
+}
+
+/// Shared by the connection that sees the commands and the task handling it.
+pub type SharedTranscript = Arc<Mutex<Transcript>>;
+
+impl Transcript {
+    pub fn new(peer: SocketAddr) -> SharedTranscript {
+        Arc::new(Mutex::new(Self {
+            peer,
+            started: Utc::now(),
+            start: Instant::now(),
+            entries: vec![],
+            dropped: 0,
+            reply: vec![],
+            error: false,
+            rejected: false,
+        }))
+    }
+
+    fn push(&mut self, dir: &'static str, line: &str) {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.dropped += 1;
+            return;
+        }
+        let mut end = line.len().min(MAX_LINE);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.entries.push(Entry {
+            ms: self.start.elapsed().as_millis(),
+            dir,
+            line: line[..end].to_string(),
+        });
+    }
+
+    /// Record a line sent by the client.
+    pub fn client(&mut self, line: &str) {
+        self.push("C", line);
+    }
+
+    /// Record bytes written to the client, split into reply lines.
+    pub fn server(&mut self, bytes: &[u8]) {
+        self.reply.extend_from_slice(bytes);
+        while let Some(end) = self.reply.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.reply.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            self.rejected |= line.starts_with('4') || line.starts_with('5');
+            self.push("S", line);
+        }
+        // e.g. the message content of relayed or looped back mail
+        if self.reply.len() > MAX_LINE {
+            self.reply.clear();
+        }
+    }
+
+    /// Record why the session failed.
+    pub fn error(&mut self, error: impl Display) {
+        self.error = true;
+        self.push("E", &error.to_string());
+    }
+
+    /// A header line with the peer, followed by a line per entry.
+    fn to_jsonl(&self) -> Result<Vec<u8>> {
+        let mut out = serde_json::to_vec(&json!({
+            "peer": self.peer,
+            "started": self.started,
+            "dropped": self.dropped,
+        }))?;
+        out.push(b'\n');
+        for entry in &self.entries {
+            serde_json::to_writer(&mut out, entry)?;
+            out.push(b'\n');
+        }
+        Ok(out)
+    }
+}
+
+/// Where transcripts are written.
+#[derive(Debug)]
+pub enum Sink {
+    Dir(PathBuf),
+    /// objects below this prefix of the bucket
+    S3(String),
+}
+
+/// Writes the transcripts of failed sessions, to debug problems with sending MTAs.
+#[derive(Debug)]
+pub struct Transcripts {
+    pub sink: Sink,
+    /// also write transcripts of sessions with 4xx or 5xx replies
+    pub on_reject: bool,
+}
+
+impl Transcripts {
+    /// Write `transcript` if the session failed.
+    #[instrument(skip_all)]
+    pub async fn finish(&self, config: &Config, transcript: &SharedTranscript) {
+        let (name, body) = {
+            let transcript = transcript.lock().unwrap();
+            if !(transcript.error || self.on_reject && transcript.rejected) {
+                return;
+            }
+            let name = format!(
+                "{}-{}.jsonl",
+                transcript.started.format("%Y%m%dT%H%M%S%.6fZ"),
+                transcript.peer.ip()
+            );
+            match transcript.to_jsonl() {
+                Ok(body) => (name, body),
+                Err(e) => {
+                    error!("could not serialize transcript: {:?}", e);
+                    return;
+                }
+            }
+        };
+        let result = match &self.sink {
+            Sink::Dir(dir) => tokio::fs::write(dir.join(&name), body)
+                .await
+                .map_err(anyhow::Error::from),
+            Sink::S3(prefix) => {
+                s3::upload_transcript(config, format!("{}{}", prefix, name), body).await
+            }
+        };
+        match result {
+            Ok(()) => info!("wrote session transcript {}", name),
+            Err(e) => error!("could not write session transcript {}: {:?}", name, e),
+        }
+    }
+}
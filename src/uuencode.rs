@@ -0,0 +1,140 @@
+use std::ops::Range;
+
+/// A file uuencoded within a text body, as old systems still send them.
+#[derive(Debug)]
+pub struct UuFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// The files uuencoded in `text`, between `begin <mode> <name>` and `end` lines.
+pub fn files(text: &str) -> Vec<UuFile> {
+    blocks(text).into_iter().map(|(_, file)| file).collect()
+}
+
+/// `text` without its uuencoded files, `None` if there are none.
+pub fn strip(text: &str) -> Option<String> {
+    let blocks = blocks(text);
+    if blocks.is_empty() {
+        return None;
+    }
+    let mut stripped = String::with_capacity(text.len());
+    let mut pos = 0;
+    for (range, _) in blocks {
+        stripped.push_str(&text[pos..range.start]);
+        pos = range.end;
+    }
+    stripped.push_str(&text[pos..]);
+    Some(stripped)
+}
+
+/// The uuencoded files and where they are in `text`, including their `begin` and `end`
+/// lines.
+fn blocks(text: &str) -> Vec<(Range<usize>, UuFile)> {
+    let mut lines = vec![];
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        lines.push((pos, line));
+        pos += line.len();
+    }
+
+    let mut blocks = vec![];
+    let mut ix = 0;
+    while ix < lines.len() {
+        let (start, line) = lines[ix];
+        ix += 1;
+        let Some(name) = begin(line) else {
+            continue;
+        };
+        let mut data = vec![];
+        for (offset, &(line_start, line)) in lines[ix..].iter().enumerate() {
+            if line.trim_end() == "end" {
+                blocks.push((
+                    start..line_start + line.len(),
+                    UuFile {
+                        name: name.to_string(),
+                        data,
+                    },
+                ));
+                ix += offset + 1;
+                break;
+            }
+            // not uuencoded after all, scanning continues after the `begin` line
+            let Some(decoded) = decode_line(line.trim_end()) else {
+                break;
+            };
+            data.extend(decoded);
+        }
+    }
+    blocks
+}
+
+/// The file name of a `begin` line, without any directories.
+fn begin(line: &str) -> Option<&str> {
+    let (mode, name) = line.trim_end().strip_prefix("begin ")?.split_once(' ')?;
+    if !(3..=4).contains(&mode.len()) || !mode.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return None;
+    }
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    (!name.is_empty()).then_some(name)
+}
+
+/// A line starts with its decoded length, followed by groups of 4 characters encoding 3
+/// bytes; both space and backtick encode 0, trailing spaces may have been removed.
+fn decode_line(line: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| (b' '..=b'`').contains(&c).then_some((c - b' ') & 0x3f);
+    let (&first, rest) = line.as_bytes().split_first()?;
+    let len = usize::from(value(first)?);
+    let mut decoded = Vec::with_capacity(len + 2);
+    for group in 0..len.div_ceil(3) {
+        let mut bits = 0u32;
+        for ix in 0..4 {
+            let c = rest.get(group * 4 + ix).copied().unwrap_or(b' ');
+            bits = (bits << 6) | u32::from(value(c)?);
+        }
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..]);
+    }
+    decoded.truncate(len);
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAT: &str = "Hello\r\nbegin 644 dir/cat.txt\r\n#0V%T\r\n`\r\nend\r\nBye\r\n";
+
+    #[test]
+    fn known_file() {
+        let blocks = blocks(CAT);
+        assert_eq!(blocks.len(), 1);
+        let (range, file) = &blocks[0];
+        assert_eq!(
+            &CAT[range.clone()],
+            "begin 644 dir/cat.txt\r\n#0V%T\r\n`\r\nend\r\n"
+        );
+        assert_eq!(file.name, "cat.txt");
+        assert_eq!(file.data, b"Cat");
+        assert_eq!(strip(CAT).as_deref(), Some("Hello\r\nBye\r\n"));
+    }
+
+    #[test]
+    fn decode_lines() {
+        assert_eq!(decode_line("#0V%T"), Some(b"Cat".to_vec()));
+        // trailing spaces removed
+        assert_eq!(decode_line("!80"), Some(b"a".to_vec()));
+        assert_eq!(decode_line("`"), Some(vec![]));
+        assert_eq!(decode_line("#0V%t"), None);
+        assert_eq!(decode_line(""), None);
+    }
+
+    #[test]
+    fn begin_without_end() {
+        let text = "begin 644 cat.txt\n#0V%T\n";
+        assert!(blocks(text).is_empty());
+        assert_eq!(strip(text), None);
+        // nor is text that only looks like a begin line
+        assert!(files("begin 644 cat.txt\nnot uuencoded\nend\n").is_empty());
+        assert!(files("begin here and now\n#0V%T\nend\n").is_empty());
+    }
+}
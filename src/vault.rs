@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::postgres::{PgConnectOptions, PgPool};
+use tracing::{error, info, instrument, trace};
+
+const KUBERNETES_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+/// fetch new credentials instead of renewing leases shorter than this
+const MIN_LEASE: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Auth {
+    Token(String),
+    /// role of the Kubernetes auth method, logging in with the service account's token
+    Kubernetes {
+        mount: String,
+        role: String,
+    },
+}
+
+/// Client for the parts of the Vault HTTP API needed for dynamic DB credentials.
+pub struct Vault {
+    client: reqwest::Client,
+    addr: String,
+    auth: Auth,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lease<T> {
+    lease_id: String,
+    lease_duration: u64,
+    renewable: bool,
+    data: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
+impl Vault {
+    pub fn new(addr: &str, auth: Auth) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            addr: addr.trim_end_matches('/').to_string(),
+            auth,
+        }
+    }
+
+    /// A token for the next request, Kubernetes logins are repeated as they are rare.
+    async fn token(&self) -> Result<String> {
+        let (mount, role) = match &self.auth {
+            Auth::Token(token) => return Ok(token.clone()),
+            Auth::Kubernetes { mount, role } => (mount, role),
+        };
+        let jwt = std::fs::read_to_string(KUBERNETES_TOKEN_PATH)
+            .context("could not read service account token")?;
+        let response: LoginResponse = self
+            .client
+            .post(format!("{}/v1/auth/{}/login", self.addr, mount))
+            .json(&json!({ "role": role, "jwt": jwt.trim() }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.auth.client_token)
+    }
+
+    #[instrument(skip(self))]
+    async fn credentials(&self, path: &str) -> Result<Lease<Credentials>> {
+        trace!("fetching DB credentials");
+        let lease = self
+            .client
+            .get(format!("{}/v1/{}", self.addr, path))
+            .header("X-Vault-Token", self.token().await?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("could not parse DB credentials")?;
+        Ok(lease)
+    }
+
+    /// Extend the lease, returns its new duration.
+    #[instrument(skip(self))]
+    async fn renew(&self, lease_id: &str, increment: u64) -> Result<u64> {
+        trace!("renewing lease");
+        let lease: Lease<Option<serde_json::Value>> = self
+            .client
+            .put(format!("{}/v1/sys/leases/renew", self.addr))
+            .header("X-Vault-Token", self.token().await?)
+            .json(&json!({ "lease_id": lease_id, "increment": increment }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(lease.lease_duration)
+    }
+
+    /// Connect to the DB with credentials from `path`, e.g. `database/creds/smtp-s3-dump`,
+    /// and keep them valid in the background. When a lease cannot be renewed anymore, new
+    /// connections use new credentials, established ones are kept.
+    #[instrument(skip(self, options))]
+    pub async fn connect(
+        self,
+        path: String,
+        options: PgConnectOptions,
+        max_connections: u32,
+    ) -> Result<PgPool> {
+        let lease = self.credentials(&path).await?;
+        info!("got DB credentials for {}", lease.data.username);
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(with_credentials(&options, &lease.data))
+            .await?;
+        // credentials without lease never expire
+        if lease.lease_duration > 0 {
+            tokio::spawn(self.keep_credentials(path, options, pool.clone(), lease));
+        }
+        Ok(pool)
+    }
+
+    async fn keep_credentials(
+        self,
+        path: String,
+        options: PgConnectOptions,
+        pool: PgPool,
+        mut lease: Lease<Credentials>,
+    ) {
+        let mut wait = renewal_interval(lease.lease_duration);
+        loop {
+            tokio::time::sleep(wait).await;
+
+            if lease.renewable {
+                match self.renew(&lease.lease_id, lease.lease_duration).await {
+                    Ok(renewed) if Duration::from_secs(renewed) >= MIN_LEASE => {
+                        wait = renewal_interval(renewed);
+                        continue;
+                    }
+                    Ok(_) => trace!("lease is about to expire"),
+                    Err(e) => error!("could not renew lease: {:?}", e),
+                }
+            }
+
+            match self.credentials(&path).await {
+                Ok(new_lease) => {
+                    info!("rotated DB credentials to {}", new_lease.data.username);
+                    pool.set_connect_options(with_credentials(&options, &new_lease.data));
+                    wait = renewal_interval(new_lease.lease_duration);
+                    lease = new_lease;
+                }
+                Err(e) => {
+                    error!("could not fetch DB credentials: {:?}", e);
+                    wait = RETRY_INTERVAL;
+                }
+            }
+        }
+    }
+}
+
+/// Renew after two thirds of the lease, but not too often.
+fn renewal_interval(lease_duration: u64) -> Duration {
+    (Duration::from_secs(lease_duration) * 2 / 3).max(RETRY_INTERVAL)
+}
+
+fn with_credentials(options: &PgConnectOptions, credentials: &Credentials) -> PgConnectOptions {
+    options
+        .clone()
+        .username(&credentials.username)
+        .password(&credentials.password)
+}
@@ -0,0 +1,171 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tracing::{info, warn};
+
+use crate::dsn;
+
+/// Attributes accepted with `XCLIENT`, see Postfix' `XCLIENT_README`.
+const XCLIENT_ATTRIBUTES: &[&str] = &[
+    "NAME", "ADDR", "PORT", "PROTO", "HELO", "LOGIN", "DESTADDR", "DESTPORT",
+];
+/// Attributes accepted with `XFORWARD`, see Postfix' `XFORWARD_README`.
+const XFORWARD_ATTRIBUTES: &[&str] = &["NAME", "ADDR", "PORT", "PROTO", "HELO", "IDENT", "SOURCE"];
+
+/// The original client as told by a proxy.
+#[derive(Debug, Default, Clone)]
+pub struct Client {
+    pub addr: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// reverse DNS name
+    pub name: Option<String>,
+    pub helo: Option<String>,
+}
+
+/// Client attributes forwarded by a trusted proxy. `XCLIENT` attributes apply to the rest
+/// of the session, `XFORWARD` ones to the current transaction and take precedence.
+#[derive(Debug, Default)]
+pub struct Forwarding {
+    xclient: Option<Client>,
+    xforward: Option<Client>,
+    /// after MAIL, `XCLIENT` is refused then
+    transaction: bool,
+    /// `XCLIENT` started the session over, the session has not forgotten its HELO yet
+    restarted: bool,
+}
+
+/// Shared by the session and the connection that intercepts the commands.
+pub type SharedForwarding = Arc<Mutex<Forwarding>>;
+
+impl Forwarding {
+    fn get<T>(&self, f: impl Fn(&Client) -> Option<T>) -> Option<T> {
+        self.xforward
+            .as_ref()
+            .and_then(&f)
+            .or_else(|| self.xclient.as_ref().and_then(&f))
+    }
+
+    pub fn addr(&self) -> Option<IpAddr> {
+        self.get(|c| c.addr)
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.get(|c| c.port)
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.get(|c| c.name.clone())
+    }
+
+    pub fn helo(&self) -> Option<String> {
+        self.get(|c| c.helo.clone())
+    }
+
+    pub fn start_transaction(&mut self) {
+        self.transaction = true;
+    }
+
+    /// Forget the `XFORWARD` attributes at the end of a transaction.
+    pub fn end_transaction(&mut self) {
+        self.transaction = false;
+        self.xforward = None;
+    }
+
+    /// Whether `XCLIENT` started the session over since the last call.
+    pub fn take_restart(&mut self) -> bool {
+        std::mem::take(&mut self.restarted)
+    }
+}
+
+/// Handles `XCLIENT` and `XFORWARD` commands of a trusted proxy.
+#[derive(Debug)]
+pub struct Proxy {
+    forwarding: SharedForwarding,
+    /// sent after `XCLIENT`, as the session starts over
+    greeting: String,
+}
+
+impl Proxy {
+    pub fn new(forwarding: SharedForwarding, greeting: String) -> Self {
+        Self {
+            forwarding,
+            greeting,
+        }
+    }
+
+    /// Apply an `XCLIENT` or `XFORWARD` command, returns the reply.
+    pub fn handle(&self, verb: &str, args: &str) -> String {
+        let xclient = verb == "XCLIENT";
+        let allowed = match xclient {
+            true => XCLIENT_ATTRIBUTES,
+            false => XFORWARD_ATTRIBUTES,
+        };
+        let mut forwarding = self.forwarding.lock().unwrap();
+        // like Postfix, the client cannot change in the middle of a transaction
+        if xclient && forwarding.transaction {
+            warn!("rejected {} in a transaction", verb);
+            return "503 5.5.1 mail transaction in progress\r\n".to_string();
+        }
+        let current = match xclient {
+            true => &mut forwarding.xclient,
+            false => &mut forwarding.xforward,
+        };
+        let mut client = current.clone().unwrap_or_default();
+        if let Err(e) = update(&mut client, args, allowed) {
+            warn!("rejected {}: {}", verb, e);
+            return format!("501 5.5.4 {}\r\n", e);
+        }
+        info!(
+            "{} from {:?} ({:?}, HELO {:?})",
+            verb, client.addr, client.name, client.helo
+        );
+        *current = Some(client);
+        forwarding.restarted |= xclient;
+        match xclient {
+            true => format!("220 {}\r\n", self.greeting),
+            false => "250 2.0.0 Ok\r\n".to_string(),
+        }
+    }
+}
+
+/// Set the `attribute=value` pairs of `args` in `client`.
+fn update(client: &mut Client, args: &str, allowed: &[&str]) -> Result<(), String> {
+    let pairs: Vec<_> = args.split_ascii_whitespace().collect();
+    if pairs.is_empty() {
+        return Err("missing attributes".to_string());
+    }
+    for pair in pairs {
+        let (attribute, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("bad attribute {}", pair))?;
+        let attribute = attribute.to_ascii_uppercase();
+        if !allowed.contains(&attribute.as_str()) {
+            return Err(format!("bad attribute {}", attribute));
+        }
+        let value = dsn::decode_xtext(value).ok_or_else(|| format!("bad {} value", attribute))?;
+        // the proxy does not know the value
+        let value = match value.as_str() {
+            "[UNAVAILABLE]" | "[TEMPUNAVAIL]" => None,
+            _ => Some(value),
+        };
+        match attribute.as_str() {
+            "ADDR" => {
+                client.addr = value
+                    .map(|a| a.to_ascii_lowercase().trim_start_matches("ipv6:").parse())
+                    .transpose()
+                    .map_err(|_| "bad ADDR value".to_string())?
+            }
+            "PORT" => {
+                client.port = value
+                    .map(|p| p.parse())
+                    .transpose()
+                    .map_err(|_| "bad PORT value".to_string())?
+            }
+            "NAME" => client.name = value,
+            "HELO" => client.helo = value,
+            // the protocol, login, destination and the proxy's own client are not recorded
+            _ => {}
+        }
+    }
+    Ok(())
+}